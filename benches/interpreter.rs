@@ -0,0 +1,86 @@
+//! Measures interpreter throughput in instructions per second, decoding
+//! every instruction as it runs versus going through the decoded
+//! instruction cache used by `CPU::cycle`, and of the same ROMs recompiled
+//! to wasm. Recompiled code is run by wasmi, itself an interpreter, so this
+//! is a lower bound on what a browser's JIT would get out of it.
+//!
+//! ```text
+//! cargo bench --bench interpreter
+//! ```
+extern crate chip8;
+extern crate wasmi;
+
+#[path = "../tests/engine/mod.rs"]
+mod engine;
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chip8::cpu::CPU;
+use chip8::recompiler::{compile, run_cycles};
+
+use engine::Wasmi;
+
+const INSTRUCTIONS: u32 = 2_000_000;
+const ROMS: [&str; 4] = ["BRIX", "INVADERS", "PONG", "TETRIS"];
+
+fn cpu_for(rom: &[u8]) -> CPU {
+    let mut cpu = CPU::new();
+    cpu.seed(1);
+    cpu.reset();
+    cpu.load_rom(rom).unwrap();
+    cpu
+}
+
+/// Run `INSTRUCTIONS` instructions, decrementing the timers every 10 like
+/// the web page does
+fn measure<F: FnMut(&mut CPU)>(rom: &[u8], mut step: F) -> Duration {
+    let mut cpu = cpu_for(rom);
+    let start = Instant::now();
+    for i in 0..INSTRUCTIONS {
+        step(&mut cpu);
+        if i % 10 == 9 {
+            cpu.decrement_timers();
+        }
+    }
+    start.elapsed()
+}
+
+/// Run `INSTRUCTIONS` instructions of the recompiled ROM. The machine state
+/// is copied in and out of the module on every call, so this runs many
+/// instructions per call to measure the compiled code rather than the
+/// copying, and decrements the timers less often than `measure`
+fn measure_recompiled(rom: &[u8]) -> Duration {
+    const CYCLES_PER_CALL: usize = 10_000;
+    let mut cpu = cpu_for(rom);
+    let mut engine = Wasmi::new(&compile(rom).unwrap().wasm);
+    let start = Instant::now();
+    for _ in 0..INSTRUCTIONS / CYCLES_PER_CALL as u32 {
+        run_cycles(&mut cpu, &mut engine, CYCLES_PER_CALL);
+        cpu.decrement_timers();
+    }
+    start.elapsed()
+}
+
+fn per_second(duration: Duration) -> f64 {
+    let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    INSTRUCTIONS as f64 / seconds
+}
+
+fn main() {
+    let roms = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms");
+    println!("{:<10} {:>16} {:>16} {:>8} {:>20}",
+             "rom", "decode (instr/s)", "cached (instr/s)", "speedup", "recompiled (instr/s)");
+    for name in ROMS.iter() {
+        let rom = fs::read(roms.join(name)).unwrap();
+        let uncached = per_second(measure(&rom, |cpu| {
+            let instruction = cpu.read_instruction();
+            cpu.process_instruction(instruction);
+        }));
+        let cached = per_second(measure(&rom, |cpu| cpu.cycle()));
+        let recompiled = per_second(measure_recompiled(&rom));
+        println!("{:<10} {:>16.0} {:>16.0} {:>7.2}x {:>20.0}",
+                 name, uncached, cached, cached / uncached, recompiled);
+    }
+}
@@ -0,0 +1,130 @@
+import { Machines } from './machines';
+import { ROMS } from './roms';
+
+// the quirks each machine starts out with, by chip-8-database name
+const PROFILES = [
+  {
+    name: 'COSMAC VIP',
+    quirks: { shift: false, memoryIncrementByX: false, memoryLeaveIUnchanged: false, wrap: false, jump: false, logic: true },
+  },
+  {
+    name: 'SUPER-CHIP',
+    quirks: { shift: true, memoryIncrementByX: false, memoryLeaveIUnchanged: true, wrap: false, jump: true, logic: false },
+  },
+];
+
+// keypad key for each key of the 1234/QWER/ASDF/ZXCV block
+const KEYS = {
+  Digit1: 0x1, Digit2: 0x2, Digit3: 0x3, Digit4: 0xC,
+  KeyQ: 0x4, KeyW: 0x5, KeyE: 0x6, KeyR: 0xD,
+  KeyA: 0x7, KeyS: 0x8, KeyD: 0x9, KeyF: 0xE,
+  KeyZ: 0xA, KeyX: 0x0, KeyC: 0xB, KeyV: 0xF,
+};
+
+const run = async () => {
+
+const machines = new Machines();
+const container = document.getElementById('machines');
+const romSelector = document.getElementById('roms');
+
+// keys held, a bit per key, sent to every machine
+let keys = 0;
+let loaded = false;
+
+// a canvas, quirk checkboxes and status line for each machine
+const createPanel = async (profile) => {
+  const panel = { machine: await machines.create(0) };
+  const element = document.createElement('div');
+  const title = document.createElement('h3');
+  title.textContent = profile.name;
+  const canvas = document.createElement('canvas');
+  canvas.width = 64;
+  canvas.height = 32;
+  panel.ctx = canvas.getContext('2d');
+  panel.status = document.createElement('p');
+  panel.checkboxes = {};
+  const options = document.createElement('div');
+  for (const [name, value] of Object.entries(profile.quirks)) {
+    const label = document.createElement('label');
+    const checkbox = document.createElement('input');
+    checkbox.type = 'checkbox';
+    checkbox.checked = value;
+    checkbox.addEventListener('change', () => configure(panel));
+    panel.checkboxes[name] = checkbox;
+    label.append(checkbox, name);
+    options.append(label, document.createElement('br'));
+  }
+  element.append(title, canvas, panel.status, options);
+  container.appendChild(element);
+  return panel;
+};
+
+const configure = (panel) => {
+  const quirks = {};
+  for (const [name, checkbox] of Object.entries(panel.checkboxes)) {
+    quirks[name] = checkbox.checked;
+  }
+  return panel.machine.configure({ quirks });
+};
+
+const draw = (panel) => {
+  const pixels = new Uint8ClampedArray(panel.machine.framebuffer);
+  panel.ctx.putImageData(new ImageData(pixels, 64, 32), 0, 0);
+};
+
+const panels = await Promise.all(PROFILES.map(createPanel));
+
+const loadRom = async (romName) => {
+  loaded = false;
+  const rom = new Uint8Array(
+    await fetch(`roms/${romName}`).then(resp => resp.arrayBuffer()));
+  // loading picks the ROM database's quirks, so the chosen ones are
+  // applied again afterwards
+  await Promise.all(panels.map(async panel => {
+    await panel.machine.load(rom);
+    await configure(panel);
+  }));
+  loaded = true;
+};
+
+// run a frame on every machine in the worker, then wait for the next
+// display refresh
+const loop = async () => {
+  if (loaded) {
+    await Promise.all(panels.map(async panel => {
+      const report = await panel.machine.run(1, keys);
+      const beeping = report.audio.some(beep => beep) ? ', beeping' : '';
+      panel.status.textContent = `frame ${report.frame}, ${report.status}${beeping}`;
+      if (report.drawn) {
+        draw(panel);
+      }
+    }));
+  }
+  requestAnimationFrame(loop);
+};
+
+ROMS.forEach(rom => {
+  const opt = document.createElement('option');
+  opt.value = rom;
+  opt.textContent = rom.toLowerCase();
+  romSelector.appendChild(opt);
+});
+romSelector.addEventListener('change', e => loadRom(e.target.value));
+
+document.addEventListener('keydown', event => {
+  if (event.code in KEYS) {
+    keys |= 1 << KEYS[event.code];
+  }
+});
+document.addEventListener('keyup', event => {
+  if (event.code in KEYS) {
+    keys &= ~(1 << KEYS[event.code]);
+  }
+});
+
+await loadRom(ROMS[0]);
+loop();
+
+}
+
+run();
@@ -0,0 +1,14 @@
+//! Runs random machine states and instruction streams through `CPU` and the
+//! reference model from `tests/reference`, checking that neither panics,
+//! that the CPU's invariants hold and that the two agree.
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate chip8;
+
+#[path = "../../tests/reference/mod.rs"]
+#[allow(dead_code)]
+mod reference;
+
+fuzz_target!(|data: &[u8]| {
+    reference::check(data);
+});
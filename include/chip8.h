@@ -0,0 +1,102 @@
+#ifndef CHIP8_H
+#define CHIP8_H
+
+/* Generated from src/capi.rs by cbindgen, run `cargo test --test header -- --bless` to update */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define CHIP8_SCREEN_WIDTH 64
+#define CHIP8_SCREEN_HEIGHT 32
+
+/**
+ * Result of a call, 0 on success
+ */
+typedef enum Chip8Status {
+  CHIP8_STATUS_OK = 0,
+  CHIP8_STATUS_NULL_POINTER,
+  CHIP8_STATUS_INVALID_ROM,
+  CHIP8_STATUS_INVALID_KEY,
+  CHIP8_STATUS_INVALID_STATE,
+  CHIP8_STATUS_BUFFER_TOO_SMALL,
+  /**
+   * the interpreter hit a bug; the machine should be freed
+   */
+  CHIP8_STATUS_PANIC,
+} Chip8Status;
+
+/**
+ * A machine with a ROM loaded, opaque to C
+ */
+typedef struct Chip8 Chip8;
+
+/**
+ * Create a machine with no ROM loaded, whose random numbers are seeded
+ * with `seed`. Free it with `chip8_free`
+ */
+struct Chip8 *chip8_new(uint32_t seed);
+
+void chip8_free(struct Chip8 *machine);
+
+/**
+ * Describe why the last call that failed did. The string belongs to the
+ * machine and lasts until the next failure
+ */
+const char *chip8_last_error(const struct Chip8 *machine);
+
+/**
+ * Reset the machine and load a ROM, with the quirks and speed the ROM
+ * database has for it. On failure the machine is left as it was
+ */
+enum Chip8Status chip8_load_rom(struct Chip8 *machine, const uint8_t *rom, size_t len);
+
+/**
+ * Execute a single instruction, without touching the timers
+ */
+enum Chip8Status chip8_step(struct Chip8 *machine);
+
+/**
+ * Run `frames` 60 Hz frames, each executing the ROM's instructions per
+ * frame and then decrementing the timers
+ */
+enum Chip8Status chip8_run_frames(struct Chip8 *machine, uint32_t frames);
+
+/**
+ * Press a key, 0 to F
+ */
+enum Chip8Status chip8_key_down(struct Chip8 *machine, uint8_t key);
+
+enum Chip8Status chip8_key_up(struct Chip8 *machine, uint8_t key);
+
+/**
+ * The 64x32 screen, one byte per pixel row by row from the top left, 1
+ * for set pixels and 0 for unset ones. The pointer stays valid until the
+ * machine is freed
+ */
+const uint8_t *chip8_framebuffer(const struct Chip8 *machine);
+
+/**
+ * Read the delay and sound timers. Either pointer may be NULL
+ */
+enum Chip8Status chip8_timers(struct Chip8 *machine, uint8_t *delay, uint8_t *sound);
+
+/**
+ * Bytes needed to save the state of a machine
+ */
+size_t chip8_state_size(void);
+
+/**
+ * Save the state of the machine into `buffer`, which must hold at least
+ * `chip8_state_size()` bytes
+ */
+enum Chip8Status chip8_save_state(struct Chip8 *machine, uint8_t *buffer, size_t len);
+
+/**
+ * Restore a state saved by `chip8_save_state`
+ */
+enum Chip8Status chip8_load_state(struct Chip8 *machine, const uint8_t *buffer, size_t len);
+
+#endif /* CHIP8_H */
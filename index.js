@@ -1,56 +1,10 @@
+import { ROMS } from './roms';
+
 const rust = import('./chip8');
 const wasm = import('./chip8_bg')
 
 const CELL_SIZE = 5;
 const GRID_COLOR = "#CCCCCC";
-const PIXEL_OFF_COLOR = "#FFFFFF";
-const PIXEL_ON_COLOR = "#000000";
-
-// maps key code to hex keypad index
-const KEYMAP = {
-  49: 0x1,
-  50: 0x2,
-  51: 0x3,
-  52: 0xc,
-  81: 0x4,
-  87: 0x5,
-  69: 0x6,
-  82: 0xd,
-  65: 0x7,
-  83: 0x8,
-  68: 0x9,
-  70: 0xe,
-  90: 0xa,
-  88: 0x0,
-  67: 0xb,
-  86: 0xf
-};
-
-const ROMS = [
-  '15PUZZLE',
-  'BLINKY',
-  'BLITZ',
-  'BRIX',
-  'CONNECT4',
-  'GUESS',
-  'HIDDEN',
-  'INVADERS',
-  'KALEID',
-  'MAZE',
-  'MERLIN',
-  'MISSILE',
-  'PONG',
-  'PONG2',
-  'PUZZLE',
-  'SYZYGY',
-  'TANK',
-  'TETRIS',
-  'TICTAC',
-  'UFO',
-  'VBRIX',
-  'VERS',
-  'WIPEOFF',
-]
 
 const run = async () => {
 
@@ -63,32 +17,230 @@ cpu.reset();
 const canvas = document.getElementById('chip8-canvas');
 const playPauseButton = document.getElementById('play-pause');
 const stepButton = document.getElementById('step');
+const frameButton = document.getElementById('frame');
+const memoryPanel = document.getElementById('memory');
+const spritePanel = document.getElementById('sprite');
+const pokeForm = document.getElementById('poke');
+const cheatList = document.getElementById('cheat-list');
+const achievementList = document.getElementById('achievement-list');
+const achievementToast = document.getElementById('achievement-toast');
+const searchResults = document.getElementById('search-results');
+const speedSelector = document.getElementById('speed');
 const romSelector = document.getElementById('roms')
+const keymapSelector = document.getElementById('keymap');
 const width = 64;
 const height = 32;
 canvas.height = (CELL_SIZE + 1) * height + 1;
 canvas.width = (CELL_SIZE + 1) * width + 1;
 const ctx = canvas.getContext('2d');
 
+// per-ROM colours from the ROM database or cartridge, updated by
+// applyRomSettings
+let pixelOnColor = cpu.pixel_color(true);
+let pixelOffColor = cpu.pixel_color(false);
+
 const loadRom = async (romName) => {
   pause();
-  cpu.reset();
   let rom = new Uint8Array(
     await fetch(`roms/${romName}`).then(resp => resp.arrayBuffer()));
-  cpu.load_rom(rom);
+  try {
+    cpu.load_rom(rom);
+  } catch (e) {
+    alert(`Could not load ${romName}: ${e}`);
+    return;
+  }
+  currentRom = rom;
+  applyRomSettings();
+}
+
+// the last plain ROM loaded, which dropped patches are applied to
+let currentRom = null;
+
+// pick up the colours, saved cheats and unlocked achievements for the ROM
+// that was just loaded. Its speed is applied by the CPU itself
+const applyRomSettings = () => {
+  restoreCheats();
+  restoreAchievements();
+  pixelOnColor = cpu.pixel_color(true);
+  pixelOffColor = cpu.pixel_color(false);
+  drawScreen();
 }
 
+// dropping a ROM file or an Octo cartridge GIF onto the page loads it.
+// Dropping an IPS or BPS patch applies it to the last ROM loaded
+const addDropListener = () => {
+  document.addEventListener('dragover', event => event.preventDefault());
+  document.addEventListener('drop', async event => {
+    event.preventDefault();
+    const file = event.dataTransfer.files[0];
+    if (!file) {
+      return;
+    }
+    pause();
+    const data = new Uint8Array(await file.arrayBuffer());
+    const magic = String.fromCharCode(...data.slice(0, 5));
+    try {
+      if (magic.startsWith('GIF8')) {
+        cpu.load_cartridge(data);
+      } else if (magic === 'PATCH' || magic.startsWith('BPS1')) {
+        if (!currentRom) {
+          throw 'load a ROM to apply the patch to first';
+        }
+        cpu.load_patched_rom(currentRom, data);
+      } else {
+        cpu.load_rom(data);
+        currentRom = data;
+      }
+    } catch (e) {
+      alert(`Could not load ${file.name}: ${e}`);
+      return;
+    }
+    applyRomSettings();
+  });
+}
+
+// key mapping is done by the CPU wrapper, which knows the layout and the
+// keys the loaded ROM uses
 const addKeyListeners = () => {
   document.addEventListener('keydown', event => {
-    if (KEYMAP.hasOwnProperty(event.keyCode)) {
-      cpu.key_down(KEYMAP[event.keyCode])
+    if (cpu.key_event(event.code, true)) {
+      event.preventDefault();
     }
   })
   document.addEventListener('keyup', event => {
-    if (KEYMAP.hasOwnProperty(event.keyCode)) {
-      cpu.key_up(KEYMAP[event.keyCode])
+    if (cpu.key_event(event.code, false)) {
+      event.preventDefault();
     }
   })
+  window.addEventListener('gamepaddisconnected', () => cpu.gamepad_disconnected());
+  keymapSelector.addEventListener("change", e => { cpu.set_keymap(e.target.value); });
+}
+
+// the memory around the program counter and the sprite at I, shown
+// alongside the screen
+const drawMemory = () => {
+  const start = Math.max(0, (cpu.pc() & 0xFF0) - 0x20);
+  memoryPanel.textContent = cpu.hex_dump(start, 0x60);
+  spritePanel.textContent = cpu.sprite_preview(15);
+}
+
+// write hex bytes such as "6A 02" to memory at a hex address
+const addPokeListener = () => {
+  pokeForm.addEventListener('submit', event => {
+    event.preventDefault();
+    const address = parseInt(document.getElementById('poke-address').value, 16);
+    const bytes = document.getElementById('poke-bytes').value.trim().split(/\s+/)
+      .map(byte => parseInt(byte, 16));
+    if (isNaN(address) || bytes.some(byte => isNaN(byte) || byte > 0xFF)) {
+      alert('Enter a hex address and hex bytes');
+      return;
+    }
+    cpu.write_memory(address, Uint8Array.from(bytes));
+    drawMemory();
+  });
+}
+
+// cheats are saved in local storage by the hash of the ROM they are for
+const cheatsKey = () => `cheats-${cpu.rom_sha1()}`;
+
+const saveCheats = () => {
+  localStorage.setItem(cheatsKey(), cpu.cheats_text());
+  drawCheats();
+}
+
+const restoreCheats = () => {
+  try {
+    cpu.load_cheats(localStorage.getItem(cheatsKey()) || '');
+  } catch (e) {
+    console.log(`Ignoring saved cheats: ${e}`);
+  }
+  cpu.cheat_search_start();
+  drawCheats();
+}
+
+const drawCheats = () => {
+  cheatList.innerHTML = '';
+  for (let i = 0; i < cpu.cheat_count(); i++) {
+    const code = cpu.cheat(i);
+    const enabled = !code.startsWith('-');
+    const item = document.createElement('li');
+    const toggle = document.createElement('input');
+    toggle.type = 'checkbox';
+    toggle.checked = enabled;
+    toggle.addEventListener('change', () => {
+      cpu.set_cheat_enabled(i, toggle.checked);
+      saveCheats();
+    });
+    const remove = document.createElement('button');
+    remove.textContent = 'remove';
+    remove.addEventListener('click', () => {
+      cpu.remove_cheat(i);
+      saveCheats();
+    });
+    item.append(toggle, enabled ? code : code.slice(1), remove);
+    cheatList.appendChild(item);
+  }
+}
+
+const addCheatListeners = () => {
+  cpu.cheat_search_start();
+  document.getElementById('cheat-search').addEventListener('submit', event => {
+    event.preventDefault();
+    const comparison = document.getElementById('search-comparison').value;
+    const value = parseInt(document.getElementById('search-value').value, 16) || 0;
+    const count = cpu.cheat_search(comparison, value);
+    const candidates = Array.from(cpu.cheat_candidates(8), addr => addr.toString(16).toUpperCase());
+    searchResults.textContent = `${count} left: ${candidates.join(' ')}${count > 8 ? ' ...' : ''}`;
+  });
+  document.getElementById('search-reset').addEventListener('click', () => {
+    cpu.cheat_search_start();
+    searchResults.textContent = '';
+  });
+  document.getElementById('cheat-add').addEventListener('submit', event => {
+    event.preventDefault();
+    const code = document.getElementById('cheat-code');
+    try {
+      cpu.add_cheat(code.value);
+    } catch (e) {
+      alert(e);
+      return;
+    }
+    code.value = '';
+    saveCheats();
+  });
+}
+
+// unlocked achievements are saved in local storage by ROM hash, like cheats
+const achievementsKey = () => `achievements-${cpu.rom_sha1()}`;
+
+// titles of achievements unlocked during the last tick. The CPU can't be
+// used from its own callback, so they are handled after the tick
+const unlockedAchievements = [];
+
+const restoreAchievements = () => {
+  cpu.load_unlocked_achievements(localStorage.getItem(achievementsKey()) || '');
+  achievementToast.textContent = '';
+  drawAchievements();
+}
+
+const drawAchievements = () => {
+  achievementList.innerHTML = '';
+  for (let i = 0; i < cpu.achievement_count(); i++) {
+    const item = document.createElement('li');
+    item.textContent = `${cpu.achievement_unlocked(i) ? '★' : '☆'} ${cpu.achievement_title(i)}`;
+    achievementList.appendChild(item);
+  }
+}
+
+const saveAchievements = () => {
+  const titles = unlockedAchievements.splice(0);
+  localStorage.setItem(achievementsKey(), cpu.achievements_text());
+  achievementToast.textContent = `Achievement unlocked: ${titles.join(', ')}`;
+  drawAchievements();
+}
+
+const addAchievementListener = () => {
+  cpu.on_achievement((id, title) => unlockedAchievements.push(title));
 }
 
 const drawScreen = () => {
@@ -96,7 +248,7 @@ const drawScreen = () => {
     
     for (let row = 0; row < height; row++) {
       for (let col = 0; col < width; col++) {
-        ctx.fillStyle = cpu.get_pixel(col, row) ? PIXEL_ON_COLOR : PIXEL_OFF_COLOR;
+        ctx.fillStyle = cpu.get_pixel(col, row) ? pixelOnColor : pixelOffColor;
         ctx.fillRect(
             col * (CELL_SIZE + 1) + 1,
             row * (CELL_SIZE + 1) + 1,
@@ -107,33 +259,66 @@ const drawScreen = () => {
     }
 
     ctx.stroke();
+    drawMemory();
 }
 
 const play = () => {
   playPauseButton.textContent = "⏸";
-  renderLoop();
+  cpu.resume();
 }
 
 const pause = () => {
   playPauseButton.textContent = "▶";
-  cancelAnimationFrame(animationId);
-  animationId = null;
+  cpu.pause();
 }
 
 const step = () => {
+  pause();
   cpu.cycle(true);
   drawScreen();
 }
 
-let animationId = null;
-const isPaused = () => animationId === null;
+// run one whole frame, timers included, then stay paused
+const frameAdvance = () => {
+  playPauseButton.textContent = "▶";
+  cpu.frame_advance();
+}
+
+const isPaused = () => cpu.is_paused();
+// the Gamepad API has no events for buttons, so the first pad is polled
+// every frame and mapped to keys by the CPU wrapper
+const pollGamepad = () => {
+  const pad = Array.from(navigator.getGamepads ? navigator.getGamepads() : []).find(pad => pad);
+  if (pad) {
+    cpu.gamepad_state(
+      Float64Array.from(pad.buttons, button => button.value),
+      Float64Array.from(pad.axes));
+  }
+}
+
+// runs on every display refresh, paused or not. The CPU wrapper decides how
+// many frames to emulate for the chosen speed
 const renderLoop = () => {
-  for (let i = 0; i < 10; i++) {
-    cpu.cycle();
+  pollGamepad();
+  if (cpu.tick() > 0) {
+    drawScreen();
+  }
+  if (unlockedAchievements.length > 0) {
+    saveAchievements();
+  }
+  requestAnimationFrame(renderLoop);
+}
+
+const setSpeed = (speed) => {
+  if (speed === 'uncapped') {
+    cpu.uncapped();
+  } else if (speed.startsWith('/')) {
+    cpu.slow_motion(parseInt(speed.slice(1)));
+  } else if (speed === '1') {
+    cpu.normal_speed();
+  } else {
+    cpu.fast_forward(parseInt(speed));
   }
-  cpu.decrement_timers();
-  drawScreen();
-  animationId = requestAnimationFrame(renderLoop);
 }
 
 const addPlayPauseListener = () => {
@@ -145,6 +330,8 @@ const addPlayPauseListener = () => {
     }
   })
   stepButton.addEventListener("click", event => step());
+  frameButton.addEventListener("click", event => frameAdvance());
+  speedSelector.addEventListener("change", e => { setSpeed(e.target.value); });
 }
 
 const addRomListener = () => {
@@ -159,9 +346,14 @@ const addRomListener = () => {
 
 drawScreen();
 addKeyListeners();
+addDropListener();
 addPlayPauseListener();
 addRomListener();
+addPokeListener();
+addCheatListeners();
+addAchievementListener();
 pause();
+renderLoop();
 
 }
 
@@ -0,0 +1,88 @@
+// Machines running in a Web Worker (see worker.js), driven with promises
+// from the page. Screens are drawn into SharedArrayBuffers when the page is
+// cross-origin isolated, and sent back with every frame otherwise.
+const FRAMEBUFFER_LEN = 64 * 32 * 4;
+
+export class Machines {
+  constructor(url = 'worker.js') {
+    this.worker = new Worker(url);
+    this.nextId = 0;
+    this.nextSeq = 0;
+    // resolve and reject for each message waiting for its reply, by seq
+    this.pending = new Map();
+    this.worker.onmessage = ({ data: reply }) => {
+      const { resolve, reject } = this.pending.get(reply.seq);
+      this.pending.delete(reply.seq);
+      if (reply.error) {
+        reject(reply.error);
+      } else {
+        resolve(reply);
+      }
+    };
+  }
+
+  request(message, transfer = []) {
+    const seq = this.nextSeq++;
+    return new Promise((resolve, reject) => {
+      this.pending.set(seq, { resolve, reject });
+      this.worker.postMessage(Object.assign({}, message, { seq }), transfer);
+    });
+  }
+
+  // a new machine, with its random numbers seeded with `seed`
+  async create(seed = Math.floor(Math.random() * 0x100000000)) {
+    const id = this.nextId++;
+    const framebuffer = self.crossOriginIsolated ? new SharedArrayBuffer(FRAMEBUFFER_LEN) : null;
+    await this.request({ type: 'create', id, seed, framebuffer });
+    return new WorkerMachine(this, id, framebuffer);
+  }
+
+  terminate() {
+    this.worker.terminate();
+  }
+}
+
+export class WorkerMachine {
+  constructor(machines, id, framebuffer) {
+    this.machines = machines;
+    this.id = id;
+    // the latest screen as RGBA
+    this.framebuffer = framebuffer ? new Uint8Array(framebuffer) : new Uint8Array(FRAMEBUFFER_LEN);
+  }
+
+  request(message, transfer) {
+    return this.machines.request(Object.assign({}, message, { id: this.id }), transfer);
+  }
+
+  load(rom) {
+    return this.request({ type: 'load', rom });
+  }
+
+  // override quirks by their chip-8-database names, and the speed
+  configure({ quirks = {}, cyclesPerFrame = 0 } = {}) {
+    return this.request({ type: 'configure', quirks, cyclesPerFrame });
+  }
+
+  // run frames holding `keys`, a bit per key. Resolves to the frame
+  // number, the status, the beeper on each frame and whether the screen
+  // changed, with the screen in `framebuffer`
+  async run(frames, keys = 0) {
+    const { frame, status, audio, drawn, framebuffer } = await this.request({ type: 'run', frames, keys });
+    if (framebuffer) {
+      this.framebuffer = framebuffer;
+    }
+    return { frame, status, audio, drawn };
+  }
+
+  async save() {
+    return (await this.request({ type: 'save' })).state;
+  }
+
+  restore(state) {
+    return this.request({ type: 'restore', state });
+  }
+
+  destroy() {
+    return this.request({ type: 'destroy' });
+  }
+}
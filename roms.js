@@ -0,0 +1,26 @@
+// the ROMs in roms/, offered in the ROM selectors
+export const ROMS = [
+  '15PUZZLE',
+  'BLINKY',
+  'BLITZ',
+  'BRIX',
+  'CONNECT4',
+  'GUESS',
+  'HIDDEN',
+  'INVADERS',
+  'KALEID',
+  'MAZE',
+  'MERLIN',
+  'MISSILE',
+  'PONG',
+  'PONG2',
+  'PUZZLE',
+  'SYZYGY',
+  'TANK',
+  'TETRIS',
+  'TICTAC',
+  'UFO',
+  'VBRIX',
+  'VERS',
+  'WIPEOFF',
+];
@@ -0,0 +1,423 @@
+//! Achievements, unlocked by conditions on a ROM's memory and registers
+//! that are checked after every frame.
+//!
+//! Achievements are written as text, grouped by the SHA-1 of the ROM they
+//! are for. Each `achievement` line gives an id, used to save which ones
+//! are unlocked, and a title, and is followed by its conditions, all of
+//! which must hold on the same frame to unlock it. Lines starting with `#`
+//! are comments:
+//!
+//! ```text
+//! rom f13766c14aeb02ad8d4d103cb5eadd282d20cddc
+//! achievement ten-bricks Break 10 bricks
+//!   V5 > prev hits 10
+//! achievement no-miss Break 5 bricks without losing a ball
+//!   V5 >= 05
+//!   VE == 05
+//! ```
+//!
+//! A condition compares a hex address or a register `V0` to `VF` with a hex
+//! value, or with `prev`, its value on the frame before, using `==`, `!=`,
+//! `<`, `<=`, `>` or `>=`. It may end with `for N`, to only hold once it has
+//! held for N frames in a row, or `hits N`, to only hold once it has held on
+//! N frames in all.
+use std::collections::HashMap;
+
+use cheats::Target;
+use cpu::CPU;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn parse(text: &str) -> Option<Comparison> {
+        match text {
+            "==" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessOrEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterOrEqual),
+            _ => None,
+        }
+    }
+
+    fn holds(self, left: u8, right: u8) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+/// What a byte is compared with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    Value(u8),
+    /// the byte's value on the previous frame
+    Previous,
+}
+
+/// How often a comparison has to hold for the condition to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Count {
+    Once,
+    /// on this many frames in a row
+    For(u32),
+    /// on this many frames in all
+    Hits(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub target: Target,
+    pub comparison: Comparison,
+    pub operand: Operand,
+    pub count: Count,
+}
+
+impl Condition {
+    /// Parse a condition in the format above
+    pub fn parse(line: &str) -> Result<Condition, String> {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        let (target, comparison, operand, count) = match parts.as_slice() {
+            [target, comparison, operand] => (target, comparison, operand, None),
+            [target, comparison, operand, kind, frames] => (target, comparison, operand, Some((kind, frames))),
+            _ => return Err(format!("invalid condition `{}`, expected <location> <comparison> <value> [for|hits N]", line)),
+        };
+        let target = Target::parse(target).ok_or_else(|| format!("invalid location `{}`", target))?;
+        let comparison = Comparison::parse(comparison).ok_or_else(|| format!("invalid comparison `{}`", comparison))?;
+        let operand = match *operand {
+            "prev" => Operand::Previous,
+            value => Operand::Value(u8::from_str_radix(value, 16).map_err(|_| format!("invalid value `{}`", value))?),
+        };
+        let count = match count {
+            None => Count::Once,
+            Some((kind, frames)) => {
+                let frames = frames.parse().map_err(|_| format!("invalid number of frames `{}`", frames))?;
+                match *kind {
+                    "for" => Count::For(frames),
+                    "hits" => Count::Hits(frames),
+                    _ => return Err(format!("expected `for` or `hits`, not `{}`", kind)),
+                }
+            }
+        };
+        Ok(Condition { target, comparison, operand, count })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Achievement {
+    pub id: String,
+    pub title: String,
+    pub conditions: Vec<Condition>,
+}
+
+/// Achievements for any number of ROMs, by the SHA-1 of the ROM
+#[derive(Clone, Default)]
+pub struct Library {
+    roms: HashMap<String, Vec<Achievement>>,
+}
+
+impl Library {
+    /// Create an empty library
+    pub fn new() -> Library {
+        Library { roms: HashMap::new() }
+    }
+
+    /// The achievements for the ROMs in `roms/`
+    pub fn builtin() -> Library {
+        let mut library = Library::new();
+        library.load(include_str!("achievements.txt")).unwrap();
+        library
+    }
+
+    /// Add the achievements in the format above, replacing those of any ROM
+    /// they are given for. Returns how many ROMs they were for
+    pub fn load(&mut self, text: &str) -> Result<usize, String> {
+        let mut roms: Vec<(String, Vec<Achievement>)> = Vec::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", lineno + 1, message);
+            let (keyword, rest) = match line.find(char::is_whitespace) {
+                Some(end) => (&line[..end], line[end..].trim()),
+                None => (line, ""),
+            };
+            match keyword {
+                "rom" => {
+                    if rest.len() != 40 || !rest.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err(error(format!("invalid SHA-1 `{}`", rest)));
+                    }
+                    let sha1 = rest.to_lowercase();
+                    if roms.iter().any(|rom| rom.0 == sha1) {
+                        return Err(error(format!("duplicate ROM `{}`", sha1)));
+                    }
+                    roms.push((sha1, Vec::new()));
+                },
+                "achievement" => {
+                    let achievements = &mut roms.last_mut()
+                        .ok_or_else(|| error("achievement before any `rom` line".to_string()))?.1;
+                    let (id, title) = match rest.find(char::is_whitespace) {
+                        Some(end) => (&rest[..end], rest[end..].trim()),
+                        None => (rest, rest),
+                    };
+                    if id.is_empty() {
+                        return Err(error("missing achievement id".to_string()));
+                    }
+                    if achievements.iter().any(|achievement| achievement.id == id) {
+                        return Err(error(format!("duplicate achievement `{}`", id)));
+                    }
+                    achievements.push(Achievement { id: id.to_string(), title: title.to_string(), conditions: Vec::new() });
+                },
+                _ => {
+                    let achievement = roms.last_mut().and_then(|rom| rom.1.last_mut())
+                        .ok_or_else(|| error("condition before any `achievement` line".to_string()))?;
+                    achievement.conditions.push(Condition::parse(line).map_err(error)?);
+                },
+            }
+        }
+
+        for (_, achievements) in roms.iter() {
+            if let Some(achievement) = achievements.iter().find(|achievement| achievement.conditions.is_empty()) {
+                return Err(format!("achievement `{}` has no conditions", achievement.id));
+            }
+        }
+        let count = roms.len();
+        self.roms.extend(roms);
+        Ok(count)
+    }
+
+    /// The achievements for the ROM with this hex SHA-1, if there are any
+    pub fn for_rom(&self, sha1: &str) -> Option<&[Achievement]> {
+        self.roms.get(&sha1.to_lowercase()).map(|achievements| achievements.as_slice())
+    }
+}
+
+/// How far a condition is from holding
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Progress {
+    /// frames held in a row for `Count::For`, or in all for `Count::Hits`
+    frames: u32,
+    /// the byte's value after the last frame, if any
+    previous: Option<u8>,
+}
+
+/// The achievements of the loaded ROM and which are unlocked, checked with
+/// `update` after every frame
+#[derive(Clone)]
+pub struct Achievements {
+    achievements: Vec<Achievement>,
+    unlocked: Vec<bool>,
+    /// for each condition of each achievement
+    progress: Vec<Vec<Progress>>,
+}
+
+impl Achievements {
+    pub fn new(achievements: Vec<Achievement>) -> Achievements {
+        let progress = achievements.iter()
+            .map(|achievement| vec![Progress { frames: 0, previous: None }; achievement.conditions.len()])
+            .collect();
+        Achievements { unlocked: vec![false; achievements.len()], achievements, progress }
+    }
+
+    /// Check the conditions against the machine after a frame and return
+    /// the achievements this unlocked
+    pub fn update(&mut self, cpu: &CPU) -> Vec<Achievement> {
+        let mut unlocked = Vec::new();
+        for (index, achievement) in self.achievements.iter().enumerate() {
+            if self.unlocked[index] {
+                continue;
+            }
+            let mut holds = true;
+            // every condition is checked, to keep its progress up to date
+            for (condition, progress) in achievement.conditions.iter().zip(self.progress[index].iter_mut()) {
+                let value = condition.target.read(cpu);
+                let operand = match condition.operand {
+                    Operand::Value(operand) => Some(operand),
+                    Operand::Previous => progress.previous,
+                };
+                let compared = operand.is_some_and(|operand| condition.comparison.holds(value, operand));
+                progress.previous = Some(value);
+                holds &= match condition.count {
+                    Count::Once => compared,
+                    Count::For(frames) => {
+                        progress.frames = if compared { progress.frames.saturating_add(1) } else { 0 };
+                        progress.frames >= frames
+                    },
+                    Count::Hits(frames) => {
+                        if compared {
+                            progress.frames = progress.frames.saturating_add(1);
+                        }
+                        progress.frames >= frames
+                    },
+                };
+            }
+            if holds {
+                self.unlocked[index] = true;
+                unlocked.push(achievement.clone());
+            }
+        }
+        unlocked
+    }
+
+    /// Forget the progress towards locked achievements, such as after
+    /// resetting the machine
+    pub fn reset(&mut self) {
+        for progress in self.progress.iter_mut().flat_map(|progress| progress.iter_mut()) {
+            *progress = Progress { frames: 0, previous: None };
+        }
+    }
+
+    pub fn achievements(&self) -> &[Achievement] {
+        &self.achievements
+    }
+
+    pub fn is_unlocked(&self, index: usize) -> bool {
+        self.unlocked.get(index).cloned().unwrap_or(false)
+    }
+
+    /// The ids of the unlocked achievements, one per line, for saving by
+    /// ROM hash
+    pub fn unlocked_text(&self) -> String {
+        self.achievements.iter().zip(self.unlocked.iter())
+            .filter(|&(_, &unlocked)| unlocked)
+            .map(|(achievement, _)| format!("{}\n", achievement.id))
+            .collect()
+    }
+
+    /// Mark the achievements saved by `unlocked_text` as unlocked. Ids that
+    /// are no longer used are ignored
+    pub fn load_unlocked(&mut self, text: &str) {
+        for id in text.lines().map(str::trim) {
+            if let Some(index) = self.achievements.iter().position(|achievement| achievement.id == id) {
+                self.unlocked[index] = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu
+    }
+
+    fn achievements(text: &str) -> Achievements {
+        let mut library = Library::new();
+        library.load(&format!("rom {}\n{}", "0".repeat(40), text)).unwrap();
+        Achievements::new(library.for_rom(&"0".repeat(40)).unwrap().to_vec())
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Condition::parse("2F0 >= 0A for 60"), Ok(Condition {
+            target: Target::Memory(0x2F0),
+            comparison: Comparison::GreaterOrEqual,
+            operand: Operand::Value(0x0A),
+            count: Count::For(60),
+        }));
+        assert_eq!(Condition::parse("VE < prev hits 3").unwrap().operand, Operand::Previous);
+        assert!(Condition::parse("VE =< 1").is_err());
+        assert!(Condition::parse("VE == 100").is_err());
+        assert!(Condition::parse("VE == 1 during 3").is_err());
+
+        let library = Library::builtin();
+        let brix = library.for_rom("F13766C14AEB02AD8D4D103CB5EADD282D20CDDC").unwrap();
+        assert_eq!(brix[0].title, "Break a brick");
+        assert_eq!(brix[2].conditions.len(), 2);
+        assert!(library.for_rom(&"0".repeat(40)).is_none());
+
+        let mut library = Library::new();
+        assert_eq!(library.load("achievement a A\n  V0 == 1").unwrap_err(), "line 1: achievement before any `rom` line");
+        assert_eq!(library.load("rom 1234").unwrap_err(), "line 1: invalid SHA-1 `1234`");
+        let rom = format!("rom {}\n", "0".repeat(40));
+        assert_eq!(library.load(&format!("{}  V0 == 1", rom)).unwrap_err(), "line 2: condition before any `achievement` line");
+        assert_eq!(library.load(&format!("{}achievement a A", rom)).unwrap_err(), "achievement `a` has no conditions");
+        assert!(library.load(&format!("{}achievement a A\nV0 == 1\nachievement a B\nV0 == 2", rom)).is_err());
+        assert_eq!(library.load(&format!("{}achievement a A\nV0 == 1\n{}achievement b B\nV0 == 2", rom, rom)).unwrap_err(),
+            format!("line 4: duplicate ROM `{}`", "0".repeat(40)));
+    }
+
+    #[test]
+    fn conditions() {
+        let mut achievements = achievements("
+            achievement up V0 went up
+              V0 > prev
+            achievement held V1 stayed 5 for 3 frames
+              V1 == 05 for 3
+            achievement hits V2 was odd on 3 frames
+              V2 == 01 hits 3
+            achievement both 300 and V3 at once
+              300 == AA
+              V3 == BB
+        ");
+        let mut cpu = cpu();
+        let mut frame = |cpu: &CPU| -> Vec<String> {
+            achievements.update(cpu).into_iter().map(|achievement| achievement.id).collect()
+        };
+
+        // nothing to compare with on the first frame
+        cpu.v[0] = 1;
+        assert!(frame(&cpu).is_empty());
+        cpu.v[0] = 1;
+        cpu.v[1] = 5;
+        cpu.v[2] = 1;
+        assert!(frame(&cpu).is_empty());
+        cpu.v[1] = 5;
+        cpu.v[2] = 0;
+        cpu.v[3] = 0xBB;
+        assert!(frame(&cpu).is_empty());
+        // held, but not for long enough
+        cpu.v[1] = 0;
+        cpu.v[2] = 1;
+        assert!(frame(&cpu).is_empty());
+        cpu.v[1] = 5;
+        cpu.v[2] = 1;
+        cpu.write_memory(0x300, &[0xAA]);
+        cpu.v[0] = 2;
+        assert_eq!(frame(&cpu), vec!["up", "hits", "both"]);
+        assert!(frame(&cpu).is_empty());
+        assert_eq!(frame(&cpu), vec!["held"]);
+        assert!(frame(&cpu).is_empty());
+    }
+
+    #[test]
+    fn unlocked() {
+        let mut achievements = achievements("
+            achievement a A
+              V0 == 01
+            achievement b B
+              V1 == 01
+        ");
+        let mut cpu = cpu();
+        cpu.v[1] = 1;
+        achievements.update(&cpu);
+        assert!(!achievements.is_unlocked(0));
+        assert!(achievements.is_unlocked(1));
+        assert_eq!(achievements.unlocked_text(), "b\n");
+
+        let mut restored = Achievements::new(achievements.achievements().to_vec());
+        restored.load_unlocked("b\nremoved\n");
+        assert_eq!(restored.unlocked_text(), "b\n");
+        // already unlocked, so not again
+        assert!(restored.update(&cpu).is_empty());
+    }
+}
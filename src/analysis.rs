@@ -0,0 +1,414 @@
+//! Static analysis of ROMs: recovers the control-flow graph by following
+//! jumps, calls, skips and returns from the entry point, and uses it to find
+//! subroutines, the call graph, bytes that are never run as code, probable
+//! sprite data and computed `Bnnn` jumps, whose targets can't be known
+//! without running the program. The result can be written out as Graphviz
+//! DOT or JSON.
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+
+use instruction::{decode, Instruction};
+
+/// Programs are loaded at this address and start running from it
+const START: u16 = 0x200;
+
+/// A run of instructions that is only entered at the top and only left at
+/// the bottom
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    /// address, raw opcode and decoded instruction
+    pub instructions: Vec<(u16, u16, Instruction)>,
+    /// blocks control continues at in the same subroutine. For a block
+    /// ending in a call this is the return address
+    pub successors: Vec<u16>,
+    /// the subroutine called by the last instruction
+    pub call: Option<u16>,
+    /// whether the block ends in a `Bnnn` jump
+    pub indirect: bool,
+}
+
+impl BasicBlock {
+    /// Address of the first byte after the block
+    pub fn end(&self) -> u16 {
+        self.start + 2 * self.instructions.len() as u16
+    }
+}
+
+/// A subroutine, or the main program for the entry point
+#[derive(Clone, Debug, PartialEq)]
+pub struct Subroutine {
+    pub entry: u16,
+    /// start addresses of the blocks reachable from the entry without
+    /// following calls
+    pub blocks: Vec<u16>,
+    /// entry points of the subroutines it calls
+    pub calls: Vec<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Analysis {
+    pub entry: u16,
+    /// ordered by address
+    pub blocks: Vec<BasicBlock>,
+    /// the main program first, then subroutines ordered by address
+    pub subroutines: Vec<Subroutine>,
+    /// memory an `Annn` points at when a `Dxyn` later in the same block
+    /// draws it
+    pub sprites: Vec<Range<u16>>,
+    /// bytes of the ROM that are never run as code. This includes any data
+    /// the program uses, such as sprites
+    pub unreachable: Vec<Range<u16>>,
+    /// addresses of `Bnnn` instructions
+    pub indirect_jumps: Vec<u16>,
+}
+
+/// Whether the instruction changes the flow of control
+fn branches(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Jump(_) | Instruction::Call(_) | Instruction::Return |
+        Instruction::JumpOffset(_) | Instruction::SkipEqual(_, _) |
+        Instruction::SkipNotEqual(_, _) | Instruction::SkipEqualReg(_, _) |
+        Instruction::SkipNotEqualReg(_, _) | Instruction::SkipKeyDown(_) |
+        Instruction::SkipKeyUp(_))
+}
+
+/// Addresses control can continue at after running the instruction at
+/// `addr`, including the target of a call. `Bnnn` and `00EE` have targets
+/// that are only known at runtime
+pub fn successors(addr: u16, instruction: Instruction) -> Vec<u16> {
+    let next = (addr + 2) & 0xFFF;
+    let skip = (addr + 4) & 0xFFF;
+    match instruction {
+        Instruction::Jump(nnn) => vec![nnn],
+        Instruction::Call(nnn) => vec![nnn, next],
+        Instruction::Return | Instruction::JumpOffset(_) => vec![],
+        Instruction::SkipEqual(_, _) | Instruction::SkipNotEqual(_, _) |
+        Instruction::SkipEqualReg(_, _) | Instruction::SkipNotEqualReg(_, _) |
+        Instruction::SkipKeyDown(_) | Instruction::SkipKeyUp(_) => vec![next, skip],
+        _ => vec![next],
+    }
+}
+
+/// Read the opcode at `addr`, unless it would run past the end of memory
+fn opcode(memory: &[u8], addr: u16) -> Option<u16> {
+    let addr = addr as usize;
+    if addr + 1 < memory.len() {
+        Some((memory[addr] as u16) << 8 | memory[addr + 1] as u16)
+    } else {
+        None
+    }
+}
+
+/// Merge a list of ranges into a sorted list of disjoint ones
+fn merge(mut ranges: Vec<Range<u16>>) -> Vec<Range<u16>> {
+    ranges.sort_by_key(|range| (range.start, range.end));
+    let mut merged: Vec<Range<u16>> = Vec::new();
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Analyze a ROM loaded at the usual address
+pub fn analyze(rom: &[u8]) -> Analysis {
+    let mut memory = vec![0; 4096];
+    let len = rom.len().min(memory.len() - START as usize);
+    memory[START as usize..START as usize + len].copy_from_slice(&rom[..len]);
+
+    // find every reachable instruction, and the ones that start a block
+    let mut code = vec![false; memory.len()];
+    let mut leaders = BTreeSet::new();
+    leaders.insert(START);
+    let mut pending = vec![START];
+    while let Some(addr) = pending.pop() {
+        if code[addr as usize] {
+            continue;
+        }
+        let instruction = match opcode(&memory, addr) {
+            Some(opcode) => decode(opcode),
+            None => continue,
+        };
+        code[addr as usize] = true;
+        let next = successors(addr, instruction);
+        if branches(instruction) {
+            leaders.extend(next.iter().cloned());
+        }
+        pending.extend(next);
+    }
+
+    let mut blocks = Vec::new();
+    for &start in leaders.iter() {
+        if !code[start as usize] {
+            continue;
+        }
+        let mut block = BasicBlock {
+            start,
+            instructions: Vec::new(),
+            successors: Vec::new(),
+            call: None,
+            indirect: false,
+        };
+        let mut addr = start;
+        loop {
+            let opcode = opcode(&memory, addr).unwrap();
+            let instruction = decode(opcode);
+            block.instructions.push((addr, opcode, instruction));
+            let next = (addr + 2) & 0xFFF;
+            if branches(instruction) || leaders.contains(&next) || !code[next as usize] {
+                block.successors = successors(addr, instruction);
+                break;
+            }
+            addr = next;
+        }
+        match block.instructions.last().unwrap().2 {
+            Instruction::Call(nnn) => {
+                block.call = Some(nnn);
+                block.successors.retain(|&addr| addr != nnn);
+            },
+            Instruction::JumpOffset(_) => block.indirect = true,
+            _ => {}
+        }
+        // falling off the end of memory
+        block.successors.retain(|&addr| code[addr as usize]);
+        blocks.push(block);
+    }
+
+    let by_start: BTreeMap<u16, &BasicBlock> = blocks.iter().map(|b| (b.start, b)).collect();
+    let mut entries: Vec<u16> = blocks.iter().filter_map(|b| b.call).collect();
+    entries.sort();
+    entries.dedup();
+    entries.retain(|&entry| entry != START && by_start.contains_key(&entry));
+    entries.insert(0, START);
+    let subroutines = entries.iter().map(|&entry| {
+        let mut seen = BTreeSet::new();
+        let mut calls = BTreeSet::new();
+        let mut pending = vec![entry];
+        while let Some(start) = pending.pop() {
+            if !seen.insert(start) {
+                continue;
+            }
+            let block = by_start[&start];
+            calls.extend(block.call.iter().filter(|addr| by_start.contains_key(addr)));
+            pending.extend(block.successors.iter().cloned());
+        }
+        Subroutine {
+            entry,
+            blocks: seen.into_iter().collect(),
+            calls: calls.into_iter().collect(),
+        }
+    }).collect();
+
+    let mut sprites = Vec::new();
+    for block in blocks.iter() {
+        let mut i = None;
+        for &(_, _, instruction) in block.instructions.iter() {
+            match instruction {
+                Instruction::LoadI(nnn) => i = Some(nnn),
+                Instruction::AddI(_) | Instruction::LoadFont(_) |
+                Instruction::StoreRegs(_) | Instruction::LoadRegs(_) => i = None,
+                Instruction::Draw(_, _, n) if n > 0 => {
+                    if let Some(start) = i {
+                        sprites.push(start..start + n as u16);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+    let mut covered = vec![false; memory.len()];
+    for (addr, _) in code.iter().enumerate().filter(|&(_, &reached)| reached) {
+        covered[addr] = true;
+        covered[addr + 1] = true;
+    }
+    let unreachable = (START as usize..START as usize + len)
+        .filter(|&addr| !covered[addr])
+        .map(|addr| addr as u16..addr as u16 + 1)
+        .collect();
+
+    let indirect_jumps = blocks.iter()
+        .filter(|b| b.indirect)
+        .map(|b| b.end() - 2)
+        .collect();
+
+    Analysis {
+        entry: START,
+        blocks,
+        subroutines,
+        sprites: merge(sprites),
+        unreachable: merge(unreachable),
+        indirect_jumps,
+    }
+}
+
+fn hex_ranges(ranges: &[Range<u16>]) -> String {
+    let ranges: Vec<String> = ranges.iter()
+        .map(|range| format!("{:03x}-{:03x}", range.start, range.end - 1))
+        .collect();
+    ranges.join(" ")
+}
+
+fn json_ranges(ranges: &[Range<u16>]) -> String {
+    let ranges: Vec<String> = ranges.iter()
+        .map(|range| format!("{{\"start\": {}, \"end\": {}}}", range.start, range.end))
+        .collect();
+    format!("[{}]", ranges.join(", "))
+}
+
+fn json_numbers(numbers: &[u16]) -> String {
+    let numbers: Vec<String> = numbers.iter().map(|n| n.to_string()).collect();
+    format!("[{}]", numbers.join(", "))
+}
+
+impl Analysis {
+    /// The control-flow graph as a Graphviz digraph, with a cluster per
+    /// subroutine. Calls are dashed edges, computed jumps are drawn to a
+    /// `?` node and the data regions are listed in a note
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        dot += "digraph rom {\n";
+        dot += "    node [shape=box fontname=monospace];\n";
+
+        // a block shared between subroutines is drawn in the first one
+        let mut drawn = BTreeSet::new();
+        for subroutine in self.subroutines.iter() {
+            let name = if subroutine.entry == self.entry { "main".to_string() } else {
+                format!("sub {:03x}", subroutine.entry)
+            };
+            writeln!(dot, "    subgraph cluster_{:03x} {{", subroutine.entry).unwrap();
+            writeln!(dot, "        label=\"{}\";", name).unwrap();
+            for block in self.blocks.iter().filter(|b| subroutine.blocks.contains(&b.start)) {
+                if !drawn.insert(block.start) {
+                    continue;
+                }
+                let mut label = String::new();
+                for &(addr, opcode, instruction) in block.instructions.iter() {
+                    write!(label, "{:03x}: {:04x} {:?}\\l", addr, opcode, instruction).unwrap();
+                }
+                writeln!(dot, "        b{:03x} [label=\"{}\"];", block.start, label).unwrap();
+            }
+            dot += "    }\n";
+        }
+
+        for block in self.blocks.iter() {
+            for &next in block.successors.iter() {
+                writeln!(dot, "    b{:03x} -> b{:03x};", block.start, next).unwrap();
+            }
+            if let Some(target) = block.call {
+                writeln!(dot, "    b{:03x} -> b{:03x} [style=dashed label=call];", block.start, target).unwrap();
+            }
+            if block.indirect {
+                writeln!(dot, "    b{:03x} -> indirect;", block.start).unwrap();
+            }
+        }
+        if !self.indirect_jumps.is_empty() {
+            dot += "    indirect [shape=circle label=\"?\"];\n";
+        }
+        writeln!(dot, "    data [shape=note label=\"sprites: {}\\lunreachable: {}\\l\"];",
+                 hex_ranges(&self.sprites), hex_ranges(&self.unreachable)).unwrap();
+        dot += "}\n";
+        dot
+    }
+
+    /// The analysis as a JSON object. Addresses are numbers, and ranges
+    /// have an inclusive `start` and exclusive `end`
+    pub fn to_json(&self) -> String {
+        let blocks: Vec<String> = self.blocks.iter().map(|block| {
+            let instructions: Vec<String> = block.instructions.iter()
+                .map(|&(addr, opcode, instruction)| format!(
+                    "{{\"address\": {}, \"opcode\": {}, \"instruction\": \"{:?}\"}}",
+                    addr, opcode, instruction))
+                .collect();
+            format!(
+                "    {{\"start\": {}, \"end\": {}, \"instructions\": [{}], \"successors\": {}, \
+                 \"call\": {}, \"indirect\": {}}}",
+                block.start, block.end(), instructions.join(", "), json_numbers(&block.successors),
+                block.call.map_or("null".to_string(), |addr| addr.to_string()), block.indirect)
+        }).collect();
+        let subroutines: Vec<String> = self.subroutines.iter().map(|subroutine| format!(
+            "    {{\"entry\": {}, \"blocks\": {}, \"calls\": {}}}",
+            subroutine.entry, json_numbers(&subroutine.blocks), json_numbers(&subroutine.calls)
+        )).collect();
+
+        let mut json = String::new();
+        json += "{\n";
+        writeln!(json, "  \"entry\": {},", self.entry).unwrap();
+        writeln!(json, "  \"blocks\": [\n{}\n  ],", blocks.join(",\n")).unwrap();
+        writeln!(json, "  \"subroutines\": [\n{}\n  ],", subroutines.join(",\n")).unwrap();
+        writeln!(json, "  \"sprites\": {},", json_ranges(&self.sprites)).unwrap();
+        writeln!(json, "  \"unreachable\": {},", json_ranges(&self.unreachable)).unwrap();
+        writeln!(json, "  \"indirect_jumps\": {}", json_numbers(&self.indirect_jumps)).unwrap();
+        json += "}\n";
+        json
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 200: call 0x20A
+    // 202: skip if V0 == 0
+    // 204: jump V0 + 0x300
+    // 206: jump 0x206
+    // 208: (data) 0xFF 0x00
+    // 20A: I = 0x212
+    // 20C: draw 3 rows at V0, V1
+    // 20E: return
+    // 210: (data) 0x12 0x34
+    // 212: (sprite) 0x80 0xC0 0xE0
+    const PROGRAM: [u8; 21] = [
+        0x22, 0x0A, 0x30, 0x00, 0xB3, 0x00, 0x12, 0x06,
+        0xFF, 0x00, 0xA2, 0x12, 0xD0, 0x13, 0x00, 0xEE,
+        0x12, 0x34, 0x80, 0xC0, 0xE0,
+    ];
+
+    #[test]
+    fn control_flow() {
+        let analysis = analyze(&PROGRAM);
+        let blocks: Vec<(u16, u16, Vec<u16>)> = analysis.blocks.iter()
+            .map(|b| (b.start, b.end(), b.successors.clone()))
+            .collect();
+        assert_eq!(blocks, vec![
+            (0x200, 0x202, vec![0x202]),
+            (0x202, 0x204, vec![0x204, 0x206]),
+            (0x204, 0x206, vec![]),
+            (0x206, 0x208, vec![0x206]),
+            (0x20A, 0x210, vec![]),
+        ]);
+        assert_eq!(analysis.blocks[0].call, Some(0x20A));
+        assert!(analysis.blocks[2].indirect);
+        assert_eq!(analysis.indirect_jumps, vec![0x204]);
+
+        assert_eq!(analysis.subroutines, vec![
+            Subroutine { entry: 0x200, blocks: vec![0x200, 0x202, 0x204, 0x206], calls: vec![0x20A] },
+            Subroutine { entry: 0x20A, blocks: vec![0x20A], calls: vec![] },
+        ]);
+    }
+
+    #[test]
+    fn data() {
+        let analysis = analyze(&PROGRAM);
+        assert_eq!(analysis.sprites, vec![0x212..0x215]);
+        assert_eq!(analysis.unreachable, vec![0x208..0x20A, 0x210..0x215]);
+    }
+
+    #[test]
+    fn output() {
+        let analysis = analyze(&PROGRAM);
+        let dot = analysis.to_dot();
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("b200 -> b20a [style=dashed label=call];"));
+        assert!(dot.contains("b204 -> indirect;"));
+        assert!(dot.contains("sprites: 212-214"));
+
+        let json = analysis.to_json();
+        assert!(json.contains("\"indirect_jumps\": [516]"));
+        assert!(json.contains("\"sprites\": [{\"start\": 530, \"end\": 533}]"));
+    }
+}
@@ -0,0 +1,241 @@
+//! Run a ROM without a browser, optionally with scripted input, and record
+//! the result as an animated GIF or print the final screen. With
+//! `--analyze` the ROM's control-flow graph is printed instead of running it.
+//! The ROM may also be Octo source (`.8o`) or an Octo cartridge GIF, which
+//! are assembled first.
+//! `--database` adds entries in the chip-8-database format to the built-in
+//! ROM database, overriding its quirks and speed for those ROMs.
+//! `--patch` applies an IPS or BPS patch to the ROM before running it.
+//! `--script` runs a Rhai script alongside the ROM, printing what it logs
+//! and drawing its overlay into the GIF's frames, when built with the
+//! `scripting` feature.
+//! Input scripts may name host keys, such as `KeyQ` or `ArrowLeft`, which
+//! are mapped with the `--keymap` preset (`qwerty`, `azerty` or `numpad`)
+//! or JSON file, plus the arrow keys and space for ROMs in the database.
+//!
+//! ```text
+//! cargo run --bin headless -- roms/PONG --frames 600 --input pong.txt --gif pong.gif
+//! cargo run --bin headless -- roms/PONG --analyze dot | dot -Tsvg > pong.svg
+//! ```
+extern crate chip8;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use chip8::analysis::analyze;
+use chip8::cartridge::Cartridge;
+use chip8::cpu::CPU;
+use chip8::database::Database;
+use chip8::gif::GifRecorder;
+use chip8::input::InputScript;
+use chip8::keymap::KeyMap;
+use chip8::octo;
+use chip8::patch;
+use chip8::runner::Runner;
+#[cfg(feature = "scripting")]
+use chip8::script::Script;
+
+const USAGE: &str = "usage: headless <rom> [--frames N] [--seed N] [--input FILE] \
+                     [--gif FILE] [--scale N] [--analyze dot|json] [--database FILE] \
+                     [--keymap qwerty|azerty|numpad|FILE] \
+                     [--patch FILE] [--script FILE]";
+
+enum Format {
+    Dot,
+    Json,
+}
+
+struct Options {
+    rom: String,
+    frames: u64,
+    seed: u32,
+    input: Option<String>,
+    gif: Option<String>,
+    scale: usize,
+    analyze: Option<Format>,
+    database: Option<String>,
+    keymap: Option<String>,
+    patch: Option<String>,
+    script: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        frames: 600,
+        seed: 1,
+        input: None,
+        gif: None,
+        scale: 4,
+        analyze: None,
+        database: None,
+        keymap: None,
+        patch: None,
+        script: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("missing value for {}", name));
+        match arg.as_str() {
+            "--frames" => options.frames = value("--frames")?.parse().map_err(|e| format!("--frames: {}", e))?,
+            "--seed" => options.seed = value("--seed")?.parse().map_err(|e| format!("--seed: {}", e))?,
+            "--input" => options.input = Some(value("--input")?),
+            "--gif" => options.gif = Some(value("--gif")?),
+            "--scale" => options.scale = value("--scale")?.parse().map_err(|e| format!("--scale: {}", e))?,
+            "--analyze" => options.analyze = match value("--analyze")?.as_str() {
+                "dot" => Some(Format::Dot),
+                "json" => Some(Format::Json),
+                format => return Err(format!("--analyze: unknown format {}", format))
+            },
+            "--database" => options.database = Some(value("--database")?),
+            "--keymap" => options.keymap = Some(value("--keymap")?),
+            "--patch" => options.patch = Some(value("--patch")?),
+            "--script" => options.script = Some(value("--script")?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if options.rom.is_empty() => options.rom = arg,
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+    if options.rom.is_empty() {
+        return Err("missing ROM path".to_string());
+    }
+    if options.scale == 0 || options.scale > 64 {
+        return Err("--scale must be between 1 and 64".to_string());
+    }
+    Ok(options)
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let data = fs::read(&options.rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    let cartridge = if data.starts_with(b"GIF8") {
+        Some(Cartridge::from_gif(&data).map_err(|e| format!("{}: {}", options.rom, e))?)
+    } else {
+        None
+    };
+    let rom = match cartridge {
+        Some(ref cartridge) => cartridge.rom.clone(),
+        None if options.rom.ends_with(".8o") => {
+            let source = String::from_utf8(data).map_err(|_| format!("{}: not UTF-8", options.rom))?;
+            octo::assemble(&source).map_err(|e| format!("{}: {}", options.rom, e))?
+        },
+        None => data,
+    };
+    let rom = match options.patch {
+        Some(ref path) => {
+            let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            patch::apply(&rom, &data).map_err(|e| format!("{}: {}", path, e))?
+        },
+        None => rom,
+    };
+    if let Some(format) = options.analyze {
+        let analysis = analyze(&rom);
+        match format {
+            Format::Dot => print!("{}", analysis.to_dot()),
+            Format::Json => print!("{}", analysis.to_json()),
+        }
+        return Ok(());
+    }
+    let mut runner = Runner::new(&rom, options.seed).map_err(|e| format!("{}: {}", options.rom, e))?;
+    if let Some(ref path) = options.database {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut database = Database::builtin();
+        database.load(&text).map_err(|e| format!("{}: {}", path, e))?;
+        runner.cpu.set_database(database);
+    }
+    if let Some(ref cartridge) = cartridge {
+        cartridge.configure(&mut runner.cpu);
+    }
+    if let Some(ref path) = options.input {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let keymap = load_keymap(options.keymap.as_deref().unwrap_or("qwerty"))?;
+        let keymap = match runner.cpu.rom_info() {
+            Some(info) => keymap.for_rom(info),
+            None => keymap,
+        };
+        runner.set_input(InputScript::parse_with(&text, &keymap).map_err(|e| format!("{}: {}", path, e))?);
+    }
+    let mut script = load_script(options.script.as_deref(), &mut runner.cpu)?;
+
+    let [off, on] = cartridge.as_ref().and_then(|cartridge| cartridge.palette)
+        .or_else(|| runner.cpu.rom_info().and_then(|info| info.palette))
+        .unwrap_or([[255, 255, 255], [0, 0, 0]]);
+    let mut recorder = options.gif.as_ref()
+        .map(|_| GifRecorder::new(options.scale, off, on));
+    for _ in 0..options.frames {
+        let overlay = run_frame(&mut runner, &mut script)?;
+        if let Some(ref mut recorder) = recorder {
+            recorder.capture_with_overlay(&runner.cpu.screen, &overlay);
+        }
+    }
+
+    match (recorder, options.gif) {
+        (Some(recorder), Some(path)) => {
+            fs::write(&path, recorder.finish()).map_err(|e| format!("{}: {}", path, e))?
+        },
+        _ => print!("{}", runner.cpu.screen)
+    }
+    Ok(())
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(path: Option<&str>, cpu: &mut CPU) -> Result<Option<Script>, String> {
+    match path {
+        Some(path) => {
+            let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            Script::new(&source, cpu).map(Some).map_err(|e| format!("{}: {}", path, e))
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn load_script(path: Option<&str>, _cpu: &mut CPU) -> Result<Option<()>, String> {
+    match path {
+        Some(_) => Err("--script needs the scripting feature".to_string()),
+        None => Ok(None),
+    }
+}
+
+/// Run a frame and return the script's overlay, laid out like the screen
+#[cfg(feature = "scripting")]
+fn run_frame(runner: &mut Runner, script: &mut Option<Script>) -> Result<[u64; 32], String> {
+    match *script {
+        Some(ref mut script) => {
+            runner.run_frame_with(|cpu| script.run_frame(cpu))?;
+            for line in script.take_log() {
+                eprintln!("{}", line);
+            }
+            Ok(script.overlay_rows())
+        },
+        None => {
+            runner.run_frame();
+            Ok([0; 32])
+        },
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+fn run_frame(runner: &mut Runner, _script: &mut Option<()>) -> Result<[u64; 32], String> {
+    runner.run_frame();
+    Ok([0; 32])
+}
+
+/// A preset by name, or else a JSON file
+fn load_keymap(name: &str) -> Result<KeyMap, String> {
+    if let Some(keymap) = KeyMap::preset(name) {
+        return Ok(keymap);
+    }
+    let text = fs::read_to_string(name).map_err(|e| format!("{}: {}", name, e))?;
+    KeyMap::from_json(&text).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn main() {
+    let result = parse_args()
+        .map_err(|e| format!("{}\n{}", e, USAGE))
+        .and_then(run);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,38 @@
+//! Recompile a ROM ahead of time into a WebAssembly module, see
+//! `chip8::recompiler` for how to run it.
+//!
+//! ```text
+//! cargo run --bin recompile -- roms/PONG pong.wasm
+//! ```
+extern crate chip8;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use chip8::recompiler::compile;
+
+const USAGE: &str = "usage: recompile <rom> <output.wasm>";
+
+fn run(rom_path: &str, output: &str) -> Result<(), String> {
+    let rom = fs::read(rom_path).map_err(|e| format!("{}: {}", rom_path, e))?;
+    let program = compile(&rom).map_err(|e| format!("{}: {}", rom_path, e))?;
+    fs::write(output, &program.wasm).map_err(|e| format!("{}: {}", output, e))?;
+
+    let instructions: usize = program.blocks.iter().map(|b| b.instructions.len()).sum();
+    println!("{} blocks, {} instructions, {} bytes of wasm",
+             program.blocks.len(), instructions, program.wasm.len());
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 2 {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    if let Err(e) = run(&args[0], &args[1]) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,300 @@
+//! A C API around `CPU`, exported from the `staticlib` and `cdylib` builds
+//! of the crate. The header, `include/chip8.h`, is generated from this file
+//! with cbindgen and checked by `cargo test --test header`:
+//!
+//! ```text
+//! Chip8 *machine = chip8_new(0);
+//! if (chip8_load_rom(machine, rom, rom_len) != CHIP8_STATUS_OK) {
+//!     fprintf(stderr, "%s\n", chip8_last_error(machine));
+//! }
+//! chip8_key_down(machine, 5);
+//! chip8_run_frames(machine, 60);
+//! const uint8_t *pixels = chip8_framebuffer(machine);
+//! chip8_free(machine);
+//! ```
+//!
+//! Functions report failures with a `Chip8Status` rather than panicking,
+//! and `chip8_last_error` describes the last one. Machines must come from
+//! `chip8_new`, but may be NULL, and buffers must hold at least the number
+//! of bytes passed with them.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use cpu::{State, CPU, STATE_LEN};
+
+/// Result of a call, 0 on success
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Chip8Status {
+    Ok = 0,
+    NullPointer,
+    InvalidRom,
+    InvalidKey,
+    InvalidState,
+    BufferTooSmall,
+    /// the interpreter hit a bug; the machine should be freed
+    Panic,
+}
+
+/// A machine with a ROM loaded, opaque to C
+pub struct Chip8 {
+    cpu: CPU,
+    /// the screen as returned by `chip8_framebuffer`, updated whenever the
+    /// machine runs or is restored
+    framebuffer: Vec<u8>,
+    /// message for the last call that failed
+    error: CString,
+}
+
+impl Chip8 {
+    fn update_framebuffer(&mut self) {
+        self.framebuffer.copy_from_slice(&self.cpu.screen.to_bytes());
+    }
+}
+
+/// Run `f` on a machine, turning NULL pointers, errors and panics into a
+/// status and keeping the message for `chip8_last_error`
+unsafe fn with_machine<F>(machine: *mut Chip8, f: F) -> Chip8Status
+    where F: FnOnce(&mut Chip8) -> Result<(), (Chip8Status, String)>
+{
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return Chip8Status::NullPointer,
+    };
+    let result = panic::catch_unwind(AssertUnwindSafe(|| f(machine)))
+        .unwrap_or_else(|_| Err((Chip8Status::Panic, "the interpreter panicked".to_string())));
+    match result {
+        Ok(()) => Chip8Status::Ok,
+        Err((status, message)) => {
+            machine.error = CString::new(message).unwrap_or_default();
+            status
+        },
+    }
+}
+
+fn check_key(key: u8) -> Result<(), (Chip8Status, String)> {
+    if key > 0xF {
+        return Err((Chip8Status::InvalidKey, format!("invalid key {}", key)));
+    }
+    Ok(())
+}
+
+/// Create a machine with no ROM loaded, whose random numbers are seeded
+/// with `seed`. Free it with `chip8_free`
+#[no_mangle]
+pub extern "C" fn chip8_new(seed: u32) -> *mut Chip8 {
+    let mut cpu = CPU::new();
+    cpu.seed(seed);
+    cpu.reset();
+    let mut machine = Chip8 { cpu, framebuffer: vec![0; 64 * 32], error: CString::default() };
+    machine.update_framebuffer();
+    Box::into_raw(Box::new(machine))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(machine: *mut Chip8) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+/// Describe why the last call that failed did. The string belongs to the
+/// machine and lasts until the next failure
+#[no_mangle]
+pub unsafe extern "C" fn chip8_last_error(machine: *const Chip8) -> *const c_char {
+    match machine.as_ref() {
+        Some(machine) => machine.error.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Reset the machine and load a ROM, with the quirks and speed the ROM
+/// database has for it. On failure the machine is left as it was
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(machine: *mut Chip8, rom: *const u8, len: usize) -> Chip8Status {
+    if rom.is_null() {
+        return Chip8Status::NullPointer;
+    }
+    let rom = slice::from_raw_parts(rom, len);
+    with_machine(machine, |machine| {
+        let mut cpu = machine.cpu.clone();
+        cpu.reset();
+        cpu.load_rom(rom).map_err(|e| (Chip8Status::InvalidRom, e))?;
+        machine.cpu = cpu;
+        machine.update_framebuffer();
+        Ok(())
+    })
+}
+
+/// Execute a single instruction, without touching the timers
+#[no_mangle]
+pub unsafe extern "C" fn chip8_step(machine: *mut Chip8) -> Chip8Status {
+    with_machine(machine, |machine| {
+        machine.cpu.cycle();
+        machine.update_framebuffer();
+        Ok(())
+    })
+}
+
+/// Run `frames` 60 Hz frames, each executing the ROM's instructions per
+/// frame and then decrementing the timers
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frames(machine: *mut Chip8, frames: u32) -> Chip8Status {
+    with_machine(machine, |machine| {
+        for _ in 0..frames {
+            machine.cpu.run_frame();
+        }
+        machine.update_framebuffer();
+        Ok(())
+    })
+}
+
+/// Press a key, 0 to F
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_down(machine: *mut Chip8, key: u8) -> Chip8Status {
+    with_machine(machine, |machine| {
+        check_key(key)?;
+        machine.cpu.keypad.key_down(key);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chip8_key_up(machine: *mut Chip8, key: u8) -> Chip8Status {
+    with_machine(machine, |machine| {
+        check_key(key)?;
+        machine.cpu.keypad.key_up(key);
+        Ok(())
+    })
+}
+
+/// The 64x32 screen, one byte per pixel row by row from the top left, 1
+/// for set pixels and 0 for unset ones. The pointer stays valid until the
+/// machine is freed
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(machine: *const Chip8) -> *const u8 {
+    match machine.as_ref() {
+        Some(machine) => machine.framebuffer.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// Read the delay and sound timers. Either pointer may be NULL
+#[no_mangle]
+pub unsafe extern "C" fn chip8_timers(machine: *mut Chip8, delay: *mut u8, sound: *mut u8) -> Chip8Status {
+    with_machine(machine, |machine| {
+        if let Some(delay) = delay.as_mut() {
+            *delay = machine.cpu.delay_timer();
+        }
+        if let Some(sound) = sound.as_mut() {
+            *sound = machine.cpu.sound_timer();
+        }
+        Ok(())
+    })
+}
+
+/// Bytes needed to save the state of a machine
+#[no_mangle]
+pub extern "C" fn chip8_state_size() -> usize {
+    STATE_LEN
+}
+
+/// Save the state of the machine into `buffer`, which must hold at least
+/// `chip8_state_size()` bytes
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(machine: *mut Chip8, buffer: *mut u8, len: usize) -> Chip8Status {
+    if buffer.is_null() {
+        return Chip8Status::NullPointer;
+    }
+    let buffer = slice::from_raw_parts_mut(buffer, len);
+    with_machine(machine, |machine| {
+        if len < STATE_LEN {
+            return Err((Chip8Status::BufferTooSmall,
+                        format!("state needs {} bytes, buffer has {}", STATE_LEN, len)));
+        }
+        buffer[..STATE_LEN].copy_from_slice(&machine.cpu.state().to_bytes());
+        Ok(())
+    })
+}
+
+/// Restore a state saved by `chip8_save_state`
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(machine: *mut Chip8, buffer: *const u8, len: usize) -> Chip8Status {
+    if buffer.is_null() {
+        return Chip8Status::NullPointer;
+    }
+    let buffer = slice::from_raw_parts(buffer, len);
+    with_machine(machine, |machine| {
+        let state = State::from_bytes(buffer).map_err(|e| (Chip8Status::InvalidState, e))?;
+        machine.cpu.set_state(&state).map_err(|e| (Chip8Status::InvalidState, e))?;
+        machine.update_framebuffer();
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn run() {
+        unsafe {
+            let machine = chip8_new(0);
+            // draw the font's 0 and wait for key 5
+            let rom = [0xD0, 0x15, 0x6E, 0x05, 0xEE, 0x9E, 0x12, 0x04, 0x12, 0x08];
+            assert_eq!(chip8_load_rom(machine, rom.as_ptr(), rom.len()), Chip8Status::Ok);
+            assert_eq!(chip8_step(machine), Chip8Status::Ok);
+            let framebuffer = chip8_framebuffer(machine);
+            let pixels = slice::from_raw_parts(framebuffer, 64 * 32);
+            assert_eq!(&pixels[..5], &[1, 1, 1, 1, 0]);
+
+            assert_eq!(chip8_key_down(machine, 5), Chip8Status::Ok);
+            assert_eq!(chip8_run_frames(machine, 2), Chip8Status::Ok);
+            assert_eq!(chip8_framebuffer(machine), framebuffer);
+            let (mut delay, mut sound) = (0, 0);
+            assert_eq!(chip8_timers(machine, &mut delay, &mut sound), Chip8Status::Ok);
+            assert_eq!((delay, sound), (253, 0));
+            assert_eq!(chip8_key_up(machine, 16), Chip8Status::InvalidKey);
+            assert_eq!(CStr::from_ptr(chip8_last_error(machine)).to_str(), Ok("invalid key 16"));
+
+            assert_eq!(chip8_load_rom(machine, rom.as_ptr(), 0), Chip8Status::InvalidRom);
+            assert_eq!(CStr::from_ptr(chip8_last_error(machine)).to_str(), Ok("ROM is empty"));
+            chip8_free(machine);
+        }
+    }
+
+    #[test]
+    fn state() {
+        unsafe {
+            let machine = chip8_new(0);
+            let rom = [0x70, 0x01, 0x12, 0x00];
+            chip8_load_rom(machine, rom.as_ptr(), rom.len());
+            let mut state = vec![0; chip8_state_size()];
+            assert_eq!(chip8_save_state(machine, state.as_mut_ptr(), state.len()), Chip8Status::Ok);
+            chip8_run_frames(machine, 1);
+            assert_eq!(chip8_load_state(machine, state.as_ptr(), state.len()), Chip8Status::Ok);
+            assert_eq!((*machine).cpu.v[0], 0);
+
+            assert_eq!(chip8_save_state(machine, state.as_mut_ptr(), 10), Chip8Status::BufferTooSmall);
+            assert_eq!(chip8_load_state(machine, state.as_ptr(), 10), Chip8Status::InvalidState);
+            chip8_free(machine);
+        }
+    }
+
+    #[test]
+    fn null_pointers() {
+        unsafe {
+            assert_eq!(chip8_step(ptr::null_mut()), Chip8Status::NullPointer);
+            assert_eq!(chip8_load_rom(ptr::null_mut(), [0].as_ptr(), 1), Chip8Status::NullPointer);
+            assert!(chip8_framebuffer(ptr::null()).is_null());
+            assert!(chip8_last_error(ptr::null()).is_null());
+            chip8_free(ptr::null_mut());
+        }
+    }
+}
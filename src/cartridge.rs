@@ -0,0 +1,194 @@
+//! Octo cartridges: GIF images, with a label for people to look at, that
+//! carry a program's Octo source and the settings it should run with.
+//!
+//! Each pixel's colour index holds two bits of payload in its low bits,
+//! most significant bits first, four pixels to a byte, through every image
+//! of the GIF in order. The payload is a big endian 32 bit length followed
+//! by that many bytes of JSON:
+//!
+//! ```text
+//! {"program": ": main ...", "options": {"tickrate": 20, "shiftQuirks": true, ...}}
+//! ```
+//!
+//! The program is assembled with `octo::assemble`. Of the options, the tick
+//! rate, the `shiftQuirks`, `loadStoreQuirks`, `clipQuirks`, `jumpQuirks`
+//! and `logicQuirks` quirks, and `backgroundColor` and `fillColor` are used.
+//! The rest only apply to Octo's own emulator and are ignored. That includes
+//! key layouts and touch input modes, which aren't supported: keys are
+//! always mapped by the front-end's `KeyMap`.
+use cpu::CPU;
+use database::parse_color;
+use gif;
+use json::{self, Value};
+use octo;
+use quirks::Quirks;
+
+pub struct Cartridge {
+    /// Octo source of the program
+    pub source: String,
+    /// the assembled program
+    pub rom: Vec<u8>,
+    pub quirks: Quirks,
+    /// instructions per frame, if the cartridge sets a tick rate
+    pub cycles_per_frame: Option<usize>,
+    /// colours for unset and set pixels, if the cartridge sets both
+    pub palette: Option<[[u8; 3]; 2]>,
+}
+
+impl Cartridge {
+    /// Read a cartridge from the bytes of its GIF image
+    pub fn from_gif(data: &[u8]) -> Result<Cartridge, String> {
+        let mut bytes = Vec::new();
+        let mut byte = 0;
+        for (i, &index) in gif::decode(data)?.iter().flat_map(|image| image.indices.iter()).enumerate() {
+            byte = byte << 2 | (index & 3);
+            if i % 4 == 3 {
+                bytes.push(byte);
+                byte = 0;
+            }
+        }
+        if bytes.len() < 4 {
+            return Err("the image holds no cartridge data".to_string());
+        }
+        let len = (bytes[0] as usize) << 24 | (bytes[1] as usize) << 16 |
+            (bytes[2] as usize) << 8 | bytes[3] as usize;
+        let payload = bytes.get(4..4 + len)
+            .ok_or("the image is too small for its cartridge data, or isn't a cartridge")?;
+        let text = String::from_utf8(payload.to_vec()).map_err(|_| "cartridge data is not UTF-8")?;
+        Cartridge::from_json(&text)
+    }
+
+    /// Read a cartridge from its JSON payload
+    pub fn from_json(text: &str) -> Result<Cartridge, String> {
+        let payload = json::parse(text).map_err(|e| format!("cartridge data: {}", e))?;
+        let source = payload.get("program").and_then(Value::as_str)
+            .ok_or("cartridge data has no program")?;
+        let rom = octo::assemble(source)?;
+
+        let mut cartridge = Cartridge {
+            source: source.to_string(),
+            rom,
+            quirks: Quirks::new(),
+            cycles_per_frame: None,
+            palette: None,
+        };
+        let options = match payload.get("options") {
+            Some(options) => options,
+            None => return Ok(cartridge),
+        };
+        let quirk = |name: &str| options.get(name).and_then(Value::as_bool);
+        if let Some(shift) = quirk("shiftQuirks") {
+            cartridge.quirks.shift = shift;
+        }
+        if let Some(load_store) = quirk("loadStoreQuirks") {
+            cartridge.quirks.memory_leave_i_unchanged = load_store;
+        }
+        if let Some(clip) = quirk("clipQuirks") {
+            cartridge.quirks.wrap = !clip;
+        }
+        if let Some(jump) = quirk("jumpQuirks") {
+            cartridge.quirks.jump = jump;
+        }
+        if let Some(logic) = quirk("logicQuirks") {
+            cartridge.quirks.logic = logic;
+        }
+        cartridge.cycles_per_frame = match options.get("tickrate") {
+            Some(tickrate) => match tickrate.as_u64() {
+                Some(rate) if rate > 0 && rate <= 100_000 => Some(rate as usize),
+                _ => return Err(format!("invalid tickrate {}", tickrate)),
+            },
+            None => None,
+        };
+        let color = |name: &str| options.get(name).and_then(Value::as_str).and_then(parse_color);
+        if let (Some(off), Some(on)) = (color("backgroundColor"), color("fillColor")) {
+            cartridge.palette = Some([off, on]);
+        }
+        Ok(cartridge)
+    }
+
+    /// Switch `cpu` to the cartridge's settings, after loading its ROM.
+    /// These take precedence over the ROM database
+    pub fn configure(&self, cpu: &mut CPU) {
+        cpu.set_quirks(self.quirks);
+        if let Some(cycles) = self.cycles_per_frame {
+            cpu.set_cycles_per_frame(cycles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Build a cartridge image, with a blank label and without compression
+    fn cartridge_gif(payload: &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        let indices: Vec<u8> = bytes.iter()
+            .flat_map(|&byte| (0..4).rev().map(move |i| byte >> (2 * i) & 3))
+            .collect();
+        let (width, height) = (64, indices.len().div_ceil(64));
+
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&[64, 0, height as u8, 0, 0x81, 0, 0]);
+        gif.extend_from_slice(&[0, 0, 0, 85, 85, 85, 170, 170, 170, 255, 255, 255]);
+        gif.extend_from_slice(&[0x2C, 0, 0, 0, 0, width as u8, 0, height as u8, 0, 0, 2]);
+        // 3 bit codes, with a clear code after every pair so they stay 3 bits
+        let mut codes = vec![4];
+        for pair in indices.chunks(2) {
+            codes.extend(pair.iter().map(|&i| i as u16));
+            codes.push(4);
+        }
+        codes.push(5);
+        let mut data = Vec::new();
+        for (i, &code) in codes.iter().enumerate() {
+            let bit = i * 3;
+            if bit / 8 + 1 >= data.len() {
+                data.resize(bit / 8 + 2, 0);
+            }
+            let shifted = (code as u32) << (bit % 8);
+            data[bit / 8] |= shifted as u8;
+            data[bit / 8 + 1] |= (shifted >> 8) as u8;
+        }
+        for block in data.chunks(255) {
+            gif.push(block.len() as u8);
+            gif.extend_from_slice(block);
+        }
+        gif.extend_from_slice(&[0, 0x3B]);
+        gif
+    }
+
+    #[test]
+    fn load() {
+        let gif = cartridge_gif(r##"{
+            "program": ": main\n v0 := 7\n loop again",
+            "options": {"tickrate": 100, "shiftQuirks": true, "clipQuirks": true,
+                        "backgroundColor": "#996600", "fillColor": "#FFCC00", "fontStyle": "octo"}
+        }"##);
+        let cartridge = Cartridge::from_gif(&gif).unwrap();
+        assert_eq!(cartridge.rom, vec![0x12, 0x02, 0x60, 0x07, 0x12, 0x04]);
+        assert_eq!(cartridge.palette, Some([[0x99, 0x66, 0], [0xFF, 0xCC, 0]]));
+
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&cartridge.rom).unwrap();
+        cartridge.configure(&mut cpu);
+        assert_eq!(cpu.cycles_per_frame(), 100);
+        assert!(cpu.quirks().shift);
+        assert!(!cpu.quirks().wrap);
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!(cpu.v[0], 7);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Cartridge::from_gif(b"not a gif").is_err());
+        assert_eq!(Cartridge::from_gif(&cartridge_gif("{}")).err(),
+                   Some("cartridge data has no program".to_string()));
+        assert!(Cartridge::from_gif(&cartridge_gif(r#"{"program": ": main hires"}"#)).is_err());
+        let mut truncated = cartridge_gif(r#"{"program": ": main"}"#);
+        truncated.truncate(40);
+        assert!(Cartridge::from_gif(&truncated).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Cheats: searching memory for values such as lives or the score, and
+//! freezing memory or registers to fixed values.
+//!
+//! Cheats are written one per line as `ADDR:VALUE`, in hex, where `ADDR` is
+//! a memory address or a register `V0` to `VF`, optionally followed by a
+//! description. Disabled cheats start with `-`, and lines starting with `#`
+//! are comments:
+//!
+//! ```text
+//! 2F0:03 infinite lives
+//! -VE:00 always score zero
+//! ```
+//!
+//! Enabled cheats in `CPU::cheats` are written at the start of every frame.
+//! Cheats only make sense for the ROM they were found in, so front-ends
+//! store them by `CPU::rom_sha1`.
+use std::fmt;
+
+use cpu::CPU;
+
+/// Where a cheat writes its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Memory(u16),
+    Register(u8),
+}
+
+impl Target {
+    /// Parse a hex address below 0x1000, or a register `V0` to `VF`
+    pub fn parse(text: &str) -> Option<Target> {
+        if text.starts_with('V') || text.starts_with('v') {
+            match u8::from_str_radix(&text[1..], 16) {
+                Ok(register) if register < 16 && text.len() == 2 => Some(Target::Register(register)),
+                _ => None,
+            }
+        } else {
+            match u16::from_str_radix(text, 16) {
+                Ok(addr) if addr < 0x1000 => Some(Target::Memory(addr)),
+                _ => None,
+            }
+        }
+    }
+
+    /// The byte stored there
+    pub fn read(self, cpu: &CPU) -> u8 {
+        match self {
+            Target::Memory(addr) => cpu.read_memory(addr, 1)[0],
+            Target::Register(register) => cpu.v[register as usize],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub target: Target,
+    pub value: u8,
+    pub enabled: bool,
+    pub description: String,
+}
+
+impl Cheat {
+    /// Parse a cheat from a line in the format above
+    pub fn parse(line: &str) -> Result<Cheat, String> {
+        let line = line.trim();
+        let (enabled, line) = match line.strip_prefix('-') {
+            Some(rest) => (false, rest),
+            None => (true, line),
+        };
+        let (code, description) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim()),
+            None => (line, ""),
+        };
+        let invalid = || format!("invalid cheat `{}`, expected ADDR:VALUE", code);
+        let mut parts = code.splitn(2, ':');
+        let target = parts.next().ok_or_else(invalid)?;
+        let value = parts.next().ok_or_else(invalid)?;
+        let target = Target::parse(target).ok_or_else(invalid)?;
+        let value = u8::from_str_radix(value, 16).map_err(|_| invalid())?;
+        Ok(Cheat { target, value, enabled, description: description.to_string() })
+    }
+
+    /// Write the cheat's value to the CPU
+    pub fn apply(&self, cpu: &mut CPU) {
+        match self.target {
+            Target::Memory(addr) => cpu.write_memory(addr, &[self.value]),
+            Target::Register(register) => cpu.v[register as usize] = self.value,
+        }
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.enabled {
+            write!(f, "-")?;
+        }
+        match self.target {
+            Target::Memory(addr) => write!(f, "{:03X}:{:02X}", addr, self.value)?,
+            Target::Register(register) => write!(f, "V{:X}:{:02X}", register, self.value)?,
+        }
+        if !self.description.is_empty() {
+            write!(f, " {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+/// The cheats for a ROM, in the order they were added
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cheats {
+    pub cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats { cheats: Vec::new() }
+    }
+
+    /// Parse cheats, one per line
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let mut cheats = Cheats::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let cheat = Cheat::parse(line).map_err(|e| format!("line {}: {}", lineno + 1, e))?;
+            cheats.cheats.push(cheat);
+        }
+        Ok(cheats)
+    }
+
+    /// Write every enabled cheat to the CPU
+    pub fn apply(&self, cpu: &mut CPU) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            cheat.apply(cpu);
+        }
+    }
+}
+
+/// Prints one cheat per line, in the format `parse` reads
+impl fmt::Display for Cheats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cheat in self.cheats.iter() {
+            writeln!(f, "{}", cheat)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a byte must compare to its value in the last snapshot to stay a
+/// candidate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    /// is now this value
+    Equal(u8),
+    Unchanged,
+    Changed,
+    Increased,
+    Decreased,
+}
+
+impl Comparison {
+    fn matches(self, before: u8, after: u8) -> bool {
+        match self {
+            Comparison::Equal(value) => after == value,
+            Comparison::Unchanged => after == before,
+            Comparison::Changed => after != before,
+            Comparison::Increased => after > before,
+            Comparison::Decreased => after < before,
+        }
+    }
+}
+
+/// An incremental search of memory. Starting with every address, each
+/// `filter` keeps the addresses whose bytes compare as asked against the
+/// previous snapshot, such as a lives counter that decreased after losing
+/// a life, and then takes a new snapshot
+pub struct MemorySearch {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl MemorySearch {
+    /// Start a search over all of memory, snapshotting it as it is now
+    pub fn new(cpu: &CPU) -> MemorySearch {
+        MemorySearch { snapshot: cpu.read_memory(0, 0x1000), candidates: (0..0x1000).collect() }
+    }
+
+    /// Narrow the candidates down and return how many are left
+    pub fn filter(&mut self, cpu: &CPU, comparison: Comparison) -> usize {
+        let memory = cpu.read_memory(0, 0x1000);
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            comparison.matches(snapshot[addr as usize], memory[addr as usize])
+        });
+        self.snapshot = memory;
+        self.candidates.len()
+    }
+
+    /// Addresses that matched every filter so far
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    /// Value of a candidate in the latest snapshot
+    pub fn value(&self, addr: u16) -> u8 {
+        self.snapshot[addr as usize & 0xFFF]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let cheats = Cheats::parse("# lives\n2f0:03 infinite lives\n-VE:00\n").unwrap();
+        assert_eq!(cheats.cheats, vec![
+            Cheat { target: Target::Memory(0x2F0), value: 3, enabled: true, description: "infinite lives".to_string() },
+            Cheat { target: Target::Register(0xE), value: 0, enabled: false, description: String::new() },
+        ]);
+        assert_eq!(cheats.to_string(), "2F0:03 infinite lives\n-VE:00\n");
+        assert_eq!(Cheats::parse(&cheats.to_string()), Ok(cheats));
+
+        assert_eq!(Cheats::parse("\n1000:00").unwrap_err(), "line 2: invalid cheat `1000:00`, expected ADDR:VALUE");
+        assert!(Cheat::parse("VG:00").is_err());
+        assert!(Cheat::parse("V10:00").is_err());
+        assert!(Cheat::parse("200:100").is_err());
+        assert!(Cheat::parse("200").is_err());
+    }
+
+    #[test]
+    fn freeze() {
+        // count V0 down from 3 and keep a copy of it at 0x300
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0x60, 0x03, 0x70, 0xFF, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02]).unwrap();
+        cpu.set_cycles_per_frame(4);
+        *cpu.cheats_mut() = Cheats::parse("V0:09\n-300:AA").unwrap();
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        assert_eq!(cpu.read_memory(0x300, 1), vec![8]);
+    }
+
+    #[test]
+    fn search() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.write_memory(0x300, &[3, 3]);
+        let mut search = MemorySearch::new(&cpu);
+        assert_eq!(search.filter(&cpu, Comparison::Equal(3)), 2);
+        cpu.write_memory(0x300, &[2]);
+        assert_eq!(search.filter(&cpu, Comparison::Decreased), 1);
+        assert_eq!(search.candidates(), &[0x300]);
+        assert_eq!(search.value(0x300), 2);
+        assert_eq!(search.filter(&cpu, Comparison::Unchanged), 1);
+        assert_eq!(search.filter(&cpu, Comparison::Changed), 0);
+    }
+}
@@ -1,4 +1,14 @@
+use std::mem;
+use std::sync::Arc;
+
+use cheats::Cheats;
+use database::{Database, RomInfo};
+use hash::{sha1_hex, Fnv64};
+use instruction::{decode, Instruction};
 use keypad::Keypad;
+use quirks::Quirks;
+use rng::Rng;
+use runner::CYCLES_PER_FRAME;
 use screen::Screen;
 
 const SPRITES: [u8; 80] = [
@@ -20,6 +30,112 @@ const SPRITES: [u8; 80] = [
   0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
+/// Where programs are usually loaded, and where execution starts after a
+/// reset
+pub const PROGRAM_START: u16 = 0x200;
+/// Where programs for the ETI-660 are loaded
+pub const ETI_660_START: u16 = 0x600;
+/// Bytes of memory
+pub const MEMORY_LEN: usize = 0x1000;
+
+thread_local! {
+    /// The built-in ROM database, parsed once per thread and shared by
+    /// every CPU on it
+    static BUILTIN_DATABASE: Arc<Database> = Arc::new(Database::builtin());
+}
+
+/// Addresses are 12 bits wide. Anything that computes an address wraps
+/// around at the end of memory instead of indexing out of bounds
+const ADDRESS_MASK: u16 = 0xFFF;
+
+/// Index into memory for the address `offset` bytes past `base`
+fn address(base: u16, offset: u16) -> usize {
+    (base.wrapping_add(offset) & ADDRESS_MASK) as usize
+}
+
+/// A snapshot of the machine, used to inspect or restore a CPU
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    /// always 4096 bytes long
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub delay: u8,
+    pub sound: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; 16],
+    pub screen: [u64; 32],
+    pub keys: [bool; 16],
+    /// state of the random number generator
+    pub rng: u32,
+}
+
+/// Bytes in `State::to_bytes`: memory, registers, I, timers, PC, stack
+/// pointer, stack, screen rows, keys and the random number generator
+pub const STATE_LEN: usize = 4096 + 16 + 2 + 2 + 2 + 1 + 32 + 256 + 2 + 4;
+
+impl State {
+    /// Serialize the snapshot, with numbers in big endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(STATE_LEN);
+        bytes.extend_from_slice(&self.memory);
+        bytes.extend_from_slice(&self.v);
+        bytes.extend_from_slice(&self.i.to_be_bytes());
+        bytes.extend_from_slice(&[self.delay, self.sound]);
+        bytes.extend_from_slice(&self.pc.to_be_bytes());
+        bytes.push(self.sp);
+        for addr in self.stack.iter() {
+            bytes.extend_from_slice(&addr.to_be_bytes());
+        }
+        for row in self.screen.iter() {
+            bytes.extend_from_slice(&row.to_be_bytes());
+        }
+        let keys = (0..16).fold(0u16, |keys, key| keys | (self.keys[key] as u16) << key);
+        bytes.extend_from_slice(&keys.to_be_bytes());
+        bytes.extend_from_slice(&self.rng.to_be_bytes());
+        bytes
+    }
+
+    /// Read a snapshot serialized by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<State, String> {
+        if bytes.len() != STATE_LEN {
+            return Err(format!("expected {} bytes of state, got {}", STATE_LEN, bytes.len()));
+        }
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            pos += len;
+            &bytes[pos - len..pos]
+        };
+        let number = |bytes: &[u8]| bytes.iter().fold(0u64, |n, &byte| n << 8 | byte as u64);
+
+        let memory = take(4096).to_vec();
+        let mut v = [0; 16];
+        v.copy_from_slice(take(16));
+        let i = number(take(2)) as u16;
+        let timers = take(2);
+        let (delay, sound) = (timers[0], timers[1]);
+        let pc = number(take(2)) as u16;
+        let sp = take(1)[0];
+        let mut stack = [0; 16];
+        for addr in stack.iter_mut() {
+            *addr = number(take(2)) as u16;
+        }
+        let mut screen = [0; 32];
+        for row in screen.iter_mut() {
+            *row = number(take(8));
+        }
+        let key_bits = number(take(2));
+        let mut keys = [false; 16];
+        for (key, down) in keys.iter_mut().enumerate() {
+            *down = key_bits >> key & 1 == 1;
+        }
+        let rng = number(take(4)) as u32;
+        Ok(State { memory, v, i, delay, sound, pc, sp, stack, screen, keys, rng })
+    }
+}
+
+#[derive(Clone)]
 pub struct CPU {
     /// 4096 bytes of RAM. The first 512 bytes are where the original interpreter
     /// was located, so most programs start at location 512
@@ -44,7 +160,30 @@ pub struct CPU {
     /// 16 key keypad
     pub keypad: Keypad,
     /// 64x32 pixel monochrome display
-    pub screen: Screen
+    pub screen: Screen,
+    /// random number generator backing the `Cxkk` instruction
+    rng: Rng,
+    /// cache of decoded instructions, indexed by the address they start at.
+    /// Clones share it until one of them changes it
+    decoded: Arc<Vec<Option<Instruction>>>,
+    /// behaviours that differ between interpreters
+    quirks: Quirks,
+    /// how many instructions to run per 60 Hz frame
+    cycles_per_frame: usize,
+    /// per-ROM settings, the built-in database unless replaced. Shared by
+    /// clones
+    database: Option<Arc<Database>>,
+    /// address the last ROM was loaded at
+    rom_start: u16,
+    /// size in bytes of the last ROM loaded
+    rom_len: usize,
+    /// SHA-1 of the last ROM loaded
+    rom_sha1: Option<String>,
+    /// values frozen by cheats, written at the start of every frame
+    cheats: Cheats,
+    /// whether `Fx0A` is waiting for a key, so key events from before it
+    /// started waiting have been discarded
+    waiting_for_key: bool,
 }
 
 impl CPU {
@@ -61,14 +200,118 @@ impl CPU {
             sp: 0,
             stack: [0; 16],
             keypad: Keypad::new(),
-            screen: Screen::new()
+            screen: Screen::new(),
+            rng: Rng::new(0),
+            decoded: Arc::new(vec![None; MEMORY_LEN]),
+            quirks: Quirks::new(),
+            cycles_per_frame: CYCLES_PER_FRAME,
+            database: None,
+            rom_start: PROGRAM_START,
+            rom_len: 0,
+            rom_sha1: None,
+            cheats: Cheats::new(),
+            waiting_for_key: false,
         }
     }
 
+    /// Reseed the random number generator used by `Cxkk`. Two CPUs with the
+    /// same seed running the same program and input produce the same output
+    pub fn seed(&mut self, seed: u32) {
+        self.rng = Rng::new(seed);
+    }
+
     pub fn get_pc(&self) -> u16 {
         self.pc
     }
 
+    pub fn delay_timer(&self) -> u8 {
+        self.delay
+    }
+
+    /// The sound timer, which beeps while it is above 0
+    pub fn sound_timer(&self) -> u8 {
+        self.sound
+    }
+
+    /// Whether an `Fx0A` instruction is waiting for a key to be pressed
+    pub fn is_waiting_for_key(&self) -> bool {
+        self.waiting_for_key
+    }
+
+    /// Hash of the full machine state: memory, registers, timers, stack and
+    /// screen. Two CPUs with equal hashes are almost certainly in the same
+    /// state
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv64::new();
+        hasher.write(&self.memory);
+        hasher.write(&self.v);
+        hasher.write_u16(self.i);
+        hasher.write(&[self.delay, self.sound, self.sp]);
+        hasher.write_u16(self.pc);
+        for &addr in self.stack.iter() {
+            hasher.write_u16(addr);
+        }
+        hasher.write_u64(self.screen.hash());
+        hasher.finish()
+    }
+
+    /// Take a snapshot of the machine state
+    pub fn state(&self) -> State {
+        State {
+            memory: self.memory.to_vec(),
+            v: self.v,
+            i: self.i,
+            delay: self.delay,
+            sound: self.sound,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            screen: *self.screen.rows(),
+            keys: self.keypad.keys,
+            rng: self.rng.state(),
+        }
+    }
+
+    /// Restore a snapshot taken with `state()`. Snapshots with addresses
+    /// outside of memory or an overflowed stack are rejected. Snapshots
+    /// don't include key presses queued for `Fx0A`, so any are dropped and
+    /// a wait in progress starts again, answered only by keys pressed after
+    /// the restore
+    pub fn set_state(&mut self, state: &State) -> Result<(), String> {
+        self.sync_state(state)?;
+        self.keypad.clear_events();
+        self.waiting_for_key = false;
+        Ok(())
+    }
+
+    /// Like `set_state`, but keeping queued key presses and any wait in
+    /// progress, for handing the machine back from compiled code
+    pub(crate) fn sync_state(&mut self, state: &State) -> Result<(), String> {
+        if state.memory.len() != self.memory.len() {
+            return Err(format!("expected {} bytes of memory, got {}", self.memory.len(), state.memory.len()));
+        }
+        if state.pc > ADDRESS_MASK || state.i > ADDRESS_MASK {
+            return Err(format!("PC {:#x} or I {:#x} is outside of memory", state.pc, state.i));
+        }
+        if state.sp as usize > self.stack.len() {
+            return Err(format!("stack pointer {} is past the end of the stack", state.sp));
+        }
+
+        self.memory.copy_from_slice(&state.memory);
+        self.v = state.v;
+        self.i = state.i;
+        self.delay = state.delay;
+        self.sound = state.sound;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.stack = state.stack;
+        self.screen.set_rows(&state.screen);
+        self.keypad.keys = state.keys;
+        self.rng = Rng::new(state.rng);
+        self.invalidate_decoded();
+        Ok(())
+    }
+
     /// Reset the CPU and its display to their initial states
     pub fn reset(&mut self) {
         for i in 0..4096 {
@@ -84,11 +327,16 @@ impl CPU {
         self.i = 0;
         self.screen.clear();
         self.delay = 255;
-        self.sound = 255;
-        self.pc = 512;
+        // silent until the program starts the sound timer
+        self.sound = 0;
+        self.pc = PROGRAM_START;
         self.sp = 0;
+        self.waiting_for_key = false;
+        self.invalidate_decoded();
     }
 
+    /// Decrement the timers and advance the keypad's clock, once per 60 Hz
+    /// frame
     pub fn decrement_timers(&mut self) {
        if self.delay > 0 {
         self.delay -= 1;
@@ -96,137 +344,366 @@ impl CPU {
        if self.sound > 0 {
         self.sound -= 1;
        }
+       self.keypad.tick();
+    }
+
+    /// Run a single 60 Hz frame: apply the cheats, run `cycles_per_frame`
+    /// instructions, then decrement the timers
+    pub fn run_frame(&mut self) {
+        self.apply_cheats();
+        for _ in 0..self.cycles_per_frame {
+            self.cycle();
+        }
+        self.decrement_timers();
+    }
+
+    /// Write the values frozen by the enabled cheats, as `run_frame` does
+    /// at the start of every frame
+    pub fn apply_cheats(&mut self) {
+        let cheats = mem::replace(&mut self.cheats, Cheats::new());
+        cheats.apply(self);
+        self.cheats = cheats;
+    }
+
+    /// Load a ROM at the start of program memory, and switch to the quirks
+    /// and speed the ROM database recommends for it, or the defaults if it
+    /// isn't in the database
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), String> {
+        self.load_rom_at(data, PROGRAM_START)
+    }
+
+    /// Load a ROM at `address`, such as `ETI_660_START`, and start running
+    /// it from there. Fails without changing anything if the ROM is empty
+    /// or doesn't fit between `address` and the end of memory
+    pub fn load_rom_at(&mut self, data: &[u8], address: u16) -> Result<(), String> {
+        if data.is_empty() {
+            return Err("ROM is empty".to_string());
+        }
+        if address < PROGRAM_START || address as usize >= self.memory.len() {
+            return Err(format!("load address {:#X} is outside program memory ({:#X} to {:#X})",
+                               address, PROGRAM_START, self.memory.len() - 1));
+        }
+        let space = self.memory.len() - address as usize;
+        if data.len() > space {
+            return Err(format!("ROM is {} bytes, but only {} fit in memory at {:#X}",
+                               data.len(), space, address));
+        }
+
+        for (i, &byte) in data.iter().enumerate() {
+            self.store(address as usize + i, byte);
+        }
+        self.pc = address;
+        self.rom_start = address;
+        self.rom_len = data.len();
+        self.rom_sha1 = Some(sha1_hex(data));
+        self.cheats = Cheats::new();
+        self.apply_rom_info();
+        Ok(())
+    }
+
+    /// Cheats for the loaded ROM. Loading a ROM removes them
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    /// Address the loaded ROM starts at
+    pub fn rom_start(&self) -> u16 {
+        self.rom_start
+    }
+
+    /// Size in bytes of the loaded ROM, or 0 if none was loaded
+    pub fn rom_len(&self) -> usize {
+        self.rom_len
+    }
+
+    /// Hex SHA-1 of the loaded ROM
+    pub fn rom_sha1(&self) -> Option<&str> {
+        self.rom_sha1.as_deref()
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        for i in 0..data.len() {
-            self.memory[512 + i] = data[i];
+    /// Replace the ROM database, looking up the loaded ROM again
+    pub fn set_database(&mut self, database: Database) {
+        self.database = Some(Arc::new(database));
+        self.apply_rom_info();
+    }
+
+    /// The database entry for the loaded ROM
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        match (self.database.as_ref(), self.rom_sha1.as_ref()) {
+            (Some(database), Some(sha1)) => database.get(sha1),
+            _ => None,
         }
     }
 
+    fn apply_rom_info(&mut self) {
+        if self.database.is_none() {
+            self.database = Some(BUILTIN_DATABASE.with(|database| database.clone()));
+        }
+        let (quirks, cycles_per_frame) = match self.rom_info() {
+            Some(info) => (info.quirks, info.cycles_per_frame),
+            None => (Quirks::new(), CYCLES_PER_FRAME),
+        };
+        self.quirks = quirks;
+        self.cycles_per_frame = cycles_per_frame;
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Override the quirks picked by `load_rom`
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Number of instructions to run per 60 Hz frame
+    pub fn cycles_per_frame(&self) -> usize {
+        self.cycles_per_frame
+    }
+
+    /// Override the speed picked by `load_rom`
+    pub fn set_cycles_per_frame(&mut self, cycles: usize) {
+        self.cycles_per_frame = cycles;
+    }
+
     /// Read a single instruction at the program counter in memory
     pub fn read_instruction(&self) -> u16 {
-        (self.memory[self.pc as usize] as u16) << 8 |
-        (self.memory[(self.pc + 1) as usize] as u16)
+        (self.memory[address(self.pc, 0)] as u16) << 8 |
+        (self.memory[address(self.pc, 1)] as u16)
+    }
+
+    /// Fetch and execute the instruction at the program counter. Decoded
+    /// instructions are cached by address, so running the same code again
+    /// skips decoding until the memory it was decoded from is written to
+    pub fn cycle(&mut self) {
+        let pc = (self.pc & ADDRESS_MASK) as usize;
+        let instruction = match self.decoded[pc] {
+            Some(instruction) => instruction,
+            None => {
+                let instruction = decode(self.read_instruction());
+                Arc::make_mut(&mut self.decoded)[pc] = Some(instruction);
+                instruction
+            }
+        };
+        self.execute(instruction);
     }
 
     /// Process a single instruction and update the state of the CPU.
     /// This method is responsible for incrementing the PC after
-    /// exeucting the instruction but does not decrement the delay timers
+    /// exeucting the instruction but does not decrement the delay timers.
+    /// Calls past the end of the stack and returns from an empty stack are
+    /// ignored
     pub fn process_instruction(&mut self, instruction: u16) -> () {
-        // separate out instruction nibbles
-        let op1 = (instruction & 0xF000) >> 12;
-        let op2 = (instruction & 0x0F00) >> 8;
-        let op3 = (instruction & 0x00F0) >> 4;
-        let op4 = instruction & 0x000F;
-
-        // separate out the possible operands
-        let nnn = instruction & 0x0FFF;
-        let kk = (instruction & 0x00FF) as u8;
-        let x = op2 as usize;
-        let y = op3 as usize;
-        let n = op4 as u8;
-
-        self.pc += 2;
-        match (op1, op2, op3, op4) {
-            (0, 0, 0xE, 0) => self.screen.clear(),
-            (0, 0, 0xE, 0xE) => {
-                self.sp -= 1;
-                self.pc = self.stack[self.sp as usize];
+        self.execute(decode(instruction));
+    }
+
+    /// Read `len` bytes of memory starting at `addr`, wrapping around at the
+    /// end of memory like the interpreter does. At most all of memory is read
+    pub fn read_memory(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len.min(MEMORY_LEN)).map(|offset| self.memory[address(addr, offset as u16)]).collect()
+    }
+
+    /// Overwrite memory starting at `addr`, wrapping around at the end of
+    /// memory. Code that was overwritten is decoded again before it runs
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.store(address(addr, offset as u16), byte);
+        }
+    }
+
+    /// Write a byte to memory, dropping any cached instructions that
+    /// include it
+    fn store(&mut self, addr: usize, value: u8) {
+        // the byte is also the second half of an instruction starting one
+        // address earlier
+        let previous = if addr == 0 { self.memory.len() - 1 } else { addr - 1 };
+        self.memory[addr] = value;
+        // most writes are to data, which is never decoded, so the cache is
+        // only copied when it changes
+        if self.decoded[addr].is_some() || self.decoded[previous].is_some() {
+            let decoded = Arc::make_mut(&mut self.decoded);
+            decoded[addr] = None;
+            decoded[previous] = None;
+        }
+    }
+
+    /// Forget every cached instruction, after memory was replaced wholesale
+    fn invalidate_decoded(&mut self) {
+        match Arc::get_mut(&mut self.decoded) {
+            Some(decoded) => {
+                for entry in decoded.iter_mut() {
+                    *entry = None;
+                }
+            },
+            None => self.decoded = Arc::new(vec![None; MEMORY_LEN]),
+        }
+    }
+
+    /// The register `8xy6` and `8xyE` shift
+    fn shift_source(&self, x: u8, y: u8) -> usize {
+        if self.quirks.shift { x as usize } else { y as usize }
+    }
+
+    fn reset_flag_after_logic(&mut self) {
+        if self.quirks.logic {
+            self.v[0xF] = 0;
+        }
+    }
+
+    /// The value of I after `Fx55` or `Fx65`
+    fn i_after_load_store(&self, x: u8) -> u16 {
+        if self.quirks.memory_leave_i_unchanged {
+            self.i
+        } else if self.quirks.memory_increment_by_x {
+            address(self.i, x as u16) as u16
+        } else {
+            address(self.i, x as u16 + 1) as u16
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) {
+        self.pc = address(self.pc, 2) as u16;
+        match instruction {
+            Instruction::Clear => self.screen.clear(),
+            Instruction::Return => {
+                if self.sp > 0 {
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize];
+                }
             },
-            (0, ..) => println!("Unsupported subroutine {:#x?}", instruction),
-            (1, ..) => self.pc = nnn,
-            (2, ..) => {
-                // TODO: check for stack overflows
-                self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
-                self.pc = nnn;
+            Instruction::Sys(nnn) => println!("Unsupported subroutine {:#x?}", nnn),
+            Instruction::Jump(nnn) => self.pc = nnn,
+            Instruction::Call(nnn) => {
+                if (self.sp as usize) < self.stack.len() {
+                    self.stack[self.sp as usize] = self.pc;
+                    self.sp += 1;
+                    self.pc = nnn;
+                }
             }
-            (3, ..) => self.pc += if self.v[x] == kk { 2 } else { 0 },
-            (4, ..) => self.pc += if self.v[x] != kk { 2 } else { 0 },
-            (5, .., 0) => self.pc += if self.v[x] == self.v[y] { 2 } else { 0 },
-            (6, ..) => self.v[x] = kk as u8,
-            (7, ..) => self.v[x] = self.v[x].wrapping_add(kk),
-            (8, .., 0) => self.v[x] = self.v[y],
-            (8, .., 1) => self.v[x] |= self.v[y],
-            (8, .., 2) => self.v[x] &= self.v[y],
-            (8, .., 3) => self.v[x] ^= self.v[y],
-            (8, .., 4) => {
-                let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
-                self.v[x] = sum;
+            Instruction::SkipEqual(x, kk) => self.pc += if self.v[x as usize] == kk { 2 } else { 0 },
+            Instruction::SkipNotEqual(x, kk) => self.pc += if self.v[x as usize] != kk { 2 } else { 0 },
+            Instruction::SkipEqualReg(x, y) => self.pc += if self.v[x as usize] == self.v[y as usize] { 2 } else { 0 },
+            Instruction::Load(x, kk) => self.v[x as usize] = kk,
+            Instruction::Add(x, kk) => self.v[x as usize] = self.v[x as usize].wrapping_add(kk),
+            Instruction::Move(x, y) => self.v[x as usize] = self.v[y as usize],
+            Instruction::Or(x, y) => {
+                self.v[x as usize] |= self.v[y as usize];
+                self.reset_flag_after_logic();
+            },
+            Instruction::And(x, y) => {
+                self.v[x as usize] &= self.v[y as usize];
+                self.reset_flag_after_logic();
+            },
+            Instruction::Xor(x, y) => {
+                self.v[x as usize] ^= self.v[y as usize];
+                self.reset_flag_after_logic();
+            },
+            Instruction::AddReg(x, y) => {
+                let (sum, carry) = self.v[x as usize].overflowing_add(self.v[y as usize]);
+                self.v[x as usize] = sum;
                 self.v[0xF] = carry as u8;
             },
-            (8, .., 5) => {
-                let (diff, borrow) = self.v[x].overflowing_sub(self.v[y]);
-                self.v[x] = diff;
+            Instruction::Sub(x, y) => {
+                let (diff, borrow) = self.v[x as usize].overflowing_sub(self.v[y as usize]);
+                self.v[x as usize] = diff;
                 self.v[0xF] = (!borrow) as u8;
             },
-            (8, .., 6) => {
-                self.v[0xF] = self.v[y] & 1;
-                self.v[x] = self.v[y] >> 1;
+            Instruction::ShiftRight(x, y) => {
+                let value = self.v[self.shift_source(x, y)];
+                self.v[x as usize] = value >> 1;
+                self.v[0xF] = value & 1;
             }
-            (8, .., 7) => {
-                let (diff, borrow) = self.v[y].overflowing_sub(self.v[x]);
-                self.v[x] = diff;
+            Instruction::SubReverse(x, y) => {
+                let (diff, borrow) = self.v[y as usize].overflowing_sub(self.v[x as usize]);
+                self.v[x as usize] = diff;
                 self.v[0xF] = (!borrow) as u8;
             },
-            (8, .., 0xE) => {
-                self.v[0xF] = self.v[y] >> 7;
-                self.v[x] = self.v[y] << 1;
+            Instruction::ShiftLeft(x, y) => {
+                let value = self.v[self.shift_source(x, y)];
+                self.v[x as usize] = value << 1;
+                self.v[0xF] = value >> 7;
             },
-            (9, .., 0) => self.pc += if self.v[x] != self.v[y] { 2 } else { 0 },
-            (0xA, ..) => self.i = nnn,
-            (0xB, ..) => self.pc = nnn + (self.v[0] as u16),
-            (0xC, ..) => self.v[x] = (::js_sys::Math::random() * 255.0) as u8 & kk,
-            (0xD, ..) => {
-                let (start, end) = (self.i as usize, (self.i + (n as u16)) as usize);
-                self.v[0xF] = self.screen.draw_sprite(
-                    self.v[x as usize] as usize,
-                    self.v[y as usize] as usize,
-                    &self.memory[start .. end]) as u8;
+            Instruction::SkipNotEqualReg(x, y) => self.pc += if self.v[x as usize] != self.v[y as usize] { 2 } else { 0 },
+            Instruction::LoadI(nnn) => self.i = nnn,
+            Instruction::JumpOffset(nnn) => {
+                let offset = if self.quirks.jump { self.v[(nnn >> 8) as usize] } else { self.v[0] };
+                self.pc = nnn + offset as u16;
+            },
+            Instruction::Random(x, kk) => self.v[x as usize] = self.rng.next_u8() & kk,
+            Instruction::Draw(x, y, n) => {
+                let mut sprite = [0; 15];
+                for row in 0..n as u16 {
+                    sprite[row as usize] = self.memory[address(self.i, row)];
+                }
+                // the sprite starts at Vx and Vy wrapped onto the screen,
+                // even when the rest of it is clipped
+                let (x, y) = (self.v[x as usize] as usize % 64, self.v[y as usize] as usize % 32);
+                let sprite = &sprite[.. n as usize];
+                self.v[0xF] = if self.quirks.wrap {
+                    self.screen.draw_sprite(x, y, sprite)
+                } else {
+                    self.screen.draw_sprite_clipped(x, y, sprite)
+                } as u8;
             },
-            (0xE, _, 9, 0xE) => {
-                if self.keypad.is_key_down(self.v[x]) {
+            Instruction::SkipKeyDown(x) => {
+                if self.keypad.is_key_down(self.v[x as usize] & 0xF) {
                     self.pc += 2
                 }
             },
-            (0xE, _, 0xA, 1) => {
-                if !self.keypad.is_key_down(self.v[x]) {
+            Instruction::SkipKeyUp(x) => {
+                if !self.keypad.is_key_down(self.v[x as usize] & 0xF) {
                     self.pc += 2
                 }
             },
-            (0xF, _, 0, 7) => self.v[x] = self.delay,
-            (0xF, _, 0, 0xA) => {
-                match self.keypad.get_first_key_down() {
-                    Some(key) => self.v[x] = key,
-                    None => self.pc -= 2 // rerun this instruction again
+            Instruction::LoadDelay(x) => self.v[x as usize] = self.delay,
+            Instruction::WaitKey(x) => {
+                // only keys pressed after the wait started count, so
+                // holding a key down doesn't answer every prompt
+                if !self.waiting_for_key {
+                    self.keypad.clear_events();
+                    self.waiting_for_key = true;
+                }
+                match self.keypad.take_key(self.quirks.key_release) {
+                    Some(key) => {
+                        self.v[x as usize] = key;
+                        self.waiting_for_key = false;
+                    },
+                    // rerun this instruction again
+                    None => self.pc = self.pc.wrapping_sub(2)
                 }
             },
-            (0xF, _, 1, 5) => self.delay = self.v[x],
-            (0xF, _, 1, 8) => self.sound = self.v[x],
-            (0xF, _, 1, 0xE) => self.i += self.v[x] as u16,
-            // TODO check that 0 <= vX <= 16
-            (0xF, _, 2, 9) => self.i = self.v[x] as u16 * 5,
-            (0xF, _, 3, 3) => {
-                // TODO: check bounds on i register
-                self.memory[self.i as usize] = self.v[x] % 10;
-                self.memory[(self.i + 1) as usize] = (self.v[x] / 10) % 10;
-                self.memory[(self.i + 2) as usize] = (self.v[x] / 100) % 10;
+            Instruction::SetDelay(x) => self.delay = self.v[x as usize],
+            Instruction::SetSound(x) => self.sound = self.v[x as usize],
+            Instruction::AddI(x) => self.i = address(self.i, self.v[x as usize] as u16) as u16,
+            // only the low nibble of vX selects a font character
+            Instruction::LoadFont(x) => self.i = (self.v[x as usize] & 0xF) as u16 * 5,
+            Instruction::StoreBcd(x) => {
+                let value = self.v[x as usize];
+                self.store(address(self.i, 0), value / 100);
+                self.store(address(self.i, 1), (value / 10) % 10);
+                self.store(address(self.i, 2), value % 10);
             },
-            (0xF, _, 5, 5) => {
-                for i in 0..x {
-                    self.memory[self.i as usize] = self.v[i];
-                    self.i += 1;
+            Instruction::StoreRegs(x) => {
+                for i in 0..=x as usize {
+                    let value = self.v[i];
+                    self.store(address(self.i, i as u16), value);
                 }
+                self.i = self.i_after_load_store(x);
             },
-            (0xF, _, 6, 5) => {
-                for i in 0..x {
-                    self.v[i] = self.memory[self.i as usize];
-                    self.i += 1;
+            Instruction::LoadRegs(x) => {
+                for i in 0..=x as usize {
+                    self.v[i] = self.memory[address(self.i, i as u16)];
                 }
+                self.i = self.i_after_load_store(x);
             },
-            _ => println!("Unknown instruction: {:#x?}", instruction)
+            Instruction::Unknown(instruction) => println!("Unknown instruction: {:#x?}", instruction)
         }
+        // skips and Bnnn can step past the end of memory
+        self.pc &= ADDRESS_MASK;
     }
 }
 
@@ -440,7 +917,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.v[0xB] = 17;
         cpu.process_instruction(0xFB15);
-        assert_eq!(cpu.delay, 17);
+        assert_eq!(cpu.delay_timer(), 17);
 
         cpu.process_instruction(0xFA07);
         assert_eq!(cpu.v[0xA], 17);
@@ -451,7 +928,7 @@ mod test {
         let mut cpu = CPU::new();
         cpu.v[0xE] = 211;
         cpu.process_instruction(0xFE18);
-        assert_eq!(cpu.sound, 211);
+        assert_eq!(cpu.sound_timer(), 211);
     }
 
     #[test]
@@ -469,4 +946,331 @@ mod test {
         cpu.process_instruction(0xF31E);
         assert_eq!(cpu.i, 3032);
     }
+
+    #[test]
+    fn shift_flag_wins() {
+        let mut cpu = CPU::new();
+        cpu.v[3] = 0b10000100;
+        cpu.process_instruction(0x8F36);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.process_instruction(0x8F3E);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn bcd() {
+        let mut cpu = CPU::new();
+        cpu.v[7] = 254;
+        cpu.i = 0x300;
+        cpu.process_instruction(0xF733);
+        assert_eq!(&cpu.memory[0x300..0x303], &[2, 5, 4]);
+    }
+
+    #[test]
+    fn store_load_regs() {
+        let mut cpu = CPU::new();
+        for i in 0..16 {
+            cpu.v[i] = i as u8 + 1;
+        }
+        cpu.i = 0x300;
+        cpu.process_instruction(0xF255);
+        assert_eq!(&cpu.memory[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(cpu.i, 0x303);
+
+        cpu.i = 0x300;
+        cpu.v = [0; 16];
+        cpu.process_instruction(0xF265);
+        assert_eq!(&cpu.v[0..4], &[1, 2, 3, 0]);
+        assert_eq!(cpu.i, 0x303);
+    }
+
+    #[test]
+    fn stack_bounds() {
+        let mut cpu = CPU::new();
+        cpu.pc = 100;
+        cpu.process_instruction(0x00EE);
+        assert_eq!(cpu.sp, 0);
+        assert_eq!(cpu.pc, 102);
+
+        for _ in 0..17 {
+            cpu.process_instruction(0x2200);
+        }
+        assert_eq!(cpu.sp, 16);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
+    #[test]
+    fn addresses_wrap() {
+        let mut cpu = CPU::new();
+        cpu.i = 0xFFE;
+        cpu.v[0] = 254;
+        cpu.process_instruction(0xF033);
+        assert_eq!(cpu.memory[0xFFE], 2);
+        assert_eq!(cpu.memory[0xFFF], 5);
+        assert_eq!(cpu.memory[0], 4);
+
+        cpu.v[1] = 4;
+        cpu.process_instruction(0xF11E);
+        assert_eq!(cpu.i, 2);
+
+        cpu.v[0] = 0xFF;
+        cpu.process_instruction(0xB0FF);
+        assert_eq!(cpu.pc, 0x1FE);
+    }
+
+    #[test]
+    fn draw_start_wraps() {
+        for &wrap in [true, false].iter() {
+            let mut cpu = CPU::new();
+            cpu.reset();
+            let mut quirks = Quirks::new();
+            quirks.wrap = wrap;
+            cpu.set_quirks(quirks);
+            // the 0 glyph at (70, 40) starts at (6, 8)
+            cpu.v[1] = 70;
+            cpu.v[2] = 40;
+            cpu.process_instruction(0xD125);
+            assert_eq!(cpu.v[0xF], 0);
+            assert!(cpu.screen.get_pixel(6, 8));
+            cpu.process_instruction(0xD125);
+            assert_eq!(cpu.v[0xF], 1);
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0x12, 0x34]).unwrap();
+        cpu.process_instruction(0x2300);
+        cpu.screen.draw_sprite(3, 4, &[0xFF]);
+        let state = cpu.state();
+
+        let mut other = CPU::new();
+        other.set_state(&state).unwrap();
+        assert_eq!(other.state(), state);
+        assert_eq!(other.state_hash(), cpu.state_hash());
+
+        let mut bad = state.clone();
+        bad.sp = 17;
+        assert!(other.set_state(&bad).is_err());
+    }
+
+    #[test]
+    fn state_bytes() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.seed(1234);
+        cpu.load_rom(&[0x12, 0x34]).unwrap();
+        cpu.process_instruction(0x2300);
+        cpu.i = 0xABC;
+        cpu.v[0xF] = 7;
+        cpu.keypad.key_down(0xA);
+        cpu.screen.draw_sprite(60, 31, &[0xFF]);
+        let state = cpu.state();
+        assert_eq!(State::from_bytes(&state.to_bytes()), Ok(state.clone()));
+        assert!(State::from_bytes(&state.to_bytes()[1..]).is_err());
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        // 200: V1 += 1
+        // 202: I = 0x201, V0 = 2
+        // 206: store V0 over the constant of the first instruction
+        // 208: jump back to the start
+        cpu.load_rom(&[0x71, 0x01, 0xA2, 0x01, 0x60, 0x02, 0xF0, 0x55, 0x12, 0x00]).unwrap();
+        for _ in 0..5 {
+            cpu.cycle();
+        }
+        assert_eq!(cpu.v[1], 1);
+        assert_eq!(cpu.get_pc(), 0x200);
+
+        // the cached `V1 += 1` must have been dropped by the store
+        cpu.cycle();
+        assert_eq!(cpu.v[1], 3);
+    }
+
+    #[test]
+    fn clones_share_caches() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        // 200: V1 += 1, 202: jump back to the start
+        cpu.load_rom(&[0x71, 0x01, 0x12, 0x00]).unwrap();
+        cpu.cycle();
+        cpu.cycle();
+        let mut other = CPU::new();
+        other.load_rom(&[0x00, 0xE0]).unwrap();
+        assert!(Arc::ptr_eq(cpu.database.as_ref().unwrap(), other.database.as_ref().unwrap()));
+
+        let mut clone = cpu.clone();
+        assert!(Arc::ptr_eq(&cpu.decoded, &clone.decoded));
+        clone.write_memory(0x300, &[1]);
+        assert!(Arc::ptr_eq(&cpu.decoded, &clone.decoded));
+
+        // overwriting code in the clone leaves the original's cache alone
+        clone.write_memory(0x200, &[0x71, 0x02]);
+        assert!(!Arc::ptr_eq(&cpu.decoded, &clone.decoded));
+        cpu.cycle();
+        clone.cycle();
+        assert_eq!((cpu.v[1], clone.v[1]), (2, 3));
+    }
+
+    #[test]
+    fn quirks() {
+        let mut cpu = CPU::new();
+        let mut quirks = Quirks::new();
+        quirks.shift = true;
+        quirks.memory_increment_by_x = true;
+        quirks.jump = true;
+        quirks.logic = true;
+        cpu.set_quirks(quirks);
+
+        cpu.v[1] = 0x81;
+        cpu.v[2] = 0x02;
+        cpu.process_instruction(0x8126);
+        assert_eq!((cpu.v[1], cpu.v[0xF]), (0x40, 1));
+
+        cpu.i = 0x300;
+        cpu.process_instruction(0xF265);
+        assert_eq!(cpu.i, 0x302);
+
+        cpu.v[0xF] = 1;
+        cpu.process_instruction(0x8121);
+        assert_eq!(cpu.v[0xF], 0);
+
+        cpu.v[3] = 4;
+        cpu.process_instruction(0xB310);
+        assert_eq!(cpu.get_pc(), 0x314);
+    }
+
+    #[test]
+    fn database_settings() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(include_bytes!("../roms/BLINKY")).unwrap();
+        assert_eq!(cpu.rom_info().unwrap().title, "Blinky");
+        assert!(cpu.quirks().shift);
+        assert!(cpu.cycles_per_frame() > CYCLES_PER_FRAME);
+
+        cpu.set_database(Database::new());
+        assert!(cpu.rom_info().is_none());
+        assert_eq!(cpu.quirks(), Quirks::new());
+        assert_eq!(cpu.cycles_per_frame(), CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn load_rom_errors() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        assert_eq!(cpu.load_rom(&[]), Err("ROM is empty".to_string()));
+        assert_eq!(cpu.load_rom(&[0; 3585]),
+                   Err("ROM is 3585 bytes, but only 3584 fit in memory at 0x200".to_string()));
+        assert!(cpu.load_rom_at(&[0x12, 0x00], 0x100).is_err());
+        assert!(cpu.load_rom_at(&[0x12, 0x00], 0x1000).is_err());
+        assert_eq!(cpu.rom_len(), 0);
+        assert_eq!(cpu.rom_sha1(), None);
+
+        assert_eq!(cpu.load_rom(&[0xAB; 3584]), Ok(()));
+        assert_eq!(cpu.state().memory[0xFFF], 0xAB);
+        assert_eq!(cpu.rom_len(), 3584);
+    }
+
+    #[test]
+    fn load_rom_at() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom_at(b"abc", ETI_660_START).unwrap();
+        assert_eq!(cpu.get_pc(), 0x600);
+        assert_eq!(&cpu.state().memory[0x600..0x603], b"abc");
+        assert_eq!(cpu.rom_len(), 3);
+        assert_eq!(cpu.rom_sha1(), Some("a9993e364706816aba3e25717850c26c9cd0d89d"));
+    }
+
+    #[test]
+    fn read_write_memory() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0x60, 0x01, 0x12, 0x00]).unwrap();
+        assert_eq!(cpu.read_memory(0x200, 4), vec![0x60, 0x01, 0x12, 0x00]);
+        assert_eq!(cpu.read_memory(0, 2), vec![0xF0, 0x90]);
+
+        cpu.cycle();
+        assert_eq!(cpu.v[0], 1);
+        // the cached instruction is replaced along with the memory
+        cpu.write_memory(0x200, &[0x60, 0x02]);
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!(cpu.v[0], 2);
+
+        cpu.write_memory(0xFFF, &[1, 2]);
+        assert_eq!(cpu.read_memory(0xFFF, 2), vec![1, 2]);
+        assert_eq!(cpu.read_memory(0, 1), vec![2]);
+        assert_eq!(cpu.read_memory(0x200, usize::MAX).len(), MEMORY_LEN);
+    }
+
+    #[test]
+    fn wait_key() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        // 200: V0 = key, 202: V1 = key
+        cpu.load_rom(&[0xF0, 0x0A, 0xF1, 0x0A]).unwrap();
+        // a key held before the wait doesn't count
+        cpu.keypad.key_down(3);
+        cpu.cycle();
+        assert_eq!(cpu.get_pc(), 0x200);
+
+        cpu.keypad.key_down(8);
+        cpu.keypad.key_down(5);
+        cpu.cycle();
+        assert_eq!((cpu.v[0], cpu.get_pc()), (5, 0x202));
+
+        // holding keys doesn't answer the next prompt
+        cpu.cycle();
+        assert_eq!(cpu.get_pc(), 0x202);
+        cpu.keypad.key_up(8);
+        cpu.keypad.key_down(8);
+        cpu.cycle();
+        assert_eq!((cpu.v[1], cpu.get_pc()), (8, 0x204));
+    }
+
+    #[test]
+    fn wait_key_release() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0xF0, 0x0A]).unwrap();
+        let mut quirks = Quirks::new();
+        quirks.key_release = true;
+        cpu.set_quirks(quirks);
+
+        cpu.cycle();
+        cpu.keypad.key_down(0xC);
+        cpu.decrement_timers();
+        cpu.cycle();
+        assert_eq!(cpu.get_pc(), 0x200);
+        cpu.keypad.key_up(0xC);
+        cpu.cycle();
+        assert_eq!((cpu.v[0], cpu.get_pc()), (0xC, 0x202));
+    }
+
+    #[test]
+    fn wait_key_state() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0xF0, 0x0A]).unwrap();
+        cpu.cycle();
+        let state = cpu.state();
+
+        // a press queued before the restore doesn't answer the restored wait
+        cpu.keypad.key_down(5);
+        cpu.set_state(&state).unwrap();
+        assert!(!cpu.is_waiting_for_key());
+        cpu.cycle();
+        assert_eq!(cpu.get_pc(), 0x200);
+        cpu.keypad.key_down(7);
+        cpu.cycle();
+        assert_eq!((cpu.v[0], cpu.get_pc()), (7, 0x202));
+    }
 }
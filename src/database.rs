@@ -0,0 +1,267 @@
+//! A database of per-ROM settings, keyed by the SHA-1 of the ROM. ROMs are
+//! written for different interpreters and run at different speeds, so
+//! `CPU::load_rom` looks the ROM up here to pick its quirks and how many
+//! instructions to run per frame.
+//!
+//! Databases are read from JSON in the format of the community
+//! [chip-8-database](https://github.com/chip-8/chip-8-database)'s
+//! `programs.json`: a list of programs, each with a `title`, optional
+//! `authors` and `release`, and `roms` mapping SHA-1 hashes to settings:
+//!
+//! ```text
+//! [{
+//!   "title": "Pong",
+//!   "authors": ["Paul Vervalin"],
+//!   "release": "1990",
+//!   "roms": {
+//!     "b232ef880bd6060fb45fa6effed7edf0ae95670e": {
+//!       "platforms": ["originalChip8"],
+//!       "quirkyPlatforms": {"originalChip8": {"shift": true}},
+//!       "tickrate": 10,
+//!       "keys": {"player1Up": 1, "player1Down": 4},
+//!       "colors": {"pixels": ["#000000", "#ffffff"]}
+//!     }
+//!   }
+//! }]
+//! ```
+//!
+//! The first of `platforms` is the one used. Quirks start from the
+//! interpreter's defaults, see `Quirks`, and are overridden by that
+//! platform's entry in `quirkyPlatforms`. Unsupported quirks such as
+//! `vblank` and other fields are ignored.
+use std::collections::HashMap;
+
+use hash::sha1_hex;
+use json::{self, Value};
+use quirks::Quirks;
+use runner::CYCLES_PER_FRAME;
+
+/// The family of interpreters a ROM was written for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Platform {
+    /// Map a chip-8-database platform id to its family
+    fn from_id(id: &str) -> Option<Platform> {
+        match id {
+            "originalChip8" | "hybridVIP" | "modernChip8" | "chip8x" => Some(Platform::Chip8),
+            "chip48" | "superchip1" | "superchip" | "megachip8" => Some(Platform::Schip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub year: Option<u16>,
+    pub platform: Platform,
+    /// recommended number of instructions to run per 60 Hz frame
+    pub cycles_per_frame: usize,
+    pub quirks: Quirks,
+    /// what each key does in the game, such as `("left", 4)`
+    pub keys: Vec<(String, u8)>,
+    /// RGB colours for unset and set pixels, if the ROM has a preferred
+    /// palette
+    pub palette: Option<[[u8; 3]; 2]>,
+}
+
+#[derive(Clone, Default)]
+pub struct Database {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    /// Create an empty database
+    pub fn new() -> Database {
+        Database { roms: HashMap::new() }
+    }
+
+    /// The database of the ROMs in `roms/`
+    pub fn builtin() -> Database {
+        let mut database = Database::new();
+        database.load(include_str!("database.json")).unwrap();
+        database
+    }
+
+    /// Add the ROMs in a chip-8-database style JSON document, replacing any
+    /// with the same hash. Returns how many were added
+    pub fn load(&mut self, text: &str) -> Result<usize, String> {
+        let document = json::parse(text)?;
+        let programs = match document {
+            Value::Array(ref programs) => programs.iter().collect(),
+            Value::Object(_) => vec![&document],
+            _ => return Err("expected a list of programs".to_string()),
+        };
+
+        let mut added = Vec::new();
+        for (index, program) in programs.into_iter().enumerate() {
+            let title = program.get("title").and_then(Value::as_str)
+                .ok_or_else(|| format!("program {}: missing title", index))?;
+            let roms = program.get("roms").and_then(Value::as_object)
+                .ok_or_else(|| format!("{}: missing roms", title))?;
+            for (hash, rom) in roms.iter() {
+                let info = parse_rom(program, title, rom)
+                    .map_err(|e| format!("{} ({}): {}", title, hash, e))?;
+                added.push((hash.to_lowercase(), info));
+            }
+        }
+        let count = added.len();
+        self.roms.extend(added);
+        Ok(count)
+    }
+
+    /// Look up a ROM by the hex SHA-1 of its contents
+    pub fn get(&self, sha1: &str) -> Option<&RomInfo> {
+        self.roms.get(&sha1.to_lowercase())
+    }
+
+    /// Look up a ROM by its contents
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.get(&sha1_hex(rom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.roms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roms.is_empty()
+    }
+}
+
+fn parse_rom(program: &Value, title: &str, rom: &Value) -> Result<RomInfo, String> {
+    let mut info = RomInfo {
+        title: title.to_string(),
+        authors: Vec::new(),
+        year: None,
+        platform: Platform::Chip8,
+        cycles_per_frame: CYCLES_PER_FRAME,
+        quirks: Quirks::new(),
+        keys: Vec::new(),
+        palette: None,
+    };
+
+    if let Some(authors) = program.get("authors").and_then(Value::as_array) {
+        info.authors = authors.iter().filter_map(Value::as_str).map(String::from).collect();
+    }
+    info.year = program.get("release").and_then(Value::as_str)
+        .and_then(|release| release.get(..4))
+        .and_then(|year| year.parse().ok());
+
+    let platform_id = rom.get("platforms").and_then(Value::as_array)
+        .and_then(|platforms| platforms.first())
+        .and_then(Value::as_str);
+    if let Some(id) = platform_id {
+        info.platform = Platform::from_id(id).ok_or_else(|| format!("unknown platform {}", id))?;
+        let quirks = rom.get("quirkyPlatforms").and_then(|q| q.get(id)).and_then(Value::as_object);
+        for (name, value) in quirks.unwrap_or(&[]).iter() {
+            let value = value.as_bool().ok_or_else(|| format!("quirk {} must be true or false", name))?;
+            info.quirks.set(name, value);
+        }
+    }
+
+    if let Some(tickrate) = rom.get("tickrate") {
+        info.cycles_per_frame = match tickrate.as_u64() {
+            Some(rate) if rate > 0 && rate <= 100_000 => rate as usize,
+            _ => return Err(format!("invalid tickrate {}", tickrate)),
+        };
+    }
+
+    if let Some(keys) = rom.get("keys").and_then(Value::as_object) {
+        for (name, key) in keys.iter() {
+            match key.as_u64() {
+                Some(key) if key < 16 => info.keys.push((name.clone(), key as u8)),
+                _ => return Err(format!("key {} must be between 0 and 15", name)),
+            }
+        }
+    }
+
+    if let Some(colors) = rom.get("colors").and_then(|c| c.get("pixels")).and_then(Value::as_array) {
+        if colors.len() >= 2 {
+            info.palette = Some([color_value(&colors[0])?, color_value(&colors[1])?]);
+        }
+    }
+    Ok(info)
+}
+
+/// Parse a `#rrggbb` colour
+pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+    if text.len() != 7 || !text.starts_with('#') {
+        return None;
+    }
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = text.get(1 + 2 * i..3 + 2 * i)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())?;
+    }
+    Some(color)
+}
+
+fn color_value(value: &Value) -> Result<[u8; 3], String> {
+    value.as_str().and_then(parse_color).ok_or_else(|| format!("invalid colour {}", value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DATABASE: &str = r##"[{
+        "title": "Test",
+        "authors": ["Someone", "Someone Else"],
+        "release": "1991-02",
+        "roms": {
+            "A9993E364706816ABA3E25717850C26C9CD0D89D": {
+                "platforms": ["superchip", "xochip"],
+                "quirkyPlatforms": {"superchip": {"shift": true, "wrap": false, "vblank": true}},
+                "tickrate": 30,
+                "keys": {"left": 4, "right": 6},
+                "colors": {"pixels": ["#000000", "#ff8000"], "buzzer": "#ffffff"}
+            }
+        }
+    }]"##;
+
+    #[test]
+    fn load() {
+        let mut database = Database::new();
+        assert_eq!(database.load(DATABASE), Ok(1));
+        let info = database.lookup(b"abc").unwrap();
+        assert_eq!(info.title, "Test");
+        assert_eq!(info.authors, vec!["Someone", "Someone Else"]);
+        assert_eq!(info.year, Some(1991));
+        assert_eq!(info.platform, Platform::Schip);
+        assert_eq!(info.cycles_per_frame, 30);
+        let mut quirks = Quirks::new();
+        quirks.shift = true;
+        quirks.wrap = false;
+        assert_eq!(info.quirks, quirks);
+        assert_eq!(info.keys, vec![("left".to_string(), 4), ("right".to_string(), 6)]);
+        assert_eq!(info.palette, Some([[0, 0, 0], [0xFF, 0x80, 0]]));
+        assert!(database.lookup(b"abd").is_none());
+    }
+
+    #[test]
+    fn errors() {
+        let mut database = Database::new();
+        assert!(database.load("{}").unwrap_err().contains("missing title"));
+        let bad_key = DATABASE.replace("\"right\": 6", "\"right\": 16");
+        assert!(database.load(&bad_key).unwrap_err().contains("key right"));
+        let bad_color = DATABASE.replace("#ff8000", "orange");
+        assert!(database.load(&bad_color).unwrap_err().contains("invalid colour"));
+        assert!(database.is_empty());
+    }
+
+    #[test]
+    fn builtin() {
+        let database = Database::builtin();
+        assert_eq!(database.len(), 23);
+        let pong = database.lookup(include_bytes!("../roms/PONG")).unwrap();
+        assert_eq!(pong.title, "Pong");
+    }
+}
@@ -0,0 +1,316 @@
+//! A reinforcement learning environment in the style of OpenAI Gym, for
+//! training agents to play ROMs.
+//!
+//! Each step holds down a combination of keys for a number of frames and
+//! returns what the screen shows, the reward and whether the game is over.
+//! Games don't report their score, so the reward and the end of the game
+//! come from a `Script` saying where a ROM keeps them:
+//!
+//! ```text
+//! # the score is drawn from 3 BCD digits written by Fx33 at 0x2F0
+//! score bcd 2F0
+//! # the game is over when V7, the lives left, is 0
+//! done V7 == 0
+//! ```
+//!
+//! `score` lines name a register, a hex address or, with `bcd`, the first
+//! of 3 decimal digits in memory, and are added up. The reward for a step
+//! is how much the score went up. `done` lines compare a register or
+//! address with a hex value, using `==` or `!=`, and end the game when any
+//! of them holds.
+//!
+//! Environments are cheap to clone, for running rollouts from the same
+//! state in parallel.
+use cheats::Target;
+use cpu::CPU;
+use rng::Rng;
+
+/// Where part of the score is kept
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Score {
+    Byte(Target),
+    /// hundreds, tens and ones digits at consecutive addresses, as written
+    /// by `Fx33`
+    Bcd(u16),
+}
+
+impl Score {
+    fn read(self, cpu: &CPU) -> i64 {
+        match self {
+            Score::Byte(target) => target.read(cpu) as i64,
+            Score::Bcd(addr) => cpu.read_memory(addr, 3).iter().fold(0, |n, &digit| n * 10 + digit as i64),
+        }
+    }
+}
+
+/// A condition that ends the game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Done {
+    pub target: Target,
+    pub value: u8,
+    /// whether the condition holds when the byte equals `value`, or when it
+    /// doesn't
+    pub equal: bool,
+}
+
+/// How to read the score and the end of the game from a ROM's memory
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    pub score: Vec<Score>,
+    pub done: Vec<Done>,
+}
+
+impl Script {
+    /// A script with no score and no end
+    pub fn new() -> Script {
+        Script { score: Vec::new(), done: Vec::new() }
+    }
+
+    /// Parse a script in the format above
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut script = Script::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}", lineno + 1, message);
+            let target = |text: &str| Target::parse(text).ok_or_else(|| error(&format!("invalid location `{}`", text)));
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["score", "bcd", location] => match target(location)? {
+                    Target::Memory(addr) => script.score.push(Score::Bcd(addr)),
+                    Target::Register(_) => return Err(error("BCD digits must be in memory")),
+                },
+                ["score", location] => script.score.push(Score::Byte(target(location)?)),
+                ["done", location, comparison, value] => {
+                    let equal = match *comparison {
+                        "==" => true,
+                        "!=" => false,
+                        _ => return Err(error(&format!("invalid comparison `{}`", comparison))),
+                    };
+                    let value = u8::from_str_radix(value, 16)
+                        .map_err(|_| error(&format!("invalid value `{}`", value)))?;
+                    script.done.push(Done { target: target(location)?, value, equal });
+                },
+                _ => return Err(error("expected `score [bcd] <location>` or `done <location> ==|!= <value>`")),
+            }
+        }
+        Ok(script)
+    }
+
+    /// The total score
+    pub fn score(&self, cpu: &CPU) -> i64 {
+        self.score.iter().map(|score| score.read(cpu)).sum()
+    }
+
+    /// Whether the game is over
+    pub fn done(&self, cpu: &CPU) -> bool {
+        self.done.iter().any(|done| (done.target.read(cpu) == done.value) == done.equal)
+    }
+}
+
+#[derive(Clone)]
+pub struct Env {
+    /// the machine just after loading the ROM, copied on every reset
+    initial: CPU,
+    cpu: CPU,
+    script: Script,
+    /// the keys held by each action, as a bit per key
+    actions: Vec<u16>,
+    /// frames each step lasts
+    frame_skip: u32,
+    /// chance that a step repeats the previous action instead
+    sticky: f64,
+    /// decides when actions stick, seeded by `reset`
+    rng: Rng,
+    previous: usize,
+    score: i64,
+    done: bool,
+}
+
+impl Env {
+    /// Create an environment for a ROM. The actions are doing nothing and
+    /// holding each key the ROM database lists for the ROM, or each of the
+    /// 16 keys if it lists none
+    pub fn new(rom: &[u8], script: Script) -> Result<Env, String> {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(rom)?;
+        let mut keys: Vec<u8> = match cpu.rom_info() {
+            Some(info) if !info.keys.is_empty() => info.keys.iter().map(|&(_, key)| key).collect(),
+            _ => (0..16).collect(),
+        };
+        keys.sort();
+        keys.dedup();
+        let mut actions = vec![0];
+        actions.extend(keys.iter().map(|&key| 1 << key));
+
+        let mut env = Env {
+            initial: cpu.clone(),
+            cpu,
+            script,
+            actions,
+            frame_skip: 1,
+            sticky: 0.0,
+            rng: Rng::new(0),
+            previous: 0,
+            score: 0,
+            done: false,
+        };
+        env.reset(0);
+        Ok(env)
+    }
+
+    /// The keys held by each action, as a bit per key
+    pub fn actions(&self) -> &[u16] {
+        &self.actions
+    }
+
+    /// Replace the actions, each a combination of keys with a bit per key.
+    /// Action 0 should usually be no keys at all, which is what every
+    /// episode starts out holding
+    pub fn set_actions(&mut self, actions: Vec<u16>) {
+        assert!(!actions.is_empty(), "there must be at least one action");
+        self.actions = actions;
+        self.previous = 0;
+    }
+
+    /// Make each step last `frames` frames, at least 1
+    pub fn set_frame_skip(&mut self, frames: u32) {
+        self.frame_skip = frames.max(1);
+    }
+
+    /// Repeat the previous action instead of the chosen one with the given
+    /// probability, to keep agents from memorising exact timings
+    pub fn set_sticky_actions(&mut self, probability: f64) {
+        self.sticky = probability.clamp(0.0, 1.0);
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    /// Start a new episode from the ROM's first instruction, with `seed`
+    /// for the ROM's random numbers and for sticky actions. Returns the
+    /// first observation
+    pub fn reset(&mut self, seed: u32) -> Vec<u8> {
+        self.cpu = self.initial.clone();
+        self.cpu.seed(seed);
+        self.rng = Rng::new(seed ^ 0xA5A5_A5A5);
+        self.previous = 0;
+        self.score = self.script.score(&self.cpu);
+        self.done = false;
+        self.observation()
+    }
+
+    /// Hold the keys of `action` for the next few frames. Returns the
+    /// observation, the reward and whether the game is over, after which
+    /// steps do nothing until `reset`
+    pub fn step(&mut self, action: usize) -> (Vec<u8>, f64, bool) {
+        assert!(action < self.actions.len(), "invalid action {}", action);
+        if self.done {
+            return (self.observation(), 0.0, true);
+        }
+        let sticks = (self.rng.next_u8() as f64) < self.sticky * 256.0;
+        let action = if sticks { self.previous } else { action };
+        self.previous = action;
+
+        let keys = self.actions[action];
+        for key in 0..16 {
+            if keys & 1 << key != 0 {
+                self.cpu.keypad.key_down(key);
+            } else {
+                self.cpu.keypad.key_up(key);
+            }
+        }
+        for _ in 0..self.frame_skip {
+            self.cpu.run_frame();
+            if self.script.done(&self.cpu) {
+                self.done = true;
+                break;
+            }
+        }
+
+        let score = self.script.score(&self.cpu);
+        let reward = (score - self.score) as f64;
+        self.score = score;
+        (self.observation(), reward, self.done)
+    }
+
+    /// The screen, one byte per pixel row by row, 1 for set pixels and 0
+    /// for unset ones
+    pub fn observation(&self) -> Vec<u8> {
+        self.cpu.screen.to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// While key 5 is held, count up in V0 twice a frame and write it out as
+    /// BCD at 0x300
+    const COUNTER: [u8; 14] = [
+        0x6E, 0x05, 0xEE, 0x9E, 0x12, 0x02, 0x70, 0x01, 0xA3, 0x00, 0xF0, 0x33, 0x12, 0x02,
+    ];
+
+    fn counter() -> Env {
+        let script = Script::parse("score bcd 300\ndone V0 == 6").unwrap();
+        let mut env = Env::new(&COUNTER, script).unwrap();
+        env.set_actions(vec![0, 1 << 5]);
+        env
+    }
+
+    #[test]
+    fn parse() {
+        let script = Script::parse("# comment\nscore bcd 2F0\nscore VE\ndone 2F3 != 1").unwrap();
+        assert_eq!(script.score, vec![Score::Bcd(0x2F0), Score::Byte(Target::Register(0xE))]);
+        assert_eq!(script.done, vec![Done { target: Target::Memory(0x2F3), value: 1, equal: false }]);
+        assert_eq!(Script::parse("score bcd V0").unwrap_err(), "line 1: BCD digits must be in memory");
+        assert!(Script::parse("done V0 < 1").is_err());
+        assert!(Script::parse("score 1000").is_err());
+    }
+
+    #[test]
+    fn episode() {
+        let mut env = counter();
+        assert_eq!(env.actions(), &[0, 1 << 5]);
+        assert_eq!(env.step(0).1, 0.0);
+        assert_eq!(env.step(1).1, 2.0);
+        assert_eq!(env.step(1), (env.observation(), 2.0, false));
+        assert_eq!(env.step(1), (env.observation(), 2.0, true));
+        assert_eq!(env.step(1).1, 0.0);
+
+        env.reset(1);
+        env.set_frame_skip(2);
+        assert_eq!(env.step(1).1, 4.0);
+    }
+
+    #[test]
+    fn sticky_actions() {
+        let mut env = counter();
+        env.set_sticky_actions(1.0);
+        assert_eq!(env.step(1).1, 0.0);
+    }
+
+    #[test]
+    fn clone() {
+        let mut env = counter();
+        env.step(1);
+        let mut copy = env.clone();
+        assert_eq!(env.step(1), copy.step(1));
+        assert_eq!(env.cpu().state(), copy.cpu().state());
+    }
+
+    #[test]
+    fn observation() {
+        // draw the font's 0 in the top left corner
+        let mut env = Env::new(&[0xD0, 0x15, 0x12, 0x02], Script::new()).unwrap();
+        assert_eq!(env.actions().len(), 17);
+        let (observation, _, _) = env.step(0);
+        assert_eq!(observation.len(), 64 * 32);
+        assert_eq!(&observation[..5], &[1, 1, 1, 1, 0]);
+        assert_eq!(&observation[64..69], &[1, 0, 0, 1, 0]);
+    }
+}
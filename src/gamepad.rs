@@ -0,0 +1,244 @@
+//! Gamepads, in the browser's "standard" layout, mapped to the keypad.
+//!
+//! Front-ends poll the pad themselves and pass its state in as plain arrays:
+//! a value between 0 and 1 for each button and between -1 and 1 for each
+//! axis, as found in the Gamepad API's `buttons[i].value` and `axes`. The
+//! left stick works like the d-pad once it is pushed past the dead zone.
+use database::RomInfo;
+use keypad::Keypad;
+
+pub const BUTTON_A: usize = 0;
+pub const BUTTON_B: usize = 1;
+pub const BUTTON_X: usize = 2;
+pub const BUTTON_Y: usize = 3;
+pub const BUTTON_START: usize = 9;
+pub const BUTTON_UP: usize = 12;
+pub const BUTTON_DOWN: usize = 13;
+pub const BUTTON_LEFT: usize = 14;
+pub const BUTTON_RIGHT: usize = 15;
+
+/// How far the stick must be pushed, from 0 to 1, before it counts
+pub const DEAD_ZONE: f64 = 0.25;
+
+/// Buttons at least this far down are pressed
+const BUTTON_THRESHOLD: f64 = 0.5;
+
+/// Buttons for the actions named in the ROM database
+const ACTIONS: [(&str, usize); 8] = [
+    ("up", BUTTON_UP),
+    ("player1Up", BUTTON_UP),
+    ("down", BUTTON_DOWN),
+    ("player1Down", BUTTON_DOWN),
+    ("left", BUTTON_LEFT),
+    ("right", BUTTON_RIGHT),
+    ("a", BUTTON_A),
+    ("fire", BUTTON_A),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GamepadMap {
+    /// button index and the keypad key it presses
+    buttons: Vec<(usize, u8)>,
+    dead_zone: f64,
+}
+
+impl Default for GamepadMap {
+    fn default() -> GamepadMap {
+        GamepadMap::new()
+    }
+}
+
+impl GamepadMap {
+    /// Create a map with no buttons bound
+    pub fn new() -> GamepadMap {
+        GamepadMap { buttons: Vec::new(), dead_zone: DEAD_ZONE }
+    }
+
+    /// The d-pad as 2, 4, 6 and 8, which most games move with, and A as 5
+    pub fn standard() -> GamepadMap {
+        let mut map = GamepadMap::new();
+        map.bind(BUTTON_UP, 0x2);
+        map.bind(BUTTON_LEFT, 0x4);
+        map.bind(BUTTON_RIGHT, 0x6);
+        map.bind(BUTTON_DOWN, 0x8);
+        map.bind(BUTTON_A, 0x5);
+        map
+    }
+
+    /// The keys the ROM database lists for a ROM, such as 4 and 6 for
+    /// moving the paddle in BRIX, or the standard map for ROMs it doesn't
+    /// list keys for
+    pub fn for_rom(info: Option<&RomInfo>) -> GamepadMap {
+        let mut map = GamepadMap::new();
+        for (action, key) in info.iter().flat_map(|info| info.keys.iter()) {
+            if let Some(&(_, button)) = ACTIONS.iter().find(|&&(name, _)| name == action) {
+                map.bind(button, *key);
+            }
+        }
+        if map.buttons.is_empty() {
+            GamepadMap::standard()
+        } else {
+            map
+        }
+    }
+
+    /// Bind a button, replacing any previous binding of it
+    pub fn bind(&mut self, button: usize, key: u8) {
+        assert!(key < 16, "invalid keypad key {}", key);
+        self.buttons.retain(|&(b, _)| b != button);
+        self.buttons.push((button, key));
+    }
+
+    /// The keypad key a button is bound to
+    pub fn key(&self, button: usize) -> Option<u8> {
+        self.buttons.iter().find(|&&(b, _)| b == button).map(|&(_, key)| key)
+    }
+
+    pub fn dead_zone(&self) -> f64 {
+        self.dead_zone
+    }
+
+    /// Set how far, from 0 to 1, the stick must be pushed to count
+    pub fn set_dead_zone(&mut self, dead_zone: f64) {
+        self.dead_zone = dead_zone.clamp(0.0, 1.0);
+    }
+
+    /// The keys held with the pad in the given state. Missing buttons and
+    /// axes are treated as released and centred
+    pub fn keys(&self, buttons: &[f64], axes: &[f64]) -> [bool; 16] {
+        let mut pressed: Vec<usize> = buttons.iter().enumerate()
+            .filter(|&(_, &value)| value >= BUTTON_THRESHOLD)
+            .map(|(i, _)| i)
+            .collect();
+        pressed.extend(self.stick_directions(axes));
+
+        let mut keys = [false; 16];
+        for &(button, key) in self.buttons.iter() {
+            if pressed.contains(&button) {
+                keys[key as usize] = true;
+            }
+        }
+        keys
+    }
+
+    /// The d-pad buttons the left stick stands in for. Inside the dead zone
+    /// the stick is centred. Outside it, a direction counts if it makes up
+    /// at least half of how far the stick is pushed, so diagonals press two
+    fn stick_directions(&self, axes: &[f64]) -> Vec<usize> {
+        let x = axes.first().cloned().unwrap_or(0.0);
+        let y = axes.get(1).cloned().unwrap_or(0.0);
+        let distance = (x * x + y * y).sqrt();
+        if distance < self.dead_zone || distance == 0.0 {
+            return Vec::new();
+        }
+        let mut directions = Vec::new();
+        if x <= -distance / 2.0 {
+            directions.push(BUTTON_LEFT);
+        }
+        if x >= distance / 2.0 {
+            directions.push(BUTTON_RIGHT);
+        }
+        if y <= -distance / 2.0 {
+            directions.push(BUTTON_UP);
+        }
+        if y >= distance / 2.0 {
+            directions.push(BUTTON_DOWN);
+        }
+        directions
+    }
+}
+
+/// A pad and the keys it is holding down
+pub struct Gamepad {
+    pub map: GamepadMap,
+    held: [bool; 16],
+}
+
+impl Gamepad {
+    pub fn new(map: GamepadMap) -> Gamepad {
+        Gamepad { map, held: [false; 16] }
+    }
+
+    /// Press and release keys to match the pad's latest state. Only keys
+    /// the pad pressed are released, so the keyboard can be used alongside
+    pub fn update(&mut self, buttons: &[f64], axes: &[f64], keypad: &mut Keypad) {
+        let keys = self.map.keys(buttons, axes);
+        for (key, (&down, &held)) in keys.iter().zip(self.held.iter()).enumerate() {
+            if down && !held {
+                keypad.key_down(key as u8);
+            } else if !down && held {
+                keypad.key_up(key as u8);
+            }
+        }
+        self.held = keys;
+    }
+
+    /// Release everything the pad is holding, such as when it is unplugged
+    pub fn release(&mut self, keypad: &mut Keypad) {
+        self.update(&[], &[], keypad);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use database::Database;
+
+    fn held(keys: [bool; 16]) -> Vec<u8> {
+        (0..16).filter(|&key| keys[key as usize]).collect()
+    }
+
+    #[test]
+    fn buttons() {
+        let map = GamepadMap::standard();
+        let mut buttons = [0.0; 17];
+        buttons[BUTTON_UP] = 1.0;
+        buttons[BUTTON_A] = 0.75;
+        buttons[BUTTON_B] = 1.0;
+        buttons[BUTTON_RIGHT] = 0.25;
+        assert_eq!(held(map.keys(&buttons, &[])), vec![0x2, 0x5]);
+        assert_eq!(held(map.keys(&[], &[])), vec![]);
+    }
+
+    #[test]
+    fn stick() {
+        let map = GamepadMap::standard();
+        assert_eq!(held(map.keys(&[], &[0.2, -0.1])), vec![]);
+        assert_eq!(held(map.keys(&[], &[-0.9, 0.1])), vec![0x4]);
+        assert_eq!(held(map.keys(&[], &[0.6, 0.6])), vec![0x6, 0x8]);
+        assert_eq!(held(map.keys(&[], &[0.0, -0.3, 1.0, 1.0])), vec![0x2]);
+
+        let mut map = map;
+        map.set_dead_zone(0.5);
+        assert_eq!(held(map.keys(&[], &[0.0, -0.3])), vec![]);
+    }
+
+    #[test]
+    fn for_rom() {
+        let database = Database::builtin();
+        let brix = GamepadMap::for_rom(database.lookup(include_bytes!("../roms/BRIX")));
+        assert_eq!(brix.key(BUTTON_LEFT), Some(0x4));
+        assert_eq!(brix.key(BUTTON_RIGHT), Some(0x6));
+        assert_eq!(brix.key(BUTTON_UP), None);
+
+        let pong = GamepadMap::for_rom(database.lookup(include_bytes!("../roms/PONG")));
+        assert_eq!(pong.key(BUTTON_UP), Some(0x1));
+        assert_eq!(pong.key(BUTTON_DOWN), Some(0x4));
+        assert_eq!(GamepadMap::for_rom(None), GamepadMap::standard());
+    }
+
+    #[test]
+    fn update() {
+        let mut gamepad = Gamepad::new(GamepadMap::standard());
+        let mut keypad = Keypad::new();
+        keypad.key_down(0xF);
+        gamepad.update(&[], &[-1.0, 0.0], &mut keypad);
+        assert!(keypad.is_key_down(0x4));
+        gamepad.update(&[], &[0.0, 1.0], &mut keypad);
+        assert!(!keypad.is_key_down(0x4));
+        assert!(keypad.is_key_down(0x8));
+        gamepad.release(&mut keypad);
+        assert!(!keypad.is_key_down(0x8));
+        assert!(keypad.is_key_down(0xF));
+    }
+}
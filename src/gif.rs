@@ -0,0 +1,566 @@
+//! Animated GIF recording of the screen, and decoding of the colour indices
+//! of GIF images such as Octo cartridges.
+//!
+//! The recorder captures the screen once per 60 Hz frame. Consecutive
+//! identical frames are merged into a single GIF frame with a longer delay,
+//! so static title screens cost almost nothing. Frames may carry an overlay,
+//! such as a script's, drawn over the screen in `OVERLAY_COLOR`.
+
+use std::collections::HashMap;
+
+use screen::Screen;
+
+/// GIF frame delays are in hundredths of a second
+const CENTISECONDS_PER_SECOND: u64 = 100;
+const FRAMES_PER_SECOND: u64 = 60;
+
+/// An RGB colour
+pub type Color = [u8; 3];
+
+/// Colour of overlay pixels, which stands out against the usual palettes
+pub const OVERLAY_COLOR: Color = [255, 0, 64];
+
+pub struct GifRecorder {
+    /// each screen pixel is drawn as a `scale` x `scale` square
+    scale: usize,
+    /// colours of unset, set and overlay pixels
+    palette: [Color; 3],
+    /// encoded output so far, starting with the GIF header
+    data: Vec<u8>,
+    /// the most recently captured distinct frame and its overlay, not yet
+    /// written out
+    pending: Option<([u64; 32], [u64; 32])>,
+    /// number of 60 Hz frames captured so far
+    frames: u64,
+    /// value of `frames` when the pending frame was first captured
+    pending_start: u64,
+}
+
+impl GifRecorder {
+    /// Create a recorder that draws unset pixels in `off` and set pixels in
+    /// `on`, scaling the 64x32 screen up by `scale`
+    pub fn new(scale: usize, off: Color, on: Color) -> GifRecorder {
+        assert!(scale > 0 && 64 * scale <= 0xFFFF, "invalid GIF scale {}", scale);
+        let mut recorder = GifRecorder {
+            scale,
+            palette: [off, on, OVERLAY_COLOR],
+            data: Vec::new(),
+            pending: None,
+            frames: 0,
+            pending_start: 0,
+        };
+        recorder.write_header();
+        recorder
+    }
+
+    /// Record the current contents of the screen as the next 60 Hz frame
+    pub fn capture(&mut self, screen: &Screen) {
+        self.capture_with_overlay(screen, &[0; 32]);
+    }
+
+    /// Like `capture`, with the set pixels of `overlay`, in the same layout
+    /// as `Screen::rows()`, drawn over the screen
+    pub fn capture_with_overlay(&mut self, screen: &Screen, overlay: &[u64; 32]) {
+        let frame = (*screen.rows(), *overlay);
+        if self.pending != Some(frame) {
+            self.flush();
+            self.pending = Some(frame);
+            self.pending_start = self.frames;
+        }
+        self.frames += 1;
+    }
+
+    /// Finish the recording and return the encoded GIF
+    pub fn finish(mut self) -> Vec<u8> {
+        self.flush();
+        self.data.push(0x3B); // trailer
+        self.data
+    }
+
+    fn write_header(&mut self) {
+        let (width, height) = ((64 * self.scale) as u16, (32 * self.scale) as u16);
+        self.data.extend_from_slice(b"GIF89a");
+        push_u16(&mut self.data, width);
+        push_u16(&mut self.data, height);
+        // global colour table with 4 entries, the last unused
+        self.data.extend_from_slice(&[0x81, 0, 0]);
+        for color in self.palette.iter() {
+            self.data.extend_from_slice(color);
+        }
+        self.data.extend_from_slice(&[0, 0, 0]);
+        // loop forever
+        self.data.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        self.data.extend_from_slice(b"NETSCAPE2.0");
+        self.data.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+    }
+
+    /// Write out the pending frame, if any, with a delay covering every
+    /// 60 Hz frame it was shown for
+    fn flush(&mut self) {
+        let (pixels, overlay) = match self.pending.take() {
+            Some(frame) => frame,
+            None => return
+        };
+        // round the start and end times separately so the delays add up to
+        // the real running time instead of accumulating rounding error
+        let start = self.pending_start * CENTISECONDS_PER_SECOND / FRAMES_PER_SECOND;
+        let end = self.frames * CENTISECONDS_PER_SECOND / FRAMES_PER_SECOND;
+        let mut delay = end - start;
+        while delay > 0xFFFF {
+            self.write_frame(&pixels, &overlay, 0xFFFF);
+            delay -= 0xFFFF;
+        }
+        self.write_frame(&pixels, &overlay, delay as u16);
+    }
+
+    fn write_frame(&mut self, pixels: &[u64; 32], overlay: &[u64; 32], delay: u16) {
+        // graphic control extension
+        self.data.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+        push_u16(&mut self.data, delay);
+        self.data.extend_from_slice(&[0x00, 0x00]);
+
+        // image descriptor covering the whole canvas
+        let (width, height) = (64 * self.scale, 32 * self.scale);
+        self.data.push(0x2C);
+        push_u16(&mut self.data, 0);
+        push_u16(&mut self.data, 0);
+        push_u16(&mut self.data, width as u16);
+        push_u16(&mut self.data, height as u16);
+        self.data.push(0x00);
+
+        let mut indices = Vec::with_capacity(width * height);
+        for (row, overlay_row) in pixels.iter().zip(overlay.iter()) {
+            let mut line = Vec::with_capacity(width);
+            for x in 0..64 {
+                let index = if (overlay_row >> (63 - x)) & 1 == 1 {
+                    2
+                } else {
+                    ((row >> (63 - x)) & 1) as u8
+                };
+                for _ in 0..self.scale {
+                    line.push(index);
+                }
+            }
+            for _ in 0..self.scale {
+                indices.extend_from_slice(&line);
+            }
+        }
+
+        self.data.push(MIN_CODE_SIZE);
+        let compressed = lzw_encode(&indices);
+        for block in compressed.chunks(255) {
+            self.data.push(block.len() as u8);
+            self.data.extend_from_slice(block);
+        }
+        self.data.push(0x00);
+    }
+}
+
+fn push_u16(data: &mut Vec<u8>, value: u16) {
+    data.push(value as u8);
+    data.push((value >> 8) as u8);
+}
+
+/// GIF requires a minimum LZW code size of 2 even for 2 colour images
+const MIN_CODE_SIZE: u8 = 2;
+const MAX_CODE: u16 = 4096;
+
+/// Packs variable width codes into bytes, least significant bit first
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u32) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+/// Compress colour indices with the variable code width LZW flavour used
+/// by GIF
+fn lzw_encode(indices: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << MIN_CODE_SIZE;
+    let end = clear + 1;
+    let mut writer = BitWriter { out: Vec::new(), buffer: 0, bits: 0 };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = MIN_CODE_SIZE as u32 + 1;
+    let mut next = end + 1;
+
+    writer.write(clear, size);
+    let mut prefix: Option<u16> = None;
+    for &index in indices {
+        let current = match prefix {
+            None => {
+                prefix = Some(index as u16);
+                continue;
+            },
+            Some(current) => current
+        };
+        if let Some(&code) = table.get(&(current, index)) {
+            prefix = Some(code);
+            continue;
+        }
+
+        writer.write(current, size);
+        table.insert((current, index), next);
+        if next == 1 << size && size < 12 {
+            size += 1;
+        }
+        next += 1;
+        prefix = Some(index as u16);
+
+        if next == MAX_CODE {
+            writer.write(clear, size);
+            table.clear();
+            size = MIN_CODE_SIZE as u32 + 1;
+            next = end + 1;
+        }
+    }
+    if let Some(current) = prefix {
+        writer.write(current, size);
+        // the decoder adds a table entry after every code it reads, so it
+        // may widen its codes before reading the end code
+        if next == 1 << size && size < 12 {
+            size += 1;
+        }
+    }
+    writer.write(end, size);
+    writer.finish()
+}
+
+/// Most pixels `decode` decodes across all of a GIF's images, so a small
+/// file can't claim a huge image and run out of memory
+const MAX_PIXELS: usize = 1 << 24;
+
+/// One image of a decoded GIF
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// colour table index of each pixel, row by row from the top left
+    pub indices: Vec<u8>,
+}
+
+/// Reads a GIF byte by byte, failing at the end of the data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or("GIF is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, String> {
+        let bytes = self.bytes(2)?;
+        Ok(bytes[0] as usize | (bytes[1] as usize) << 8)
+    }
+
+    /// Concatenate a run of data sub-blocks, ended by an empty one
+    fn sub_blocks(&mut self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        loop {
+            let len = self.u8()? as usize;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(self.bytes(len)?);
+        }
+    }
+}
+
+/// Decode the colour indices of every image in a GIF, in the order they
+/// appear. Colour tables, delays and other extensions are skipped. Images
+/// must lie inside the logical screen, and GIFs with more than `MAX_PIXELS`
+/// pixels in all are rejected
+pub fn decode(data: &[u8]) -> Result<Vec<Image>, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let signature = reader.bytes(6)?;
+    if signature != b"GIF87a" && signature != b"GIF89a" {
+        return Err("not a GIF".to_string());
+    }
+    let screen_width = reader.u16()?;
+    let screen_height = reader.u16()?;
+    let flags = reader.u8()?;
+    reader.bytes(2)?; // background colour and aspect ratio
+    if flags & 0x80 != 0 {
+        reader.bytes(3 << ((flags & 7) + 1))?;
+    }
+
+    let mut images = Vec::new();
+    let mut pixels = 0;
+    loop {
+        match reader.u8()? {
+            0x21 => {
+                reader.u8()?; // extension label
+                reader.sub_blocks()?;
+            },
+            0x2C => {
+                let left = reader.u16()?;
+                let top = reader.u16()?;
+                let width = reader.u16()?;
+                let height = reader.u16()?;
+                if left + width > screen_width || top + height > screen_height {
+                    return Err(format!("{}x{} image at ({}, {}) is outside the {}x{} screen",
+                                       width, height, left, top, screen_width, screen_height));
+                }
+                pixels += width * height;
+                if pixels > MAX_PIXELS {
+                    return Err(format!("GIF has more than {} pixels", MAX_PIXELS));
+                }
+                let flags = reader.u8()?;
+                if flags & 0x80 != 0 {
+                    reader.bytes(3 << ((flags & 7) + 1))?;
+                }
+                let min_code_size = reader.u8()?;
+                if !(2..=8).contains(&min_code_size) {
+                    return Err(format!("invalid LZW code size {}", min_code_size));
+                }
+                let mut indices = lzw_decode(&reader.sub_blocks()?, min_code_size, width * height)?;
+                indices.resize(width * height, 0);
+                if flags & 0x40 != 0 {
+                    indices = deinterlace(&indices, width, height);
+                }
+                images.push(Image { width, height, indices });
+            },
+            0x3B => return Ok(images),
+            block => return Err(format!("unknown GIF block {:#04X}", block)),
+        }
+    }
+}
+
+/// Put the rows of an interlaced image, stored in four passes, back in
+/// top to bottom order
+fn deinterlace(indices: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut rows = Vec::with_capacity(height);
+    for &(start, step) in [(0, 8), (4, 8), (2, 4), (1, 2)].iter() {
+        rows.extend((start..height).step_by(step));
+    }
+    let mut out = vec![0; width * height];
+    for (stored, &row) in rows.iter().enumerate() {
+        out[row * width..(row + 1) * width]
+            .copy_from_slice(&indices[stored * width..(stored + 1) * width]);
+    }
+    out
+}
+
+/// Decompress GIF flavoured LZW, the inverse of `lzw_encode`, stopping after
+/// `limit` indices
+fn lzw_decode(data: &[u8], min_code_size: u8, limit: usize) -> Result<Vec<u8>, String> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    // each code is its prefix code, if any, and its last index
+    let mut table: Vec<(Option<u16>, u8)> = (0..clear).map(|i| (None, i as u8)).collect();
+    table.push((None, 0));
+    table.push((None, 0));
+    let mut size = min_code_size as u32 + 1;
+    let mut previous: Option<u16> = None;
+    let mut out = Vec::new();
+
+    let (mut buffer, mut bits, mut pos) = (0u32, 0u32, 0);
+    while out.len() < limit {
+        while bits < size {
+            let byte = match data.get(pos) {
+                Some(&byte) => byte,
+                // some encoders leave out the end code
+                None => return Ok(out),
+            };
+            buffer |= (byte as u32) << bits;
+            bits += 8;
+            pos += 1;
+        }
+        let code = (buffer & ((1 << size) - 1)) as u16;
+        buffer >>= size;
+        bits -= size;
+
+        if code == clear {
+            table.truncate(end as usize + 1);
+            size = min_code_size as u32 + 1;
+            previous = None;
+            continue;
+        }
+        if code == end {
+            return Ok(out);
+        }
+
+        let start = out.len();
+        let known = (code as usize) < table.len();
+        let mut current = if known {
+            code
+        } else {
+            match previous {
+                // the code being defined by this very step
+                Some(previous) if code as usize == table.len() => previous,
+                _ => return Err(format!("invalid LZW code {}", code)),
+            }
+        };
+        loop {
+            let (prefix, index) = table[current as usize];
+            out.push(index);
+            match prefix {
+                Some(prefix) => current = prefix,
+                None => break,
+            }
+        }
+        out[start..].reverse();
+        if !known {
+            let first = out[start];
+            out.push(first);
+        }
+
+        if let Some(previous) = previous {
+            if table.len() < MAX_CODE as usize {
+                table.push((Some(previous), out[start]));
+                if table.len() == 1 << size && size < 12 {
+                    size += 1;
+                }
+            }
+        }
+        previous = Some(code);
+    }
+    out.truncate(limit);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame_delays(gif: &[u8]) -> Vec<u16> {
+        let mut delays = Vec::new();
+        for i in 0..gif.len() - 5 {
+            if gif[i] == 0x21 && gif[i + 1] == 0xF9 && gif[i + 2] == 0x04 {
+                delays.push(gif[i + 4] as u16 | (gif[i + 5] as u16) << 8);
+            }
+        }
+        delays
+    }
+
+    #[test]
+    fn header() {
+        let gif = GifRecorder::new(2, [255, 255, 255], [0, 0, 0]).finish();
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[128, 0, 64, 0]);
+        assert_eq!(gif[10], 0x81);
+        assert_eq!(&gif[13..19], &[255, 255, 255, 0, 0, 0]);
+        assert_eq!(&gif[19..22], &OVERLAY_COLOR);
+        assert_eq!(gif[gif.len() - 1], 0x3B);
+    }
+
+    #[test]
+    fn merges_identical_frames() {
+        let mut recorder = GifRecorder::new(1, [0; 3], [255; 3]);
+        let mut screen = Screen::new();
+        for _ in 0..60 {
+            recorder.capture(&screen);
+        }
+        screen.draw_sprite(0, 0, &[0xFF]);
+        for _ in 0..3 {
+            recorder.capture(&screen);
+        }
+        // 60 frames is exactly a second, 3 more frames is 5 hundredths
+        assert_eq!(frame_delays(&recorder.finish()), vec![100, 5]);
+    }
+
+    #[test]
+    fn delays_do_not_drift() {
+        let mut recorder = GifRecorder::new(1, [0; 3], [255; 3]);
+        let mut screen = Screen::new();
+        for _ in 0..60 {
+            screen.draw_sprite(0, 0, &[0xFF]);
+            recorder.capture(&screen);
+        }
+        let delays = frame_delays(&recorder.finish());
+        assert_eq!(delays.len(), 60);
+        assert_eq!(delays.iter().map(|&d| d as u32).sum::<u32>(), 100);
+    }
+
+    #[test]
+    fn decode_recording() {
+        let mut recorder = GifRecorder::new(2, [0; 3], [255; 3]);
+        let mut screen = Screen::new();
+        screen.draw_sprite(1, 0, &[0xA5, 0xFF]);
+        recorder.capture(&screen);
+        screen.clear();
+        recorder.capture(&screen);
+
+        let images = decode(&recorder.finish()).unwrap();
+        assert_eq!(images.len(), 2);
+        let first = &images[0];
+        assert_eq!((first.width, first.height), (128, 64));
+        assert_eq!(&first.indices[..20], &[0, 0, 1, 1, 0, 0, 1, 1, 0, 0, 0, 0, 1, 1, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(&first.indices[128..148], &first.indices[..20]);
+        assert_eq!(first.indices[256..][..20].iter().filter(|&&i| i == 1).count(), 16);
+        assert!(images[1].indices.iter().all(|&i| i == 0));
+
+        assert!(decode(b"GIF89a").is_err());
+        assert!(decode(b"PNG").is_err());
+    }
+
+    #[test]
+    fn overlay() {
+        let mut recorder = GifRecorder::new(1, [0; 3], [255; 3]);
+        let mut screen = Screen::new();
+        screen.draw_sprite(0, 0, &[0xF0]);
+        let mut overlay = [0; 32];
+        overlay[0] = 0x3 << 62;
+        recorder.capture_with_overlay(&screen, &overlay);
+        // a frame that only differs in its overlay isn't merged
+        recorder.capture(&screen);
+
+        let gif = recorder.finish();
+        assert_eq!(frame_delays(&gif).len(), 2);
+        let images = decode(&gif).unwrap();
+        assert_eq!(&images[0].indices[..6], &[2, 2, 1, 1, 0, 0]);
+        assert_eq!(&images[1].indices[..6], &[1, 1, 1, 1, 0, 0]);
+    }
+
+    /// A GIF with a `screen` sized logical screen and one image of `size`
+    /// whose data decodes to `indices`
+    fn gif(screen: (u16, u16), size: (u16, u16), indices: &[u8]) -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+        push_u16(&mut gif, screen.0);
+        push_u16(&mut gif, screen.1);
+        gif.extend_from_slice(&[0, 0, 0, 0x2C, 0, 0, 0, 0]);
+        push_u16(&mut gif, size.0);
+        push_u16(&mut gif, size.1);
+        gif.extend_from_slice(&[0, MIN_CODE_SIZE]);
+        for block in lzw_encode(indices).chunks(255) {
+            gif.push(block.len() as u8);
+            gif.extend_from_slice(block);
+        }
+        gif.extend_from_slice(&[0, 0x3B]);
+        gif
+    }
+
+    #[test]
+    fn decode_limits() {
+        let images = decode(&gif((4, 1), (4, 1), &[1; 100])).unwrap();
+        assert_eq!(images[0].indices, vec![1; 4]);
+        assert_eq!(decode(&gif((1, 1), (0xFFFF, 0xFFFF), &[1])).err().unwrap(),
+                   "65535x65535 image at (0, 0) is outside the 1x1 screen");
+        assert_eq!(decode(&gif((0xFFFF, 0xFFFF), (0xFFFF, 0xFFFF), &[1])).err().unwrap(),
+                   format!("GIF has more than {} pixels", MAX_PIXELS));
+    }
+}
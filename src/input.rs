@@ -0,0 +1,119 @@
+//! Scripted keypad input for headless runs.
+//!
+//! A script is a plain text file with one event per line of the form
+//! `<frame> <key> <down|up>`, where the key is a hexadecimal keypad index
+//! or, with `parse_with`, the name of a host key in a `KeyMap` such as
+//! `ArrowLeft`. Blank lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # start the game, then hold 6 to move right for a second
+//! 30 5 down
+//! 32 5 up
+//! 90 6 down
+//! 150 6 up
+//! ```
+use keymap::KeyMap;
+
+/// A single key press or release that happens at the start of a frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InputEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub pressed: bool,
+}
+
+/// A list of input events ordered by the frame they happen on
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    /// Create an empty script
+    pub fn new() -> InputScript {
+        InputScript { events: Vec::new() }
+    }
+
+    /// Parse a script from its text representation
+    pub fn parse(text: &str) -> Result<InputScript, String> {
+        InputScript::parse_with(text, &KeyMap::new())
+    }
+
+    /// Parse a script whose keys may also be host keys bound in `keymap`.
+    /// Hexadecimal keypad indexes take precedence, so `a` is key A
+    pub fn parse_with(text: &str, keymap: &KeyMap) -> Result<InputScript, String> {
+        let mut script = InputScript::new();
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(format!("line {}: expected `<frame> <key> <down|up>`", lineno + 1));
+            }
+            let frame = parts[0].parse::<u64>()
+                .map_err(|_| format!("line {}: invalid frame `{}`", lineno + 1, parts[0]))?;
+            let key = match u8::from_str_radix(parts[1], 16) {
+                Ok(key) if key < 16 => Some(key),
+                _ => keymap.key(parts[1]),
+            };
+            let key = key.ok_or_else(|| format!("line {}: invalid key `{}`", lineno + 1, parts[1]))?;
+            let pressed = match parts[2] {
+                "down" => true,
+                "up" => false,
+                other => return Err(format!("line {}: invalid action `{}`", lineno + 1, other))
+            };
+            script.push(frame, key, pressed);
+        }
+        Ok(script)
+    }
+
+    /// Add an event to the script. Events on the same frame are applied in
+    /// the order they were added
+    pub fn push(&mut self, frame: u64, key: u8, pressed: bool) {
+        let index = self.events.iter().position(|e| e.frame > frame).unwrap_or(self.events.len());
+        self.events.insert(index, InputEvent { frame, key, pressed });
+    }
+
+    /// All events in frame order
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let script = InputScript::parse("# comment\n\n10 a down\n 12 A up \n5 0 down").unwrap();
+        assert_eq!(script.events(), &[
+            InputEvent { frame: 5, key: 0, pressed: true },
+            InputEvent { frame: 10, key: 0xA, pressed: true },
+            InputEvent { frame: 12, key: 0xA, pressed: false },
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(InputScript::parse("10 5").is_err());
+        assert!(InputScript::parse("x 5 down").is_err());
+        assert!(InputScript::parse("10 10 down").is_err());
+        assert!(InputScript::parse("10 5 press").is_err());
+        assert!(InputScript::parse("10 KeyQ down").is_err());
+    }
+
+    #[test]
+    fn parse_host_keys() {
+        let script = InputScript::parse_with("1 KeyQ down\n2 a down\n3 KeyB up", &KeyMap::qwerty());
+        assert_eq!(script.unwrap_err(), "line 3: invalid key `KeyB`");
+        let script = InputScript::parse_with("1 KeyQ down\n2 a down", &KeyMap::qwerty()).unwrap();
+        assert_eq!(script.events(), &[
+            InputEvent { frame: 1, key: 4, pressed: true },
+            InputEvent { frame: 2, key: 0xA, pressed: true },
+        ]);
+    }
+}
@@ -0,0 +1,92 @@
+//! Text views of a running CPU's memory, for debuggers and memory panels.
+//!
+//! `hex_dump` prints 16 bytes to a row, labelling rows in the font and the
+//! loaded ROM and marking the byte the program counter points at with `>`,
+//! the one `I` points at with `*`, or `@` if they point at the same byte:
+//!
+//! ```text
+//! 200 rom  >6A 02 6B 0C 6C 3F 6D 0C*A2 EA DA B6 DC D6 6E 00
+//! ```
+use cpu::{CPU, MEMORY_LEN};
+
+/// Bytes of memory holding the built-in font, from address 0
+pub const FONT_LEN: u16 = 0x50;
+
+const ROW_LEN: u16 = 16;
+
+/// Dump the rows of memory holding `len` bytes starting at `start`. Rows
+/// always start at a multiple of 16, so a few bytes either side may be
+/// included too. At most all of memory is dumped
+pub fn hex_dump(cpu: &CPU, start: u16, len: usize) -> String {
+    let first = start & !(ROW_LEN - 1) & 0xFFF;
+    let last = (start & 0xFFF) as usize + len.min(MEMORY_LEN);
+    let pc = cpu.get_pc() & 0xFFF;
+    let i = cpu.i & 0xFFF;
+    let rom = cpu.rom_start() as usize..cpu.rom_start() as usize + cpu.rom_len();
+
+    let mut out = String::new();
+    let mut row = first as usize;
+    while row < last {
+        let addr = (row & 0xFFF) as u16;
+        let region = if addr < FONT_LEN {
+            "font"
+        } else if (addr..addr + ROW_LEN).any(|a| rom.contains(&(a as usize))) {
+            "rom"
+        } else {
+            ""
+        };
+        out += &format!("{:03X} {:<4}", addr, region);
+        for (offset, byte) in cpu.read_memory(addr, ROW_LEN as usize).iter().enumerate() {
+            let a = (addr + offset as u16) & 0xFFF;
+            let marker = match (a == pc, a == i) {
+                (true, true) => '@',
+                (true, false) => '>',
+                (false, true) => '*',
+                (false, false) => ' ',
+            };
+            out += &format!("{}{:02X}", marker, byte);
+        }
+        out.push('\n');
+        row += ROW_LEN as usize;
+    }
+    out
+}
+
+/// Draw `rows` bytes starting at `I` the way `Dxyn` would, one row of 8
+/// pixels per byte, with `#` for set pixels and `.` for unset ones
+pub fn sprite_preview(cpu: &CPU, rows: usize) -> String {
+    cpu.read_memory(cpu.i, rows).iter()
+        .map(|byte| (0..8).rev().map(|bit| if byte >> bit & 1 == 1 { '#' } else { '.' }).collect::<String>() + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dump() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0x6A, 0x02, 0xA2, 0x0A]).unwrap();
+        cpu.cycle();
+        cpu.cycle();
+        assert_eq!(hex_dump(&cpu, 0x40, 0x10),
+                   "040 font F0 E0 90 90 90 E0 F0 80 F0 80 F0 F0 80 F0 80 80\n");
+        assert_eq!(hex_dump(&cpu, 0x1F8, 12),
+                   "1F0      00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+                    200 rom  6A 02 A2 0A>00 00 00 00 00 00*00 00 00 00 00 00\n");
+
+        cpu.i = 0x204;
+        assert!(hex_dump(&cpu, 0x200, 1).contains("0A@00"));
+        assert_eq!(hex_dump(&cpu, 0x200, usize::MAX).lines().count(), MEMORY_LEN / ROW_LEN as usize);
+    }
+
+    #[test]
+    fn sprite() {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.i = 5;
+        assert_eq!(sprite_preview(&cpu, 5), "..#.....\n.##.....\n..#.....\n..#.....\n.###....\n");
+    }
+}
@@ -0,0 +1,157 @@
+//! Decoding of raw 16-bit opcodes into instructions
+
+/// A decoded CHIP-8 instruction. `x` and `y` are register indices, `nnn`
+/// is a 12-bit address, `kk` an 8-bit constant and `n` a 4-bit constant
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Instruction {
+    /// `00E0` clear the screen
+    Clear,
+    /// `00EE` return from a subroutine
+    Return,
+    /// `0nnn` call a machine code routine, unsupported
+    Sys(u16),
+    /// `1nnn` jump to nnn
+    Jump(u16),
+    /// `2nnn` call the subroutine at nnn
+    Call(u16),
+    /// `3xkk` skip the next instruction if Vx == kk
+    SkipEqual(u8, u8),
+    /// `4xkk` skip the next instruction if Vx != kk
+    SkipNotEqual(u8, u8),
+    /// `5xy0` skip the next instruction if Vx == Vy
+    SkipEqualReg(u8, u8),
+    /// `6xkk` Vx = kk
+    Load(u8, u8),
+    /// `7xkk` Vx += kk, without carry
+    Add(u8, u8),
+    /// `8xy0` Vx = Vy
+    Move(u8, u8),
+    /// `8xy1` Vx |= Vy
+    Or(u8, u8),
+    /// `8xy2` Vx &= Vy
+    And(u8, u8),
+    /// `8xy3` Vx ^= Vy
+    Xor(u8, u8),
+    /// `8xy4` Vx += Vy, VF = carry
+    AddReg(u8, u8),
+    /// `8xy5` Vx -= Vy, VF = not borrow
+    Sub(u8, u8),
+    /// `8xy6` Vx = Vy >> 1, VF = shifted out bit
+    ShiftRight(u8, u8),
+    /// `8xy7` Vx = Vy - Vx, VF = not borrow
+    SubReverse(u8, u8),
+    /// `8xyE` Vx = Vy << 1, VF = shifted out bit
+    ShiftLeft(u8, u8),
+    /// `9xy0` skip the next instruction if Vx != Vy
+    SkipNotEqualReg(u8, u8),
+    /// `Annn` I = nnn
+    LoadI(u16),
+    /// `Bnnn` jump to nnn + V0
+    JumpOffset(u16),
+    /// `Cxkk` Vx = random byte & kk
+    Random(u8, u8),
+    /// `Dxyn` draw the n byte sprite at I at (Vx, Vy), VF = collision
+    Draw(u8, u8, u8),
+    /// `Ex9E` skip the next instruction if key Vx is down
+    SkipKeyDown(u8),
+    /// `ExA1` skip the next instruction if key Vx is up
+    SkipKeyUp(u8),
+    /// `Fx07` Vx = delay timer
+    LoadDelay(u8),
+    /// `Fx0A` wait for a key press and store it in Vx
+    WaitKey(u8),
+    /// `Fx15` delay timer = Vx
+    SetDelay(u8),
+    /// `Fx18` sound timer = Vx
+    SetSound(u8),
+    /// `Fx1E` I += Vx
+    AddI(u8),
+    /// `Fx29` I = address of the font sprite for digit Vx
+    LoadFont(u8),
+    /// `Fx33` store the decimal digits of Vx at I, I + 1 and I + 2
+    StoreBcd(u8),
+    /// `Fx55` store V0 through Vx at I
+    StoreRegs(u8),
+    /// `Fx65` load V0 through Vx from I
+    LoadRegs(u8),
+    /// anything else
+    Unknown(u16),
+}
+
+/// Split a raw opcode into its instruction and operands
+pub fn decode(instruction: u16) -> Instruction {
+    // separate out instruction nibbles
+    let op1 = (instruction & 0xF000) >> 12;
+    let op2 = (instruction & 0x0F00) >> 8;
+    let op3 = (instruction & 0x00F0) >> 4;
+    let op4 = instruction & 0x000F;
+
+    // separate out the possible operands
+    let nnn = instruction & 0x0FFF;
+    let kk = (instruction & 0x00FF) as u8;
+    let x = op2 as u8;
+    let y = op3 as u8;
+    let n = op4 as u8;
+
+    match (op1, op2, op3, op4) {
+        (0, 0, 0xE, 0) => Instruction::Clear,
+        (0, 0, 0xE, 0xE) => Instruction::Return,
+        (0, ..) => Instruction::Sys(nnn),
+        (1, ..) => Instruction::Jump(nnn),
+        (2, ..) => Instruction::Call(nnn),
+        (3, ..) => Instruction::SkipEqual(x, kk),
+        (4, ..) => Instruction::SkipNotEqual(x, kk),
+        (5, .., 0) => Instruction::SkipEqualReg(x, y),
+        (6, ..) => Instruction::Load(x, kk),
+        (7, ..) => Instruction::Add(x, kk),
+        (8, .., 0) => Instruction::Move(x, y),
+        (8, .., 1) => Instruction::Or(x, y),
+        (8, .., 2) => Instruction::And(x, y),
+        (8, .., 3) => Instruction::Xor(x, y),
+        (8, .., 4) => Instruction::AddReg(x, y),
+        (8, .., 5) => Instruction::Sub(x, y),
+        (8, .., 6) => Instruction::ShiftRight(x, y),
+        (8, .., 7) => Instruction::SubReverse(x, y),
+        (8, .., 0xE) => Instruction::ShiftLeft(x, y),
+        (9, .., 0) => Instruction::SkipNotEqualReg(x, y),
+        (0xA, ..) => Instruction::LoadI(nnn),
+        (0xB, ..) => Instruction::JumpOffset(nnn),
+        (0xC, ..) => Instruction::Random(x, kk),
+        (0xD, ..) => Instruction::Draw(x, y, n),
+        (0xE, _, 9, 0xE) => Instruction::SkipKeyDown(x),
+        (0xE, _, 0xA, 1) => Instruction::SkipKeyUp(x),
+        (0xF, _, 0, 7) => Instruction::LoadDelay(x),
+        (0xF, _, 0, 0xA) => Instruction::WaitKey(x),
+        (0xF, _, 1, 5) => Instruction::SetDelay(x),
+        (0xF, _, 1, 8) => Instruction::SetSound(x),
+        (0xF, _, 1, 0xE) => Instruction::AddI(x),
+        (0xF, _, 2, 9) => Instruction::LoadFont(x),
+        (0xF, _, 3, 3) => Instruction::StoreBcd(x),
+        (0xF, _, 5, 5) => Instruction::StoreRegs(x),
+        (0xF, _, 6, 5) => Instruction::LoadRegs(x),
+        _ => Instruction::Unknown(instruction)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn operands() {
+        assert_eq!(decode(0x00E0), Instruction::Clear);
+        assert_eq!(decode(0x0123), Instruction::Sys(0x123));
+        assert_eq!(decode(0x3A42), Instruction::SkipEqual(0xA, 0x42));
+        assert_eq!(decode(0x8AB6), Instruction::ShiftRight(0xA, 0xB));
+        assert_eq!(decode(0xD12F), Instruction::Draw(1, 2, 0xF));
+        assert_eq!(decode(0xF965), Instruction::LoadRegs(9));
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(decode(0x5121), Instruction::Unknown(0x5121));
+        assert_eq!(decode(0x8128), Instruction::Unknown(0x8128));
+        assert_eq!(decode(0xE1A2), Instruction::Unknown(0xE1A2));
+        assert_eq!(decode(0xF1FF), Instruction::Unknown(0xF1FF));
+    }
+}
@@ -0,0 +1,260 @@
+//! Mapping from keys on the host keyboard to the 16 key hex keypad, shared
+//! by every front-end.
+//!
+//! Host keys are named by strings. Browsers use `KeyboardEvent.code`, such
+//! as `KeyQ` or `ArrowLeft`, which names a physical key position whatever
+//! the keyboard layout. Front-ends that only see typed characters, such as
+//! terminals, use the character, such as `q`. Since codes are physical
+//! positions, the presets only differ in their characters.
+//!
+//! A map is stored as a JSON object from host key to keypad index:
+//!
+//! ```text
+//! {"ArrowLeft": 4, "ArrowRight": 6, "KeyQ": 4}
+//! ```
+use std::collections::BTreeMap;
+
+use database::RomInfo;
+use json::{self, Value};
+use keypad::Keypad;
+
+/// The keys of the original keypad, row by row, as the presets lay them out
+/// on the keyboard
+const KEYPAD: [u8; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF,
+];
+
+/// The 4x4 block of keys on the left of the keyboard
+const BLOCK_CODES: [&str; 16] = [
+    "Digit1", "Digit2", "Digit3", "Digit4",
+    "KeyQ", "KeyW", "KeyE", "KeyR",
+    "KeyA", "KeyS", "KeyD", "KeyF",
+    "KeyZ", "KeyX", "KeyC", "KeyV",
+];
+
+const QWERTY_CHARACTERS: [&str; 16] = [
+    "1", "2", "3", "4",
+    "q", "w", "e", "r",
+    "a", "s", "d", "f",
+    "z", "x", "c", "v",
+];
+
+/// The same positions on an AZERTY keyboard, where the top row types
+/// symbols unless shift is held
+const AZERTY_CHARACTERS: [&str; 16] = [
+    "&", "é", "\"", "'",
+    "a", "z", "e", "r",
+    "q", "s", "d", "f",
+    "w", "x", "c", "v",
+];
+
+/// Host keys for the actions named in the ROM database
+const ACTIONS: [(&str, &str); 8] = [
+    ("up", "ArrowUp"),
+    ("player1Up", "ArrowUp"),
+    ("down", "ArrowDown"),
+    ("player1Down", "ArrowDown"),
+    ("left", "ArrowLeft"),
+    ("right", "ArrowRight"),
+    ("a", "Space"),
+    ("fire", "Space"),
+];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyMap {
+    bindings: BTreeMap<String, u8>,
+}
+
+impl KeyMap {
+    /// Create a map with no keys bound
+    pub fn new() -> KeyMap {
+        KeyMap { bindings: BTreeMap::new() }
+    }
+
+    fn block(characters: &[&str; 16]) -> KeyMap {
+        let mut map = KeyMap::new();
+        for i in 0..16 {
+            map.bind(BLOCK_CODES[i], KEYPAD[i]);
+            map.bind(characters[i], KEYPAD[i]);
+        }
+        map
+    }
+
+    /// 1234, QWER, ASDF and ZXCV, in the shape of the original keypad
+    pub fn qwerty() -> KeyMap {
+        KeyMap::block(&QWERTY_CHARACTERS)
+    }
+
+    /// The same keys on an AZERTY keyboard: 1234, AZER, QSDF and WXCV
+    pub fn azerty() -> KeyMap {
+        KeyMap::block(&AZERTY_CHARACTERS)
+    }
+
+    /// The numeric keypad, with digits as themselves and `/`, `*`, `-`,
+    /// `+`, Enter and `.` as A to F
+    pub fn numpad() -> KeyMap {
+        let mut map = KeyMap::new();
+        for digit in 0..10 {
+            map.bind(&format!("Numpad{}", digit), digit);
+        }
+        let letters = [
+            ("NumpadDivide", "/"), ("NumpadMultiply", "*"), ("NumpadSubtract", "-"),
+            ("NumpadAdd", "+"), ("NumpadEnter", "\n"), ("NumpadDecimal", "."),
+        ];
+        for (i, &(code, character)) in letters.iter().enumerate() {
+            map.bind(code, 0xA + i as u8);
+            map.bind(character, 0xA + i as u8);
+        }
+        map
+    }
+
+    /// Look up a preset by name: `qwerty`, `azerty` or `numpad`
+    pub fn preset(name: &str) -> Option<KeyMap> {
+        match name {
+            "qwerty" => Some(KeyMap::qwerty()),
+            "azerty" => Some(KeyMap::azerty()),
+            "numpad" => Some(KeyMap::numpad()),
+            _ => None,
+        }
+    }
+
+    /// This map plus the arrow keys and space bound to the keys the ROM
+    /// database says the ROM uses for moving and firing
+    pub fn for_rom(&self, info: &RomInfo) -> KeyMap {
+        let mut map = self.clone();
+        for (action, key) in info.keys.iter() {
+            if let Some(&(_, host)) = ACTIONS.iter().find(|&&(name, _)| name == action) {
+                map.bind(host, *key);
+            }
+        }
+        map
+    }
+
+    /// Bind a host key, replacing any previous binding of it
+    pub fn bind(&mut self, host: &str, key: u8) {
+        assert!(key < 16, "invalid keypad key {}", key);
+        self.bindings.insert(host.to_string(), key);
+    }
+
+    pub fn unbind(&mut self, host: &str) {
+        self.bindings.remove(host);
+    }
+
+    /// The keypad key a host key is bound to
+    pub fn key(&self, host: &str) -> Option<u8> {
+        self.bindings.get(host).cloned()
+    }
+
+    /// Every binding, sorted by host key
+    pub fn bindings(&self) -> Vec<(&str, u8)> {
+        self.bindings.iter().map(|(host, &key)| (host.as_str(), key)).collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let members = self.bindings.iter()
+            .map(|(host, &key)| (host.clone(), Value::Number(key as f64)))
+            .collect();
+        Value::Object(members).to_string()
+    }
+
+    pub fn from_json(text: &str) -> Result<KeyMap, String> {
+        let value = json::parse(text)?;
+        let members = value.as_object().ok_or("expected an object of host keys")?;
+        let mut map = KeyMap::new();
+        for (host, key) in members.iter() {
+            match key.as_u64() {
+                Some(key) if key < 16 => map.bind(host, key as u8),
+                _ => return Err(format!("{}: key must be between 0 and 15", host)),
+            }
+        }
+        Ok(map)
+    }
+}
+
+/// The host keys held down and the keypad keys they press. A keypad key
+/// bound to several host keys stays down until the last of them is released
+#[derive(Clone, Debug, Default)]
+pub struct HeldKeys {
+    held: BTreeMap<String, u8>,
+}
+
+impl HeldKeys {
+    pub fn new() -> HeldKeys {
+        HeldKeys { held: BTreeMap::new() }
+    }
+
+    /// Press or release the host key `code`, which is bound to `key`
+    pub fn key_event(&mut self, code: &str, key: u8, pressed: bool, keypad: &mut Keypad) {
+        if pressed {
+            self.held.insert(code.to_string(), key);
+            keypad.key_down(key);
+            return;
+        }
+        // the key it pressed, in case the map changed while it was held
+        let key = self.held.remove(code).unwrap_or(key);
+        if !self.held.values().any(|&held| held == key) {
+            keypad.key_up(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use database::Database;
+
+    #[test]
+    fn presets() {
+        let qwerty = KeyMap::qwerty();
+        assert_eq!(qwerty.key("Digit4"), Some(0xC));
+        assert_eq!(qwerty.key("KeyX"), Some(0x0));
+        assert_eq!(qwerty.key("z"), Some(0xA));
+        assert_eq!(qwerty.key("KeyB"), None);
+
+        let azerty = KeyMap::azerty();
+        assert_eq!(azerty.key("KeyQ"), Some(0x4));
+        assert_eq!(azerty.key("a"), Some(0x4));
+        assert_eq!(azerty.key("é"), Some(0x2));
+
+        let numpad = KeyMap::preset("numpad").unwrap();
+        assert_eq!(numpad.key("Numpad7"), Some(0x7));
+        assert_eq!(numpad.key("NumpadDecimal"), Some(0xF));
+        assert!(KeyMap::preset("dvorak").is_none());
+    }
+
+    #[test]
+    fn for_rom() {
+        let database = Database::builtin();
+        let brix = database.lookup(include_bytes!("../roms/BRIX")).unwrap();
+        let map = KeyMap::qwerty().for_rom(brix);
+        assert_eq!(map.key("ArrowLeft"), Some(0x4));
+        assert_eq!(map.key("ArrowRight"), Some(0x6));
+        assert_eq!(map.key("KeyQ"), Some(0x4));
+    }
+
+    #[test]
+    fn json() {
+        let mut map = KeyMap::new();
+        map.bind("KeyJ", 0xA);
+        map.bind("ArrowUp", 2);
+        assert_eq!(map.to_json(), r#"{"ArrowUp":2,"KeyJ":10}"#);
+        assert_eq!(KeyMap::from_json(&map.to_json()), Ok(map));
+        assert_eq!(KeyMap::from_json(r#"{"KeyJ": 16}"#).unwrap_err(), "KeyJ: key must be between 0 and 15");
+        assert!(KeyMap::from_json("[]").is_err());
+    }
+
+    #[test]
+    fn held_keys() {
+        let mut held = HeldKeys::new();
+        let mut keypad = Keypad::new();
+        held.key_event("KeyQ", 4, true, &mut keypad);
+        held.key_event("ArrowLeft", 4, true, &mut keypad);
+        held.key_event("KeyQ", 4, false, &mut keypad);
+        assert!(keypad.is_key_down(4));
+        held.key_event("ArrowLeft", 4, false, &mut keypad);
+        assert!(!keypad.is_key_down(4));
+    }
+}
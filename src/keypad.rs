@@ -1,34 +1,115 @@
+use std::collections::VecDeque;
+
+/// Most events kept in the queue. Older ones are dropped, so the queue
+/// doesn't grow without bound when nothing waits for keys
+const MAX_EVENTS: usize = 32;
+
+/// A key being pressed or released
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    /// true for a press, false for a release
+    pub down: bool,
+    /// value of the keypad's clock when it happened
+    pub time: u64,
+}
+
+#[derive(Clone)]
 pub struct Keypad {
     /// 16 key hexadecimal keypad. keys[i] is true if it is currently
     /// being pressed
     pub keys: [bool; 16],
+    /// presses and releases since the queue was last cleared, oldest first
+    events: VecDeque<KeyEvent>,
+    /// number of frames since the keypad was created, see `tick`
+    time: u64,
 }
 
-// TODO: check bounds of key method args
 impl Keypad {
     pub fn new() -> Keypad {
-        Keypad { keys: [false; 16] }
+        Keypad { keys: [false; 16], events: VecDeque::new(), time: 0 }
     }
 
+    /// Whether a key is held. Keys above 0xF don't exist and are never held
     pub fn is_key_down(&self, key: u8) -> bool {
-        self.keys[key as usize]
+        self.keys.get(key as usize).cloned().unwrap_or(false)
     }
 
+    /// The lowest-numbered key that is held
     pub fn get_first_key_down(&self) -> Option<u8> {
-        for i in 0..16 {
-            if self.is_key_down(i) {
-                return Some(i)
-            }
-        }
-        None
+        (0..16).find(|&key| self.is_key_down(key))
     }
 
+    /// Press a key. Keys above 0xF are ignored, as are presses of a key
+    /// that is already held
     pub fn key_down(&mut self, key: u8) {
-        self.keys[key as usize] = true;
+        self.set_key(key, true);
     }
 
+    /// Release a key. Keys above 0xF are ignored, as are releases of a key
+    /// that isn't held
     pub fn key_up(&mut self, key: u8) {
-        self.keys[key as usize] = false;
+        self.set_key(key, false);
+    }
+
+    fn set_key(&mut self, key: u8, down: bool) {
+        if key > 0xF || self.keys[key as usize] == down {
+            return;
+        }
+        self.keys[key as usize] = down;
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(KeyEvent { key, down, time: self.time });
+    }
+
+    /// Advance the clock used to timestamp events by one frame
+    pub fn tick(&mut self) {
+        self.time += 1;
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Presses and releases since the queue was last cleared, oldest first
+    pub fn events(&self) -> &VecDeque<KeyEvent> {
+        &self.events
+    }
+
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+
+    /// Look through the queued events for the key `Fx0A` is waiting for:
+    /// the first key pressed or, with `on_release`, the first key released
+    /// after being pressed. If several keys qualify at the same time the
+    /// lowest-numbered one wins. The queue is cleared when a key is found
+    pub fn take_key(&mut self, on_release: bool) -> Option<u8> {
+        let mut pressed = [false; 16];
+        let mut found: Option<KeyEvent> = None;
+        for event in self.events.iter() {
+            if let Some(first) = found {
+                if event.time != first.time {
+                    break;
+                }
+            }
+            let qualifies = if on_release {
+                !event.down && pressed[event.key as usize]
+            } else {
+                event.down
+            };
+            if event.down {
+                pressed[event.key as usize] = true;
+            }
+            if qualifies && found.map(|first| event.key < first.key).unwrap_or(true) {
+                found = Some(*event);
+            }
+        }
+        if found.is_some() {
+            self.events.clear();
+        }
+        found.map(|event| event.key)
     }
 }
 
@@ -54,4 +135,49 @@ mod test {
         assert!(keypad.is_key_down(9));
         assert!(keypad.is_key_down(15));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn out_of_range_keys() {
+        let mut keypad = Keypad::new();
+        keypad.key_down(16);
+        keypad.key_up(0xFF);
+        assert!(!keypad.is_key_down(16));
+        assert!(keypad.events().is_empty());
+    }
+
+    #[test]
+    fn events() {
+        let mut keypad = Keypad::new();
+        keypad.key_down(3);
+        keypad.key_down(3);
+        keypad.tick();
+        keypad.key_up(3);
+        assert_eq!(keypad.events().iter().cloned().collect::<Vec<_>>(), vec![
+            KeyEvent { key: 3, down: true, time: 0 },
+            KeyEvent { key: 3, down: false, time: 1 },
+        ]);
+    }
+
+    #[test]
+    fn take_key() {
+        let mut keypad = Keypad::new();
+        assert_eq!(keypad.take_key(false), None);
+        // simultaneous presses report the lowest key
+        keypad.key_down(9);
+        keypad.key_down(4);
+        keypad.tick();
+        keypad.key_down(1);
+        assert_eq!(keypad.take_key(false), Some(4));
+        assert!(keypad.events().is_empty());
+
+        // a release only counts once the press was seen
+        keypad.key_up(9);
+        assert_eq!(keypad.take_key(true), None);
+        keypad.key_down(7);
+        keypad.tick();
+        assert_eq!(keypad.take_key(true), None);
+        keypad.key_up(7);
+        keypad.key_up(4);
+        assert_eq!(keypad.take_key(true), Some(7));
+    }
+}
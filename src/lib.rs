@@ -7,10 +7,49 @@
 //! [1]: http://mattmik.com/files/chip8/mastering/chip8.html
 extern crate wasm_bindgen;
 extern crate js_sys;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "scripting")]
+extern crate rhai;
 
 pub use self::wasm::CPUWrapper;
 
 pub mod cpu;
 pub mod wasm;
 pub mod keypad;
-pub mod screen;
\ No newline at end of file
+pub mod keymap;
+pub mod gamepad;
+pub mod screen;
+pub mod rng;
+pub mod input;
+pub mod runner;
+pub mod machine;
+pub mod netplay;
+pub mod websocket;
+pub mod scheduler;
+pub mod gif;
+pub mod hash;
+pub mod instruction;
+pub mod wasm_encoder;
+pub mod recompiler;
+pub mod analysis;
+pub mod inspect;
+pub mod cheats;
+pub mod achievements;
+pub mod patch;
+pub mod env;
+pub mod capi;
+/// cbindgen:ignore
+///
+/// The libretro API has its own header, so this is left out of `include/chip8.h`
+#[cfg(feature = "libretro")]
+pub mod libretro;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod json;
+pub mod quirks;
+pub mod database;
+pub mod octo;
+pub mod cartridge;
@@ -0,0 +1,607 @@
+//! A [libretro](https://www.libretro.com) core, so ROMs can be played in
+//! RetroArch and other libretro front-ends. The `cdylib` build of the crate
+//! with the `libretro` feature is the core:
+//!
+//! ```text
+//! cargo build --release --features libretro
+//! retroarch -L target/release/libchip8.so roms/PONG
+//! ```
+//!
+//! The screen is sent as 64x32 XRGB8888 frames and the sound timer plays
+//! a square wave. The joypad works like a gamepad in the browser, with the
+//! d-pad and left stick on 2, 4, 6 and 8 and the bottom face button on 5,
+//! or the keys the ROM database lists for the ROM, and the keyboard is
+//! laid out like `KeyMap::qwerty`. Core options override the speed, the
+//! palette and each quirk the database picks for a ROM.
+//!
+//! Everything libretro passes in is checked for NULL, but a front-end that
+//! passes dangling pointers or calls the core from several threads at once
+//! is on its own.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use cartridge::Cartridge;
+use cheats::Cheat;
+use cpu::{State, CPU, STATE_LEN};
+use gamepad::{GamepadMap, BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_START,
+              BUTTON_UP, BUTTON_X, BUTTON_Y};
+use keymap::KeyMap;
+use quirks::Quirks;
+
+pub const API_VERSION: c_uint = 1;
+
+pub const DEVICE_JOYPAD: c_uint = 1;
+pub const DEVICE_KEYBOARD: c_uint = 3;
+pub const DEVICE_ANALOG: c_uint = 5;
+
+pub const DEVICE_ID_JOYPAD_B: c_uint = 0;
+pub const DEVICE_ID_JOYPAD_UP: c_uint = 4;
+pub const DEVICE_ID_JOYPAD_A: c_uint = 8;
+pub const DEVICE_INDEX_ANALOG_LEFT: c_uint = 0;
+
+pub const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+pub const ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+pub const ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+pub const ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+
+pub const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+pub const REGION_NTSC: c_uint = 0;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const SAMPLE_RATE: f64 = 44100.0;
+/// Stereo samples in each 60 Hz frame
+pub const SAMPLES_PER_FRAME: usize = 735;
+/// Samples between the edges of the beep's square wave, for a 441 Hz tone
+const BEEP_HALF_PERIOD: usize = 50;
+const BEEP_VOLUME: i16 = 0x1000;
+
+pub type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type VideoRefreshFn = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+pub type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type InputPollFn = extern "C" fn();
+pub type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    /// extensions separated by `|`
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    pub geometry: GameGeometry,
+    pub timing: SystemTiming,
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+/// A core option, declared with `ENVIRONMENT_SET_VARIABLES` as a key and
+/// `Description; first|second|...` with the default first, and read back
+/// with `ENVIRONMENT_GET_VARIABLE` as a key and the chosen value
+#[repr(C)]
+pub struct Variable {
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+const SPEED: &[u8] = b"chip8_speed\0";
+const PALETTE: &[u8] = b"chip8_palette\0";
+
+/// The quirks that can be overridden, by their name in the ROM database,
+/// with their option keys and descriptions
+const QUIRK_OPTIONS: [(&str, &[u8], &[u8]); 6] = [
+    ("shift", b"chip8_quirk_shift\0", b"Shift Vx in place (8xy6, 8xyE); auto|on|off\0"),
+    ("memoryIncrementByX", b"chip8_quirk_memory_increment_by_x\0",
+     b"Fx55 and Fx65 add x to I; auto|on|off\0"),
+    ("memoryLeaveIUnchanged", b"chip8_quirk_memory_leave_i_unchanged\0",
+     b"Fx55 and Fx65 leave I unchanged; auto|on|off\0"),
+    ("wrap", b"chip8_quirk_wrap\0", b"Sprites wrap around the screen; auto|on|off\0"),
+    ("jump", b"chip8_quirk_jump\0", b"Bnnn jumps to xnn + Vx; auto|on|off\0"),
+    ("logic", b"chip8_quirk_logic\0", b"8xy1, 8xy2 and 8xy3 reset VF; auto|on|off\0"),
+];
+
+/// Colours for unset and set pixels, by their names in the palette option
+const PALETTES: [(&str, [[u8; 3]; 2]); 4] = [
+    ("black on white", [[0xFF, 0xFF, 0xFF], [0, 0, 0]]),
+    ("white on black", [[0, 0, 0], [0xFF, 0xFF, 0xFF]]),
+    ("amber", [[0x1A, 0x10, 0], [0xFF, 0xB0, 0]]),
+    ("green", [[0x0A, 0x1A, 0x0A], [0x40, 0xFF, 0x40]]),
+];
+
+/// The standard gamepad button for each libretro joypad button, by id.
+/// The libretro joypad is laid out like a SNES pad, so its B is where the
+/// standard layout has A, its Y where it has X, and so on
+const JOYPAD_BUTTONS: [usize; 16] = [
+    BUTTON_A, BUTTON_X, 8, BUTTON_START, BUTTON_UP, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT,
+    BUTTON_B, BUTTON_Y, 4, 5, 6, 7, 10, 11,
+];
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+impl Callbacks {
+    /// The value of a core option, if the front-end has one
+    fn variable(&self, key: &[u8]) -> Option<String> {
+        let environment = self.environment?;
+        let mut variable = Variable { key: key.as_ptr() as *const c_char, value: ptr::null() };
+        if !environment(ENVIRONMENT_GET_VARIABLE, &mut variable as *mut Variable as *mut c_void)
+            || variable.value.is_null() {
+            return None;
+        }
+        unsafe { CStr::from_ptr(variable.value) }.to_str().ok().map(String::from)
+    }
+}
+
+/// A loaded game
+struct Game {
+    cpu: CPU,
+    rom: Vec<u8>,
+    /// the settings from the ROM database or cartridge, used for options
+    /// set to `auto`
+    rom_quirks: Quirks,
+    rom_cycles: usize,
+    rom_palette: Option<[[u8; 3]; 2]>,
+    palette: [[u8; 3]; 2],
+    gamepad: GamepadMap,
+    keymap: KeyMap,
+    video: Vec<u32>,
+    audio: Vec<i16>,
+    /// samples into the beep's square wave
+    beep_phase: usize,
+}
+
+impl Game {
+    fn new(data: &[u8]) -> Result<Game, String> {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        let mut rom_palette = None;
+        let rom = if data.starts_with(b"GIF8") {
+            let cartridge = Cartridge::from_gif(data)?;
+            cpu.load_rom(&cartridge.rom)?;
+            cartridge.configure(&mut cpu);
+            rom_palette = cartridge.palette;
+            cartridge.rom
+        } else {
+            cpu.load_rom(data)?;
+            data.to_vec()
+        };
+        let info = cpu.rom_info().cloned();
+        let keymap = match info {
+            Some(ref info) => KeyMap::qwerty().for_rom(info),
+            None => KeyMap::qwerty(),
+        };
+        Ok(Game {
+            rom_quirks: cpu.quirks(),
+            rom_cycles: cpu.cycles_per_frame(),
+            rom_palette: rom_palette.or_else(|| info.as_ref().and_then(|info| info.palette)),
+            palette: PALETTES[0].1,
+            gamepad: GamepadMap::for_rom(info.as_ref()),
+            keymap,
+            cpu,
+            rom,
+            video: vec![0; WIDTH * HEIGHT],
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            beep_phase: 0,
+        })
+    }
+
+    /// Start the ROM again, keeping the cheats and options
+    fn restart(&mut self) {
+        let cheats = self.cpu.cheats().clone();
+        let (quirks, cycles) = (self.cpu.quirks(), self.cpu.cycles_per_frame());
+        self.cpu.reset();
+        self.cpu.load_rom(&self.rom).expect("the ROM loaded before");
+        *self.cpu.cheats_mut() = cheats;
+        self.cpu.set_quirks(quirks);
+        self.cpu.set_cycles_per_frame(cycles);
+    }
+
+    fn apply_options(&mut self, callbacks: &Callbacks) {
+        let mut quirks = self.rom_quirks;
+        for &(name, key, _) in QUIRK_OPTIONS.iter() {
+            match callbacks.variable(key) {
+                Some(ref value) if value != "auto" => quirks.set(name, value == "on"),
+                _ => continue,
+            };
+        }
+        self.cpu.set_quirks(quirks);
+        let cycles = callbacks.variable(SPEED).and_then(|speed| speed.parse().ok());
+        self.cpu.set_cycles_per_frame(cycles.unwrap_or(self.rom_cycles));
+        let palette = callbacks.variable(PALETTE)
+            .and_then(|name| PALETTES.iter().find(|&&(n, _)| n == name).map(|&(_, palette)| palette));
+        self.palette = palette.or(self.rom_palette).unwrap_or(PALETTES[0].1);
+    }
+
+    /// Hold the keys pressed on the joypad and keyboard of port 0
+    fn poll_input(&mut self, input_state: InputStateFn) {
+        let mut buttons = vec![0.0; 17];
+        for (id, &button) in JOYPAD_BUTTONS.iter().enumerate() {
+            if input_state(0, DEVICE_JOYPAD, 0, id as c_uint) != 0 {
+                buttons[button] = 1.0;
+            }
+        }
+        let axes: Vec<f64> = (0..2)
+            .map(|id| input_state(0, DEVICE_ANALOG, DEVICE_INDEX_ANALOG_LEFT, id) as f64 / 32768.0)
+            .collect();
+        let mut keys = self.gamepad.keys(&buttons, &axes);
+        for (code, key) in self.keymap.bindings() {
+            if let Some(id) = retro_key(code) {
+                if input_state(0, DEVICE_KEYBOARD, 0, id) != 0 {
+                    keys[key as usize] = true;
+                }
+            }
+        }
+        for (key, &down) in keys.iter().enumerate() {
+            if down {
+                self.cpu.keypad.key_down(key as u8);
+            } else {
+                self.cpu.keypad.key_up(key as u8);
+            }
+        }
+    }
+
+    fn render(&mut self) {
+        let [off, on] = self.palette.map(|[r, g, b]| (r as u32) << 16 | (g as u32) << 8 | b as u32);
+        for (pixel, &set) in self.video.iter_mut().zip(self.cpu.screen.to_bytes().iter()) {
+            *pixel = if set == 1 { on } else { off };
+        }
+        let beeping = self.cpu.sound_timer() > 0;
+        for frame in self.audio.chunks_mut(2) {
+            let sample = if !beeping {
+                0
+            } else if self.beep_phase < BEEP_HALF_PERIOD {
+                BEEP_VOLUME
+            } else {
+                -BEEP_VOLUME
+            };
+            frame[0] = sample;
+            frame[1] = sample;
+            self.beep_phase = (self.beep_phase + 1) % (BEEP_HALF_PERIOD * 2);
+        }
+    }
+}
+
+/// The libretro keyboard code for a `KeyMap` host key
+fn retro_key(code: &str) -> Option<c_uint> {
+    let id = match code {
+        "ArrowUp" => 273,
+        "ArrowDown" => 274,
+        "ArrowRight" => 275,
+        "ArrowLeft" => 276,
+        "Space" => 32,
+        _ => {
+            if let Some(digit) = code.strip_prefix("Numpad") {
+                256 + digit.parse::<c_uint>().ok().filter(|&digit| digit < 10)?
+            } else if let Some(digit) = code.strip_prefix("Digit") {
+                let digit = digit.parse::<c_uint>().ok().filter(|&digit| digit < 10)?;
+                b'0' as c_uint + digit
+            } else {
+                let letter = code.strip_prefix("Key")?;
+                match letter.as_bytes() {
+                    [letter] if letter.is_ascii_uppercase() => letter.to_ascii_lowercase() as c_uint,
+                    _ => return None,
+                }
+            }
+        },
+    };
+    Some(id)
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static GAME: Mutex<Option<Game>> = Mutex::new(None);
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *GAME.lock().unwrap() = None;
+}
+
+/// Store the environment callback and declare the core options
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: Option<EnvironmentFn>) {
+    CALLBACKS.lock().unwrap().environment = environment;
+    if let Some(environment) = environment {
+        let mut variables = vec![
+            Variable {
+                key: SPEED.as_ptr() as *const c_char,
+                value: b"Instructions per frame; auto|5|10|15|20|30|50|100|200|500|1000\0".as_ptr() as *const c_char,
+            },
+            Variable {
+                key: PALETTE.as_ptr() as *const c_char,
+                value: b"Palette; auto|black on white|white on black|amber|green\0".as_ptr() as *const c_char,
+            },
+        ];
+        for &(_, key, description) in QUIRK_OPTIONS.iter() {
+            variables.push(Variable {
+                key: key.as_ptr() as *const c_char,
+                value: description.as_ptr() as *const c_char,
+            });
+        }
+        variables.push(Variable { key: ptr::null(), value: ptr::null() });
+        environment(ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: Option<VideoRefreshFn>) {
+    CALLBACKS.lock().unwrap().video_refresh = video_refresh;
+}
+
+/// Unused, audio is sent a frame at a time with the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: Option<AudioSampleFn>) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: Option<AudioSampleBatchFn>) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = audio_sample_batch;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: Option<InputPollFn>) {
+    CALLBACKS.lock().unwrap().input_poll = input_poll;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: Option<InputStateFn>) {
+    CALLBACKS.lock().unwrap().input_state = input_state;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemInfo {
+            library_name: b"chip8\0".as_ptr() as *const c_char,
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: b"ch8|c8|gif\0".as_ptr() as *const c_char,
+            need_fullpath: false,
+            block_extract: false,
+        };
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: WIDTH as c_uint,
+                base_height: HEIGHT as c_uint,
+                max_width: WIDTH as c_uint,
+                max_height: HEIGHT as c_uint,
+                aspect_ratio: 2.0,
+            },
+            timing: SystemTiming { fps: 60.0, sample_rate: SAMPLE_RATE },
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(ref mut game) = *GAME.lock().unwrap() {
+        game.restart();
+    }
+}
+
+/// Run a frame: read the input, run the CPU and send the frame's video and
+/// audio
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = callbacks();
+    let mut game = GAME.lock().unwrap();
+    let game = match *game {
+        Some(ref mut game) => game,
+        None => return,
+    };
+
+    let mut updated = false;
+    if let Some(environment) = callbacks.environment {
+        environment(ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void);
+    }
+    if updated {
+        game.apply_options(&callbacks);
+    }
+    if let Some(input_poll) = callbacks.input_poll {
+        input_poll();
+    }
+    if let Some(input_state) = callbacks.input_state {
+        game.poll_input(input_state);
+    }
+
+    game.cpu.run_frame();
+    game.render();
+    if let Some(video_refresh) = callbacks.video_refresh {
+        video_refresh(game.video.as_ptr() as *const c_void, WIDTH as c_uint, HEIGHT as c_uint, WIDTH * 4);
+    }
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        audio_sample_batch(game.audio.as_ptr(), SAMPLES_PER_FRAME);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    STATE_LEN
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let game = GAME.lock().unwrap();
+    match *game {
+        Some(ref game) if !data.is_null() && size >= STATE_LEN => {
+            let data = slice::from_raw_parts_mut(data as *mut u8, STATE_LEN);
+            data.copy_from_slice(&game.cpu.state().to_bytes());
+            true
+        },
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut game = GAME.lock().unwrap();
+    match *game {
+        Some(ref mut game) if !data.is_null() => {
+            let data = slice::from_raw_parts(data as *const u8, size);
+            State::from_bytes(data).and_then(|state| game.cpu.set_state(&state)).is_ok()
+        },
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(ref mut game) = *GAME.lock().unwrap() {
+        game.cpu.cheats_mut().cheats.clear();
+    }
+}
+
+/// Add a cheat in the `ADDR:VALUE` format of `cheats`, or several joined
+/// with `+`. Front-ends reset the cheats and set each enabled one again
+/// whenever they change, so the index isn't needed
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(_index: c_uint, enabled: bool, code: *const c_char) {
+    if !enabled || code.is_null() {
+        return;
+    }
+    let code = match CStr::from_ptr(code).to_str() {
+        Ok(code) => code,
+        Err(_) => return,
+    };
+    if let Some(ref mut game) = *GAME.lock().unwrap() {
+        for code in code.split('+') {
+            if let Ok(cheat) = Cheat::parse(code) {
+                game.cpu.cheats_mut().cheats.push(cheat);
+            }
+        }
+    }
+}
+
+/// Load a ROM, or an Octo cartridge GIF
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(info: *const GameInfo) -> bool {
+    let info = match info.as_ref() {
+        Some(info) if !info.data.is_null() => info,
+        _ => return false,
+    };
+    let callbacks = callbacks();
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    match callbacks.environment {
+        Some(environment) if environment(ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) => {},
+        _ => return false,
+    }
+    let data = slice::from_raw_parts(info.data as *const u8, info.size);
+    match Game::new(data) {
+        Ok(mut game) => {
+            game.apply_options(&callbacks);
+            *GAME.lock().unwrap() = Some(game);
+            true
+        },
+        Err(_) => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const GameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *GAME.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    REGION_NTSC
+}
+
+/// Memory isn't exposed to the front-end
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keyboard_codes() {
+        assert_eq!(retro_key("Digit1"), Some(b'1' as c_uint));
+        assert_eq!(retro_key("KeyQ"), Some(b'q' as c_uint));
+        assert_eq!(retro_key("Numpad7"), Some(263));
+        assert_eq!(retro_key("ArrowLeft"), Some(276));
+        assert_eq!(retro_key("Keyq"), None);
+        assert_eq!(retro_key("Digit10"), None);
+        assert_eq!(retro_key("Enter"), None);
+        let mut keys: Vec<u8> = KeyMap::qwerty().bindings().into_iter()
+            .filter(|&(code, _)| retro_key(code).is_some())
+            .map(|(_, key)| key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, (0..16).collect::<Vec<u8>>());
+    }
+}
@@ -0,0 +1,205 @@
+//! A self-contained machine for front-ends that run several at once, such
+//! as comparing quirk profiles side by side, or that run them away from the
+//! UI, such as in a Web Worker.
+//!
+//! Everything a front-end needs after running some frames comes back from
+//! `run` at once: the status, whether the beeper sounded on each frame and
+//! whether the screen changed, with the screen itself written as RGBA into
+//! a buffer the front-end owns, which may be shared with another thread.
+use cartridge::Cartridge;
+use cpu::{State, CPU};
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+/// Bytes in the RGBA framebuffer written by `write_framebuffer`
+pub const FRAMEBUFFER_LEN: usize = WIDTH * HEIGHT * 4;
+
+/// Colours for unset and set pixels, unless the ROM has its own
+const PALETTE: [[u8; 3]; 2] = [[0xFF, 0xFF, 0xFF], [0, 0, 0]];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Running,
+    /// stopped at `Fx0A` until a key is pressed
+    WaitingForKey,
+    /// stuck on a jump to itself, which is how most ROMs end
+    Halted,
+}
+
+impl Status {
+    /// Code for the status used by the wasm bindings
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Running => 0,
+            Status::WaitingForKey => 1,
+            Status::Halted => 2,
+        }
+    }
+}
+
+/// What happened over a call to `run`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Report {
+    /// frames run since the ROM was loaded
+    pub frame: u64,
+    pub status: Status,
+    /// whether the sound timer was running, for each frame run
+    pub audio: Vec<bool>,
+    /// whether the screen is different from before the call
+    pub drawn: bool,
+}
+
+#[derive(Clone)]
+pub struct Machine {
+    pub cpu: CPU,
+    /// colours from the loaded cartridge or the ROM database
+    palette: Option<[[u8; 3]; 2]>,
+    frame: u64,
+}
+
+impl Machine {
+    /// Create a machine with no ROM loaded, whose random numbers are seeded
+    /// with `seed`
+    pub fn new(seed: u32) -> Machine {
+        let mut cpu = CPU::new();
+        cpu.seed(seed);
+        cpu.reset();
+        Machine { cpu, palette: None, frame: 0 }
+    }
+
+    /// Reset the machine and load a ROM or an Octo cartridge GIF, with its
+    /// quirks, speed and colours from the cartridge or the ROM database
+    pub fn load(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut cpu = self.cpu.clone();
+        cpu.reset();
+        let palette = if data.starts_with(b"GIF8") {
+            let cartridge = Cartridge::from_gif(data)?;
+            cpu.load_rom(&cartridge.rom)?;
+            cartridge.configure(&mut cpu);
+            cartridge.palette
+        } else {
+            cpu.load_rom(data)?;
+            None
+        };
+        self.palette = palette.or_else(|| cpu.rom_info().and_then(|info| info.palette));
+        self.cpu = cpu;
+        self.frame = 0;
+        Ok(())
+    }
+
+    /// Hold down exactly the keys in `keys`, a bit per key
+    pub fn set_keys(&mut self, keys: u16) {
+        for key in 0..16 {
+            if keys >> key & 1 == 1 {
+                self.cpu.keypad.key_down(key);
+            } else {
+                self.cpu.keypad.key_up(key);
+            }
+        }
+    }
+
+    /// Run `frames` 60 Hz frames
+    pub fn run(&mut self, frames: u32) -> Report {
+        let screen = self.cpu.screen.hash();
+        let mut audio = Vec::with_capacity(frames as usize);
+        for _ in 0..frames {
+            self.cpu.run_frame();
+            self.frame += 1;
+            audio.push(self.cpu.sound_timer() > 0);
+        }
+        Report { frame: self.frame, status: self.status(), audio, drawn: self.cpu.screen.hash() != screen }
+    }
+
+    pub fn status(&self) -> Status {
+        let pc = self.cpu.get_pc();
+        if self.cpu.is_waiting_for_key() {
+            Status::WaitingForKey
+        } else if self.cpu.read_instruction() == 0x1000 | (pc & 0xFFF) {
+            Status::Halted
+        } else {
+            Status::Running
+        }
+    }
+
+    /// Frames run since the ROM was loaded
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn palette(&self) -> [[u8; 3]; 2] {
+        self.palette.unwrap_or(PALETTE)
+    }
+
+    pub fn set_palette(&mut self, palette: [[u8; 3]; 2]) {
+        self.palette = Some(palette);
+    }
+
+    /// Draw the screen into `out` as RGBA, row by row from the top left.
+    /// `out` must be `FRAMEBUFFER_LEN` bytes long
+    pub fn write_framebuffer(&self, out: &mut [u8]) {
+        assert_eq!(out.len(), FRAMEBUFFER_LEN, "framebuffer must be {} bytes", FRAMEBUFFER_LEN);
+        let palette = self.palette();
+        for (pixel, &set) in out.chunks_mut(4).zip(self.cpu.screen.to_bytes().iter()) {
+            let [r, g, b] = palette[set as usize];
+            pixel.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+
+    /// A snapshot of the machine for `load_state`, as `State::to_bytes`
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.state().to_bytes()
+    }
+
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.cpu.set_state(&State::from_bytes(bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run() {
+        // draw the font's 0, wait for a key and halt
+        let mut machine = Machine::new(0);
+        machine.load(&[0xD0, 0x15, 0xF0, 0x0A, 0x12, 0x04]).unwrap();
+        let report = machine.run(2);
+        assert_eq!(report, Report { frame: 2, status: Status::WaitingForKey, audio: vec![false; 2], drawn: true });
+
+        machine.set_keys(1 << 7);
+        machine.run(1);
+        machine.set_keys(0);
+        let report = machine.run(1);
+        assert_eq!((report.frame, report.status, report.drawn), (4, Status::Halted, false));
+        assert_eq!(machine.cpu.v[0], 7);
+
+        let mut framebuffer = vec![0; FRAMEBUFFER_LEN];
+        machine.write_framebuffer(&mut framebuffer);
+        assert_eq!(&framebuffer[..8], &[0, 0, 0, 0xFF, 0, 0, 0, 0xFF]);
+        assert_eq!(&framebuffer[16..20], &[0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn independent() {
+        // two machines with different quirks running the same shift
+        let rom = [0x61, 0x04, 0x80, 0x16, 0x12, 0x04];
+        let mut vip = Machine::new(0);
+        let mut schip = Machine::new(0);
+        vip.load(&rom).unwrap();
+        schip.load(&rom).unwrap();
+        let mut quirks = schip.cpu.quirks();
+        quirks.shift = true;
+        schip.cpu.set_quirks(quirks);
+        vip.run(1);
+        schip.run(1);
+        assert_eq!((vip.cpu.v[0], schip.cpu.v[0]), (2, 0));
+
+        let state = vip.save_state();
+        vip.run(1);
+        schip.load_state(&state).unwrap();
+        assert_eq!(schip.save_state(), state);
+        assert!(schip.load(&[]).is_err());
+        assert_eq!(schip.frame(), 1);
+    }
+}
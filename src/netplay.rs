@@ -0,0 +1,402 @@
+//! Lockstep netplay, for two-player ROMs such as `PONG2`, `TANK` and
+//! `CONNECT4` that share one keypad.
+//!
+//! Both peers run the same ROM with the same seed and send each other the
+//! keys they hold on every frame, as a bitmask. The keys a player presses
+//! are scheduled `input_delay` frames ahead, which gives them that long to
+//! reach the other peer. When they arrive late, the peer carries on with
+//! the last keys it received and, if it guessed wrong, rolls back to a
+//! snapshot taken before that frame and runs the frames again. Every
+//! `HASH_INTERVAL` frames, both peers send a hash of their machine so a
+//! desync is reported instead of going unnoticed.
+//!
+//! Messages travel over a `Transport`, which only has to deliver packets
+//! reliably and in order: `Loopback` between sessions in the same process,
+//! or `websocket::WebSocket` over the network.
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use machine::Machine;
+
+/// Frames between machine state hashes
+pub const HASH_INTERVAL: u64 = 60;
+/// Frames a session may run ahead of the last frame with both players'
+/// keys before it waits for the other peer
+pub const MAX_ROLLBACK: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Message {
+    /// keys held by the sender on `frame`, a bit per key
+    Input { frame: u64, keys: u16 },
+    /// hash of the sender's machine after running `frame` frames
+    Hash { frame: u64, hash: u64 },
+}
+
+impl Message {
+    /// A tag byte followed by the fields, big-endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match *self {
+            Message::Input { frame, keys } => {
+                bytes.push(0);
+                bytes.extend_from_slice(&frame.to_be_bytes());
+                bytes.extend_from_slice(&keys.to_be_bytes());
+            }
+            Message::Hash { frame, hash } => {
+                bytes.push(1);
+                bytes.extend_from_slice(&frame.to_be_bytes());
+                bytes.extend_from_slice(&hash.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Message, String> {
+        let u64_at = |at: usize| {
+            let mut field = [0; 8];
+            field.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_be_bytes(field)
+        };
+        match (bytes.first(), bytes.len()) {
+            (Some(0), 11) => Ok(Message::Input { frame: u64_at(1), keys: u16::from_be_bytes([bytes[9], bytes[10]]) }),
+            (Some(1), 17) => Ok(Message::Hash { frame: u64_at(1), hash: u64_at(9) }),
+            _ => Err(format!("invalid netplay message of {} bytes", bytes.len())),
+        }
+    }
+}
+
+/// A reliable, ordered connection to the other peer
+pub trait Transport {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String>;
+
+    /// The next packet received, or `None` if there isn't one yet. Must not
+    /// block
+    fn receive(&mut self) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// One end of an in-process connection, see `loopback`
+pub struct Loopback {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+}
+
+/// Two connected transports, for sessions in the same process or on
+/// different threads
+pub fn loopback() -> (Loopback, Loopback) {
+    let (a_sender, b_receiver) = channel();
+    let (b_sender, a_receiver) = channel();
+    (Loopback { sender: a_sender, receiver: a_receiver }, Loopback { sender: b_sender, receiver: b_receiver })
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+        self.sender.send(packet.to_vec()).map_err(|_| "the other peer has gone".to_string())
+    }
+
+    fn receive(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match self.receiver.try_recv() {
+            Ok(packet) => Ok(Some(packet)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err("the other peer has gone".to_string()),
+        }
+    }
+}
+
+/// One peer's side of a game. The machine runs with the keys of both
+/// players held at once, so each player should stick to their own keys
+pub struct Session<T: Transport> {
+    machine: Machine,
+    transport: T,
+    input_delay: u64,
+    /// the next frame to run
+    frame: u64,
+    /// frames before this one have been run with both players' keys
+    confirmed: u64,
+    /// keys by frame, from `confirmed` on
+    local: BTreeMap<u64, u16>,
+    remote: BTreeMap<u64, u16>,
+    /// remote keys guessed for frames run before they arrived
+    predicted: BTreeMap<u64, u16>,
+    /// the latest remote keys received, the guess for frames to come
+    last_remote: u16,
+    /// the machine before each frame from `confirmed` on
+    snapshots: VecDeque<Machine>,
+    /// hashes by frame, until the other peer's arrives
+    local_hashes: BTreeMap<u64, u64>,
+    remote_hashes: BTreeMap<u64, u64>,
+    rollbacks: u64,
+}
+
+impl<T: Transport> Session<T> {
+    /// Start a session on `machine`, which must have the same ROM, seed and
+    /// settings as the other peer's. Both peers must use the same
+    /// `input_delay`, the number of frames keys are held back by
+    pub fn new(machine: Machine, transport: T, input_delay: u64) -> Session<T> {
+        // nobody could press a key in time for the first frames
+        let none: BTreeMap<u64, u16> = (0..input_delay).map(|frame| (frame, 0)).collect();
+        Session {
+            machine,
+            transport,
+            input_delay,
+            frame: 0,
+            confirmed: 0,
+            local: none.clone(),
+            remote: none,
+            predicted: BTreeMap::new(),
+            last_remote: 0,
+            snapshots: VecDeque::new(),
+            local_hashes: BTreeMap::new(),
+            remote_hashes: BTreeMap::new(),
+            rollbacks: 0,
+        }
+    }
+
+    /// Hold `keys`, a bit per key, `input_delay` frames from now and run a
+    /// frame. Returns false without running one if the other peer is too
+    /// far behind, in which case this should be called again later
+    pub fn advance(&mut self, keys: u16) -> Result<bool, String> {
+        let target = self.frame + self.input_delay;
+        if let Entry::Vacant(entry) = self.local.entry(target) {
+            entry.insert(keys);
+            self.transport.send(&Message::Input { frame: target, keys }.to_bytes())?;
+        }
+        self.poll()?;
+        if self.frame - self.confirmed >= MAX_ROLLBACK {
+            return Ok(false);
+        }
+        self.run_frame();
+        self.confirm()?;
+        Ok(true)
+    }
+
+    /// Handle the messages received, rolling back if a guess was wrong.
+    /// Fails if the machines have desynced
+    pub fn poll(&mut self) -> Result<(), String> {
+        let mut rollback: Option<u64> = None;
+        while let Some(packet) = self.transport.receive()? {
+            match Message::from_bytes(&packet)? {
+                Message::Input { frame, keys } => {
+                    if frame < self.confirmed || self.remote.contains_key(&frame) {
+                        continue;
+                    }
+                    self.remote.insert(frame, keys);
+                    self.last_remote = keys;
+                    if let Some(guess) = self.predicted.remove(&frame) {
+                        if guess != keys {
+                            rollback = Some(rollback.map_or(frame, |from| from.min(frame)));
+                        }
+                    }
+                }
+                Message::Hash { frame, hash } => {
+                    self.remote_hashes.insert(frame, hash);
+                }
+            }
+        }
+        if let Some(from) = rollback {
+            self.rollback(from);
+        }
+        self.confirm()
+    }
+
+    /// Go back to before `from` and run the frames since again, with the
+    /// keys received since
+    fn rollback(&mut self, from: u64) {
+        let index = (from - self.confirmed) as usize;
+        self.machine = self.snapshots[index].clone();
+        self.snapshots.truncate(index);
+        let frame = self.frame;
+        self.frame = from;
+        while self.frame < frame {
+            self.run_frame();
+        }
+        self.rollbacks += 1;
+    }
+
+    fn run_frame(&mut self) {
+        let frame = self.frame;
+        let remote = match self.remote.get(&frame) {
+            Some(&keys) => keys,
+            None => {
+                self.predicted.insert(frame, self.last_remote);
+                self.last_remote
+            }
+        };
+        self.snapshots.push_back(self.machine.clone());
+        self.machine.set_keys(self.local[&frame] | remote);
+        self.machine.run(1);
+        self.frame += 1;
+    }
+
+    /// Move `confirmed` past the frames run with the real keys of both
+    /// players, exchanging hashes along the way
+    // `is_multiple_of` needs a newer Rust than the crate otherwise does
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn confirm(&mut self) -> Result<(), String> {
+        // a frame whose guess was wrong was run again, so any frame that
+        // has arrived was run with the right keys
+        while self.confirmed < self.frame && self.remote.contains_key(&self.confirmed) {
+            self.local.remove(&self.confirmed);
+            self.remote.remove(&self.confirmed);
+            self.predicted.remove(&self.confirmed);
+            self.snapshots.pop_front();
+            self.confirmed += 1;
+            if self.confirmed % HASH_INTERVAL == 0 {
+                let machine = self.snapshots.front().unwrap_or(&self.machine);
+                let hash = machine.cpu.state_hash();
+                self.local_hashes.insert(self.confirmed, hash);
+                self.transport.send(&Message::Hash { frame: self.confirmed, hash }.to_bytes())?;
+            }
+        }
+
+        let checked: Vec<u64> = self.local_hashes.keys().filter(|frame| self.remote_hashes.contains_key(frame)).cloned().collect();
+        for frame in checked {
+            if self.local_hashes[&frame] != self.remote_hashes[&frame] {
+                return Err(format!("desync after frame {}", frame));
+            }
+            self.local_hashes.remove(&frame);
+            self.remote_hashes.remove(&frame);
+        }
+        Ok(())
+    }
+
+    /// The machine after the frames run so far, some of which may have used
+    /// guessed keys for the other player
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Frames run so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Frames run with both players' keys, which won't be rolled back
+    pub fn confirmed(&self) -> u64 {
+        self.confirmed
+    }
+
+    /// Times the session has rolled back after guessing wrong
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Adds `step` to V0 on every cycle
+    fn machine(step: u8) -> Machine {
+        let mut machine = Machine::new(1);
+        machine.load(&[0x70, step, 0x12, 0x00]).unwrap();
+        machine
+    }
+
+    #[test]
+    fn message_bytes() {
+        for &message in [Message::Input { frame: 1 << 40, keys: 0x8001 }, Message::Hash { frame: 60, hash: u64::MAX }].iter() {
+            assert_eq!(Message::from_bytes(&message.to_bytes()), Ok(message));
+        }
+        assert!(Message::from_bytes(&[0, 1, 2]).is_err());
+        assert!(Message::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn lockstep() {
+        let (a, b) = loopback();
+        let mut a = Session::new(machine(1), a, 2);
+        let mut b = Session::new(machine(1), b, 2);
+        for frame in 0..200 {
+            assert!(a.advance(frame as u16 & 0x0F).unwrap());
+            assert!(b.advance(frame as u16 & 0xF0).unwrap());
+        }
+        a.poll().unwrap();
+        assert_eq!(a.frame(), 200);
+        assert_eq!(a.confirmed(), 200);
+        // the keys always arrived in time
+        assert_eq!(a.rollbacks(), 0);
+        assert_eq!(a.machine().cpu.state_hash(), b.machine().cpu.state_hash());
+    }
+
+    #[test]
+    fn rollback() {
+        // keys decide where a sprite is drawn, so a wrong guess shows on
+        // the screen
+        let rom = [
+            0xA2, 0x10, // LD I, 0x210
+            0x60, 0x00, // LD V0, 0
+            0xE0, 0xA1, // SKNP V0
+            0x70, 0x08, // ADD V0, 8
+            0x61, 0x01, // LD V1, 1
+            0xE1, 0xA1, // SKNP V1
+            0xD0, 0x11, // DRW V0, V1, 1
+            0x12, 0x00, // JP 0x200
+            0xF0, 0x00, // sprite
+        ];
+        let load = || {
+            let mut machine = Machine::new(1);
+            machine.load(&rom).unwrap();
+            machine
+        };
+        let (a, b) = loopback();
+        let mut a = Session::new(load(), a, 0);
+        let mut b = Session::new(load(), b, 0);
+        for round in 0..20u16 {
+            // b falls behind, so a has to guess its keys and gets them
+            // wrong when they change
+            for _ in 0..4 {
+                assert!(a.advance(round & 1).unwrap());
+            }
+            for _ in 0..4 {
+                assert!(b.advance(round & 2).unwrap());
+            }
+        }
+        a.poll().unwrap();
+        b.poll().unwrap();
+        assert_eq!(a.confirmed(), 80);
+        assert_eq!(b.confirmed(), 80);
+        assert!(a.rollbacks() > 0);
+        assert_eq!(a.machine().cpu.state_hash(), b.machine().cpu.state_hash());
+    }
+
+    #[test]
+    fn waits_for_other_peer() {
+        let (a, _b) = loopback();
+        let mut a = Session::new(machine(1), a, 0);
+        for _ in 0..MAX_ROLLBACK {
+            assert!(a.advance(0).unwrap());
+        }
+        assert!(!a.advance(0).unwrap());
+        assert_eq!(a.frame(), MAX_ROLLBACK);
+    }
+
+    #[test]
+    fn desync() {
+        let (a, b) = loopback();
+        let mut a = Session::new(machine(1), a, 1);
+        let mut b = Session::new(machine(2), b, 1);
+        let mut error = None;
+        for _ in 0..HASH_INTERVAL + 2 {
+            // b may find out first
+            let _ = b.advance(0);
+            if let Err(e) = a.advance(0) {
+                error = Some(e);
+                break;
+            }
+        }
+        assert_eq!(error, Some(format!("desync after frame {}", HASH_INTERVAL)));
+    }
+
+    #[test]
+    fn disconnected() {
+        let (a, b) = loopback();
+        let mut a = Session::new(machine(1), a, 0);
+        drop(b);
+        assert!(a.advance(0).is_err());
+    }
+}
@@ -0,0 +1,582 @@
+//! An assembler for the CHIP-8 subset of [Octo](https://github.com/JohnEarnest/Octo),
+//! the assembly language most new CHIP-8 programs are written in, and the
+//! source format stored in Octo cartridges.
+//!
+//! Supported are labels, `:const`, `:alias`, `:unpack`, `:next`, `:org`,
+//! `:call`, the structured `if ... then`, `if ... begin ... else ... end`
+//! and `loop ... while ... again` forms, and every CHIP-8 instruction.
+//! Macros, `:calc`, `:stringmode`, the `<`, `>`, `<=` and `>=` comparisons
+//! and SCHIP and XO-CHIP instructions are reported as errors. As in Octo,
+//! the program starts with a jump to the `main` label.
+use std::collections::HashMap;
+
+use cpu::PROGRAM_START;
+
+/// SCHIP and XO-CHIP instructions, which the interpreter doesn't support
+const UNSUPPORTED: [&str; 13] = [
+    "hires", "lores", "scroll-down", "scroll-up", "scroll-left", "scroll-right",
+    "exit", "saveflags", "loadflags", "bighex", "long", "plane", "audio",
+];
+
+/// Parts of an instruction filled in once a label is defined
+#[derive(Clone, Copy)]
+enum Fixup {
+    /// the 12 bit address of an instruction like `1nnn` or `Annn`
+    Address,
+    /// the `v0 := ` and `v1 := ` pair of `:unpack` with its high nibble
+    Unpack(u8),
+}
+
+/// An open `if`, `else` or `loop`
+enum Block {
+    /// position of the jump past the `begin` body
+    If(usize),
+    /// position of the jump past the `else` body
+    Else(usize),
+    /// address of the start of the loop, and positions of the jumps out of
+    /// it for each `while`
+    Loop(u16, Vec<usize>),
+}
+
+/// A condition of `if` or `while`
+enum Condition {
+    Equal(u8, u8),
+    NotEqual(u8, u8),
+    EqualReg(u8, u8),
+    NotEqualReg(u8, u8),
+    Key(u8),
+    NotKey(u8),
+}
+
+impl Condition {
+    /// The instruction that skips the next one when the condition holds
+    fn skip(&self) -> u16 {
+        match *self {
+            Condition::Equal(x, n) => 0x3000 | (x as u16) << 8 | n as u16,
+            Condition::NotEqual(x, n) => 0x4000 | (x as u16) << 8 | n as u16,
+            Condition::EqualReg(x, y) => 0x5000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::NotEqualReg(x, y) => 0x9000 | (x as u16) << 8 | (y as u16) << 4,
+            Condition::Key(x) => 0xE09E | (x as u16) << 8,
+            Condition::NotKey(x) => 0xE0A1 | (x as u16) << 8,
+        }
+    }
+
+    /// The instruction that skips the next one unless the condition holds
+    fn skip_unless(&self) -> u16 {
+        match *self {
+            Condition::Equal(x, n) => Condition::NotEqual(x, n).skip(),
+            Condition::NotEqual(x, n) => Condition::Equal(x, n).skip(),
+            Condition::EqualReg(x, y) => Condition::NotEqualReg(x, y).skip(),
+            Condition::NotEqualReg(x, y) => Condition::EqualReg(x, y).skip(),
+            Condition::Key(x) => Condition::NotKey(x).skip(),
+            Condition::NotKey(x) => Condition::Key(x).skip(),
+        }
+    }
+}
+
+struct Assembler<'a> {
+    tokens: Vec<(usize, &'a str)>,
+    next_token: usize,
+    /// line of the token most recently read, for error messages
+    line: usize,
+    rom: Vec<u8>,
+    /// offset into `rom` where the next byte goes
+    here: usize,
+    labels: HashMap<&'a str, u16>,
+    constants: HashMap<&'a str, u16>,
+    aliases: HashMap<&'a str, u8>,
+    fixups: Vec<(usize, &'a str, Fixup, usize)>,
+    blocks: Vec<Block>,
+    /// label to define as the address of the second byte of the next
+    /// instruction
+    next_label: Option<&'a str>,
+}
+
+/// Assemble Octo source into a ROM to be loaded at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut tokens = Vec::new();
+    for (line, text) in source.lines().enumerate() {
+        let code = text.split('#').next().unwrap();
+        tokens.extend(code.split_whitespace().map(|token| (line + 1, token)));
+    }
+    let mut assembler = Assembler {
+        tokens,
+        next_token: 0,
+        line: 1,
+        rom: Vec::new(),
+        here: 0,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        next_label: None,
+    };
+    assembler.run()?;
+    Ok(assembler.rom)
+}
+
+/// Parse a decimal, `0x` hex or `0b` binary number, possibly negative
+fn parse_number(token: &str) -> Option<i32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i32::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+impl<'a> Assembler<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let &(line, token) = self.tokens.get(self.next_token)
+            .ok_or_else(|| self.error("unexpected end of program"))?;
+        self.next_token += 1;
+        self.line = line;
+        Ok(token)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next_token).map(|&(_, token)| token)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`, found `{}`", expected, token)))
+        }
+    }
+
+    fn address(&self) -> u16 {
+        PROGRAM_START + self.here as u16
+    }
+
+    fn byte(&mut self, value: u8) -> Result<(), String> {
+        if PROGRAM_START as usize + self.here >= 0x1000 {
+            return Err(self.error("program is too big to fit in memory"));
+        }
+        if self.here >= self.rom.len() {
+            self.rom.resize(self.here + 1, 0);
+        }
+        self.rom[self.here] = value;
+        self.here += 1;
+        Ok(())
+    }
+
+    /// Emit an instruction, returning its position in the ROM
+    fn emit(&mut self, opcode: u16) -> Result<usize, String> {
+        if let Some(name) = self.next_label.take() {
+            let address = self.address() + 1;
+            self.define(name, address)?;
+        }
+        let position = self.here;
+        self.byte((opcode >> 8) as u8)?;
+        self.byte(opcode as u8)?;
+        Ok(position)
+    }
+
+    /// Set the address of the instruction at `position`
+    fn patch(&mut self, position: usize, address: u16) {
+        self.rom[position] = (self.rom[position] & 0xF0) | (address >> 8) as u8 & 0x0F;
+        self.rom[position + 1] = address as u8;
+    }
+
+    fn define(&mut self, name: &'a str, address: u16) -> Result<(), String> {
+        if self.labels.insert(name, address).is_some() {
+            return Err(self.error(&format!("label `{}` is defined twice", name)));
+        }
+        Ok(())
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.as_register(token).ok_or_else(|| self.error(&format!("expected a register, found `{}`", token)))
+    }
+
+    fn as_register(&self, token: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(token) {
+            return Some(register);
+        }
+        let mut chars = token.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) =>
+                digit.to_digit(16).map(|digit| digit as u8),
+            _ => None,
+        }
+    }
+
+    /// A number or constant, or the address of a label defined earlier
+    fn as_value(&self, token: &str) -> Option<i32> {
+        parse_number(token)
+            .or_else(|| self.constants.get(token).map(|&value| value as i32))
+            .or_else(|| self.labels.get(token).map(|&value| value as i32))
+    }
+
+    fn value(&mut self, max: i32) -> Result<u16, String> {
+        let token = self.next()?;
+        match self.as_value(token) {
+            // negative numbers are two's complement bytes
+            Some(value) if (-128..0).contains(&value) && max == 0xFF => Ok((value & 0xFF) as u16),
+            Some(value) if (0..=max).contains(&value) => Ok(value as u16),
+            Some(_) => Err(self.error(&format!("`{}` is out of range", token))),
+            None => Err(self.error(&format!("expected a number, found `{}`", token))),
+        }
+    }
+
+    /// Emit an instruction taking a 12 bit address, which may be a label
+    /// defined later on
+    fn address_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let token = self.next()?;
+        match self.as_value(token) {
+            Some(value) if (0..=0xFFF).contains(&value) => {
+                self.emit(opcode | value as u16)?;
+            },
+            Some(_) => return Err(self.error(&format!("`{}` is out of range", token))),
+            None => {
+                let position = self.emit(opcode)?;
+                self.fixups.push((position, token, Fixup::Address, self.line));
+            },
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        match operator {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            "==" | "!=" => (),
+            "<" | ">" | "<=" | ">=" =>
+                return Err(self.error(&format!("comparison `{}` is not supported", operator))),
+            _ => return Err(self.error(&format!("expected a comparison, found `{}`", operator))),
+        }
+        let equal = operator == "==";
+        if let Some(y) = self.peek().and_then(|token| self.as_register(token)) {
+            self.next()?;
+            return Ok(if equal { Condition::EqualReg(x, y) } else { Condition::NotEqualReg(x, y) });
+        }
+        let n = self.value(0xFF)? as u8;
+        Ok(if equal { Condition::Equal(x, n) } else { Condition::NotEqual(x, n) })
+    }
+
+    /// `vx` followed by an assignment operator
+    fn register_operation(&mut self, x: u8) -> Result<(), String> {
+        let x16 = (x as u16) << 8;
+        let operator = self.next()?;
+        let y = self.peek().and_then(|token| self.as_register(token));
+        if let Some(y) = y {
+            self.next()?;
+            let y16 = (y as u16) << 4;
+            let opcode = match operator {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800E,
+                _ => return Err(self.error(&format!("unknown operator `{}`", operator))),
+            };
+            self.emit(opcode | x16 | y16)?;
+            return Ok(());
+        }
+
+        match (operator, self.peek()) {
+            (":=", Some("random")) => {
+                self.next()?;
+                let n = self.value(0xFF)?;
+                self.emit(0xC000 | x16 | n)?;
+            },
+            (":=", Some("key")) => {
+                self.next()?;
+                self.emit(0xF00A | x16)?;
+            },
+            (":=", Some("delay")) => {
+                self.next()?;
+                self.emit(0xF007 | x16)?;
+            },
+            (":=", _) => {
+                let n = self.value(0xFF)?;
+                self.emit(0x6000 | x16 | n)?;
+            },
+            ("+=", _) => {
+                let n = self.value(0xFF)?;
+                self.emit(0x7000 | x16 | n)?;
+            },
+            ("-=", _) => {
+                let n = self.value(0xFF)?;
+                self.emit(0x7000 | x16 | (0x100 - n) & 0xFF)?;
+            },
+            _ => return Err(self.error(&format!("`{}` needs a register on the right", operator))),
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &'a str) -> Result<(), String> {
+        match token {
+            ":" => {
+                let name = self.next()?;
+                let address = self.address();
+                self.define(name, address)?;
+            },
+            ":const" => {
+                let name = self.next()?;
+                let value = self.value(0xFFFF)?;
+                self.constants.insert(name, value);
+            },
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            },
+            ":unpack" => {
+                let nibble = self.value(0xF)? as u8;
+                let label = self.next()?;
+                let position = self.emit(0x6000)?;
+                self.emit(0x6100)?;
+                self.fixups.push((position, label, Fixup::Unpack(nibble), self.line));
+            },
+            ":next" => self.next_label = Some(self.next()?),
+            ":org" => {
+                let address = self.value(0xFFF)?;
+                if address < PROGRAM_START {
+                    return Err(self.error("`:org` must not be below 0x200"));
+                }
+                self.here = (address - PROGRAM_START) as usize;
+            },
+            ":call" => self.address_instruction(0x2000)?,
+            ":breakpoint" => {
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            "clear" => {
+                self.emit(0x00E0)?;
+            },
+            "return" | ";" => {
+                self.emit(0x00EE)?;
+            },
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(0xF033 | (x as u16) << 8)?;
+            },
+            "save" => {
+                let x = self.register()?;
+                self.emit(0xF055 | (x as u16) << 8)?;
+            },
+            "load" => {
+                let x = self.register()?;
+                self.emit(0xF065 | (x as u16) << 8)?;
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.value(0xF)?;
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n)?;
+            },
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xB000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let opcode = if token == "delay" { 0xF015 } else { 0xF018 };
+                self.emit(opcode | (x as u16) << 8)?;
+            },
+            "i" => match self.next()? {
+                ":=" if self.peek() == Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(0xF029 | (x as u16) << 8)?;
+                },
+                ":=" => self.address_instruction(0xA000)?,
+                "+=" => {
+                    let x = self.register()?;
+                    self.emit(0xF01E | (x as u16) << 8)?;
+                },
+                operator => return Err(self.error(&format!("unknown operator `{}` for i", operator))),
+            },
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()? {
+                    "then" => {
+                        self.emit(condition.skip_unless())?;
+                    },
+                    "begin" => {
+                        self.emit(condition.skip())?;
+                        let jump = self.emit(0x1000)?;
+                        self.blocks.push(Block::If(jump));
+                    },
+                    other => return Err(self.error(&format!("expected `then` or `begin`, found `{}`", other))),
+                }
+            },
+            "else" => match self.blocks.pop() {
+                Some(Block::If(jump)) => {
+                    let end = self.emit(0x1000)?;
+                    let address = self.address();
+                    self.patch(jump, address);
+                    self.blocks.push(Block::Else(end));
+                },
+                _ => return Err(self.error("`else` without `if ... begin`")),
+            },
+            "end" => match self.blocks.pop() {
+                Some(Block::If(jump)) | Some(Block::Else(jump)) => {
+                    let address = self.address();
+                    self.patch(jump, address);
+                },
+                _ => return Err(self.error("`end` without `if ... begin`")),
+            },
+            "loop" => {
+                let start = self.address();
+                self.blocks.push(Block::Loop(start, Vec::new()));
+            },
+            "while" => {
+                let condition = self.condition()?;
+                self.emit(condition.skip())?;
+                let jump = self.emit(0x1000)?;
+                match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop(_, _))) {
+                    Some(Block::Loop(_, exits)) => exits.push(jump),
+                    _ => return Err(self.error("`while` outside of a loop")),
+                }
+            },
+            "again" => match self.blocks.pop() {
+                Some(Block::Loop(start, exits)) => {
+                    self.emit(0x1000 | start)?;
+                    let address = self.address();
+                    for jump in exits {
+                        self.patch(jump, address);
+                    }
+                },
+                _ => return Err(self.error("`again` without `loop`")),
+            },
+            _ if token.starts_with(':') =>
+                return Err(self.error(&format!("directive `{}` is not supported", token))),
+            _ if UNSUPPORTED.contains(&token) =>
+                return Err(self.error(&format!("`{}` is not a CHIP-8 instruction", token))),
+            _ => {
+                if let Some(x) = self.as_register(token) {
+                    self.register_operation(x)?;
+                } else if let Some(value) = parse_number(token).or_else(|| self.constants.get(token).map(|&v| v as i32)) {
+                    if !(-128..=0xFF).contains(&value) {
+                        return Err(self.error(&format!("byte `{}` is out of range", token)));
+                    }
+                    self.byte(value as u8)?;
+                } else {
+                    // a bare label name calls it
+                    self.next_token -= 1;
+                    self.address_instruction(0x2000)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        // jump to main, filled in below
+        self.emit(0x1000)?;
+        while self.next_token < self.tokens.len() {
+            let token = self.next()?;
+            self.statement(token)?;
+        }
+        if !self.blocks.is_empty() {
+            return Err(self.error("unterminated `if ... begin` or `loop`"));
+        }
+
+        let main = *self.labels.get("main").ok_or("the program has no `main` label")?;
+        self.patch(0, main);
+        for (position, name, fixup, line) in self.fixups.clone() {
+            let address = *self.labels.get(name)
+                .ok_or_else(|| format!("line {}: undefined label `{}`", line, name))?;
+            match fixup {
+                Fixup::Address => self.patch(position, address),
+                Fixup::Unpack(nibble) => {
+                    self.rom[position + 1] = nibble << 4 | (address >> 8) as u8;
+                    self.rom[position + 3] = address as u8;
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        let rom = assemble("
+            : main
+                clear
+                v0 := 5   v1 += -1   v2 -= 3   va := random 0x0F
+                v3 := v4  v3 ^= v5   v3 >>= v3   v3 =- v6
+                i := sprites   i := hex v7   i += v8
+                sprite v0 v1 5
+                delay := v0   v9 := delay   vb := key
+                bcd v3  save v3  load v3
+                helper
+                jump main
+            : helper ;
+            : sprites 0xFF 0b10000001 -1
+        ").unwrap();
+        let words: Vec<u16> = rom.chunks(2).map(|w| (w[0] as u16) << 8 | *w.get(1).unwrap_or(&0) as u16).collect();
+        assert_eq!(words, vec![
+            0x1202, 0x00E0, 0x6005, 0x71FF, 0x72FD, 0xCA0F, 0x8340, 0x8353, 0x8336, 0x8367,
+            0xA22E, 0xF729, 0xF81E, 0xD015, 0xF015, 0xF907, 0xFB0A, 0xF333, 0xF355, 0xF365,
+            0x222C, 0x1202, 0x00EE, 0xFF81, 0xFF00,
+        ]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let rom = assemble("
+            :alias counter v1
+            :const LIMIT 3
+            : main
+                if counter == LIMIT then counter := 0
+                if v0 key begin
+                    v2 := 1
+                else
+                    v2 := 2
+                end
+                loop
+                    counter += 1
+                    while counter != 10
+                again
+        ").unwrap();
+        assert_eq!(rom, vec![
+            0x12, 0x02,
+            0x41, 0x03, 0x61, 0x00,
+            0xE0, 0x9E, 0x12, 0x0E, 0x62, 0x01, 0x12, 0x10, 0x62, 0x02,
+            0x71, 0x01, 0x41, 0x0A, 0x12, 0x18, 0x12, 0x10,
+        ]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(assemble(": start clear").unwrap_err(), "the program has no `main` label");
+        assert_eq!(assemble(": main\n jump nowhere").unwrap_err(), "line 2: undefined label `nowhere`");
+        assert_eq!(assemble(": main\n\n hires").unwrap_err(), "line 3: `hires` is not a CHIP-8 instruction");
+        assert!(assemble(": main :macro foo { }").is_err());
+        assert!(assemble(": main if v0 < v1 then clear").is_err());
+        assert!(assemble(": main v0 := 256").is_err());
+        assert!(assemble(": main loop").is_err());
+        assert!(assemble(": main : main").is_err());
+    }
+}
@@ -0,0 +1,335 @@
+//! IPS and BPS patches, for distributing fixed-up versions of ROMs as the
+//! changes to make to the original rather than as a copy of it.
+//!
+//! IPS patches are a list of bytes to overwrite at given offsets, with no
+//! way to tell whether they are being applied to the right ROM. BPS patches
+//! carry CRC-32 checksums of the ROM they apply to, the ROM they produce and
+//! themselves, which are all checked. `apply` tells the two apart by their
+//! headers.
+use cpu::{MEMORY_LEN, PROGRAM_START};
+use hash::crc32;
+
+const IPS_HEADER: &[u8] = b"PATCH";
+const IPS_FOOTER: &[u8] = b"EOF";
+const BPS_HEADER: &[u8] = b"BPS1";
+
+/// Offsets in IPS patches are 3 bytes, and the one that spells `EOF` ends
+/// the patch, so records must start before it
+const IPS_MAX_OFFSET: usize = 0x45_4F46;
+/// Largest number of bytes in an IPS record
+const IPS_MAX_RECORD: usize = 0xFFFF;
+/// Largest ROM a BPS patch may produce, the most that fits in memory. The
+/// length is read before the checksums of the output can be checked, so it
+/// is limited before anything is allocated for it
+const BPS_MAX_TARGET: usize = MEMORY_LEN - PROGRAM_START as usize;
+
+/// Whether `data` starts like an IPS or BPS patch
+pub fn is_patch(data: &[u8]) -> bool {
+    data.starts_with(IPS_HEADER) || data.starts_with(BPS_HEADER)
+}
+
+/// Apply an IPS or BPS patch to `rom`, returning the patched ROM
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_HEADER) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_HEADER) {
+        apply_bps(rom, patch)
+    } else {
+        Err("not an IPS or BPS patch".to_string())
+    }
+}
+
+/// Reads the fields of a patch, failing at the end of the data instead of
+/// panicking
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or("patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// A big endian number, as used by IPS
+    fn number(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self.bytes(len)?.iter().fold(0, |n, &byte| n << 8 | byte as usize))
+    }
+
+    /// A variable length number, as used by BPS: 7 bits at a time, least
+    /// significant first, with the top bit marking the last byte
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.bytes(1)?[0] as usize;
+            value = (byte & 0x7F).checked_mul(shift).and_then(|n| n.checked_add(value))
+                .ok_or("patch number is too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("patch number is too large")?;
+            value = value.checked_add(shift).ok_or("patch number is too large")?;
+        }
+    }
+}
+
+pub fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(IPS_HEADER) {
+        return Err("not an IPS patch".to_string());
+    }
+    let mut reader = Reader { data: patch, pos: IPS_HEADER.len() };
+    let mut output = rom.to_vec();
+    loop {
+        if reader.bytes(3)? == IPS_FOOTER {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.number(3)?;
+        let len = reader.number(2)?;
+        let bytes = if len == 0 {
+            // run length encoded: a count and the byte to repeat
+            let count = reader.number(2)?;
+            vec![reader.bytes(1)?[0]; count]
+        } else {
+            reader.bytes(len)?.to_vec()
+        };
+        if output.len() < offset + bytes.len() {
+            output.resize(offset + bytes.len(), 0);
+        }
+        output[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    // an extension to the format: the size to truncate the output to
+    if patch.len() - reader.pos == 3 {
+        let len = reader.number(3)?;
+        output.truncate(len);
+    }
+    Ok(output)
+}
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if !patch.starts_with(BPS_HEADER) || patch.len() < BPS_HEADER.len() + 12 {
+        return Err("not a BPS patch".to_string());
+    }
+    let end = patch.len() - 12;
+    let checksum = |at: usize| {
+        patch[at..at + 4].iter().rev().fold(0, |n, &byte| n << 8 | byte as u32)
+    };
+    if crc32(&patch[..patch.len() - 4]) != checksum(end + 8) {
+        return Err("patch is corrupt, its checksum doesn't match".to_string());
+    }
+
+    let mut reader = Reader { data: &patch[..end], pos: BPS_HEADER.len() };
+    let source_len = reader.varint()?;
+    let target_len = reader.varint()?;
+    let metadata_len = reader.varint()?;
+    reader.bytes(metadata_len)?;
+    if source.len() != source_len || crc32(source) != checksum(end) {
+        return Err("patch is for a different ROM".to_string());
+    }
+    if target_len > BPS_MAX_TARGET {
+        return Err("patched ROM is too big to fit in memory".to_string());
+    }
+
+    let mut target = Vec::with_capacity(target_len);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.pos < end {
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_len {
+            return Err("patch writes past the end of the patched ROM".to_string());
+        }
+        match action & 3 {
+            // copy from the same offset in the source
+            0 => {
+                let bytes = source.get(target.len()..target.len() + len)
+                    .ok_or("patch reads past the end of the ROM")?;
+                target.extend_from_slice(bytes);
+            },
+            // copy from the patch
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // copy from elsewhere in the source
+            2 => {
+                source_offset = relative(source_offset, reader.varint()?)?;
+                let bytes = source.get(source_offset..source_offset + len)
+                    .ok_or("patch reads past the end of the ROM")?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            },
+            // copy from earlier in the output, one byte at a time since the
+            // ranges may overlap
+            _ => {
+                target_offset = relative(target_offset, reader.varint()?)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset)
+                        .ok_or("patch reads past the end of the patched ROM")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+    if target.len() != target_len || crc32(&target) != checksum(end + 4) {
+        return Err("patched ROM doesn't match the patch's checksum".to_string());
+    }
+    Ok(target)
+}
+
+/// Move `offset` by a BPS signed offset: the magnitude shifted left by
+/// one, with the sign in the lowest bit
+fn relative(offset: usize, encoded: usize) -> Result<usize, String> {
+    let distance = encoded >> 1;
+    let moved = if encoded & 1 == 1 {
+        offset.checked_sub(distance)
+    } else {
+        offset.checked_add(distance)
+    };
+    moved.ok_or_else(|| "patch reads before the start of the ROM".to_string())
+}
+
+/// Create an IPS patch that turns `original` into `modified`
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Result<Vec<u8>, String> {
+    if modified.len() > IPS_MAX_OFFSET {
+        return Err("ROM is too big for an IPS patch".to_string());
+    }
+    let mut patch = IPS_HEADER.to_vec();
+    let mut i = 0;
+    while i < modified.len() {
+        if original.get(i) == Some(&modified[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < modified.len() && i - start < IPS_MAX_RECORD && original.get(i) != Some(&modified[i]) {
+            i += 1;
+        }
+        patch.extend_from_slice(&[(start >> 16) as u8, (start >> 8) as u8, start as u8]);
+        patch.extend_from_slice(&[((i - start) >> 8) as u8, (i - start) as u8]);
+        patch.extend_from_slice(&modified[start..i]);
+    }
+    patch.extend_from_slice(IPS_FOOTER);
+    if modified.len() < original.len() {
+        let len = modified.len();
+        patch.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    }
+    Ok(patch)
+}
+
+/// Create a BPS patch that turns `source` into `target`
+pub fn create_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut patch = BPS_HEADER.to_vec();
+    write_varint(&mut patch, source.len());
+    write_varint(&mut patch, target.len());
+    write_varint(&mut patch, 0);
+
+    let same = |i: usize| source.get(i) == Some(&target[i]);
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        let copy = same(i);
+        while i < target.len() && same(i) == copy {
+            i += 1;
+        }
+        write_varint(&mut patch, (i - start - 1) << 2 | if copy { 0 } else { 1 });
+        if !copy {
+            patch.extend_from_slice(&target[start..i]);
+        }
+    }
+
+    for &checksum in [crc32(source), crc32(target)].iter() {
+        patch.extend_from_slice(&checksum.to_le_bytes());
+    }
+    let checksum = crc32(&patch);
+    patch.extend_from_slice(&checksum.to_le_bytes());
+    patch
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let low = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(0x80 | low);
+            return;
+        }
+        out.push(low);
+        value -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ips() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAA\xBB\x00\x00\x05\x00\x00\x00\x03\xCCEOF";
+        assert_eq!(apply(b"0123", patch), Ok(vec![b'0', 0xAA, 0xBB, b'3', 0, 0xCC, 0xCC, 0xCC]));
+        assert_eq!(apply(b"0123", b"PATCHEOF\x00\x00\x02"), Ok(b"01".to_vec()));
+        assert_eq!(apply(b"0123", b"PATCH\x00\x00\x01\x00\x02\xAA"), Err("patch is truncated".to_string()));
+    }
+
+    #[test]
+    fn bps() {
+        let blinky = include_bytes!("../roms/BLINKY");
+        let mut fixed = blinky.to_vec();
+        fixed[0x10] ^= 0xFF;
+        fixed[0x11] ^= 0xFF;
+        fixed.extend_from_slice(b"extra");
+        let patch = create_bps(blinky, &fixed);
+        assert!(patch.len() < 64);
+        assert_eq!(apply(blinky, &patch), Ok(fixed.clone()));
+
+        assert_eq!(apply(&fixed, &patch), Err("patch is for a different ROM".to_string()));
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        assert_eq!(apply(blinky, &corrupt), Err("patch is corrupt, its checksum doesn't match".to_string()));
+    }
+
+    #[test]
+    fn bps_copies() {
+        // source copy of "cd" from offset 2, then a target copy repeating
+        // the output from its start
+        let mut patch = b"BPS1".to_vec();
+        for &n in [4, 8, 0, (2 - 1) << 2 | 2, 2 << 1, (6 - 1) << 2 | 3, 0].iter() {
+            write_varint(&mut patch, n);
+        }
+        patch.extend_from_slice(&crc32(b"abcd").to_le_bytes());
+        patch.extend_from_slice(&crc32(b"cdcdcdcd").to_le_bytes());
+        let checksum = crc32(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(apply(b"abcd", &patch), Ok(b"cdcdcdcd".to_vec()));
+    }
+
+    #[test]
+    fn bps_too_big() {
+        // a patch with valid checksums for a ROM of a quarter of the address
+        // space
+        let mut patch = b"BPS1".to_vec();
+        for &n in [4, usize::MAX / 4, 0].iter() {
+            write_varint(&mut patch, n);
+        }
+        patch.extend_from_slice(&crc32(b"abcd").to_le_bytes());
+        patch.extend_from_slice(&[0; 4]);
+        let checksum = crc32(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(apply(b"abcd", &patch), Err("patched ROM is too big to fit in memory".to_string()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut modified = original.clone();
+        modified[0] = 7;
+        modified[100..110].copy_from_slice(&[0; 10]);
+        modified.truncate(200);
+        for patch in [create_ips(&original, &modified).unwrap(), create_bps(&original, &modified)].iter() {
+            assert!(is_patch(patch));
+            assert_eq!(apply(&original, patch), Ok(modified.clone()));
+        }
+        assert_eq!(apply(&original, b"not a patch"), Err("not an IPS or BPS patch".to_string()));
+    }
+}
@@ -0,0 +1,116 @@
+//! Python bindings for running ROMs headlessly, built with the `python`
+//! feature:
+//!
+//! ```text
+//! import chip8
+//! machine = chip8.Chip8(open("roms/PONG", "rb").read(), quirks={"shift": True}, seed=1)
+//! machine.press(1)
+//! machine.run_frames(60)
+//! pixels = machine.screen()
+//! ```
+//!
+//! Quirks use the chip-8-database names, see `Quirks::set`.
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use cpu::State;
+use runner::Runner;
+
+#[pyclass]
+pub struct Chip8 {
+    runner: Runner,
+}
+
+fn check_key(key: u8) -> PyResult<()> {
+    if key > 0xF {
+        return Err(PyValueError::new_err(format!("invalid key {}", key)));
+    }
+    Ok(())
+}
+
+#[pymethods]
+impl Chip8 {
+    /// Load a ROM with the quirks and speed the ROM database has for it,
+    /// overridden by `quirks`
+    #[new]
+    #[pyo3(signature = (rom, quirks = None, seed = 0))]
+    fn new(rom: &[u8], quirks: Option<&Bound<'_, PyDict>>, seed: u32) -> PyResult<Chip8> {
+        let mut runner = Runner::new(rom, seed).map_err(PyValueError::new_err)?;
+        if let Some(quirks) = quirks {
+            let mut settings = runner.cpu.quirks();
+            for (name, value) in quirks.iter() {
+                let name: &str = name.extract()?;
+                if !settings.set(name, value.extract()?) {
+                    return Err(PyValueError::new_err(format!("unknown quirk {}", name)));
+                }
+            }
+            runner.cpu.set_quirks(settings);
+        }
+        Ok(Chip8 { runner })
+    }
+
+    fn run_frames(&mut self, frames: u64) {
+        self.runner.run_frames(frames);
+    }
+
+    /// Number of frames run so far
+    #[getter]
+    fn frame(&self) -> u64 {
+        self.runner.frame()
+    }
+
+    /// Hold a key down until it is released
+    fn press(&mut self, key: u8) -> PyResult<()> {
+        check_key(key)?;
+        self.runner.cpu.keypad.key_down(key);
+        Ok(())
+    }
+
+    fn release(&mut self, key: u8) -> PyResult<()> {
+        check_key(key)?;
+        self.runner.cpu.keypad.key_up(key);
+        Ok(())
+    }
+
+    /// The 64x32 screen, one byte per pixel row by row, 1 for set pixels
+    fn screen<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.runner.cpu.screen.to_bytes())
+    }
+
+    /// `v` as a list, `i`, `pc`, `sp`, `delay`, `sound` and the `stack` of
+    /// return addresses
+    fn registers<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let state = self.runner.cpu.state();
+        let registers = PyDict::new_bound(py);
+        registers.set_item("v", state.v.to_vec())?;
+        registers.set_item("i", state.i)?;
+        registers.set_item("pc", state.pc)?;
+        registers.set_item("sp", state.sp)?;
+        registers.set_item("delay", state.delay)?;
+        registers.set_item("sound", state.sound)?;
+        registers.set_item("stack", state.stack[..state.sp as usize].to_vec())?;
+        Ok(registers)
+    }
+
+    /// All 4096 bytes of memory
+    fn memory<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.runner.cpu.read_memory(0, 0x1000))
+    }
+
+    /// A snapshot of the machine for `load_state`
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.runner.cpu.state().to_bytes())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        let state = State::from_bytes(state).map_err(PyValueError::new_err)?;
+        self.runner.cpu.set_state(&state).map_err(PyValueError::new_err)
+    }
+}
+
+#[pymodule]
+fn chip8(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Chip8>()?;
+    Ok(())
+}
@@ -0,0 +1,96 @@
+//! Behaviours that differ between CHIP-8 interpreters. Programs written for
+//! one interpreter often rely on its particular behaviour, so these can be
+//! switched per ROM. They are named after the quirks in the community
+//! [chip-8-database](https://github.com/chip-8/chip-8-database)
+//!
+//! By default the interpreter behaves like the original COSMAC VIP, except
+//! that sprites wrap around the edges of the screen, `8xy1`, `8xy2` and
+//! `8xy3` leave VF alone and `Fx0A` returns as soon as a key is pressed
+use json;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// `8xy6` and `8xyE` shift Vx in place, ignoring Vy
+    pub shift: bool,
+    /// `Fx55` and `Fx65` add x to I rather than x + 1
+    pub memory_increment_by_x: bool,
+    /// `Fx55` and `Fx65` leave I unchanged. Takes precedence over
+    /// `memory_increment_by_x`
+    pub memory_leave_i_unchanged: bool,
+    /// sprites wrap around the edges of the screen rather than being clipped
+    pub wrap: bool,
+    /// `Bnnn` is `Bxnn`, jumping to xnn + Vx instead of nnn + V0
+    pub jump: bool,
+    /// `8xy1`, `8xy2` and `8xy3` reset VF to 0
+    pub logic: bool,
+    /// `Fx0A` waits for a key to be pressed and released, as on the COSMAC
+    /// VIP, rather than just pressed
+    pub key_release: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::new()
+    }
+}
+
+impl Quirks {
+    /// The interpreter's default behaviour
+    pub fn new() -> Quirks {
+        Quirks {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: false,
+            wrap: true,
+            jump: false,
+            logic: false,
+            key_release: false,
+        }
+    }
+
+    /// Set a quirk by its name in the chip-8-database. Returns false for
+    /// unknown or unsupported quirks
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "shift" => self.shift = value,
+            "memoryIncrementByX" => self.memory_increment_by_x = value,
+            "memoryLeaveIUnchanged" => self.memory_leave_i_unchanged = value,
+            "wrap" => self.wrap = value,
+            "jump" => self.jump = value,
+            "logic" => self.logic = value,
+            _ => return false,
+        }
+        true
+    }
+
+    /// A copy with the quirks in a JSON object of names and true or false,
+    /// such as `{"shift": true}`, set. Fails without setting any if a name
+    /// is unknown or a value isn't true or false
+    pub fn with_json(&self, text: &str) -> Result<Quirks, String> {
+        let value = json::parse(text)?;
+        let members = value.as_object().ok_or("quirks must be an object")?;
+        let mut quirks = *self;
+        for (name, value) in members.iter() {
+            let value = value.as_bool().ok_or_else(|| format!("quirk {} must be true or false", name))?;
+            if !quirks.set(name, value) {
+                return Err(format!("unknown quirk {}", name));
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn with_json() {
+        let quirks = Quirks::new().with_json(r#"{"shift": true, "wrap": false}"#).unwrap();
+        assert!(quirks.shift && !quirks.wrap);
+        assert_eq!(quirks.with_json("{}"), Ok(quirks));
+        assert_eq!(Quirks::new().with_json(r#"{"shift": true, "vblank": true}"#), Err("unknown quirk vblank".to_string()));
+        assert_eq!(Quirks::new().with_json(r#"{"shift": 1}"#), Err("quirk shift must be true or false".to_string()));
+        assert!(Quirks::new().with_json("[]").is_err());
+    }
+}
@@ -0,0 +1,789 @@
+//! Ahead-of-time recompiler from a CHIP-8 ROM to a WebAssembly module
+//!
+//! Code reachable from the entry point is split into basic blocks, and each
+//! block becomes a wasm function that works directly on the machine state,
+//! which lives in the module's memory in the fixed `layout` below. The
+//! exported `run(budget)` function looks up the block containing the PC,
+//! runs blocks until the budget of instructions is used up, and returns how
+//! many instructions it executed. Blocks can be entered at any of their
+//! instructions, so running out of budget in the middle of a block doesn't
+//! stop the next call from picking up where this one left off.
+//!
+//! Anything the module can't handle is left to the interpreter: `run` stops
+//! when the PC isn't at the start of a known block (for example after a
+//! `Bnnn` jump), when a block's code was overwritten since the ROM was
+//! loaded, on `0nnn` and unknown instructions, on stack overflow and
+//! underflow, and on `Fx0A`, which works on the keypad's queue of presses
+//! and releases rather than the machine state. Instructions whose
+//! behaviour is changed by the ROM's quirks are left to the interpreter too.
+//! `run_cycles` ties the two together, so the result is identical to running
+//! the interpreter
+use analysis::successors;
+use cpu::{CPU, State};
+use instruction::{decode, Instruction};
+use quirks::Quirks;
+use wasm_encoder::{op, Function, Module, I32};
+
+/// Where each part of the machine state lives in the module's memory.
+/// Multi-byte values are little endian
+pub mod layout {
+    /// 4096 bytes of RAM
+    pub const MEMORY: u32 = 0x0000;
+    /// `V0` through `VF`
+    pub const V: u32 = 0x1000;
+    /// u16
+    pub const I: u32 = 0x1010;
+    /// u16
+    pub const PC: u32 = 0x1012;
+    pub const SP: u32 = 0x1014;
+    pub const DELAY: u32 = 0x1015;
+    pub const SOUND: u32 = 0x1016;
+    /// u32 state of the random number generator
+    pub const RNG: u32 = 0x1018;
+    /// 16 u16 return addresses
+    pub const STACK: u32 = 0x1020;
+    /// 16 bytes, 1 if the key is down
+    pub const KEYS: u32 = 0x1040;
+    /// 32 u64 rows, the leftmost pixel in the most significant bit
+    pub const SCREEN: u32 = 0x1100;
+    /// bytes taken up by the machine state
+    pub const STATE_SIZE: usize = 0x1200;
+    /// u16 per address, the table index of a block with an instruction
+    /// there or 0
+    pub const BLOCK_MAP: u32 = 0x2000;
+    /// u8 per address, which instruction of the block in `BLOCK_MAP` it is
+    pub const BLOCK_ENTRIES: u32 = 0x4000;
+}
+
+use self::layout::*;
+
+/// Programs are loaded at this address and start running from it
+const START: u16 = 0x200;
+
+/// Blocks are split after this many instructions to keep functions small
+pub const MAX_BLOCK: usize = 64;
+
+/// A straight line run of instructions, entered only at the top
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub instructions: Vec<Instruction>,
+}
+
+impl Block {
+    /// Address of the first byte after the block
+    pub fn end(&self) -> u16 {
+        self.start + 2 * self.instructions.len() as u16
+    }
+}
+
+/// A recompiled ROM
+pub struct Program {
+    pub blocks: Vec<Block>,
+    /// the quirks the ROM was compiled for, which the interpreter running
+    /// the rest of the ROM must use as well
+    pub quirks: Quirks,
+    /// the encoded wasm module
+    pub wasm: Vec<u8>,
+}
+
+/// Whether the recompiler translates the instruction, rather than leaving
+/// it to the interpreter. Only the default behaviour of instructions
+/// affected by quirks is compiled
+fn compiled(instruction: Instruction, quirks: &Quirks) -> bool {
+    match instruction {
+        Instruction::Sys(_) | Instruction::Unknown(_) | Instruction::WaitKey(_) => false,
+        Instruction::ShiftRight(_, _) | Instruction::ShiftLeft(_, _) => !quirks.shift,
+        Instruction::StoreRegs(_) | Instruction::LoadRegs(_) =>
+            !quirks.memory_increment_by_x && !quirks.memory_leave_i_unchanged,
+        Instruction::Draw(_, _, _) => quirks.wrap,
+        Instruction::JumpOffset(_) => !quirks.jump,
+        Instruction::Or(_, _) | Instruction::And(_, _) | Instruction::Xor(_, _) => !quirks.logic,
+        _ => true,
+    }
+}
+
+/// Whether the instruction sets the PC to something other than the next
+/// instruction
+fn branches(instruction: Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Jump(_) | Instruction::Call(_) | Instruction::Return |
+        Instruction::JumpOffset(_) | Instruction::SkipEqual(_, _) |
+        Instruction::SkipNotEqual(_, _) | Instruction::SkipEqualReg(_, _) |
+        Instruction::SkipNotEqualReg(_, _) | Instruction::SkipKeyDown(_) |
+        Instruction::SkipKeyUp(_))
+}
+
+/// Whether the instruction has to be the last one in its block. Besides
+/// branches, this includes stores to memory, which may overwrite code later
+/// in the same block
+fn ends_block(instruction: Instruction) -> bool {
+    match instruction {
+        Instruction::StoreBcd(_) | Instruction::StoreRegs(_) => true,
+        _ => branches(instruction),
+    }
+}
+
+/// Split the code reachable from the entry point of `memory` into basic
+/// blocks, in the order they were found
+pub fn find_blocks(memory: &[u8], quirks: &Quirks) -> Vec<Block> {
+    let mut seen = vec![false; memory.len()];
+    let mut blocks = Vec::new();
+    let mut pending = vec![START];
+    while let Some(start) = pending.pop() {
+        if seen[start as usize] {
+            continue;
+        }
+        seen[start as usize] = true;
+
+        let mut block = Block { start, instructions: Vec::new() };
+        let mut pc = start as usize;
+        let mut interpreted = None;
+        // instructions that would wrap around the end of memory are left
+        // to the interpreter
+        while pc + 1 < memory.len() && block.instructions.len() < MAX_BLOCK {
+            let instruction = decode((memory[pc] as u16) << 8 | memory[pc + 1] as u16);
+            if !compiled(instruction, quirks) {
+                if !matches!(instruction, Instruction::Sys(_) | Instruction::Unknown(_)) {
+                    interpreted = Some(instruction);
+                }
+                break;
+            }
+            block.instructions.push(instruction);
+            pc += 2;
+            if ends_block(instruction) {
+                break;
+            }
+        }
+        if block.instructions.is_empty() {
+            // the interpreter runs `Fx0A` or an instruction affected by
+            // quirks and then carries on into compiled code
+            if let Some(instruction) = interpreted {
+                for &addr in successors(start, instruction).iter().rev() {
+                    pending.push(addr);
+                }
+            }
+            continue;
+        }
+        // push in reverse so blocks come out roughly in address order
+        let last = *block.instructions.last().unwrap();
+        for &addr in successors(block.end() - 2, last).iter().rev() {
+            pending.push(addr);
+        }
+        blocks.push(block);
+    }
+    blocks
+}
+
+/// Recompile a ROM, loaded at the usual address on a freshly reset machine,
+/// for the quirks the ROM database recommends for it. Fails if the ROM
+/// doesn't fit in memory
+pub fn compile(rom: &[u8]) -> Result<Program, String> {
+    let mut cpu = CPU::new();
+    cpu.reset();
+    cpu.load_rom(rom)?;
+    let memory = cpu.state().memory;
+    let quirks = cpu.quirks();
+    let blocks = find_blocks(&memory, &quirks);
+
+    let mut module = Module::new(1);
+    let draw_type = module.add_type(&[I32, I32, I32], &[I32]);
+    let block_type = module.add_type(&[I32, I32], &[I32]);
+    let run_type = module.add_type(&[I32], &[I32]);
+
+    let helpers = Helpers {
+        draw: module.add_function(draw_type, draw()),
+    };
+
+    // an address is entered through the block that starts there, or else
+    // the first block found that runs through it
+    let mut entries: Vec<Option<(u32, usize)>> = vec![None; memory.len()];
+    for block in blocks.iter() {
+        let function = module.add_function(block_type, translate(block, &memory, &helpers));
+        let index = module.add_to_table(function);
+        entries[block.start as usize] = Some((index, 0));
+        for position in 1..block.instructions.len() {
+            let addr = block.start as usize + 2 * position;
+            if entries[addr].is_none() {
+                entries[addr] = Some((index, position));
+            }
+        }
+    }
+    let mut block_map = vec![0; 2 * memory.len()];
+    let mut block_entries = vec![0; memory.len()];
+    for (addr, entry) in entries.iter().enumerate() {
+        if let Some((index, position)) = *entry {
+            block_map[2 * addr] = index as u8;
+            block_map[2 * addr + 1] = (index >> 8) as u8;
+            block_entries[addr] = position as u8;
+        }
+    }
+    module.add_data(BLOCK_MAP, block_map);
+    module.add_data(BLOCK_ENTRIES, block_entries);
+
+    let run = module.add_function(run_type, dispatcher(block_type));
+    module.export("run", run);
+
+    Ok(Program { blocks, quirks, wasm: module.encode() })
+}
+
+/// Indices of the helper functions shared by all blocks
+struct Helpers {
+    draw: u32,
+}
+
+/// `run(budget: i32) -> i32`
+fn dispatcher(block_type: u32) -> Function {
+    const BUDGET: u32 = 0;
+    const DONE: u32 = 1;
+    const INDEX: u32 = 2;
+    const COUNT: u32 = 3;
+
+    let mut f = Function::new(3, 0);
+    f.structured(op::BLOCK).structured(op::LOOP);
+    f.op_index(op::LOCAL_GET, DONE).op_index(op::LOCAL_GET, BUDGET).op(op::I32_GE_U)
+        .op_index(op::BR_IF, 1);
+    // find the block at the PC, stopping if there isn't one
+    f.i32_const(0).memory(op::I32_LOAD16_U, PC).i32_const(2).op(op::I32_MUL)
+        .memory(op::I32_LOAD16_U, BLOCK_MAP)
+        .op_index(op::LOCAL_TEE, INDEX).op(op::I32_EQZ).op_index(op::BR_IF, 1);
+    // run it from the PC with the remaining budget, stopping if it bailed
+    // out before running anything
+    f.op_index(op::LOCAL_GET, BUDGET).op_index(op::LOCAL_GET, DONE).op(op::I32_SUB)
+        .i32_const(0).memory(op::I32_LOAD16_U, PC).memory(op::I32_LOAD8_U, BLOCK_ENTRIES)
+        .op_index(op::LOCAL_GET, INDEX).call_indirect(block_type)
+        .op_index(op::LOCAL_TEE, COUNT).op(op::I32_EQZ).op_index(op::BR_IF, 1);
+    f.op_index(op::LOCAL_GET, DONE).op_index(op::LOCAL_GET, COUNT).op(op::I32_ADD)
+        .op_index(op::LOCAL_SET, DONE);
+    f.op_index(op::BR, 0).op(op::END).op(op::END);
+    f.op_index(op::LOCAL_GET, DONE);
+    f
+}
+
+/// `draw(x: i32, y: i32, n: i32) -> i32`, drawing `n` rows of the sprite at
+/// I like `Screen::draw_sprite` and returning whether any pixel was erased.
+/// (x, y) must be on the screen
+fn draw() -> Function {
+    const X: u32 = 0;
+    const Y: u32 = 1;
+    const N: u32 = 2;
+    const ROW: u32 = 3;
+    const COLLISION: u32 = 4;
+    const OFFSET: u32 = 5;
+    const MASK: u32 = 6;
+
+    let mut f = Function::new(3, 1);
+    f.structured(op::BLOCK).structured(op::LOOP);
+    f.op_index(op::LOCAL_GET, ROW).op_index(op::LOCAL_GET, N).op(op::I32_GE_U)
+        .op_index(op::BR_IF, 1);
+    // sprite byte at I + row
+    f.i32_const(0).memory(op::I32_LOAD16_U, I).op_index(op::LOCAL_GET, ROW).op(op::I32_ADD)
+        .i32_const(0xFFF).op(op::I32_AND).memory(op::I32_LOAD8_U, MEMORY)
+        .op(op::I64_EXTEND_I32_U).op_index(op::LOCAL_SET, MASK);
+    // shifted into place, wrapping around the right edge
+    f.op_index(op::LOCAL_GET, X).i32_const(56).op(op::I32_GT_U).structured(op::IF)
+        .op_index(op::LOCAL_GET, MASK).op_index(op::LOCAL_GET, X).i32_const(56).op(op::I32_SUB).op(op::I64_EXTEND_I32_U)
+        .op(op::I64_ROTR).op_index(op::LOCAL_SET, MASK)
+        .op(op::ELSE)
+        .op_index(op::LOCAL_GET, MASK).i32_const(56).op_index(op::LOCAL_GET, X).op(op::I32_SUB).op(op::I64_EXTEND_I32_U)
+        .op(op::I64_SHL).op_index(op::LOCAL_SET, MASK)
+        .op(op::END);
+    // offset of the screen row, wrapping around the bottom edge
+    f.op_index(op::LOCAL_GET, Y).op_index(op::LOCAL_GET, ROW).op(op::I32_ADD)
+        .i32_const(31).op(op::I32_AND).i32_const(8).op(op::I32_MUL)
+        .op_index(op::LOCAL_SET, OFFSET);
+    f.op_index(op::LOCAL_GET, COLLISION)
+        .op_index(op::LOCAL_GET, OFFSET).memory(op::I64_LOAD, SCREEN)
+        .op_index(op::LOCAL_GET, MASK).op(op::I64_AND).i64_const(0).op(op::I64_NE)
+        .op(op::I32_OR).op_index(op::LOCAL_SET, COLLISION);
+    f.op_index(op::LOCAL_GET, OFFSET)
+        .op_index(op::LOCAL_GET, OFFSET).memory(op::I64_LOAD, SCREEN)
+        .op_index(op::LOCAL_GET, MASK).op(op::I64_XOR)
+        .memory(op::I64_STORE, SCREEN);
+    f.op_index(op::LOCAL_GET, ROW).i32_const(1).op(op::I32_ADD).op_index(op::LOCAL_SET, ROW);
+    f.op_index(op::BR, 0).op(op::END).op(op::END);
+    f.op_index(op::LOCAL_GET, COLLISION);
+    f
+}
+
+/// Locals of block functions. The parameters are the budget and the
+/// position of the first instruction to run; the budget is turned into the
+/// position of the first instruction not to run on entry
+const LIMIT: u32 = 0;
+const ENTRY: u32 = 1;
+/// scratch values
+const A: u32 = 2;
+const B: u32 = 3;
+
+/// Push Vx
+fn get_v(f: &mut Function, x: u8) {
+    f.i32_const(0).memory(op::I32_LOAD8_U, V + x as u32);
+}
+
+/// Set Vx to the value pushed by `value`
+fn set_v<F: FnOnce(&mut Function)>(f: &mut Function, x: u8, value: F) {
+    f.i32_const(0);
+    value(f);
+    f.memory(op::I32_STORE8, V + x as u32);
+}
+
+/// Set the PC to the value pushed by `value`
+fn set_pc<F: FnOnce(&mut Function)>(f: &mut Function, value: F) {
+    f.i32_const(0);
+    value(f);
+    f.memory(op::I32_STORE16, PC);
+}
+
+/// Push I + `offset`, wrapped to an address
+fn get_i(f: &mut Function, offset: i32) {
+    f.i32_const(0).memory(op::I32_LOAD16_U, I).i32_const(offset).op(op::I32_ADD)
+        .i32_const(0xFFF).op(op::I32_AND);
+}
+
+/// Leave the block with the PC at `addr`, the instruction at `position`,
+/// returning how many instructions ran before it
+fn bail(f: &mut Function, addr: u16, position: usize) {
+    set_pc(f, |f| { f.i32_const(addr as i32); });
+    ran_before(f, position);
+    f.op(op::RETURN);
+}
+
+/// Push the number of instructions run before the one at `position`
+fn ran_before(f: &mut Function, position: usize) {
+    f.i32_const(position as i32).op_index(op::LOCAL_GET, ENTRY).op(op::I32_SUB);
+}
+
+/// Return early from the block if the code it was compiled from has been
+/// overwritten
+fn guard(f: &mut Function, block: &Block, memory: &[u8]) {
+    let code = &memory[block.start as usize..block.end() as usize];
+    let mut offset = 0;
+    while offset < code.len() {
+        let addr = block.start as u32 + offset as u32;
+        if code.len() - offset >= 4 {
+            let word = code[offset] as u32 | (code[offset + 1] as u32) << 8 |
+                (code[offset + 2] as u32) << 16 | (code[offset + 3] as u32) << 24;
+            f.i32_const(0).memory(op::I32_LOAD, MEMORY + addr).i32_const(word as i32);
+            offset += 4;
+        } else {
+            let half = code[offset] as u32 | (code[offset + 1] as u32) << 8;
+            f.i32_const(0).memory(op::I32_LOAD16_U, MEMORY + addr).i32_const(half as i32);
+            offset += 2;
+        }
+        f.op(op::I32_NE).structured(op::IF).i32_const(0).op(op::RETURN).op(op::END);
+    }
+}
+
+/// Build the function for a block, `(budget: i32, entry: i32) -> i32`
+/// running at most `budget` of its instructions starting from the one at
+/// position `entry`, and returning how many ran
+fn translate(block: &Block, memory: &[u8], helpers: &Helpers) -> Function {
+    let len = block.instructions.len();
+    let mut f = Function::new(2, 0);
+    guard(&mut f, block, memory);
+    f.op_index(op::LOCAL_GET, LIMIT).op_index(op::LOCAL_GET, ENTRY).op(op::I32_ADD)
+        .op_index(op::LOCAL_SET, LIMIT);
+
+    // jump to the entry instruction: branching out of the nth nested block
+    // lands just before the code for instruction n
+    for _ in 0..len {
+        f.structured(op::BLOCK);
+    }
+    let labels: Vec<u32> = (0..len as u32).collect();
+    f.op_index(op::LOCAL_GET, ENTRY).br_table(&labels, len as u32 - 1);
+    for (position, &instruction) in block.instructions.iter().enumerate() {
+        f.op(op::END);
+        let addr = block.start + 2 * position as u16;
+        // out of budget, continue from here on the next call
+        f.op_index(op::LOCAL_GET, LIMIT).i32_const(position as i32).op(op::I32_EQ)
+            .structured(op::IF);
+        bail(&mut f, addr, position);
+        f.op(op::END);
+        instruction_body(&mut f, instruction, addr, position, helpers);
+    }
+
+    let last = block.instructions[len - 1];
+    if !branches(last) {
+        set_pc(&mut f, |f| { f.i32_const((block.end() & 0xFFF) as i32); });
+    }
+    ran_before(&mut f, len);
+    f
+}
+
+/// Set the PC to skip the next instruction if `condition` pushes non-zero
+fn skip_if<F: FnOnce(&mut Function)>(f: &mut Function, addr: u16, condition: F) {
+    set_pc(f, |f| {
+        f.i32_const(((addr + 4) & 0xFFF) as i32).i32_const(((addr + 2) & 0xFFF) as i32);
+        condition(f);
+        f.op(op::SELECT);
+    });
+}
+
+/// Emit the code for one instruction at `addr`, at `position` in its block
+fn instruction_body(f: &mut Function, instruction: Instruction, addr: u16, position: usize, helpers: &Helpers) {
+    let next = ((addr + 2) & 0xFFF) as i32;
+    match instruction {
+        Instruction::Clear => {
+            for row in 0..32 {
+                f.i32_const(0).i64_const(0).memory(op::I64_STORE, SCREEN + 8 * row);
+            }
+        },
+        Instruction::Return => {
+            f.i32_const(0).memory(op::I32_LOAD8_U, SP).op(op::I32_EQZ).structured(op::IF);
+            bail(f, addr, position);
+            f.op(op::END);
+            f.i32_const(0).i32_const(0).memory(op::I32_LOAD8_U, SP).i32_const(1).op(op::I32_SUB)
+                .op_index(op::LOCAL_TEE, A).memory(op::I32_STORE8, SP);
+            set_pc(f, |f| {
+                f.op_index(op::LOCAL_GET, A).i32_const(2).op(op::I32_MUL)
+                    .memory(op::I32_LOAD16_U, STACK);
+            });
+        },
+        Instruction::Jump(nnn) => set_pc(f, |f| { f.i32_const(nnn as i32); }),
+        Instruction::Call(nnn) => {
+            f.i32_const(0).memory(op::I32_LOAD8_U, SP).op_index(op::LOCAL_TEE, A)
+                .i32_const(16).op(op::I32_EQ).structured(op::IF);
+            bail(f, addr, position);
+            f.op(op::END);
+            f.op_index(op::LOCAL_GET, A).i32_const(2).op(op::I32_MUL).i32_const(next)
+                .memory(op::I32_STORE16, STACK);
+            f.i32_const(0).op_index(op::LOCAL_GET, A).i32_const(1).op(op::I32_ADD)
+                .memory(op::I32_STORE8, SP);
+            set_pc(f, |f| { f.i32_const(nnn as i32); });
+        },
+        Instruction::SkipEqual(x, kk) => skip_if(f, addr, |f| {
+            get_v(f, x);
+            f.i32_const(kk as i32).op(op::I32_EQ);
+        }),
+        Instruction::SkipNotEqual(x, kk) => skip_if(f, addr, |f| {
+            get_v(f, x);
+            f.i32_const(kk as i32).op(op::I32_NE);
+        }),
+        Instruction::SkipEqualReg(x, y) => skip_if(f, addr, |f| {
+            get_v(f, x);
+            get_v(f, y);
+            f.op(op::I32_EQ);
+        }),
+        Instruction::SkipNotEqualReg(x, y) => skip_if(f, addr, |f| {
+            get_v(f, x);
+            get_v(f, y);
+            f.op(op::I32_NE);
+        }),
+        Instruction::Load(x, kk) => set_v(f, x, |f| { f.i32_const(kk as i32); }),
+        Instruction::Add(x, kk) => set_v(f, x, |f| {
+            get_v(f, x);
+            f.i32_const(kk as i32).op(op::I32_ADD);
+        }),
+        Instruction::Move(x, y) => set_v(f, x, |f| get_v(f, y)),
+        Instruction::Or(x, y) | Instruction::And(x, y) | Instruction::Xor(x, y) => {
+            let opcode = match instruction {
+                Instruction::Or(_, _) => op::I32_OR,
+                Instruction::And(_, _) => op::I32_AND,
+                _ => op::I32_XOR,
+            };
+            set_v(f, x, |f| {
+                get_v(f, x);
+                get_v(f, y);
+                f.op(opcode);
+            });
+        },
+        // the remaining ALU instructions read both operands first, since x
+        // or y may be VF
+        Instruction::AddReg(x, y) => {
+            get_v(f, x);
+            get_v(f, y);
+            f.op(op::I32_ADD).op_index(op::LOCAL_SET, A);
+            set_v(f, x, |f| { f.op_index(op::LOCAL_GET, A); });
+            set_v(f, 0xF, |f| { f.op_index(op::LOCAL_GET, A).i32_const(8).op(op::I32_SHR_U); });
+        },
+        Instruction::Sub(x, y) | Instruction::SubReverse(x, y) => {
+            let (from, to) = match instruction {
+                Instruction::Sub(_, _) => (A, B),
+                _ => (B, A),
+            };
+            get_v(f, x);
+            f.op_index(op::LOCAL_SET, A);
+            get_v(f, y);
+            f.op_index(op::LOCAL_SET, B);
+            set_v(f, x, |f| {
+                f.op_index(op::LOCAL_GET, from).op_index(op::LOCAL_GET, to).op(op::I32_SUB);
+            });
+            set_v(f, 0xF, |f| {
+                f.op_index(op::LOCAL_GET, from).op_index(op::LOCAL_GET, to).op(op::I32_GE_U);
+            });
+        },
+        Instruction::ShiftRight(x, y) => {
+            get_v(f, y);
+            f.op_index(op::LOCAL_SET, A);
+            set_v(f, x, |f| { f.op_index(op::LOCAL_GET, A).i32_const(1).op(op::I32_SHR_U); });
+            set_v(f, 0xF, |f| { f.op_index(op::LOCAL_GET, A).i32_const(1).op(op::I32_AND); });
+        },
+        Instruction::ShiftLeft(x, y) => {
+            get_v(f, y);
+            f.op_index(op::LOCAL_SET, A);
+            set_v(f, x, |f| { f.op_index(op::LOCAL_GET, A).i32_const(1).op(op::I32_SHL); });
+            set_v(f, 0xF, |f| { f.op_index(op::LOCAL_GET, A).i32_const(7).op(op::I32_SHR_U); });
+        },
+        Instruction::LoadI(nnn) => {
+            f.i32_const(0).i32_const(nnn as i32).memory(op::I32_STORE16, I);
+        },
+        Instruction::JumpOffset(nnn) => set_pc(f, |f| {
+            get_v(f, 0);
+            f.i32_const(nnn as i32).op(op::I32_ADD).i32_const(0xFFF).op(op::I32_AND);
+        }),
+        Instruction::Random(x, kk) => {
+            // the same xorshift step as `Rng::next_u8`
+            f.i32_const(0).memory(op::I32_LOAD, RNG).op_index(op::LOCAL_SET, A);
+            for &(shift, opcode) in [(13, op::I32_SHL), (17, op::I32_SHR_U), (5, op::I32_SHL)].iter() {
+                f.op_index(op::LOCAL_GET, A).op_index(op::LOCAL_GET, A).i32_const(shift)
+                    .op(opcode).op(op::I32_XOR).op_index(op::LOCAL_SET, A);
+            }
+            f.i32_const(0).op_index(op::LOCAL_GET, A).memory(op::I32_STORE, RNG);
+            set_v(f, x, |f| {
+                f.op_index(op::LOCAL_GET, A).i32_const(24).op(op::I32_SHR_U)
+                    .i32_const(kk as i32).op(op::I32_AND);
+            });
+        },
+        // sprites start at Vx and Vy wrapped onto the screen
+        Instruction::Draw(x, y, n) => set_v(f, 0xF, |f| {
+            get_v(f, x);
+            f.i32_const(63).op(op::I32_AND);
+            get_v(f, y);
+            f.i32_const(31).op(op::I32_AND);
+            f.i32_const(n as i32).op_index(op::CALL, helpers.draw);
+        }),
+        Instruction::SkipKeyDown(x) | Instruction::SkipKeyUp(x) => skip_if(f, addr, |f| {
+            get_v(f, x);
+            f.i32_const(0xF).op(op::I32_AND).memory(op::I32_LOAD8_U, KEYS);
+            if let Instruction::SkipKeyUp(_) = instruction {
+                f.op(op::I32_EQZ);
+            }
+        }),
+        Instruction::LoadDelay(x) => set_v(f, x, |f| {
+            f.i32_const(0).memory(op::I32_LOAD8_U, DELAY);
+        }),
+        Instruction::SetDelay(x) => {
+            f.i32_const(0);
+            get_v(f, x);
+            f.memory(op::I32_STORE8, DELAY);
+        },
+        Instruction::SetSound(x) => {
+            f.i32_const(0);
+            get_v(f, x);
+            f.memory(op::I32_STORE8, SOUND);
+        },
+        Instruction::AddI(x) => {
+            f.i32_const(0);
+            f.i32_const(0).memory(op::I32_LOAD16_U, I);
+            get_v(f, x);
+            f.op(op::I32_ADD).i32_const(0xFFF).op(op::I32_AND).memory(op::I32_STORE16, I);
+        },
+        Instruction::LoadFont(x) => {
+            f.i32_const(0);
+            get_v(f, x);
+            f.i32_const(0xF).op(op::I32_AND).i32_const(5).op(op::I32_MUL)
+                .memory(op::I32_STORE16, I);
+        },
+        Instruction::StoreBcd(x) => {
+            get_v(f, x);
+            f.op_index(op::LOCAL_SET, A);
+            get_i(f, 0);
+            f.op_index(op::LOCAL_GET, A).i32_const(100).op(op::I32_DIV_U)
+                .memory(op::I32_STORE8, MEMORY);
+            get_i(f, 1);
+            f.op_index(op::LOCAL_GET, A).i32_const(10).op(op::I32_DIV_U)
+                .i32_const(10).op(op::I32_REM_U).memory(op::I32_STORE8, MEMORY);
+            get_i(f, 2);
+            f.op_index(op::LOCAL_GET, A).i32_const(10).op(op::I32_REM_U)
+                .memory(op::I32_STORE8, MEMORY);
+        },
+        Instruction::StoreRegs(x) | Instruction::LoadRegs(x) => {
+            for register in 0..=x {
+                if let Instruction::StoreRegs(_) = instruction {
+                    get_i(f, register as i32);
+                    get_v(f, register);
+                    f.memory(op::I32_STORE8, MEMORY);
+                } else {
+                    set_v(f, register, |f| {
+                        get_i(f, register as i32);
+                        f.memory(op::I32_LOAD8_U, MEMORY);
+                    });
+                }
+            }
+            f.i32_const(0);
+            get_i(f, x as i32 + 1);
+            f.memory(op::I32_STORE16, I);
+        },
+        Instruction::Sys(_) | Instruction::Unknown(_) | Instruction::WaitKey(_) => unreachable!(),
+    }
+}
+
+/// Runs a compiled module, for example through a wasm engine
+pub trait Engine {
+    /// Copy `bytes` into the module's memory at `offset`
+    fn write(&mut self, offset: usize, bytes: &[u8]);
+    /// Copy `len` bytes out of the module's memory at `offset`
+    fn read(&mut self, offset: usize, len: usize) -> Vec<u8>;
+    /// Call the module's `run` export with a budget of instructions and
+    /// return how many it executed
+    fn run(&mut self, budget: u32) -> u32;
+}
+
+/// Copy a snapshot into the state region of a module's memory
+pub fn write_state(state: &State, memory: &mut [u8]) {
+    memory[MEMORY as usize..MEMORY as usize + state.memory.len()].copy_from_slice(&state.memory);
+    memory[V as usize..V as usize + 16].copy_from_slice(&state.v);
+    write_u16(memory, I, state.i);
+    write_u16(memory, PC, state.pc);
+    memory[SP as usize] = state.sp;
+    memory[DELAY as usize] = state.delay;
+    memory[SOUND as usize] = state.sound;
+    for byte in 0..4 {
+        memory[RNG as usize + byte] = (state.rng >> (8 * byte)) as u8;
+    }
+    for (slot, &addr) in state.stack.iter().enumerate() {
+        write_u16(memory, STACK + 2 * slot as u32, addr);
+    }
+    for (key, &down) in state.keys.iter().enumerate() {
+        memory[KEYS as usize + key] = down as u8;
+    }
+    for (row, &pixels) in state.screen.iter().enumerate() {
+        for byte in 0..8 {
+            memory[SCREEN as usize + 8 * row + byte] = (pixels >> (8 * byte)) as u8;
+        }
+    }
+}
+
+/// Read a snapshot back out of a module's memory
+pub fn read_state(memory: &[u8]) -> State {
+    let mut state = State {
+        memory: memory[MEMORY as usize..MEMORY as usize + 4096].to_vec(),
+        v: [0; 16],
+        i: read_u16(memory, I),
+        delay: memory[DELAY as usize],
+        sound: memory[SOUND as usize],
+        pc: read_u16(memory, PC),
+        sp: memory[SP as usize],
+        stack: [0; 16],
+        screen: [0; 32],
+        keys: [false; 16],
+        rng: 0,
+    };
+    state.v.copy_from_slice(&memory[V as usize..V as usize + 16]);
+    for byte in 0..4 {
+        state.rng |= (memory[RNG as usize + byte] as u32) << (8 * byte);
+    }
+    for (slot, addr) in state.stack.iter_mut().enumerate() {
+        *addr = read_u16(memory, STACK + 2 * slot as u32);
+    }
+    for (key, down) in state.keys.iter_mut().enumerate() {
+        *down = memory[KEYS as usize + key] != 0;
+    }
+    for (row, pixels) in state.screen.iter_mut().enumerate() {
+        for byte in 0..8 {
+            *pixels |= (memory[SCREEN as usize + 8 * row + byte] as u64) << (8 * byte);
+        }
+    }
+    state
+}
+
+fn write_u16(memory: &mut [u8], offset: u32, value: u16) {
+    memory[offset as usize] = value as u8;
+    memory[offset as usize + 1] = (value >> 8) as u8;
+}
+
+fn read_u16(memory: &[u8], offset: u32) -> u16 {
+    memory[offset as usize] as u16 | (memory[offset as usize + 1] as u16) << 8
+}
+
+/// Run `cycles` instructions of `cpu` through a compiled module, handing
+/// over to the interpreter for any instruction the module stops at. The
+/// CPU ends up in the same state as after calling `cycle` `cycles` times
+pub fn run_cycles<E: Engine>(cpu: &mut CPU, engine: &mut E, cycles: usize) {
+    let mut memory = vec![0; STATE_SIZE];
+    write_state(&cpu.state(), &mut memory);
+    engine.write(0, &memory);
+    let mut remaining = cycles;
+    loop {
+        remaining -= engine.run(remaining as u32) as usize;
+        if remaining == 0 {
+            break;
+        }
+        cpu.sync_state(&read_state(&engine.read(0, STATE_SIZE))).unwrap();
+        cpu.cycle();
+        remaining -= 1;
+        write_state(&cpu.state(), &mut memory);
+        engine.write(0, &memory);
+    }
+    cpu.sync_state(&read_state(&engine.read(0, STATE_SIZE))).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn blocks(program: &[u8]) -> Vec<(u16, usize)> {
+        blocks_with_quirks(program, &Quirks::new())
+    }
+
+    fn blocks_with_quirks(program: &[u8], quirks: &Quirks) -> Vec<(u16, usize)> {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(program).unwrap();
+        find_blocks(&cpu.state().memory, quirks).iter()
+            .map(|block| (block.start, block.instructions.len()))
+            .collect()
+    }
+
+    #[test]
+    fn leaves_quirks_to_interpreter() {
+        // 200: V0 = 1
+        // 202: V0 >>= V0
+        // 204: V1 = 2
+        // 206: jump 0x206
+        let program = [0x60, 0x01, 0x80, 0x06, 0x61, 0x02, 0x12, 0x06];
+        assert_eq!(blocks(&program), vec![(0x200, 4), (0x206, 1)]);
+        let mut quirks = Quirks::new();
+        quirks.shift = true;
+        assert_eq!(blocks_with_quirks(&program, &quirks), vec![(0x200, 1), (0x204, 2), (0x206, 1)]);
+    }
+
+    #[test]
+    fn splits_at_branches() {
+        // 200: V0 = 1
+        // 202: skip if V0 == 1
+        // 204: jump 0x200
+        // 206: call 0x20A
+        // 208: jump 0x208
+        // 20A: return
+        let program = [
+            0x60, 0x01, 0x30, 0x01, 0x12, 0x00, 0x22, 0x0A,
+            0x12, 0x08, 0x00, 0xEE,
+        ];
+        assert_eq!(blocks(&program), vec![(0x200, 2), (0x204, 1), (0x206, 1), (0x20A, 1), (0x208, 1)]);
+    }
+
+    #[test]
+    fn stops_at_unsupported() {
+        // 200: V0 = 1, 202: 0nnn, 204: V0 = 2
+        assert_eq!(blocks(&[0x60, 0x01, 0x01, 0x23, 0x60, 0x02]), vec![(0x200, 1)]);
+        // computed jumps end discovery
+        assert_eq!(blocks(&[0xB2, 0x00, 0x60, 0x02]), vec![(0x200, 1)]);
+    }
+
+    #[test]
+    fn state_layout_round_trip() {
+        let mut cpu = CPU::new();
+        cpu.seed(99);
+        cpu.reset();
+        cpu.load_rom(&[0x22, 0x04, 0xC0, 0xFF, 0xD0, 0x15]).unwrap();
+        for _ in 0..3 {
+            cpu.cycle();
+        }
+        cpu.keypad.key_down(0xA);
+        let state = cpu.state();
+        let mut memory = vec![0; STATE_SIZE];
+        write_state(&state, &mut memory);
+        assert_eq!(read_state(&memory), state);
+    }
+}
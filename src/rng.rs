@@ -0,0 +1,52 @@
+/// A small xorshift pseudo random number generator used by the `Cxkk`
+/// instruction. Unlike `Math.random` it can be seeded, which makes runs
+/// reproducible and lets the interpreter run outside of the browser
+#[derive(Clone)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    /// Create a new generator from the given seed. xorshift gets stuck on a
+    /// state of 0, so a zero seed is replaced with a fixed non-zero one
+    pub fn new(seed: u32) -> Rng {
+        Rng { state: if seed == 0 { 0x2545_F491 } else { seed } }
+    }
+
+    /// The current state, which `Rng::new` accepts as a seed to continue the
+    /// same sequence
+    pub fn state(&self) -> u32 {
+        self.state
+    }
+
+    /// Generate the next random byte
+    pub fn next_u8(&mut self) -> u8 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        (x >> 24) as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::new(1234);
+        let mut b = Rng::new(1234);
+        for _ in 0..100 {
+            assert_eq!(a.next_u8(), b.next_u8());
+        }
+    }
+
+    #[test]
+    fn zero_seed() {
+        let mut rng = Rng::new(0);
+        let values: Vec<u8> = (0..16).map(|_| rng.next_u8()).collect();
+        assert!(values.iter().any(|&v| v != 0));
+    }
+}
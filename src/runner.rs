@@ -0,0 +1,104 @@
+//! A headless driver for the interpreter that runs a ROM frame by frame
+//! without a browser, for use from tests and the native `chip8` binary.
+
+use cpu::CPU;
+use input::InputScript;
+
+/// Number of instructions executed per 60 Hz frame, unless the ROM database
+/// recommends a different speed for the ROM
+pub const CYCLES_PER_FRAME: usize = 10;
+
+pub struct Runner {
+    pub cpu: CPU,
+    /// scripted key presses applied at the start of each frame
+    input: InputScript,
+    /// index of the next input event that has not been applied yet
+    next_event: usize,
+    /// number of frames run since the ROM was loaded
+    frame: u64,
+}
+
+impl Runner {
+    /// Create a runner with the given ROM loaded into a freshly reset CPU
+    /// whose random number generator is seeded with `seed`. Fails if the
+    /// ROM can't be loaded
+    pub fn new(rom: &[u8], seed: u32) -> Result<Runner, String> {
+        let mut cpu = CPU::new();
+        cpu.seed(seed);
+        cpu.reset();
+        cpu.load_rom(rom)?;
+        Ok(Runner { cpu, input: InputScript::new(), next_event: 0, frame: 0 })
+    }
+
+    /// Replace the input script. Events for frames that have already run
+    /// are skipped
+    pub fn set_input(&mut self, input: InputScript) {
+        self.next_event = input.events().iter()
+            .position(|e| e.frame >= self.frame)
+            .unwrap_or(input.events().len());
+        self.input = input;
+    }
+
+    /// Number of frames run so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Run a single 60 Hz frame: apply this frame's input events, execute
+    /// the CPU's `cycles_per_frame` instructions and then decrement the
+    /// timers
+    pub fn run_frame(&mut self) {
+        self.run_frame_with(CPU::run_frame)
+    }
+
+    /// Run a frame like `run_frame`, but with `run` in place of
+    /// `CPU::run_frame`, such as a script's `Script::run_frame`
+    pub fn run_frame_with<F: FnOnce(&mut CPU) -> R, R>(&mut self, run: F) -> R {
+        while let Some(event) = self.input.events().get(self.next_event) {
+            if event.frame > self.frame {
+                break;
+            }
+            if event.pressed {
+                self.cpu.keypad.key_down(event.key);
+            } else {
+                self.cpu.keypad.key_up(event.key);
+            }
+            self.next_event += 1;
+        }
+
+        let result = run(&mut self.cpu);
+        self.frame += 1;
+        result
+    }
+
+    /// Run `frames` frames in a row
+    pub fn run_frames(&mut self, frames: u64) {
+        for _ in 0..frames {
+            self.run_frame();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scripted_input() {
+        // wait for a key press and store the key in V0, then loop forever
+        let rom = [0xF0, 0x0A, 0x12, 0x02];
+        let mut runner = Runner::new(&rom, 1).unwrap();
+        runner.set_input(InputScript::parse("3 7 down\n5 7 up").unwrap());
+
+        runner.run_frames(3);
+        assert_eq!(runner.cpu.get_pc(), 0x200);
+        assert_eq!(runner.frame(), 3);
+
+        runner.run_frame();
+        assert_eq!(runner.cpu.v[0], 7);
+        assert!(runner.cpu.keypad.is_key_down(7));
+
+        runner.run_frames(2);
+        assert!(!runner.cpu.keypad.is_key_down(7));
+    }
+}
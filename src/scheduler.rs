@@ -0,0 +1,201 @@
+//! Decides how many frames to emulate each time the display refreshes, for
+//! running faster or slower than real time.
+//!
+//! A frame is always `CPU::run_frame`, a full frame's instructions followed
+//! by one timer tick, so the timers keep pace with the instructions at any
+//! speed. Front-ends call `tick` once per display refresh and redraw the
+//! screen when it ran any frames.
+use cpu::CPU;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    /// one frame per refresh
+    Normal,
+    /// this many frames per refresh, of which only the last is shown
+    FastForward(u32),
+    /// one frame every this many refreshes
+    SlowMotion(u32),
+    /// as many frames per refresh as there is time for, for benchmarks
+    Uncapped,
+}
+
+pub struct Scheduler {
+    speed: Speed,
+    paused: bool,
+    /// run one frame on the next tick even though paused
+    advance: bool,
+    /// refreshes since the last frame in slow motion
+    refreshes: u32,
+    /// frames run since the scheduler was created
+    frames: u64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { speed: Speed::Normal, paused: false, advance: false, refreshes: 0, frames: 0 }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Change the speed. Factors of 0 are treated as 1
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = match speed {
+            Speed::FastForward(0) => Speed::FastForward(1),
+            Speed::SlowMotion(0) => Speed::SlowMotion(1),
+            speed => speed,
+        };
+        self.refreshes = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.advance = false;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.advance = false;
+    }
+
+    /// Pause after running exactly one more frame. Unlike `CPU::cycle`,
+    /// which steps a single instruction, this steps a whole frame and its
+    /// timer tick
+    pub fn frame_advance(&mut self) {
+        self.paused = true;
+        self.advance = true;
+    }
+
+    /// Number of frames run so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Run the frames due for one display refresh and return how many ran.
+    /// When uncapped, frames run until `out_of_time` returns true, which is
+    /// asked after each frame; it isn't used at other speeds
+    pub fn tick<F: FnMut() -> bool>(&mut self, cpu: &mut CPU, out_of_time: F) -> u32 {
+        self.tick_with(cpu, CPU::run_frame, out_of_time)
+    }
+
+    /// Like `tick`, but with `run_frame` in place of `CPU::run_frame`, for
+    /// front-ends that check something after every frame
+    pub fn tick_with<R, F>(&mut self, cpu: &mut CPU, mut run_frame: R, mut out_of_time: F) -> u32
+        where R: FnMut(&mut CPU), F: FnMut() -> bool
+    {
+        let frames = if self.paused {
+            if !self.advance {
+                return 0;
+            }
+            self.advance = false;
+            1
+        } else {
+            match self.speed {
+                Speed::Normal => 1,
+                Speed::FastForward(factor) => factor,
+                Speed::SlowMotion(factor) => {
+                    self.refreshes += 1;
+                    if self.refreshes < factor {
+                        return 0;
+                    }
+                    self.refreshes = 0;
+                    1
+                },
+                Speed::Uncapped => {
+                    let mut frames = 0;
+                    loop {
+                        run_frame(cpu);
+                        frames += 1;
+                        if out_of_time() {
+                            break;
+                        }
+                    }
+                    self.frames += frames as u64;
+                    return frames;
+                },
+            }
+        };
+        for _ in 0..frames {
+            run_frame(cpu);
+        }
+        self.frames += frames as u64;
+        frames
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A CPU running a loop that adds 1 to V0 once per frame
+    fn counter() -> CPU {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        cpu.set_cycles_per_frame(2);
+        cpu
+    }
+
+    #[test]
+    fn speeds() {
+        let mut cpu = counter();
+        let mut scheduler = Scheduler::new();
+        assert_eq!(scheduler.tick(&mut cpu, || true), 1);
+
+        scheduler.set_speed(Speed::FastForward(4));
+        assert_eq!(scheduler.tick(&mut cpu, || true), 4);
+        assert_eq!(cpu.v[0], 5);
+
+        scheduler.set_speed(Speed::SlowMotion(3));
+        let ran: Vec<u32> = (0..6).map(|_| scheduler.tick(&mut cpu, || true)).collect();
+        assert_eq!(ran, vec![0, 0, 1, 0, 0, 1]);
+
+        scheduler.set_speed(Speed::Uncapped);
+        let mut checks = 0;
+        assert_eq!(scheduler.tick(&mut cpu, || { checks += 1; checks == 10 }), 10);
+        assert_eq!(cpu.v[0], 17);
+        assert_eq!(scheduler.frames(), 17);
+    }
+
+    #[test]
+    fn timers_keep_pace() {
+        let mut cpu = counter();
+        let mut state = cpu.state();
+        state.delay = 60;
+        cpu.set_state(&state).unwrap();
+        let mut scheduler = Scheduler::new();
+        scheduler.set_speed(Speed::FastForward(8));
+        scheduler.tick(&mut cpu, || true);
+        assert_eq!(cpu.state().delay, 52);
+        assert_eq!(cpu.v[0], 8);
+    }
+
+    #[test]
+    fn frame_advance() {
+        let mut cpu = counter();
+        let mut scheduler = Scheduler::new();
+        scheduler.set_speed(Speed::FastForward(4));
+        scheduler.pause();
+        assert_eq!(scheduler.tick(&mut cpu, || true), 0);
+
+        scheduler.frame_advance();
+        assert_eq!(scheduler.tick(&mut cpu, || true), 1);
+        assert_eq!(scheduler.tick(&mut cpu, || true), 0);
+        assert!(scheduler.is_paused());
+        assert_eq!(cpu.v[0], 1);
+
+        scheduler.resume();
+        assert_eq!(scheduler.tick(&mut cpu, || true), 4);
+    }
+}
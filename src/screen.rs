@@ -1,5 +1,10 @@
+use std::fmt;
+
+use hash::Fnv64;
+
 /// 64 x 32 monochrome display. (0, 0) is the top left pixel
 /// and (63, 31) is the bottom right pixel
+#[derive(Clone)]
 pub struct Screen {
     pixels: [u64; 32],
 }
@@ -31,6 +36,34 @@ impl Screen {
         ((self.pixels[y] >> (63 - x)) & 1) == 1
     }
 
+    /// The raw pixel rows. The most significant bit of each row is its
+    /// leftmost pixel
+    pub fn rows(&self) -> &[u64; 32] {
+        &self.pixels
+    }
+
+    /// One byte per pixel, row by row from the top left, 1 for set pixels
+    /// and 0 for unset ones
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.pixels.iter()
+            .flat_map(|&row| (0..64).rev().map(move |bit| (row >> bit & 1) as u8))
+            .collect()
+    }
+
+    /// Replace every pixel on the screen, in the same layout as `rows()`
+    pub fn set_rows(&mut self, rows: &[u64; 32]) {
+        self.pixels = *rows;
+    }
+
+    /// Hash of the pixels currently on the screen
+    pub fn hash(&self) -> u64 {
+        let mut hasher = Fnv64::new();
+        for &row in self.pixels.iter() {
+            hasher.write_u64(row);
+        }
+        hasher.finish()
+    }
+
     /// Draw the provided sprite with the top left corner at (x, y).
     /// If the sprite would be clipped, it does not get drawn (TODO: wrap instead)
     pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
@@ -50,6 +83,40 @@ impl Screen {
 
         collision
     }
+
+    /// Like `draw_sprite`, but the parts of the sprite past the right and
+    /// bottom edges are dropped instead of wrapping around
+    pub fn draw_sprite_clipped(&mut self, x: usize, y: usize, sprite: &[u8]) -> bool {
+        if x > 63 || y > 31 {
+            return false
+        }
+
+        let mut collision = false;
+        for (row, &byte) in (y..32).zip(sprite.iter()) {
+            let sprite_mask = if x > 56 {
+                (byte as u64) >> (x - 56)
+            } else {
+                (byte as u64) << (56 - x)
+            };
+            collision = collision || self.pixels[row] & sprite_mask > 0;
+            self.pixels[row] ^= sprite_mask;
+        }
+
+        collision
+    }
+}
+
+/// Draws the screen as text, with `#` for set and `.` for unset pixels
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for y in 0..32 {
+            for x in 0..64 {
+                write!(f, "{}", if self.get_pixel(x, y) { '#' } else { '.' })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -112,6 +179,17 @@ mod test {
         assert_eq!(screen.pixels[0], expected_row);
     }
 
+    #[test]
+    fn sprite_clipped() {
+        let mut screen = Screen::new();
+        assert!(!screen.draw_sprite_clipped(60, 30, &[0b00111100, 0b00111100, 0b00111100]));
+        // only the right 2 columns of the bottom 2 rows are written to
+        assert_eq!(screen.pixels[30], 3);
+        assert_eq!(screen.pixels[31], 3);
+        assert_eq!(screen.pixels[0], 0);
+        assert!(screen.draw_sprite_clipped(62, 31, &[0b11000000]));
+    }
+
     #[test]
     fn sprite_y() {
         let mut screen = Screen::new();
@@ -0,0 +1,533 @@
+//! Scripts in [Rhai](https://rhai.rs) that run alongside a ROM, for
+//! automated tests, tool-assisted speedruns and game-specific HUDs.
+//!
+//! A script's top level runs once, when it's loaded, and registers
+//! functions to be called on events:
+//!
+//! ```text
+//! // before the instruction at 0x2A4 runs
+//! on_pc(0x2A4, |pc| log(`hit at frame ${frame()}`));
+//! // after Fx33 or Fx55 writes to 0x2F0 through 0x2F2
+//! on_write(0x2F0, 0x2F2, |addr, value| log(`score digit ${value}`));
+//! // after every Dxyn, with Vx, Vy, the rows drawn and VF
+//! on_draw(|x, y, rows, collision| if collision == 1 { rect(x, y, 8, rows) });
+//! // at the end of every frame
+//! on_frame(|frame| text(0, 0, `lives ${v(7)}`));
+//! ```
+//!
+//! Scripts read and write the machine with `v(x)`, `set_v(x, value)`,
+//! `i()`, `set_i(value)`, `pc()`, `peek(addr)` and `poke(addr, value)`,
+//! hold keys down with `press(key)` until `release(key)`, and read the
+//! number of frames run with `frame()`. `text(x, y, string)` and
+//! `rect(x, y, width, height)` add to an overlay drawn over the screen,
+//! cleared at the start of every frame. The headless binary draws it into
+//! the frames of its GIF recordings; the browser front-end doesn't draw it. `log` and `print` add
+//! to a log the front-end collects.
+//!
+//! Only the hooks' own instructions are watched, so writes made by cheats
+//! and by scripts themselves don't call `on_write` hooks.
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::rc::Rc;
+
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST};
+
+use cpu::CPU;
+use instruction::{decode, Instruction};
+
+/// Operations a script may run per call before it's stopped, so a script
+/// stuck in a loop can't hang the front-end
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Something for the front-end to draw over the screen, in CHIP-8 pixels
+#[derive(Clone, Debug, PartialEq)]
+pub enum Overlay {
+    Text { x: i64, y: i64, text: String },
+    Rect { x: i64, y: i64, width: i64, height: i64 },
+}
+
+/// 3x5 glyphs for ' ' through '_', with lowercase letters drawn as
+/// uppercase. The lowest 3 bits of each row are its pixels, left first
+const FONT: [[u8; 5]; 64] = [
+    [0b000, 0b000, 0b000, 0b000, 0b000], // ' '
+    [0b010, 0b010, 0b010, 0b000, 0b010], // !
+    [0b101, 0b101, 0b000, 0b000, 0b000], // "
+    [0b101, 0b111, 0b101, 0b111, 0b101], // #
+    [0b011, 0b110, 0b010, 0b011, 0b110], // $
+    [0b101, 0b001, 0b010, 0b100, 0b101], // %
+    [0b010, 0b101, 0b010, 0b101, 0b011], // &
+    [0b010, 0b010, 0b000, 0b000, 0b000], // "'"
+    [0b001, 0b010, 0b010, 0b010, 0b001], // (
+    [0b100, 0b010, 0b010, 0b010, 0b100], // )
+    [0b000, 0b101, 0b010, 0b101, 0b000], // *
+    [0b000, 0b010, 0b111, 0b010, 0b000], // +
+    [0b000, 0b000, 0b000, 0b010, 0b100], // ,
+    [0b000, 0b000, 0b111, 0b000, 0b000], // -
+    [0b000, 0b000, 0b000, 0b000, 0b010], // .
+    [0b001, 0b001, 0b010, 0b100, 0b100], // /
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b010, 0b010], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+    [0b000, 0b010, 0b000, 0b010, 0b000], // :
+    [0b000, 0b010, 0b000, 0b010, 0b100], // ;
+    [0b001, 0b010, 0b100, 0b010, 0b001], // <
+    [0b000, 0b111, 0b000, 0b111, 0b000], // =
+    [0b100, 0b010, 0b001, 0b010, 0b100], // >
+    [0b111, 0b001, 0b010, 0b000, 0b010], // ?
+    [0b010, 0b101, 0b111, 0b100, 0b011], // @
+    [0b010, 0b101, 0b111, 0b101, 0b101], // A
+    [0b110, 0b101, 0b110, 0b101, 0b110], // B
+    [0b011, 0b100, 0b100, 0b100, 0b011], // C
+    [0b110, 0b101, 0b101, 0b101, 0b110], // D
+    [0b111, 0b100, 0b110, 0b100, 0b111], // E
+    [0b111, 0b100, 0b110, 0b100, 0b100], // F
+    [0b011, 0b100, 0b101, 0b101, 0b011], // G
+    [0b101, 0b101, 0b111, 0b101, 0b101], // H
+    [0b111, 0b010, 0b010, 0b010, 0b111], // I
+    [0b001, 0b001, 0b001, 0b101, 0b010], // J
+    [0b101, 0b101, 0b110, 0b101, 0b101], // K
+    [0b100, 0b100, 0b100, 0b100, 0b111], // L
+    [0b101, 0b111, 0b111, 0b101, 0b101], // M
+    [0b110, 0b101, 0b101, 0b101, 0b101], // N
+    [0b010, 0b101, 0b101, 0b101, 0b010], // O
+    [0b110, 0b101, 0b110, 0b100, 0b100], // P
+    [0b010, 0b101, 0b101, 0b110, 0b011], // Q
+    [0b110, 0b101, 0b110, 0b101, 0b101], // R
+    [0b011, 0b100, 0b010, 0b001, 0b110], // S
+    [0b111, 0b010, 0b010, 0b010, 0b010], // T
+    [0b101, 0b101, 0b101, 0b101, 0b111], // U
+    [0b101, 0b101, 0b101, 0b101, 0b010], // V
+    [0b101, 0b101, 0b111, 0b111, 0b101], // W
+    [0b101, 0b101, 0b010, 0b101, 0b101], // X
+    [0b101, 0b101, 0b010, 0b010, 0b010], // Y
+    [0b111, 0b001, 0b010, 0b100, 0b111], // Z
+    [0b011, 0b010, 0b010, 0b010, 0b011], // [
+    [0b100, 0b100, 0b010, 0b001, 0b001], // '\\'
+    [0b110, 0b010, 0b010, 0b010, 0b110], // ]
+    [0b010, 0b101, 0b000, 0b000, 0b000], // ^
+    [0b000, 0b000, 0b000, 0b000, 0b111], // _
+];
+
+/// Width of a character in `Overlay::Text`, including the space after it
+const CHAR_WIDTH: i64 = 4;
+
+fn set_pixel(rows: &mut [u64; 32], x: i64, y: i64) {
+    if (0..64).contains(&x) && (0..32).contains(&y) {
+        rows[y as usize] |= 1 << (63 - x);
+    }
+}
+
+impl Overlay {
+    /// Set the pixels of `rows`, laid out like `Screen::rows()`, that this
+    /// covers, clipped to the screen. Rectangles are drawn as outlines and
+    /// characters without a glyph as `?`
+    pub fn draw(&self, rows: &mut [u64; 32]) {
+        match *self {
+            Overlay::Text { x, y, ref text } => {
+                for (i, c) in text.chars().enumerate() {
+                    let left = x.saturating_add(i as i64 * CHAR_WIDTH);
+                    if left > 63 {
+                        break;
+                    }
+                    let index = match c.to_ascii_uppercase() as u32 {
+                        c @ 0x20..=0x5F => c as usize - 0x20,
+                        _ => '?' as usize - 0x20,
+                    };
+                    for (dy, &row) in FONT[index].iter().enumerate() {
+                        for dx in 0..3 {
+                            if row >> (2 - dx) & 1 == 1 {
+                                set_pixel(rows, left + dx, y.saturating_add(dy as i64));
+                            }
+                        }
+                    }
+                }
+            },
+            Overlay::Rect { x, y, width, height } => {
+                if width <= 0 || height <= 0 {
+                    return;
+                }
+                let (right, bottom) = (x.saturating_add(width - 1), y.saturating_add(height - 1));
+                for px in x.max(-1)..=right.min(64) {
+                    set_pixel(rows, px, y);
+                    set_pixel(rows, px, bottom);
+                }
+                for py in y.max(-1)..=bottom.min(32) {
+                    set_pixel(rows, x, py);
+                    set_pixel(rows, right, py);
+                }
+            },
+        }
+    }
+}
+
+/// What scripts can see and change, shared with the functions they call
+struct Host {
+    /// the machine the script runs on, swapped in for the length of a call
+    /// to `Script::run_frame`
+    cpu: CPU,
+    frame: u64,
+    on_frame: Vec<FnPtr>,
+    on_pc: BTreeMap<u16, Vec<FnPtr>>,
+    /// first and last address watched, inclusive
+    on_write: Vec<(u16, u16, FnPtr)>,
+    on_draw: Vec<FnPtr>,
+    overlay: Vec<Overlay>,
+    log: Vec<String>,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    host: Rc<RefCell<Host>>,
+}
+
+type Result<T> = ::std::result::Result<T, Box<EvalAltResult>>;
+
+fn register(value: i64) -> Result<usize> {
+    if (0..16).contains(&value) {
+        Ok(value as usize)
+    } else {
+        Err(format!("no register V{}", value).into())
+    }
+}
+
+fn byte(value: i64) -> Result<u8> {
+    if (0..=0xFF).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} doesn't fit in a byte", value).into())
+    }
+}
+
+fn key(value: i64) -> Result<u8> {
+    if (0..16).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("no key {}", value).into())
+    }
+}
+
+fn address(value: i64) -> u16 {
+    (value & 0xFFF) as u16
+}
+
+impl Script {
+    /// Compile `source` and run its top level on `cpu`
+    pub fn new(source: &str, cpu: &mut CPU) -> ::std::result::Result<Script, String> {
+        let host = Rc::new(RefCell::new(Host {
+            cpu: CPU::new(),
+            frame: 0,
+            on_frame: Vec::new(),
+            on_pc: BTreeMap::new(),
+            on_write: Vec::new(),
+            on_draw: Vec::new(),
+            overlay: Vec::new(),
+            log: Vec::new(),
+        }));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let h = host.clone();
+        engine.register_fn("v", move |x: i64| -> Result<i64> { Ok(h.borrow().cpu.v[register(x)?] as i64) });
+        let h = host.clone();
+        engine.register_fn("set_v", move |x: i64, value: i64| -> Result<()> {
+            h.borrow_mut().cpu.v[register(x)?] = byte(value)?;
+            Ok(())
+        });
+        let h = host.clone();
+        engine.register_fn("i", move || h.borrow().cpu.i as i64);
+        let h = host.clone();
+        engine.register_fn("set_i", move |value: i64| h.borrow_mut().cpu.i = address(value));
+        let h = host.clone();
+        engine.register_fn("pc", move || h.borrow().cpu.get_pc() as i64);
+        let h = host.clone();
+        engine.register_fn("peek", move |addr: i64| h.borrow().cpu.read_memory(address(addr), 1)[0] as i64);
+        let h = host.clone();
+        engine.register_fn("poke", move |addr: i64, value: i64| -> Result<()> {
+            h.borrow_mut().cpu.write_memory(address(addr), &[byte(value)?]);
+            Ok(())
+        });
+        let h = host.clone();
+        engine.register_fn("press", move |k: i64| -> Result<()> {
+            h.borrow_mut().cpu.keypad.key_down(key(k)?);
+            Ok(())
+        });
+        let h = host.clone();
+        engine.register_fn("release", move |k: i64| -> Result<()> {
+            h.borrow_mut().cpu.keypad.key_up(key(k)?);
+            Ok(())
+        });
+        let h = host.clone();
+        engine.register_fn("frame", move || h.borrow().frame as i64);
+
+        let h = host.clone();
+        engine.register_fn("text", move |x: i64, y: i64, text: &str| {
+            h.borrow_mut().overlay.push(Overlay::Text { x, y, text: text.to_string() });
+        });
+        let h = host.clone();
+        engine.register_fn("rect", move |x: i64, y: i64, width: i64, height: i64| {
+            h.borrow_mut().overlay.push(Overlay::Rect { x, y, width, height });
+        });
+        let h = host.clone();
+        engine.register_fn("log", move |message: &str| h.borrow_mut().log.push(message.to_string()));
+        let h = host.clone();
+        engine.on_print(move |message| h.borrow_mut().log.push(message.to_string()));
+
+        let h = host.clone();
+        engine.register_fn("on_frame", move |f: FnPtr| h.borrow_mut().on_frame.push(f));
+        let h = host.clone();
+        engine.register_fn("on_pc", move |addr: i64, f: FnPtr| {
+            h.borrow_mut().on_pc.entry(address(addr)).or_default().push(f);
+        });
+        let h = host.clone();
+        engine.register_fn("on_write", move |addr: i64, f: FnPtr| {
+            h.borrow_mut().on_write.push((address(addr), address(addr), f));
+        });
+        let h = host.clone();
+        engine.register_fn("on_write", move |first: i64, last: i64, f: FnPtr| {
+            h.borrow_mut().on_write.push((address(first), address(last), f));
+        });
+        let h = host.clone();
+        engine.register_fn("on_draw", move |f: FnPtr| h.borrow_mut().on_draw.push(f));
+
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let script = Script { engine, ast, host };
+        script.with_cpu(cpu, |script| script.engine.run_ast(&script.ast))?;
+        Ok(script)
+    }
+
+    /// Swap `cpu` in for the length of `f`
+    fn with_cpu<F: FnOnce(&Script) -> Result<()>>(&self, cpu: &mut CPU, f: F) -> ::std::result::Result<(), String> {
+        mem::swap(cpu, &mut self.host.borrow_mut().cpu);
+        let result = f(self);
+        mem::swap(cpu, &mut self.host.borrow_mut().cpu);
+        result.map_err(|e| e.to_string())
+    }
+
+    fn call(&self, hooks: Vec<FnPtr>, args: Vec<i64>) -> Result<()> {
+        for hook in hooks {
+            let args: Vec<Dynamic> = args.iter().map(|&arg| arg.into()).collect();
+            let _: Dynamic = hook.call(&self.engine, &self.ast, args)?;
+        }
+        Ok(())
+    }
+
+    /// Run a 60 Hz frame on `cpu` like `CPU::run_frame`, calling the hooks
+    /// along the way
+    pub fn run_frame(&mut self, cpu: &mut CPU) -> ::std::result::Result<(), String> {
+        self.with_cpu(cpu, |script| {
+            let cycles = {
+                let mut host = script.host.borrow_mut();
+                host.overlay.clear();
+                host.cpu.apply_cheats();
+                host.cpu.cycles_per_frame()
+            };
+            for _ in 0..cycles {
+                script.cycle()?;
+            }
+            let (frame, hooks) = {
+                let mut host = script.host.borrow_mut();
+                host.cpu.decrement_timers();
+                host.frame += 1;
+                (host.frame, host.on_frame.clone())
+            };
+            script.call(hooks, vec![frame as i64])
+        })
+    }
+
+    /// Run an instruction, with the hooks for it
+    fn cycle(&self) -> Result<()> {
+        let pc = self.host.borrow().cpu.get_pc() & 0xFFF;
+        let hooks = self.host.borrow().on_pc.get(&pc).cloned();
+        if let Some(hooks) = hooks {
+            self.call(hooks, vec![pc as i64])?;
+        }
+
+        let (instruction, i, x, y) = {
+            let mut host = self.host.borrow_mut();
+            let instruction = decode(host.cpu.read_instruction());
+            // Dxyn sets VF, which may be Vx or Vy
+            let (x, y) = match instruction {
+                Instruction::Draw(x, y, _) => (host.cpu.v[x as usize], host.cpu.v[y as usize]),
+                _ => (0, 0),
+            };
+            let i = host.cpu.i;
+            host.cpu.cycle();
+            (instruction, i, x, y)
+        };
+
+        let written = match instruction {
+            Instruction::Draw(_, _, rows) => {
+                let (hooks, collision) = {
+                    let host = self.host.borrow();
+                    (host.on_draw.clone(), host.cpu.v[0xF])
+                };
+                return self.call(hooks, vec![x as i64, y as i64, rows as i64, collision as i64]);
+            }
+            Instruction::StoreBcd(_) => 3,
+            Instruction::StoreRegs(x) => x as u16 + 1,
+            _ => return Ok(()),
+        };
+        for offset in 0..written {
+            let addr = i.wrapping_add(offset) & 0xFFF;
+            let (hooks, value) = {
+                let host = self.host.borrow();
+                let hooks: Vec<FnPtr> = host.on_write.iter()
+                    .filter(|&&(first, last, _)| first <= addr && addr <= last)
+                    .map(|(_, _, f)| f.clone())
+                    .collect();
+                (hooks, host.cpu.read_memory(addr, 1)[0])
+            };
+            self.call(hooks, vec![addr as i64, value as i64])?;
+        }
+        Ok(())
+    }
+
+    /// What the script has drawn over the screen this frame
+    pub fn overlay(&self) -> Vec<Overlay> {
+        self.host.borrow().overlay.clone()
+    }
+
+    /// The overlay drawn into pixel rows laid out like `Screen::rows()`
+    pub fn overlay_rows(&self) -> [u64; 32] {
+        let mut rows = [0; 32];
+        for overlay in self.host.borrow().overlay.iter() {
+            overlay.draw(&mut rows);
+        }
+        rows
+    }
+
+    /// Messages logged since the last call
+    pub fn take_log(&mut self) -> Vec<String> {
+        mem::take(&mut self.host.borrow_mut().log)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cpu(rom: &[u8]) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.reset();
+        cpu.load_rom(rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        let mut cpu = cpu(&[0x12, 0x00]);
+        let mut script = Script::new("
+            on_frame(|frame| {
+                set_v(3, v(3) + 2);
+                set_i(0x300);
+                poke(i() + frame, 0xAB);
+                log(`frame ${frame}: ${peek(0x301)}`);
+            });
+        ", &mut cpu).unwrap();
+        script.run_frame(&mut cpu).unwrap();
+        script.run_frame(&mut cpu).unwrap();
+        assert_eq!(cpu.v[3], 4);
+        assert_eq!(cpu.i, 0x300);
+        assert_eq!(cpu.read_memory(0x301, 2), vec![0xAB, 0xAB]);
+        assert_eq!(script.take_log(), vec!["frame 1: 171", "frame 2: 171"]);
+        assert!(script.take_log().is_empty());
+    }
+
+    #[test]
+    fn pc_and_keys() {
+        // wait for a key, then count up in V1 forever
+        let mut cpu = cpu(&[0xF0, 0x0A, 0x71, 0x01, 0x12, 0x02]);
+        let mut script = Script::new("
+            on_frame(|frame| if frame == 3 { press(7) } else if frame == 4 { release(7) });
+            on_pc(0x202, |pc| print(`reached ${pc} with V0 = ${v(0)}`));
+        ", &mut cpu).unwrap();
+        for _ in 0..3 {
+            script.run_frame(&mut cpu).unwrap();
+        }
+        assert!(script.take_log().is_empty());
+        for _ in 0..3 {
+            script.run_frame(&mut cpu).unwrap();
+        }
+        assert_eq!(cpu.v[0], 7);
+        let log = script.take_log();
+        assert!(!log.is_empty());
+        assert_eq!(log[0], "reached 514 with V0 = 7");
+    }
+
+    #[test]
+    fn writes_and_draws() {
+        let rom = [
+            0xA3, 0x00, // LD I, 0x300
+            0x60, 0xFE, // LD V0, 254
+            0xF0, 0x33, // LD B, V0
+            0xF1, 0x55, // LD [I], V1
+            0xA0, 0x00, // LD I, font 0
+            0x62, 0x05, // LD V2, 5
+            0xD2, 0x25, // DRW V2, V2, 5
+            0xD2, 0x25, // DRW V2, V2, 5
+            0x12, 0x10, // JP 0x210
+        ];
+        let mut cpu = cpu(&rom);
+        let mut script = Script::new("
+            on_write(0x301, 0x3FF, |addr, value| log(`${addr} = ${value}`));
+            on_draw(|x, y, rows, collision| {
+                log(`draw ${x},${y} ${rows} ${collision}`);
+                rect(x, y, 8, rows);
+            });
+        ", &mut cpu).unwrap();
+        script.run_frame(&mut cpu).unwrap();
+        assert_eq!(script.take_log(), vec!["769 = 5", "770 = 4", "769 = 0", "draw 5,5 5 0", "draw 5,5 5 1"]);
+        assert_eq!(script.overlay().len(), 2);
+        assert_eq!(script.overlay()[0], Overlay::Rect { x: 5, y: 5, width: 8, height: 5 });
+        // an 8x5 outline
+        let rows = script.overlay_rows();
+        assert_eq!(rows[5], 0xFF << 51);
+        assert_eq!(&rows[6..9], &[0x81 << 51; 3]);
+        assert_eq!(rows[9], 0xFF << 51);
+        assert_eq!(rows.iter().filter(|&&row| row != 0).count(), 5);
+        // the overlay only holds what was drawn this frame
+        script.run_frame(&mut cpu).unwrap();
+        assert!(script.overlay().is_empty());
+        assert_eq!(script.overlay_rows(), [0; 32]);
+    }
+
+    #[test]
+    fn draw_overlay() {
+        let mut rows = [0; 32];
+        Overlay::Text { x: 0, y: 0, text: "Hi~".to_string() }.draw(&mut rows);
+        // H, I and ? for the character without a glyph
+        assert_eq!(rows[0] >> 53, 0b101_0111_0111);
+        assert_eq!(rows[4] >> 53, 0b101_0111_0010);
+
+        // clipped to the screen rather than wrapped
+        let mut rows = [0; 32];
+        Overlay::Rect { x: 62, y: -1, width: 4, height: 3 }.draw(&mut rows);
+        Overlay::Text { x: 62, y: 30, text: "W".to_string() }.draw(&mut rows);
+        Overlay::Rect { x: i64::MAX, y: 0, width: i64::MAX, height: 1 }.draw(&mut rows);
+        assert_eq!(&rows[..2], &[0b10, 0b11]);
+        assert_eq!(&rows[30..], &[0b10, 0b10]);
+        assert_eq!(rows[2..30].iter().filter(|&&row| row != 0).count(), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let mut cpu = cpu(&[0x12, 0x00]);
+        assert!(Script::new("on_frame(|frame| {", &mut cpu).is_err());
+        assert!(Script::new("set_v(16, 0)", &mut cpu).err().unwrap().contains("no register V16"));
+        let mut script = Script::new("on_frame(|frame| poke(0x300, 256))", &mut cpu).unwrap();
+        assert!(script.run_frame(&mut cpu).unwrap_err().contains("doesn't fit in a byte"));
+        let mut script = Script::new("on_frame(|frame| loop {})", &mut cpu).unwrap();
+        assert!(script.run_frame(&mut cpu).is_err());
+        // the machine is handed back after an error
+        assert_eq!(cpu.get_pc(), 0x200);
+    }
+}
@@ -11,9 +11,32 @@ macro_rules! log {
     ($($t:tt)*) => (log(&format!($($t)*)))
 }
 
+/// Milliseconds of each display refresh spent emulating when uncapped,
+/// leaving the rest of a 60 Hz refresh for drawing
+const UNCAPPED_BUDGET_MS: f64 = 12.0;
+
 #[wasm_bindgen]
 pub struct CPUWrapper {
-    cpu: ::cpu::CPU
+    cpu: ::cpu::CPU,
+    /// colours set by the loaded cartridge, which take precedence over the
+    /// ROM database
+    palette: Option<[[u8; 3]; 2]>,
+    /// layout chosen by the user
+    layout: ::keymap::KeyMap,
+    /// the layout plus the loaded ROM's own keys, used for key events
+    keymap: ::keymap::KeyMap,
+    /// host keys held down
+    held_keys: ::keymap::HeldKeys,
+    gamepad: ::gamepad::Gamepad,
+    scheduler: ::scheduler::Scheduler,
+    /// memory search in progress for finding cheats
+    search: Option<::cheats::MemorySearch>,
+    /// achievement definitions for every ROM known
+    achievement_library: ::achievements::Library,
+    /// the loaded ROM's achievements
+    achievements: ::achievements::Achievements,
+    /// called with the id and title of each achievement unlocked
+    on_achievement: Option<::js_sys::Function>,
 }
 
 #[wasm_bindgen]
@@ -21,22 +44,102 @@ impl CPUWrapper {
     /// Initialize a new CPU with undefined state. The user should call reset()
     /// on the new instance before using it
     pub fn new() -> CPUWrapper {
-        CPUWrapper { cpu: ::cpu::CPU::new() }
+        let mut cpu = ::cpu::CPU::new();
+        cpu.seed((::js_sys::Math::random() * (u32::MAX as f64)) as u32);
+        CPUWrapper {
+            cpu,
+            palette: None,
+            layout: ::keymap::KeyMap::qwerty(),
+            keymap: ::keymap::KeyMap::qwerty(),
+            held_keys: ::keymap::HeldKeys::new(),
+            gamepad: ::gamepad::Gamepad::new(::gamepad::GamepadMap::standard()),
+            scheduler: ::scheduler::Scheduler::new(),
+            search: None,
+            achievement_library: ::achievements::Library::builtin(),
+            achievements: ::achievements::Achievements::new(Vec::new()),
+            on_achievement: None,
+        }
     }
 
     /// Reset the CPU and its screen to their initial states
     pub fn reset(&mut self) {
         self.cpu.reset();
+        self.achievements.reset();
+    }
+
+    /// Reset the CPU and load a ROM at 0x200, throwing an error if it is
+    /// empty or too big. The running game is left alone if loading fails
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.load_with(|cpu| cpu.load_rom(data).map(|_| None))
+    }
+
+    /// Apply an IPS or BPS patch to a ROM and load the result at 0x200
+    pub fn load_patched_rom(&mut self, data: &[u8], patch: &[u8]) -> Result<(), JsValue> {
+        let rom = ::patch::apply(data, patch).map_err(|e| JsValue::from_str(&e))?;
+        self.load_rom(&rom)
+    }
+
+    /// Reset the CPU and load an Octo cartridge GIF, with its speed, quirks
+    /// and colours
+    pub fn load_cartridge(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let cartridge = ::cartridge::Cartridge::from_gif(data).map_err(|e| JsValue::from_str(&e))?;
+        self.load_with(|cpu| {
+            cpu.load_rom(&cartridge.rom)?;
+            cartridge.configure(cpu);
+            Ok(cartridge.palette)
+        })
+    }
+
+    /// Reset the CPU and load a ROM at `address`, such as 0x600 for ETI-660
+    /// programs, and start running it from there
+    pub fn load_rom_at(&mut self, data: &[u8], address: u16) -> Result<(), JsValue> {
+        self.load_with(|cpu| cpu.load_rom_at(data, address).map(|_| None))
+    }
+
+    /// Size in bytes of the loaded ROM
+    pub fn rom_len(&self) -> usize {
+        self.cpu.rom_len()
+    }
+
+    /// Hex SHA-1 of the loaded ROM
+    pub fn rom_sha1(&self) -> Option<String> {
+        self.cpu.rom_sha1().map(String::from)
+    }
+
+    /// Add ROMs in the chip-8-database JSON format to the built-in database,
+    /// and apply them to the loaded ROM
+    pub fn load_database(&mut self, json: &str) -> Result<(), JsValue> {
+        let mut database = ::database::Database::builtin();
+        database.load(json).map_err(|e| JsValue::from_str(&e))?;
+        self.cpu.set_database(database);
+        self.update_controls();
+        Ok(())
+    }
+
+    /// Number of cycles to run per frame for the loaded ROM
+    pub fn cycles_per_frame(&self) -> usize {
+        self.cpu.cycles_per_frame()
     }
 
-    pub fn load_rom(&mut self, data: &[u8]) {
-        self.cpu.load_rom(data);
+    /// Title of the loaded ROM, if it is in the database
+    pub fn rom_title(&self) -> Option<String> {
+        self.cpu.rom_info().map(|info| info.title.clone())
+    }
+
+    /// CSS colour for pixels that are on or off, using the loaded ROM's
+    /// palette if it has one
+    pub fn pixel_color(&self, on: bool) -> String {
+        let palette = self.palette
+            .or_else(|| self.cpu.rom_info().and_then(|info| info.palette))
+            .unwrap_or([[0xFF, 0xFF, 0xFF], [0, 0, 0]]);
+        let [r, g, b] = palette[on as usize];
+        format!("#{:02X}{:02X}{:02X}", r, g, b)
     }
 
     /// Execute a single cycle of the CPU
     pub fn cycle(&mut self, debug: bool) {
-        let next_instruction = self.cpu.read_instruction();
         if debug {
+            let next_instruction = self.cpu.read_instruction();
             for i in 0..16 {
                 log!("V{}={}", i, self.cpu.v[i]);
             }
@@ -46,13 +149,124 @@ impl CPUWrapper {
                 next_instruction,
                 self.cpu.get_pc());
         }
-        self.cpu.process_instruction(next_instruction);
+        self.cpu.cycle();
     }
 
     pub fn decrement_timers(&mut self) {
         self.cpu.decrement_timers();
     }
 
+    /// Run the frames due for one display refresh at the current speed, and
+    /// return how many ran so the page knows whether to redraw. Achievements
+    /// are checked after every frame
+    pub fn tick(&mut self) -> u32 {
+        let deadline = ::js_sys::Date::now() + UNCAPPED_BUDGET_MS;
+        let achievements = &mut self.achievements;
+        let mut unlocked = Vec::new();
+        let frames = self.scheduler.tick_with(
+            &mut self.cpu,
+            |cpu| {
+                cpu.run_frame();
+                unlocked.extend(achievements.update(cpu));
+            },
+            || ::js_sys::Date::now() >= deadline);
+        if let Some(ref callback) = self.on_achievement {
+            for achievement in unlocked {
+                let id = JsValue::from_str(&achievement.id);
+                let title = JsValue::from_str(&achievement.title);
+                if let Err(e) = callback.call2(&JsValue::NULL, &id, &title) {
+                    log!("achievement callback failed: {:?}", e);
+                }
+            }
+        }
+        frames
+    }
+
+    /// Run one frame per refresh
+    pub fn normal_speed(&mut self) {
+        self.scheduler.set_speed(::scheduler::Speed::Normal);
+    }
+
+    /// Run `factor` frames per refresh, showing only the last
+    pub fn fast_forward(&mut self, factor: u32) {
+        self.scheduler.set_speed(::scheduler::Speed::FastForward(factor));
+    }
+
+    /// Run one frame every `factor` refreshes
+    pub fn slow_motion(&mut self, factor: u32) {
+        self.scheduler.set_speed(::scheduler::Speed::SlowMotion(factor));
+    }
+
+    /// Run as many frames per refresh as there is time for
+    pub fn uncapped(&mut self) {
+        self.scheduler.set_speed(::scheduler::Speed::Uncapped);
+    }
+
+    pub fn pause(&mut self) {
+        self.scheduler.pause();
+    }
+
+    pub fn resume(&mut self) {
+        self.scheduler.resume();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.scheduler.is_paused()
+    }
+
+    /// Run one more frame on the next tick, then stay paused
+    pub fn frame_advance(&mut self) {
+        self.scheduler.frame_advance();
+    }
+
+    /// Number of frames run so far, for measuring speed when uncapped
+    pub fn frames(&self) -> f64 {
+        self.scheduler.frames() as f64
+    }
+
+    /// Press or release a host key, named by its `KeyboardEvent.code`.
+    /// Returns whether the key is mapped to the keypad, so the page knows
+    /// whether to stop the browser from handling it
+    pub fn key_event(&mut self, code: &str, pressed: bool) -> bool {
+        match self.keymap.key(code) {
+            Some(key) => self.held_keys.key_event(code, key, pressed, &mut self.cpu.keypad),
+            None => return false,
+        }
+        true
+    }
+
+    /// Switch to a keyboard layout, either a preset (`qwerty`, `azerty` or
+    /// `numpad`) or a JSON object mapping key codes to keypad keys
+    pub fn set_keymap(&mut self, layout: &str) -> Result<(), JsValue> {
+        self.layout = match ::keymap::KeyMap::preset(layout) {
+            Some(keymap) => keymap,
+            None => ::keymap::KeyMap::from_json(layout).map_err(|e| JsValue::from_str(&e))?,
+        };
+        self.update_controls();
+        Ok(())
+    }
+
+    /// The key mapping in use for the loaded ROM, as JSON
+    pub fn keymap_json(&self) -> String {
+        self.keymap.to_json()
+    }
+
+    /// Update the keypad from a polled gamepad in the standard layout: each
+    /// button's value and each axis's position, as in the Gamepad API
+    pub fn gamepad_state(&mut self, buttons: &[f64], axes: &[f64]) {
+        self.gamepad.update(buttons, axes, &mut self.cpu.keypad);
+    }
+
+    /// Release the keys held by a gamepad that was unplugged
+    pub fn gamepad_disconnected(&mut self) {
+        self.gamepad.release(&mut self.cpu.keypad);
+    }
+
+    /// Set how far, from 0 to 1, a gamepad's stick must be pushed to count
+    pub fn set_gamepad_dead_zone(&mut self, dead_zone: f64) {
+        self.gamepad.map.set_dead_zone(dead_zone);
+    }
+
     pub fn key_down(&mut self, key: u8) {
         self.cpu.keypad.key_down(key);
     }
@@ -61,8 +275,271 @@ impl CPUWrapper {
         self.cpu.keypad.key_up(key);
     }
 
+    /// Read `len` bytes of memory starting at `addr`
+    pub fn read_memory(&self, addr: u16, len: usize) -> Vec<u8> {
+        self.cpu.read_memory(addr, len)
+    }
+
+    /// Overwrite memory starting at `addr`
+    pub fn write_memory(&mut self, addr: u16, bytes: &[u8]) {
+        self.cpu.write_memory(addr, bytes);
+    }
+
+    /// Hex dump of the memory around `addr`, with the font, ROM, `PC` and
+    /// `I` marked
+    pub fn hex_dump(&self, addr: u16, len: usize) -> String {
+        ::inspect::hex_dump(&self.cpu, addr, len)
+    }
+
+    /// The `rows` bytes at `I` drawn as a sprite
+    pub fn sprite_preview(&self, rows: usize) -> String {
+        ::inspect::sprite_preview(&self.cpu, rows)
+    }
+
+    /// Start a new memory search from a snapshot of memory as it is now
+    pub fn cheat_search_start(&mut self) {
+        self.search = Some(::cheats::MemorySearch::new(&self.cpu));
+    }
+
+    /// Keep the addresses whose bytes compare as asked against the last
+    /// snapshot: `equal` to `value`, `unchanged`, `changed`, `increased` or
+    /// `decreased`. Returns how many addresses are left
+    pub fn cheat_search(&mut self, comparison: &str, value: u8) -> Result<usize, JsValue> {
+        let comparison = match comparison {
+            "equal" => ::cheats::Comparison::Equal(value),
+            "unchanged" => ::cheats::Comparison::Unchanged,
+            "changed" => ::cheats::Comparison::Changed,
+            "increased" => ::cheats::Comparison::Increased,
+            "decreased" => ::cheats::Comparison::Decreased,
+            _ => return Err(JsValue::from_str(&format!("unknown comparison {}", comparison))),
+        };
+        let search = self.search.as_mut().ok_or_else(|| JsValue::from_str("no search started"))?;
+        Ok(search.filter(&self.cpu, comparison))
+    }
+
+    /// Up to `max` of the addresses the search has narrowed down to
+    pub fn cheat_candidates(&self, max: usize) -> Vec<u16> {
+        self.search.as_ref()
+            .map(|search| search.candidates().iter().take(max).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Add a cheat in the `ADDR:VALUE` format
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), JsValue> {
+        let cheat = ::cheats::Cheat::parse(code).map_err(|e| JsValue::from_str(&e))?;
+        self.cpu.cheats_mut().cheats.push(cheat);
+        Ok(())
+    }
+
+    pub fn cheat_count(&self) -> usize {
+        self.cpu.cheats().cheats.len()
+    }
+
+    /// A cheat in the `ADDR:VALUE` format
+    pub fn cheat(&self, index: usize) -> Option<String> {
+        self.cpu.cheats().cheats.get(index).map(|cheat| cheat.to_string())
+    }
+
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) {
+        if let Some(cheat) = self.cpu.cheats_mut().cheats.get_mut(index) {
+            cheat.enabled = enabled;
+        }
+    }
+
+    pub fn remove_cheat(&mut self, index: usize) {
+        let cheats = &mut self.cpu.cheats_mut().cheats;
+        if index < cheats.len() {
+            cheats.remove(index);
+        }
+    }
+
+    /// All cheats for the loaded ROM, one per line, for saving by ROM hash
+    pub fn cheats_text(&self) -> String {
+        self.cpu.cheats().to_string()
+    }
+
+    /// Replace the cheats with ones saved by `cheats_text`
+    pub fn load_cheats(&mut self, text: &str) -> Result<(), JsValue> {
+        *self.cpu.cheats_mut() = ::cheats::Cheats::parse(text).map_err(|e| JsValue::from_str(&e))?;
+        Ok(())
+    }
+
+    /// Call `callback(id, title)` whenever an achievement is unlocked. It's
+    /// called from inside `tick`, so it mustn't call back into the CPU
+    pub fn on_achievement(&mut self, callback: ::js_sys::Function) {
+        self.on_achievement = Some(callback);
+    }
+
+    /// Add achievement definitions for any number of ROMs, replacing the
+    /// built-in ones for those ROMs. Unlocked achievements stay unlocked
+    pub fn load_achievements(&mut self, text: &str) -> Result<(), JsValue> {
+        self.achievement_library.load(text).map_err(|e| JsValue::from_str(&e))?;
+        let unlocked = self.achievements.unlocked_text();
+        self.update_achievements();
+        self.achievements.load_unlocked(&unlocked);
+        Ok(())
+    }
+
+    pub fn achievement_count(&self) -> usize {
+        self.achievements.achievements().len()
+    }
+
+    pub fn achievement_title(&self, index: usize) -> Option<String> {
+        self.achievements.achievements().get(index).map(|achievement| achievement.title.clone())
+    }
+
+    pub fn achievement_unlocked(&self, index: usize) -> bool {
+        self.achievements.is_unlocked(index)
+    }
+
+    /// Ids of the unlocked achievements, one per line, for saving by ROM
+    /// hash
+    pub fn achievements_text(&self) -> String {
+        self.achievements.unlocked_text()
+    }
+
+    /// Unlock the achievements saved by `achievements_text`
+    pub fn load_unlocked_achievements(&mut self, text: &str) {
+        self.achievements.load_unlocked(text);
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.get_pc()
+    }
+
     pub fn get_pixel(&self, x: usize, y: usize) -> bool {
         self.cpu.screen.get_pixel(x, y)
     }
 }
 
+
+impl CPUWrapper {
+    /// Pick up the loaded ROM's keys for the keyboard and gamepad
+    fn update_controls(&mut self) {
+        self.keymap = match self.cpu.rom_info() {
+            Some(info) => self.layout.for_rom(info),
+            None => self.layout.clone(),
+        };
+        self.gamepad.release(&mut self.cpu.keypad);
+        self.gamepad.map = ::gamepad::GamepadMap::for_rom(self.cpu.rom_info());
+    }
+
+    /// Load a game with `load` into a reset copy of the CPU, which replaces
+    /// the running one only if it succeeds. `load` returns the game's
+    /// palette, if it has its own
+    fn load_with<F>(&mut self, load: F) -> Result<(), JsValue>
+        where F: FnOnce(&mut ::cpu::CPU) -> Result<Option<[[u8; 3]; 2]>, String>
+    {
+        let mut cpu = self.cpu.clone();
+        cpu.reset();
+        self.palette = load(&mut cpu).map_err(|e| JsValue::from_str(&e))?;
+        self.cpu = cpu;
+        self.update_controls();
+        self.update_achievements();
+        Ok(())
+    }
+
+    /// Pick up the loaded ROM's achievements, all locked
+    fn update_achievements(&mut self) {
+        let achievements = self.cpu.rom_sha1()
+            .and_then(|sha1| self.achievement_library.for_rom(sha1))
+            .map(|achievements| achievements.to_vec())
+            .unwrap_or_default();
+        self.achievements = ::achievements::Achievements::new(achievements);
+    }
+}
+
+/// One of any number of independent machines, for running several side by
+/// side or inside a Web Worker (see `worker.js`)
+#[wasm_bindgen]
+pub struct MachineWrapper {
+    machine: ::machine::Machine,
+    /// what happened in the last call to `run`
+    report: ::machine::Report,
+}
+
+#[wasm_bindgen]
+impl MachineWrapper {
+    pub fn new(seed: u32) -> MachineWrapper {
+        MachineWrapper {
+            machine: ::machine::Machine::new(seed),
+            report: ::machine::Report {
+                frame: 0,
+                status: ::machine::Status::Running,
+                audio: Vec::new(),
+                drawn: false,
+            },
+        }
+    }
+
+    /// Reset the machine and load a ROM or an Octo cartridge GIF
+    pub fn load(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.machine.load(data).map_err(|e| JsValue::from_str(&e))
+    }
+
+    /// Set a quirk by its chip-8-database name. Returns false for unknown
+    /// quirks
+    pub fn set_quirk(&mut self, name: &str, value: bool) -> bool {
+        let mut quirks = self.machine.cpu.quirks();
+        let known = quirks.set(name, value);
+        self.machine.cpu.set_quirks(quirks);
+        known
+    }
+
+    /// Set quirks from a JSON object of chip-8-database names and true or
+    /// false, throwing without setting any if one is invalid
+    pub fn set_quirks(&mut self, json: &str) -> Result<(), JsValue> {
+        let quirks = self.machine.cpu.quirks().with_json(json).map_err(|e| JsValue::from_str(&e))?;
+        self.machine.cpu.set_quirks(quirks);
+        Ok(())
+    }
+
+    pub fn set_cycles_per_frame(&mut self, cycles: usize) {
+        self.machine.cpu.set_cycles_per_frame(cycles);
+    }
+
+    /// Hold down exactly the keys in `keys`, a bit per key
+    pub fn set_keys(&mut self, keys: u16) {
+        self.machine.set_keys(keys);
+    }
+
+    /// Run `frames` frames and return the status: 0 while running, 1 when
+    /// waiting for a key and 2 once halted
+    pub fn run(&mut self, frames: u32) -> u8 {
+        self.report = self.machine.run(frames);
+        self.report.status.code()
+    }
+
+    /// Frames run since the ROM was loaded
+    pub fn frame(&self) -> f64 {
+        self.machine.frame() as f64
+    }
+
+    /// 1 for each frame of the last `run` the beeper sounded on, else 0
+    pub fn audio(&self) -> Vec<u8> {
+        self.report.audio.iter().map(|&beeping| beeping as u8).collect()
+    }
+
+    /// Whether the last `run` changed the screen
+    pub fn drawn(&self) -> bool {
+        self.report.drawn
+    }
+
+    /// Draw the screen as RGBA into `out`, which may be a view of a
+    /// `SharedArrayBuffer`, 64 * 32 * 4 bytes long
+    pub fn write_framebuffer(&self, out: &mut [u8]) -> Result<(), JsValue> {
+        if out.len() != ::machine::FRAMEBUFFER_LEN {
+            return Err(JsValue::from_str(&format!("framebuffer must be {} bytes", ::machine::FRAMEBUFFER_LEN)));
+        }
+        self.machine.write_framebuffer(out);
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.machine.save_state()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+        self.machine.load_state(state).map_err(|e| JsValue::from_str(&e))
+    }
+}
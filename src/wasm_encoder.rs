@@ -0,0 +1,347 @@
+//! A minimal encoder for WebAssembly binary modules, covering just what the
+//! recompiler emits
+
+/// Value types
+pub const I32: u8 = 0x7F;
+pub const I64: u8 = 0x7E;
+
+/// Opcodes, named after their text format mnemonics
+pub mod op {
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const ELSE: u8 = 0x05;
+    pub const END: u8 = 0x0B;
+    pub const BR: u8 = 0x0C;
+    pub const BR_IF: u8 = 0x0D;
+    pub const BR_TABLE: u8 = 0x0E;
+    pub const RETURN: u8 = 0x0F;
+    pub const CALL: u8 = 0x10;
+    pub const CALL_INDIRECT: u8 = 0x11;
+    pub const SELECT: u8 = 0x1B;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_LOAD: u8 = 0x28;
+    pub const I64_LOAD: u8 = 0x29;
+    pub const I32_LOAD8_U: u8 = 0x2D;
+    pub const I32_LOAD16_U: u8 = 0x2F;
+    pub const I32_STORE: u8 = 0x36;
+    pub const I64_STORE: u8 = 0x37;
+    pub const I32_STORE8: u8 = 0x3A;
+    pub const I32_STORE16: u8 = 0x3B;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I64_CONST: u8 = 0x42;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_EQ: u8 = 0x46;
+    pub const I32_NE: u8 = 0x47;
+    pub const I32_GT_U: u8 = 0x4B;
+    pub const I32_GE_U: u8 = 0x4F;
+    pub const I64_NE: u8 = 0x52;
+    pub const I32_ADD: u8 = 0x6A;
+    pub const I32_SUB: u8 = 0x6B;
+    pub const I32_MUL: u8 = 0x6C;
+    pub const I32_DIV_U: u8 = 0x6E;
+    pub const I32_REM_U: u8 = 0x70;
+    pub const I32_AND: u8 = 0x71;
+    pub const I32_OR: u8 = 0x72;
+    pub const I32_XOR: u8 = 0x73;
+    pub const I32_SHL: u8 = 0x74;
+    pub const I32_SHR_U: u8 = 0x76;
+    pub const I64_AND: u8 = 0x83;
+    pub const I64_XOR: u8 = 0x85;
+    pub const I64_SHL: u8 = 0x86;
+    pub const I64_ROTR: u8 = 0x8A;
+    pub const I64_EXTEND_I32_U: u8 = 0xAD;
+}
+
+/// Block type of a structured instruction that produces no value
+pub const EMPTY: u8 = 0x40;
+
+fn unsigned(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// The body of a single function
+pub struct Function {
+    /// number of extra i32 locals after the parameters
+    pub i32_locals: u32,
+    /// number of extra i64 locals after the i32 locals
+    pub i64_locals: u32,
+    code: Vec<u8>,
+}
+
+impl Function {
+    pub fn new(i32_locals: u32, i64_locals: u32) -> Function {
+        Function { i32_locals, i64_locals, code: Vec::new() }
+    }
+
+    /// Emit a bare opcode
+    pub fn op(&mut self, opcode: u8) -> &mut Function {
+        self.code.push(opcode);
+        self
+    }
+
+    /// Emit an opcode followed by an unsigned immediate, such as a local or
+    /// function index or a branch depth
+    pub fn op_index(&mut self, opcode: u8, index: u32) -> &mut Function {
+        self.code.push(opcode);
+        unsigned(&mut self.code, index as u64);
+        self
+    }
+
+    /// Emit a load or store with an alignment hint of 1 byte and a constant
+    /// offset
+    pub fn memory(&mut self, opcode: u8, offset: u32) -> &mut Function {
+        self.code.push(opcode);
+        unsigned(&mut self.code, 0);
+        unsigned(&mut self.code, offset as u64);
+        self
+    }
+
+    pub fn i32_const(&mut self, value: i32) -> &mut Function {
+        self.code.push(op::I32_CONST);
+        signed(&mut self.code, value as i64);
+        self
+    }
+
+    pub fn i64_const(&mut self, value: i64) -> &mut Function {
+        self.code.push(op::I64_CONST);
+        signed(&mut self.code, value);
+        self
+    }
+
+    /// Start a `block`, `loop` or `if` with no result
+    pub fn structured(&mut self, opcode: u8) -> &mut Function {
+        self.code.push(opcode);
+        self.code.push(EMPTY);
+        self
+    }
+
+    /// `br_table` branching to `labels[n]`, or `default` if `n` is out of
+    /// range
+    pub fn br_table(&mut self, labels: &[u32], default: u32) -> &mut Function {
+        self.code.push(op::BR_TABLE);
+        unsigned(&mut self.code, labels.len() as u64);
+        for &label in labels.iter() {
+            unsigned(&mut self.code, label as u64);
+        }
+        unsigned(&mut self.code, default as u64);
+        self
+    }
+
+    /// `call_indirect` through table 0 with the given type
+    pub fn call_indirect(&mut self, type_index: u32) -> &mut Function {
+        self.code.push(op::CALL_INDIRECT);
+        unsigned(&mut self.code, type_index as u64);
+        self.code.push(0);
+        self
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let groups = (self.i32_locals > 0) as u64 + (self.i64_locals > 0) as u64;
+        unsigned(&mut body, groups);
+        if self.i32_locals > 0 {
+            unsigned(&mut body, self.i32_locals as u64);
+            body.push(I32);
+        }
+        if self.i64_locals > 0 {
+            unsigned(&mut body, self.i64_locals as u64);
+            body.push(I64);
+        }
+        body.extend_from_slice(&self.code);
+        body.push(op::END);
+        body
+    }
+}
+
+/// A module with one exported memory, one function table and a list of
+/// functions, some of which are exported
+pub struct Module {
+    types: Vec<(Vec<u8>, Vec<u8>)>,
+    functions: Vec<(u32, Function)>,
+    exports: Vec<(String, u32)>,
+    /// functions placed in the table, starting at index 1
+    table: Vec<u32>,
+    data: Vec<(u32, Vec<u8>)>,
+    memory_pages: u32,
+}
+
+impl Module {
+    pub fn new(memory_pages: u32) -> Module {
+        Module {
+            types: Vec::new(),
+            functions: Vec::new(),
+            exports: Vec::new(),
+            table: Vec::new(),
+            data: Vec::new(),
+            memory_pages,
+        }
+    }
+
+    /// Add a function type and return its index
+    pub fn add_type(&mut self, params: &[u8], results: &[u8]) -> u32 {
+        let signature = (params.to_vec(), results.to_vec());
+        if let Some(index) = self.types.iter().position(|t| *t == signature) {
+            return index as u32;
+        }
+        self.types.push(signature);
+        self.types.len() as u32 - 1
+    }
+
+    /// Add a function and return its index
+    pub fn add_function(&mut self, type_index: u32, function: Function) -> u32 {
+        self.functions.push((type_index, function));
+        self.functions.len() as u32 - 1
+    }
+
+    pub fn export(&mut self, name: &str, function: u32) {
+        self.exports.push((name.to_string(), function));
+    }
+
+    /// Put a function in the table and return its table index
+    pub fn add_to_table(&mut self, function: u32) -> u32 {
+        self.table.push(function);
+        self.table.len() as u32
+    }
+
+    /// Initialize memory at `offset` with `bytes`
+    pub fn add_data(&mut self, offset: u32, bytes: Vec<u8>) {
+        self.data.push((offset, bytes));
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend_from_slice(&[1, 0, 0, 0]);
+
+        let mut types = Vec::new();
+        unsigned(&mut types, self.types.len() as u64);
+        for (params, results) in self.types.iter() {
+            types.push(0x60);
+            unsigned(&mut types, params.len() as u64);
+            types.extend_from_slice(params);
+            unsigned(&mut types, results.len() as u64);
+            types.extend_from_slice(results);
+        }
+        section(&mut out, 1, &types);
+
+        let mut functions = Vec::new();
+        unsigned(&mut functions, self.functions.len() as u64);
+        for &(type_index, _) in self.functions.iter() {
+            unsigned(&mut functions, type_index as u64);
+        }
+        section(&mut out, 3, &functions);
+
+        // funcref table large enough for index 0 to stay empty
+        let mut table = vec![1, 0x70, 0x00];
+        unsigned(&mut table, self.table.len() as u64 + 1);
+        section(&mut out, 4, &table);
+
+        let mut memory = vec![1, 0x00];
+        unsigned(&mut memory, self.memory_pages as u64);
+        section(&mut out, 5, &memory);
+
+        let mut exports = Vec::new();
+        unsigned(&mut exports, self.exports.len() as u64 + 1);
+        for (name, function) in self.exports.iter() {
+            name_bytes(&mut exports, name);
+            exports.push(0x00);
+            unsigned(&mut exports, *function as u64);
+        }
+        name_bytes(&mut exports, "memory");
+        exports.push(0x02);
+        exports.push(0);
+        section(&mut out, 7, &exports);
+
+        let mut elements = vec![1, 0x00];
+        elements.push(op::I32_CONST);
+        signed(&mut elements, 1);
+        elements.push(op::END);
+        unsigned(&mut elements, self.table.len() as u64);
+        for &function in self.table.iter() {
+            unsigned(&mut elements, function as u64);
+        }
+        section(&mut out, 9, &elements);
+
+        let mut code = Vec::new();
+        unsigned(&mut code, self.functions.len() as u64);
+        for (_, function) in self.functions.iter() {
+            let body = function.encode();
+            unsigned(&mut code, body.len() as u64);
+            code.extend_from_slice(&body);
+        }
+        section(&mut out, 10, &code);
+
+        let mut data = Vec::new();
+        unsigned(&mut data, self.data.len() as u64);
+        for (offset, bytes) in self.data.iter() {
+            data.push(0x00);
+            data.push(op::I32_CONST);
+            signed(&mut data, *offset as i64);
+            data.push(op::END);
+            unsigned(&mut data, bytes.len() as u64);
+            data.extend_from_slice(bytes);
+        }
+        section(&mut out, 11, &data);
+        out
+    }
+}
+
+fn name_bytes(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    unsigned(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let mut out = Vec::new();
+        unsigned(&mut out, 624485);
+        assert_eq!(out, vec![0xE5, 0x8E, 0x26]);
+
+        let mut out = Vec::new();
+        signed(&mut out, -123456);
+        assert_eq!(out, vec![0xC0, 0xBB, 0x78]);
+
+        let mut out = Vec::new();
+        signed(&mut out, 64);
+        assert_eq!(out, vec![0xC0, 0x00]);
+    }
+
+    #[test]
+    fn empty_module() {
+        let module = Module::new(1).encode();
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+    }
+}
@@ -0,0 +1,359 @@
+//! Just enough of WebSocket (RFC 6455) over `std::net` for netplay: the
+//! opening handshake on both ends, binary messages, pings and closing. No
+//! extensions or TLS, so a peer on the internet needs a proxy in front.
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hash::sha1;
+use netplay::Transport;
+use rng::Rng;
+
+/// Appended to the client's key to work out the server's reply
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message accepted, well over anything netplay sends
+const MAX_MESSAGE: usize = 1 << 20;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+pub struct WebSocket {
+    stream: TcpStream,
+    /// clients mask what they send, servers don't
+    client: bool,
+    /// bytes received but not handled yet
+    buffer: Vec<u8>,
+    /// payload of a message split over several frames so far
+    fragments: Vec<u8>,
+    rng: Rng,
+    closed: bool,
+}
+
+impl WebSocket {
+    /// Connect to a server at `address`, such as `"localhost:8080"`, and
+    /// ask for `path`
+    pub fn connect(address: &str, path: &str) -> Result<WebSocket, String> {
+        let stream = TcpStream::connect(address).map_err(|e| format!("couldn't connect to {}: {}", address, e))?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0);
+        let mut socket = WebSocket::new(stream, true, Rng::new(nanos));
+        let key: Vec<u8> = (0..16).map(|_| socket.rng.next_u8()).collect();
+        let key = base64(&key);
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, address, key
+        );
+        socket.write(request.as_bytes())?;
+
+        let response = socket.read_head()?;
+        let mut lines = response.lines();
+        let status = lines.next().unwrap_or("");
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(format!("the server refused the connection: {}", status));
+        }
+        if header(&response, "sec-websocket-accept") != Some(accept_key(&key)) {
+            return Err("the server's handshake is invalid".to_string());
+        }
+        socket.start()?;
+        Ok(socket)
+    }
+
+    /// Take the server's side of a connection accepted from a
+    /// `TcpListener`
+    pub fn accept(stream: TcpStream) -> Result<WebSocket, String> {
+        let mut socket = WebSocket::new(stream, false, Rng::new(0));
+        let request = socket.read_head()?;
+        let key = match header(&request, "sec-websocket-key") {
+            Some(key) if header(&request, "upgrade").map(|value| value.eq_ignore_ascii_case("websocket")) == Some(true) => key,
+            _ => {
+                socket.write(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+                return Err("not a WebSocket request".to_string());
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        socket.write(response.as_bytes())?;
+        socket.start()?;
+        Ok(socket)
+    }
+
+    fn new(stream: TcpStream, client: bool, rng: Rng) -> WebSocket {
+        WebSocket { stream, client, buffer: Vec::new(), fragments: Vec::new(), rng, closed: false }
+    }
+
+    /// Read the HTTP request or response up to the blank line, leaving
+    /// anything after it in the buffer
+    fn read_head(&mut self) -> Result<String, String> {
+        let mut chunk = [0; 1024];
+        loop {
+            if let Some(end) = self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+                self.buffer.drain(..end + 4);
+                return Ok(head);
+            }
+            if self.buffer.len() > 16 * 1024 {
+                return Err("handshake too long".to_string());
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err("connection closed during the handshake".to_string()),
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+
+    /// After the handshake, messages are sent right away and received
+    /// without blocking
+    fn start(&mut self) -> Result<(), String> {
+        self.stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        self.stream.set_nonblocking(true).map_err(|e| e.to_string())
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) -> Result<(), String> {
+        while !bytes.is_empty() {
+            match self.stream.write(bytes) {
+                Ok(0) => return Err("connection closed".to_string()),
+                Ok(n) => bytes = &bytes[n..],
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => thread::yield_now(),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<(), String> {
+        let mut frame = vec![0x80 | opcode];
+        let mask_bit = if self.client { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            frame.push(mask_bit | payload.len() as u8);
+        } else if payload.len() <= 0xFFFF {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        } else {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+        if self.client {
+            let mask: Vec<u8> = (0..4).map(|_| self.rng.next_u8()).collect();
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+        self.write(&frame)
+    }
+
+    /// Send `payload` as a binary message
+    pub fn send(&mut self, payload: &[u8]) -> Result<(), String> {
+        if self.closed {
+            return Err("connection closed".to_string());
+        }
+        self.send_frame(BINARY, payload)
+    }
+
+    /// The next text or binary message, or `None` if a whole one hasn't
+    /// arrived yet. Answers pings on the way
+    pub fn receive(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let mut chunk = [0; 4096];
+        while !self.closed {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    break;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+
+        while let Some((fin, opcode, payload)) = self.next_frame()? {
+            match opcode {
+                PING => self.send_frame(PONG, &payload)?,
+                PONG => {}
+                CLOSE => {
+                    if !self.closed {
+                        // echo the close, the other end is waiting for it
+                        let _ = self.send_frame(CLOSE, &payload);
+                        self.closed = true;
+                    }
+                    return Err("connection closed by the other end".to_string());
+                }
+                TEXT | BINARY | CONTINUATION => {
+                    self.fragments.extend_from_slice(&payload);
+                    if self.fragments.len() > MAX_MESSAGE {
+                        return Err("message too long".to_string());
+                    }
+                    if fin {
+                        return Ok(Some(self.fragments.split_off(0)));
+                    }
+                }
+                _ => return Err(format!("unknown WebSocket opcode {:#x}", opcode)),
+            }
+        }
+        if self.closed {
+            return Err("connection closed".to_string());
+        }
+        Ok(None)
+    }
+
+    /// Take the first whole frame from the buffer, unmasked
+    fn next_frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>, String> {
+        if self.buffer.len() < 2 {
+            return Ok(None);
+        }
+        let fin = self.buffer[0] & 0x80 != 0;
+        let opcode = self.buffer[0] & 0x0F;
+        let masked = self.buffer[1] & 0x80 != 0;
+        let (len, mut at) = match self.buffer[1] & 0x7F {
+            126 if self.buffer.len() >= 4 => (u16::from_be_bytes([self.buffer[2], self.buffer[3]]) as usize, 4),
+            127 if self.buffer.len() >= 10 => {
+                let mut len = [0; 8];
+                len.copy_from_slice(&self.buffer[2..10]);
+                (u64::from_be_bytes(len) as usize, 10)
+            }
+            126 | 127 => return Ok(None),
+            len => (len as usize, 2),
+        };
+        if len > MAX_MESSAGE {
+            return Err("message too long".to_string());
+        }
+        let mut mask = [0; 4];
+        if masked {
+            if self.buffer.len() < at + 4 {
+                return Ok(None);
+            }
+            mask.copy_from_slice(&self.buffer[at..at + 4]);
+            at += 4;
+        }
+        if self.buffer.len() < at + len {
+            return Ok(None);
+        }
+        let payload = self.buffer[at..at + len].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]).collect();
+        self.buffer.drain(..at + len);
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    /// Tell the other end the connection is over
+    pub fn close(&mut self) {
+        if !self.closed {
+            let _ = self.send_frame(CLOSE, &[0x03, 0xE8]);
+            self.closed = true;
+        }
+    }
+}
+
+impl Transport for WebSocket {
+    fn send(&mut self, packet: &[u8]) -> Result<(), String> {
+        WebSocket::send(self, packet)
+    }
+
+    fn receive(&mut self) -> Result<Option<Vec<u8>>, String> {
+        WebSocket::receive(self)
+    }
+}
+
+/// Value of the header `name`, in any case, in an HTTP head
+fn header(head: &str, name: &str) -> Option<String> {
+    head.lines().skip(1).filter_map(|line| {
+        let colon = line.find(':')?;
+        if line[..colon].trim().eq_ignore_ascii_case(name) {
+            Some(line[colon + 1..].trim().to_string())
+        } else {
+            None
+        }
+    }).next()
+}
+
+/// The `Sec-WebSocket-Accept` a server replies to `key` with
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    fn wait_for(socket: &mut WebSocket) -> Vec<u8> {
+        let start = Instant::now();
+        loop {
+            if let Some(message) = socket.receive().unwrap() {
+                return message;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn handshake_keys() {
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        // the example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut socket = WebSocket::accept(listener.accept().unwrap().0).unwrap();
+            for _ in 0..3 {
+                let message = wait_for(&mut socket);
+                socket.send(&message).unwrap();
+            }
+        });
+
+        let mut client = WebSocket::connect(&address, "/").unwrap();
+        // lengths that fit in the first byte, in 16 bits and in 64 bits
+        for &len in [11, 300, 70_000].iter() {
+            let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            client.send(&message).unwrap();
+            assert_eq!(wait_for(&mut client), message);
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refuses_plain_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(WebSocket::accept(listener.accept().unwrap().0).is_err());
+        assert!(client.join().unwrap().starts_with("HTTP/1.1 400"));
+    }
+}
@@ -0,0 +1,74 @@
+//! Plays recorded movies of ROMs, input scripts in `tests/movies/`, and
+//! checks which of the built-in achievements unlock on which frames.
+extern crate chip8;
+
+use std::fs;
+
+use chip8::achievements::{Achievements, Library};
+use chip8::input::InputScript;
+use chip8::runner::Runner;
+
+/// Seed the movies were recorded with
+const SEED: u32 = 1;
+
+/// Run `rom` for `frames` frames with `movie`, returning the id of each
+/// achievement unlocked and the frame it unlocked on
+fn play(rom: &str, movie: Option<&str>, frames: u64) -> Vec<(u64, String)> {
+    let rom = fs::read(format!("roms/{}", rom)).unwrap();
+    let mut runner = Runner::new(&rom, SEED).unwrap();
+    if let Some(movie) = movie {
+        let text = fs::read_to_string(format!("tests/movies/{}", movie)).unwrap();
+        runner.set_input(InputScript::parse(&text).unwrap());
+    }
+    let sha1 = runner.cpu.rom_sha1().unwrap().to_string();
+    let mut achievements = Achievements::new(Library::builtin().for_rom(&sha1).unwrap().to_vec());
+    let mut unlocked = Vec::new();
+    for _ in 0..frames {
+        runner.run_frame();
+        for achievement in achievements.update(&runner.cpu) {
+            unlocked.push((runner.frame(), achievement.id));
+        }
+    }
+    unlocked
+}
+
+fn ids(unlocked: &[(u64, &str)]) -> Vec<(u64, String)> {
+    unlocked.iter().map(|&(frame, id)| (frame, id.to_string())).collect()
+}
+
+#[test]
+fn brix_movie() {
+    // the paddle keeps up with the ball, so no ball is lost
+    assert_eq!(play("BRIX", Some("BRIX.txt"), 900), ids(&[
+        (156, "first-brick"),
+        (484, "no-miss"),
+        (600, "steady-hands"),
+    ]));
+}
+
+#[test]
+fn pong_movie() {
+    // both paddles return the ball until the right one stops
+    assert_eq!(play("PONG", Some("PONG.txt"), 1400), ids(&[
+        (1201, "rally"),
+        (1359, "first-point"),
+    ]));
+}
+
+#[test]
+fn pong_idle() {
+    // the paddles never move, so the first serve is missed and points keep
+    // being scored too often for a rally
+    assert_eq!(play("PONG", None, 1400), ids(&[(195, "first-point")]));
+}
+
+#[test]
+fn brix_idle() {
+    // the paddle never moves, so balls are lost but bounce around breaking
+    // bricks in between
+    assert_eq!(play("BRIX", None, 1500), ids(&[
+        (155, "first-brick"),
+        (807, "ten-bricks"),
+        (1000, "game-over"),
+    ]));
+}
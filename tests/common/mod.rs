@@ -0,0 +1,13 @@
+//! Helpers shared by the integration tests
+
+/// Expected and actual screens side by side, with differing rows marked
+pub fn screen_diff(expected: &[String], actual: &[String]) -> String {
+    let mut text = format!("{:<64}   {}\n", "expected", "actual");
+    for row in 0..expected.len().max(actual.len()) {
+        let left = expected.get(row).map(|s| s.as_str()).unwrap_or("");
+        let right = actual.get(row).map(|s| s.as_str()).unwrap_or("");
+        let marker = if left == right { ' ' } else { '<' };
+        text += &format!("{:<64} {} {}\n", left, marker, right);
+    }
+    text
+}
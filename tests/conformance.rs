@@ -0,0 +1,95 @@
+//! Spec conformance tests that run small test ROMs end to end.
+//!
+//! The ROMs live in `tests/roms/` as annotated listings, one instruction or
+//! run of data bytes per line prefixed with its address. Each ROM draws a
+//! tick or a cross per check into a grid of 8x6 cells, and the tests assert
+//! that the final screen shows only ticks.
+extern crate chip8;
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use chip8::input::InputScript;
+use chip8::runner::Runner;
+use chip8::screen::Screen;
+
+const PASS_GLYPH: [u8; 5] = [0x02, 0x04, 0x88, 0x50, 0x20];
+
+/// Turn a listing into ROM bytes, checking that the addresses line up
+fn assemble(name: &str) -> Vec<u8> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms").join(name);
+    let listing = fs::read_to_string(&path).unwrap();
+    let mut rom = Vec::new();
+    for (lineno, line) in listing.lines().enumerate() {
+        let line = line.split(';').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let addr = usize::from_str_radix(parts.next().unwrap(), 16).unwrap();
+        assert_eq!(addr, 0x200 + rom.len(), "{} line {}: address out of sequence", name, lineno + 1);
+        for word in parts.next().unwrap().split_whitespace() {
+            assert!(word.len() % 2 == 0, "{} line {}: odd number of digits", name, lineno + 1);
+            for i in (0..word.len()).step_by(2) {
+                rom.push(u8::from_str_radix(&word[i..i + 2], 16).unwrap());
+            }
+        }
+    }
+    rom
+}
+
+/// A screen showing the pass glyph in the first `cells` cells
+fn all_passed(cells: usize) -> Screen {
+    let mut screen = Screen::new();
+    for cell in 0..cells {
+        screen.draw_sprite(cell % 8 * 8, cell / 8 * 6, &PASS_GLYPH);
+    }
+    screen
+}
+
+fn assert_screen(name: &str, expected: &Screen, actual: &Screen) {
+    let lines = |screen: &Screen| screen.to_string().lines().map(String::from).collect::<Vec<_>>();
+    if expected.rows() != actual.rows() {
+        panic!("{}: unexpected screen\n{}", name, common::screen_diff(&lines(expected), &lines(actual)));
+    }
+}
+
+fn run(name: &str, input: InputScript) -> Runner {
+    let mut runner = Runner::new(&assemble(name), 1).unwrap();
+    runner.set_input(input);
+    runner.run_frames(120);
+    runner
+}
+
+#[test]
+fn opcodes() {
+    let runner = run("opcodes.txt", InputScript::new());
+    assert_screen("opcodes", &all_passed(38), &runner.cpu.screen);
+}
+
+#[test]
+fn flags() {
+    let runner = run("flags.txt", InputScript::new());
+    assert_screen("flags", &all_passed(10), &runner.cpu.screen);
+}
+
+#[test]
+fn quirks() {
+    let runner = run("quirks.txt", InputScript::new());
+    assert_screen("quirks", &all_passed(6), &runner.cpu.screen);
+}
+
+#[test]
+fn keypad() {
+    let input = InputScript::parse("10 b down\n30 b up").unwrap();
+    let runner = run("keypad.txt", input);
+
+    // the digit B from the built in font, followed by two ticks
+    let mut expected = Screen::new();
+    expected.draw_sprite(0, 0, &[0xE0, 0x90, 0xE0, 0x90, 0xE0]);
+    expected.draw_sprite(8, 0, &PASS_GLYPH);
+    expected.draw_sprite(16, 0, &PASS_GLYPH);
+    assert_screen("keypad", &expected, &runner.cpu.screen);
+}
@@ -0,0 +1,42 @@
+//! Differential test of `CPU` against the reference model in
+//! `tests/reference`, run on deterministic pseudo-random inputs so it works
+//! without a fuzzer. `fuzz/` runs the same check under libFuzzer.
+extern crate chip8;
+
+mod reference;
+
+use chip8::rng::Rng;
+
+fn random_input(rng: &mut Rng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| rng.next_u8()).collect()
+}
+
+#[test]
+fn random_programs() {
+    let mut rng = Rng::new(0xF022);
+    for case in 0..2000 {
+        let len = 80 + case % 400;
+        reference::check(&random_input(&mut rng, len));
+    }
+}
+
+#[test]
+fn stack_edges() {
+    // return from an empty stack, then call 17 times in a row
+    let mut input = vec![0; reference::STATE_BYTES];
+    input.extend_from_slice(&[0x00, 0xEE]);
+    for _ in 0..17 {
+        input.extend_from_slice(&[0x22, 0x00]);
+    }
+    reference::check(&input);
+}
+
+#[test]
+fn addresses_wrap() {
+    // I = 0xFFE, draw 15 rows, store and load every register, skip past
+    // the end of memory
+    let mut input = vec![0; reference::STATE_BYTES];
+    input.extend_from_slice(&[0xAF, 0xFE, 0xD0, 0x1F, 0xFF, 0x55, 0xAF, 0xFE, 0xFF, 0x65,
+                              0xAF, 0xF0, 0xFF, 0x1E, 0xFF, 0x33, 0x30, 0x00]);
+    reference::check(&input);
+}
@@ -0,0 +1,41 @@
+//! Runs modules from `chip8::recompiler` with the wasmi interpreter, so the
+//! recompiler can be tested and benchmarked without a browser
+use wasmi::{ImportsBuilder, MemoryRef, Module, ModuleInstance, ModuleRef, NopExternals, RuntimeValue};
+
+use chip8::recompiler::Engine;
+
+pub struct Wasmi {
+    instance: ModuleRef,
+    memory: MemoryRef,
+}
+
+impl Wasmi {
+    pub fn new(wasm: &[u8]) -> Wasmi {
+        let module = Module::from_buffer(wasm).expect("invalid module");
+        let instance = ModuleInstance::new(&module, &ImportsBuilder::default())
+            .expect("failed to instantiate module")
+            .assert_no_start();
+        let memory = instance.export_by_name("memory")
+            .and_then(|export| export.as_memory().cloned())
+            .expect("module has no memory");
+        Wasmi { instance, memory }
+    }
+}
+
+impl Engine for Wasmi {
+    fn write(&mut self, offset: usize, bytes: &[u8]) {
+        self.memory.set(offset as u32, bytes).unwrap();
+    }
+
+    fn read(&mut self, offset: usize, len: usize) -> Vec<u8> {
+        self.memory.get(offset as u32, len).unwrap()
+    }
+
+    fn run(&mut self, budget: u32) -> u32 {
+        let args = [RuntimeValue::I32(budget as i32)];
+        match self.instance.invoke_export("run", &args, &mut NopExternals).unwrap() {
+            Some(RuntimeValue::I32(executed)) => executed as u32,
+            other => panic!("run returned {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Golden-image regression tests for every ROM in `roms/`.
+//!
+//! Each ROM is run for a fixed number of frames with a deterministic random
+//! seed and keypress schedule. At every checkpoint the screen and full
+//! machine state are hashed and compared against `tests/golden/<ROM>.txt`.
+//! The schedule defaults to tapping every key in turn and can be replaced
+//! per ROM with a `tests/golden/<ROM>.input` script.
+//!
+//! ```text
+//! cargo test --test golden                 # check every ROM
+//! cargo test --test golden -- BRIX PONG    # check some ROMs
+//! cargo test --test golden -- --bless      # update the golden files
+//! ```
+extern crate chip8;
+
+mod common;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use chip8::input::InputScript;
+use chip8::runner::Runner;
+
+/// Frames at which the machine state is checked
+const CHECKPOINTS: [u64; 4] = [60, 180, 600, 1200];
+const SEED: u32 = 0xC8;
+
+/// A recorded machine state at a checkpoint
+#[derive(PartialEq)]
+struct Checkpoint {
+    frame: u64,
+    screen_hash: u64,
+    state_hash: u64,
+    /// the screen drawn with one line of `#` and `.` per row
+    screen: Vec<String>,
+}
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Tap each key in turn for 5 frames, one key every 20 frames
+fn default_input() -> InputScript {
+    let mut script = InputScript::new();
+    let mut frame = 20;
+    while frame < CHECKPOINTS[CHECKPOINTS.len() - 1] {
+        let key = ((frame / 20 - 1) % 16) as u8;
+        script.push(frame, key, true);
+        script.push(frame + 5, key, false);
+        frame += 20;
+    }
+    script
+}
+
+fn run(name: &str) -> Vec<Checkpoint> {
+    let rom = fs::read(root().join("roms").join(name)).unwrap();
+    let input_path = root().join("tests/golden").join(format!("{}.input", name));
+    let input = match fs::read_to_string(&input_path) {
+        Ok(text) => InputScript::parse(&text).unwrap(),
+        Err(_) => default_input()
+    };
+
+    let mut runner = Runner::new(&rom, SEED).unwrap();
+    runner.set_input(input);
+    CHECKPOINTS.iter().map(|&frame| {
+        runner.run_frames(frame - runner.frame());
+        let screen = &runner.cpu.screen;
+        Checkpoint {
+            frame,
+            screen_hash: screen.hash(),
+            state_hash: runner.cpu.state_hash(),
+            screen: screen.to_string().lines().map(String::from).collect(),
+        }
+    }).collect()
+}
+
+fn format_golden(checkpoints: &[Checkpoint]) -> String {
+    let mut text = String::new();
+    for checkpoint in checkpoints {
+        text += &format!(
+            "frame {} screen {:016x} state {:016x}\n",
+            checkpoint.frame,
+            checkpoint.screen_hash,
+            checkpoint.state_hash);
+        for line in checkpoint.screen.iter() {
+            text += line;
+            text += "\n";
+        }
+    }
+    text
+}
+
+fn parse_golden(text: &str) -> Result<Vec<Checkpoint>, String> {
+    let mut checkpoints = Vec::new();
+    let mut lines = text.lines();
+    while let Some(header) = lines.next() {
+        let parts: Vec<&str> = header.split_whitespace().collect();
+        if parts.len() != 6 || parts[0] != "frame" || parts[2] != "screen" || parts[4] != "state" {
+            return Err(format!("malformed checkpoint header `{}`", header));
+        }
+        let number = |s: &str, radix| u64::from_str_radix(s, radix)
+            .map_err(|e| format!("`{}`: {}", s, e));
+        checkpoints.push(Checkpoint {
+            frame: number(parts[1], 10)?,
+            screen_hash: number(parts[3], 16)?,
+            state_hash: number(parts[5], 16)?,
+            screen: lines.by_ref().take(32).map(String::from).collect(),
+        });
+    }
+    Ok(checkpoints)
+}
+
+/// Compare a ROM against its golden file, returning a failure report
+fn check(name: &str, actual: &[Checkpoint], golden: &Path) -> Result<(), String> {
+    let text = fs::read_to_string(golden)
+        .map_err(|e| format!("{}: {} (run with --bless to create it)", golden.display(), e))?;
+    let expected = parse_golden(&text).map_err(|e| format!("{}: {}", golden.display(), e))?;
+
+    for (i, actual) in actual.iter().enumerate() {
+        let expected = match expected.get(i) {
+            Some(expected) if expected.frame == actual.frame => expected,
+            _ => return Err(format!("{}: no checkpoint for frame {}", name, actual.frame))
+        };
+        if expected == actual {
+            continue;
+        }
+
+        let mut report = format!("{}: mismatch at frame {}\n", name, actual.frame);
+        if expected.screen_hash != actual.screen_hash || expected.screen != actual.screen {
+            report += &format!(
+                "  screen hash expected {:016x}, got {:016x}\n",
+                expected.screen_hash,
+                actual.screen_hash);
+            report += &common::screen_diff(&expected.screen, &actual.screen);
+        }
+        if expected.state_hash != actual.state_hash {
+            report += &format!(
+                "  state hash expected {:016x}, got {:016x}\n",
+                expected.state_hash,
+                actual.state_hash);
+        }
+        return Err(report);
+    }
+    if expected.len() != actual.len() {
+        return Err(format!("{}: golden file has extra checkpoints", name));
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut bless = false;
+    let mut filter = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bless" => bless = true,
+            // flags passed through by `cargo test`
+            _ if arg.starts_with("--") => {},
+            _ => filter.push(arg)
+        }
+    }
+
+    let mut roms: Vec<String> = fs::read_dir(root().join("roms")).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| filter.is_empty() || filter.contains(name))
+        .collect();
+    roms.sort();
+
+    let mut failures = 0;
+    for name in roms.iter() {
+        let actual = run(name);
+        let golden = root().join("tests/golden").join(format!("{}.txt", name));
+        if bless {
+            fs::write(&golden, format_golden(&actual)).unwrap();
+            println!("blessed {}", name);
+            continue;
+        }
+        match check(name, &actual, &golden) {
+            Ok(()) => println!("{} ... ok", name),
+            Err(report) => {
+                println!("{} ... FAILED\n{}", name, report);
+                failures += 1;
+            }
+        }
+    }
+
+    println!("\n{} ROMs, {} failed", roms.len(), failures);
+    if failures > 0 {
+        process::exit(1);
+    }
+}
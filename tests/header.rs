@@ -0,0 +1,53 @@
+//! Checks that `include/chip8.h` matches the C API in `src/capi.rs`, and
+//! that it compiles if a C compiler is installed.
+//!
+//! ```text
+//! cargo test --test header                 # check the header
+//! cargo test --test header -- --bless      # regenerate it
+//! ```
+extern crate cbindgen;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn generate() -> String {
+    let config = cbindgen::Config::from_file(root().join("cbindgen.toml")).unwrap();
+    let bindings = cbindgen::Builder::new()
+        .with_crate(root())
+        .with_config(config)
+        .generate()
+        .unwrap();
+    let mut header = Vec::new();
+    bindings.write(&mut header);
+    String::from_utf8(header).unwrap()
+}
+
+fn main() {
+    let bless = env::args().any(|arg| arg == "--bless");
+    let path = root().join("include/chip8.h");
+    let header = generate();
+    if bless {
+        fs::write(&path, &header).unwrap();
+        println!("blessed {}", path.display());
+        return;
+    }
+
+    if fs::read_to_string(&path).ok().as_deref() != Some(header.as_str()) {
+        println!("{} is out of date, run with --bless to update it", path.display());
+        process::exit(1);
+    }
+    match Command::new("cc").arg("-fsyntax-only").arg("-Wall").arg("-Werror").arg(&path).status() {
+        Ok(status) if !status.success() => {
+            println!("{} doesn't compile", path.display());
+            process::exit(1);
+        },
+        Ok(_) => println!("header ... ok"),
+        Err(_) => println!("header ... ok, not compiled since there's no C compiler"),
+    }
+}
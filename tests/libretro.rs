@@ -0,0 +1,262 @@
+//! Drives the libretro core the way a front-end such as RetroArch does:
+//! registering callbacks, declaring and changing core options, loading a
+//! game, pressing buttons and collecting the video and audio it sends.
+extern crate chip8;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use chip8::cpu::State;
+use chip8::libretro::*;
+
+/// The core is a global, so tests take turns with it
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Option keys declared by the core, and the values chosen for them
+static OPTIONS: Mutex<Vec<(String, CString)>> = Mutex::new(Vec::new());
+static OPTIONS_UPDATED: AtomicBool = AtomicBool::new(false);
+static VIDEO: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+static AUDIO: Mutex<Vec<i16>> = Mutex::new(Vec::new());
+/// joypad buttons held, a bit per id
+static JOYPAD: AtomicUsize = AtomicUsize::new(0);
+/// libretro keyboard code of the key held, or 0
+static KEYBOARD: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    unsafe {
+        match cmd {
+            ENVIRONMENT_SET_PIXEL_FORMAT => *(data as *const c_uint) == PIXEL_FORMAT_XRGB8888,
+            ENVIRONMENT_SET_VARIABLES => {
+                let mut options = OPTIONS.lock().unwrap();
+                let mut variable = data as *const Variable;
+                while !(*variable).key.is_null() {
+                    let key = CStr::from_ptr((*variable).key).to_str().unwrap().to_string();
+                    let value = CStr::from_ptr((*variable).value).to_str().unwrap();
+                    // the default is the first value
+                    let default = value.split("; ").nth(1).unwrap().split('|').next().unwrap();
+                    options.push((key, CString::new(default).unwrap()));
+                    variable = variable.add(1);
+                }
+                true
+            },
+            ENVIRONMENT_GET_VARIABLE => {
+                let variable = &mut *(data as *mut Variable);
+                let key = CStr::from_ptr(variable.key).to_str().unwrap();
+                let options = OPTIONS.lock().unwrap();
+                match options.iter().find(|option| option.0 == key) {
+                    Some(option) => {
+                        variable.value = option.1.as_ptr();
+                        true
+                    },
+                    None => false,
+                }
+            },
+            ENVIRONMENT_GET_VARIABLE_UPDATE => {
+                *(data as *mut bool) = OPTIONS_UPDATED.swap(false, Ordering::SeqCst);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!((width, height, pitch), (64, 32, 64 * 4));
+    let pixels = unsafe { slice::from_raw_parts(data as *const u32, 64 * 32) };
+    *VIDEO.lock().unwrap() = pixels.to_vec();
+}
+
+extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+    let samples = unsafe { slice::from_raw_parts(data, frames * 2) };
+    *AUDIO.lock().unwrap() = samples.to_vec();
+    frames
+}
+
+extern "C" fn input_poll() {}
+
+extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    let held = match device {
+        DEVICE_JOYPAD => JOYPAD.load(Ordering::SeqCst) >> id & 1 == 1,
+        DEVICE_KEYBOARD => KEYBOARD.load(Ordering::SeqCst) == id as usize,
+        _ => false,
+    };
+    (port == 0 && held) as i16
+}
+
+fn set_option(key: &str, value: &str) {
+    let mut options = OPTIONS.lock().unwrap();
+    let option = options.iter_mut().find(|option| option.0 == key).expect("undeclared option");
+    option.1 = CString::new(value).unwrap();
+    OPTIONS_UPDATED.store(true, Ordering::SeqCst);
+}
+
+/// Start the core with a ROM loaded, the way front-ends do
+fn start(rom: &[u8]) -> MutexGuard<'static, ()> {
+    let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    OPTIONS.lock().unwrap().clear();
+    JOYPAD.store(0, Ordering::SeqCst);
+    KEYBOARD.store(0, Ordering::SeqCst);
+
+    assert_eq!(retro_api_version(), 1);
+    retro_set_environment(Some(environment));
+    retro_set_video_refresh(Some(video_refresh));
+    retro_set_audio_sample_batch(Some(audio_sample_batch));
+    retro_set_input_poll(Some(input_poll));
+    retro_set_input_state(Some(input_state));
+    retro_init();
+    let game = GameInfo {
+        path: ptr::null(),
+        data: rom.as_ptr() as *const c_void,
+        size: rom.len(),
+        meta: ptr::null(),
+    };
+    assert!(unsafe { retro_load_game(&game) });
+    lock
+}
+
+fn stop() {
+    retro_unload_game();
+    retro_deinit();
+}
+
+fn state() -> State {
+    let mut data = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(data.as_mut_ptr() as *mut c_void, data.len()) });
+    State::from_bytes(&data).unwrap()
+}
+
+#[test]
+fn system_info() {
+    let mut info = SystemInfo {
+        library_name: ptr::null(),
+        library_version: ptr::null(),
+        valid_extensions: ptr::null(),
+        need_fullpath: true,
+        block_extract: true,
+    };
+    unsafe {
+        retro_get_system_info(&mut info);
+        assert_eq!(CStr::from_ptr(info.library_name).to_str(), Ok("chip8"));
+        assert_eq!(CStr::from_ptr(info.valid_extensions as *const c_char).to_str(), Ok("ch8|c8|gif"));
+    }
+    assert!(!info.need_fullpath);
+}
+
+#[test]
+fn video() {
+    // draw the font's 0 in the top left corner
+    let _lock = start(&[0xD0, 0x15, 0x12, 0x02]);
+    retro_run();
+    {
+        let video = VIDEO.lock().unwrap();
+        assert_eq!(&video[..5], &[0, 0, 0, 0, 0xFF_FFFF]);
+    }
+
+    set_option("chip8_palette", "white on black");
+    retro_run();
+    assert_eq!(&VIDEO.lock().unwrap()[..5], &[0xFF_FFFF, 0xFF_FFFF, 0xFF_FFFF, 0xFF_FFFF, 0]);
+    stop();
+}
+
+#[test]
+fn audio() {
+    // silent after loading and resetting, until the sound timer is started
+    let _lock = start(&[0x12, 0x00]);
+    retro_run();
+    {
+        let audio = AUDIO.lock().unwrap();
+        assert_eq!(audio.len(), 735 * 2);
+        assert!(audio.iter().all(|&sample| sample == 0));
+    }
+    retro_reset();
+    retro_run();
+    assert!(AUDIO.lock().unwrap().iter().all(|&sample| sample == 0));
+
+    let mut beeping = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(beeping.as_mut_ptr() as *mut c_void, beeping.len()) });
+    beeping[4096 + 16 + 2 + 1] = 60;
+    assert!(unsafe { retro_unserialize(beeping.as_ptr() as *const c_void, beeping.len()) });
+    retro_run();
+    {
+        let audio = AUDIO.lock().unwrap();
+        assert!(audio.contains(&0x1000) && audio.contains(&-0x1000));
+    }
+    stop();
+}
+
+#[test]
+fn input() {
+    // V0 is incremented if key 5 is held when the ROM starts
+    let _lock = start(&[0x6E, 0x05, 0xEE, 0x9E, 0x12, 0x0A, 0x70, 0x01, 0x12, 0x0A, 0x12, 0x0A]);
+    set_option("chip8_speed", "5");
+    JOYPAD.store(1 << DEVICE_ID_JOYPAD_B, Ordering::SeqCst);
+    retro_run();
+    assert_eq!(state().v[0], 1);
+    assert!(state().keys[5]);
+
+    JOYPAD.store(1 << DEVICE_ID_JOYPAD_A, Ordering::SeqCst);
+    retro_reset();
+    retro_run();
+    assert_eq!(state().v[0], 0);
+
+    // W on a QWERTY keyboard
+    JOYPAD.store(0, Ordering::SeqCst);
+    KEYBOARD.store(b'w' as usize, Ordering::SeqCst);
+    retro_reset();
+    retro_run();
+    assert_eq!(state().v[0], 1);
+    stop();
+}
+
+#[test]
+fn options() {
+    // count instructions in V0
+    let _lock = start(&[0x70, 0x01, 0x12, 0x00]);
+    {
+        let options = OPTIONS.lock().unwrap();
+        let keys: Vec<&str> = options.iter().map(|option| option.0.as_str()).collect();
+        assert!(keys.contains(&"chip8_speed") && keys.contains(&"chip8_quirk_shift"));
+        assert!(options.iter().all(|option| option.1.to_str() == Ok("auto")));
+    }
+    retro_run();
+    assert_eq!(state().v[0], 5);
+
+    set_option("chip8_speed", "100");
+    retro_run();
+    assert_eq!(state().v[0], 55);
+    stop();
+}
+
+#[test]
+fn serialize() {
+    let _lock = start(&[0x70, 0x01, 0x12, 0x00]);
+    retro_run();
+    let mut saved = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(saved.as_mut_ptr() as *mut c_void, saved.len()) });
+    retro_run();
+    assert_eq!(state().v[0], 10);
+    assert!(unsafe { retro_unserialize(saved.as_ptr() as *const c_void, saved.len()) });
+    assert_eq!(state().v[0], 5);
+    assert!(!unsafe { retro_unserialize(saved.as_ptr() as *const c_void, 10) });
+    stop();
+}
+
+#[test]
+fn cheats() {
+    let _lock = start(&[0x12, 0x00]);
+    let code = CString::new("300:2A+V3:07").unwrap();
+    unsafe { retro_cheat_set(0, true, code.as_ptr()) };
+    retro_run();
+    assert_eq!(state().memory[0x300], 0x2A);
+    assert_eq!(state().v[3], 7);
+
+    retro_cheat_reset();
+    retro_reset();
+    retro_run();
+    assert_eq!(state().memory[0x300], 0);
+    stop();
+}
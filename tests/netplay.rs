@@ -0,0 +1,75 @@
+//! Two-player ROMs played over netplay, with each peer on its own thread as
+//! it would be on its own computer: `PONG2` over a WebSocket to a server
+//! on localhost, and `TANK` over the in-process loopback. Both peers must
+//! end up with the same machine.
+extern crate chip8;
+
+use std::fs;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use chip8::machine::Machine;
+use chip8::netplay::{loopback, Session, Transport};
+use chip8::websocket::WebSocket;
+
+const FRAMES: u64 = 600;
+const SEED: u32 = 0xC8;
+const INPUT_DELAY: u64 = 2;
+
+fn load(name: &str) -> Machine {
+    let rom = fs::read(format!("roms/{}", name)).unwrap();
+    let mut machine = Machine::new(SEED);
+    machine.load(&rom).unwrap();
+    machine
+}
+
+/// Play `FRAMES` frames, switching between holding each of `keys` and
+/// nothing. The session is returned rather than dropped, so the connection
+/// stays up until the other peer is done too
+fn play<T: Transport>(machine: Machine, transport: T, keys: [u8; 2], period: u64) -> Session<T> {
+    let mut session = Session::new(machine, transport, INPUT_DELAY);
+    while session.frame() < FRAMES {
+        let held = match session.frame() / period % 4 {
+            0 => 1 << keys[0],
+            2 => 1 << keys[1],
+            _ => 0,
+        };
+        if !session.advance(held).unwrap() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    // wait for the other peer's last keys
+    while session.confirmed() < FRAMES {
+        session.poll().unwrap();
+        thread::sleep(Duration::from_millis(1));
+    }
+    session
+}
+
+#[test]
+fn pong2_over_websocket() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    // the left paddle is on 1 and 4, the right one on C and D
+    let host = thread::spawn(move || {
+        let socket = WebSocket::accept(listener.accept().unwrap().0).unwrap();
+        play(load("PONG2"), socket, [0x1, 0x4], 7)
+    });
+    let guest = thread::spawn(move || {
+        let socket = WebSocket::connect(&address, "/PONG2").unwrap();
+        play(load("PONG2"), socket, [0xC, 0xD], 11)
+    });
+    let (host, guest) = (host.join().unwrap(), guest.join().unwrap());
+    assert_eq!(host.machine().cpu.state_hash(), guest.machine().cpu.state_hash());
+}
+
+#[test]
+fn tank_over_loopback() {
+    let (a, b) = loopback();
+    // each peer sticks to keys of its own
+    let first = thread::spawn(move || play(load("TANK"), a, [0x2, 0x6], 13));
+    let second = thread::spawn(move || play(load("TANK"), b, [0xE, 0xF], 5));
+    let (first, second) = (first.join().unwrap(), second.join().unwrap());
+    assert_eq!(first.machine().cpu.state_hash(), second.machine().cpu.state_hash());
+}
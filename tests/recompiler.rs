@@ -0,0 +1,105 @@
+//! Runs every ROM in `roms/` through `chip8::recompiler` and checks that
+//! the machine state matches the interpreter after every frame, using the
+//! same seed and keypress schedule as the golden tests. A self-modifying
+//! program checks that overwritten blocks fall back to the interpreter.
+extern crate chip8;
+extern crate wasmi;
+
+mod engine;
+
+use std::fs;
+use std::path::PathBuf;
+
+use chip8::cpu::CPU;
+use chip8::input::InputScript;
+use chip8::recompiler::{compile, run_cycles};
+use chip8::runner::Runner;
+
+use engine::Wasmi;
+
+const FRAMES: u64 = 600;
+const SEED: u32 = 0xC8;
+
+/// Tap each key in turn for 5 frames, one key every 20 frames
+fn input() -> InputScript {
+    let mut script = InputScript::new();
+    for frame in (20..FRAMES).step_by(20) {
+        let key = ((frame / 20 - 1) % 16) as u8;
+        script.push(frame, key, true);
+        script.push(frame + 5, key, false);
+    }
+    script
+}
+
+/// Run `rom` on the interpreter and the recompiled module side by side,
+/// panicking on the first frame where they differ
+fn compare(name: &str, rom: &[u8], frames: u64) {
+    let program = compile(rom).unwrap();
+    let mut engine = Wasmi::new(&program.wasm);
+
+    let mut interpreter = Runner::new(rom, SEED).unwrap();
+    interpreter.set_input(input());
+    let mut cpu = CPU::new();
+    cpu.seed(SEED);
+    cpu.reset();
+    cpu.load_rom(rom).unwrap();
+
+    for frame in 0..frames {
+        interpreter.run_frame();
+        // the runner applies input before running the frame's cycles
+        for key in 0..16 {
+            if interpreter.cpu.keypad.is_key_down(key) {
+                cpu.keypad.key_down(key);
+            } else {
+                cpu.keypad.key_up(key);
+            }
+        }
+        run_cycles(&mut cpu, &mut engine, interpreter.cpu.cycles_per_frame());
+        cpu.decrement_timers();
+
+        let expected = interpreter.cpu.state();
+        let actual = cpu.state();
+        if actual != expected {
+            panic!(
+                "{}: frame {} diverged from the interpreter\n\
+                 expected v={:?} i={:#x} pc={:#x} sp={}\n\
+                 actual   v={:?} i={:#x} pc={:#x} sp={}\n\
+                 memory equal: {}, screen equal: {}, rng equal: {}",
+                name, frame,
+                expected.v, expected.i, expected.pc, expected.sp,
+                actual.v, actual.i, actual.pc, actual.sp,
+                expected.memory == actual.memory,
+                expected.screen == actual.screen,
+                expected.rng == actual.rng);
+        }
+    }
+}
+
+#[test]
+fn bundled_roms() {
+    let roms = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("roms");
+    let mut names: Vec<_> = fs::read_dir(&roms).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    for name in names.iter() {
+        let rom = fs::read(roms.join(name)).unwrap();
+        compare(name, &rom, FRAMES);
+    }
+}
+
+#[test]
+fn self_modifying_code() {
+    // 200: V1 += 1
+    // 202: I = 0x201, V0 = 2
+    // 206: store V0 over the constant of the first instruction
+    // 208: jump back to the start
+    let rom = [0x71, 0x01, 0xA2, 0x01, 0x60, 0x02, 0xF0, 0x55, 0x12, 0x00];
+    compare("self modifying", &rom, 10);
+}
+
+#[test]
+fn stack_overflow() {
+    // 200: call 0x200 forever, overflowing the stack after 16 calls
+    compare("stack overflow", &[0x22, 0x00], 5);
+}
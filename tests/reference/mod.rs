@@ -0,0 +1,302 @@
+//! An independent reference model of the CHIP-8 instruction set and a
+//! differential checker that runs it side by side with `CPU`.
+//!
+//! The model is deliberately written differently from `cpu.rs`: it decodes
+//! by instruction group, keeps the screen as a grid of booleans and does all
+//! address arithmetic modulo 4096 explicitly. It is shared by the
+//! `differential` test and the `interpreter` fuzz target.
+
+use chip8::cpu::{CPU, State};
+
+const MEMORY_SIZE: usize = 4096;
+
+pub struct Reference {
+    pub memory: Vec<u8>,
+    pub v: [u8; 16],
+    pub i: usize,
+    pub delay: u8,
+    pub sound: u8,
+    pub pc: usize,
+    pub stack: Vec<usize>,
+    pub screen: [[bool; 64]; 32],
+    pub keys: [bool; 16],
+}
+
+impl Reference {
+    pub fn from_state(state: &State) -> Reference {
+        let mut screen = [[false; 64]; 32];
+        for y in 0..32 {
+            for x in 0..64 {
+                screen[y][x] = state.screen[y] & (1 << (63 - x)) != 0;
+            }
+        }
+        Reference {
+            memory: state.memory.clone(),
+            v: state.v,
+            i: state.i as usize,
+            delay: state.delay,
+            sound: state.sound,
+            pc: state.pc as usize,
+            stack: state.stack[..state.sp as usize].iter().map(|&a| a as usize).collect(),
+            screen,
+            keys: state.keys,
+        }
+    }
+
+    /// Convert back to a `State` for comparison. Unused stack slots and the
+    /// random number generator are taken from `like`, since the model does
+    /// not track them
+    pub fn to_state(&self, like: &State) -> State {
+        let mut stack = like.stack;
+        for (slot, &addr) in self.stack.iter().enumerate() {
+            stack[slot] = addr as u16;
+        }
+        let mut screen = [0u64; 32];
+        for y in 0..32 {
+            for x in 0..64 {
+                if self.screen[y][x] {
+                    screen[y] |= 1 << (63 - x);
+                }
+            }
+        }
+        State {
+            memory: self.memory.clone(),
+            v: self.v,
+            i: self.i as u16,
+            delay: self.delay,
+            sound: self.sound,
+            pc: self.pc as u16,
+            sp: self.stack.len() as u8,
+            stack,
+            screen,
+            keys: self.keys,
+            rng: like.rng,
+        }
+    }
+
+    fn read(&self, offset: usize) -> u8 {
+        self.memory[(self.i + offset) % MEMORY_SIZE]
+    }
+
+    fn write(&mut self, offset: usize, value: u8) {
+        self.memory[(self.i + offset) % MEMORY_SIZE] = value;
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.pc = (self.pc + 2) % MEMORY_SIZE;
+        }
+    }
+
+    /// Execute one instruction. `random` is the value `Cxkk` should use
+    /// before masking
+    pub fn step(&mut self, random: u8) {
+        let hi = self.memory[self.pc];
+        let lo = self.memory[(self.pc + 1) % MEMORY_SIZE];
+        let opcode = (hi as u16) << 8 | lo as u16;
+        let x = (hi & 0xF) as usize;
+        let y = (lo >> 4) as usize;
+        let n = (lo & 0xF) as usize;
+        let nnn = (opcode & 0xFFF) as usize;
+        let (vx, vy) = (self.v[x], self.v[y]);
+        self.pc = (self.pc + 2) % MEMORY_SIZE;
+
+        match hi >> 4 {
+            0x0 if opcode == 0x00E0 => self.screen = [[false; 64]; 32],
+            0x0 if opcode == 0x00EE => {
+                if let Some(addr) = self.stack.pop() {
+                    self.pc = addr;
+                }
+            },
+            0x1 => self.pc = nnn,
+            0x2 => {
+                if self.stack.len() < 16 {
+                    self.stack.push(self.pc);
+                    self.pc = nnn;
+                }
+            },
+            0x3 => self.skip_if(vx == lo),
+            0x4 => self.skip_if(vx != lo),
+            0x5 if n == 0 => self.skip_if(vx == vy),
+            0x6 => self.v[x] = lo,
+            0x7 => self.v[x] = ((vx as u16 + lo as u16) % 256) as u8,
+            0x8 => {
+                let (result, flag) = match n {
+                    0x0 => (vy, None),
+                    0x1 => (vx | vy, None),
+                    0x2 => (vx & vy, None),
+                    0x3 => (vx ^ vy, None),
+                    0x4 => {
+                        let sum = vx as u16 + vy as u16;
+                        ((sum % 256) as u8, Some((sum > 255) as u8))
+                    },
+                    0x5 => (((vx as i16 - vy as i16 + 256) % 256) as u8, Some((vx >= vy) as u8)),
+                    0x6 => (vy / 2, Some(vy % 2)),
+                    0x7 => (((vy as i16 - vx as i16 + 256) % 256) as u8, Some((vy >= vx) as u8)),
+                    0xE => (((vy as u16 * 2) % 256) as u8, Some(vy / 128)),
+                    _ => return
+                };
+                self.v[x] = result;
+                if let Some(flag) = flag {
+                    self.v[0xF] = flag;
+                }
+            },
+            0x9 if n == 0 => self.skip_if(vx != vy),
+            0xA => self.i = nnn,
+            0xB => self.pc = (nnn + self.v[0] as usize) % MEMORY_SIZE,
+            0xC => self.v[x] = random & lo,
+            0xD => {
+                // the start wraps onto the screen, and so does the rest
+                let (left, top) = (vx as usize % 64, vy as usize % 32);
+                let mut collision = false;
+                for row in 0..n {
+                    let bits = self.read(row);
+                    for col in 0..8 {
+                        if bits & (0x80 >> col) == 0 {
+                            continue;
+                        }
+                        let pixel = &mut self.screen[(top + row) % 32][(left + col) % 64];
+                        collision |= *pixel;
+                        *pixel = !*pixel;
+                    }
+                }
+                self.v[0xF] = collision as u8;
+            },
+            0xE if lo == 0x9E => self.skip_if(self.keys[(vx % 16) as usize]),
+            0xE if lo == 0xA1 => self.skip_if(!self.keys[(vx % 16) as usize]),
+            0xF => match lo {
+                0x07 => self.v[x] = self.delay,
+                // only a key pressed while waiting answers, and keys never
+                // change while a check runs
+                0x0A => self.pc = (self.pc + MEMORY_SIZE - 2) % MEMORY_SIZE,
+                0x15 => self.delay = vx,
+                0x18 => self.sound = vx,
+                0x1E => self.i = (self.i + vx as usize) % MEMORY_SIZE,
+                0x29 => self.i = (vx % 16) as usize * 5,
+                0x33 => {
+                    self.write(0, vx / 100);
+                    self.write(1, vx / 10 % 10);
+                    self.write(2, vx % 10);
+                },
+                0x55 => {
+                    for r in 0..=x {
+                        let value = self.v[r];
+                        self.write(r, value);
+                    }
+                    self.i = (self.i + x + 1) % MEMORY_SIZE;
+                },
+                0x65 => {
+                    for r in 0..=x {
+                        self.v[r] = self.read(r);
+                    }
+                    self.i = (self.i + x + 1) % MEMORY_SIZE;
+                },
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Reads bytes from the fuzz input, yielding zeros once it runs out
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn u8(&mut self) -> u8 {
+        match self.data.split_first() {
+            Some((&byte, rest)) => {
+                self.data = rest;
+                byte
+            },
+            None => 0
+        }
+    }
+
+    fn u16(&mut self) -> u16 {
+        (self.u8() as u16) << 8 | self.u8() as u16
+    }
+}
+
+/// Maximum number of instructions executed per input
+const MAX_STEPS: usize = 256;
+
+/// Number of input bytes used to build the machine state before the
+/// instruction stream starts
+pub const STATE_BYTES: usize = 2 + 16 + 2 + 2 + 1 + 32 + 2 + 2 + 32 * 4;
+
+/// Build a random machine state from `data`, then run the rest of `data` as
+/// an instruction stream on both `CPU` and the reference model, checking
+/// invariants and comparing the two after every instruction. Panics on the
+/// first difference
+pub fn check(data: &[u8]) {
+    let mut input = Input { data };
+    let mut cpu = CPU::new();
+    cpu.seed(input.u16() as u32);
+
+    let mut state = cpu.state();
+    for value in state.v.iter_mut() {
+        *value = input.u8();
+    }
+    state.i = input.u16() & 0xFFF;
+    state.pc = input.u16() & 0xFFF;
+    state.sp = input.u8() % 17;
+    for slot in state.stack.iter_mut() {
+        *slot = input.u16() & 0xFFF;
+    }
+    state.delay = input.u8();
+    state.sound = input.u8();
+    let keys = input.u16();
+    for (key, down) in state.keys.iter_mut().enumerate() {
+        *down = keys & (1 << key) != 0;
+    }
+    for row in state.screen.iter_mut() {
+        *row = (input.u16() as u64) << 48 | (input.u16() as u64) << 16;
+    }
+    // the rest of the input is the program, starting at the PC
+    let program = input.data;
+    for (offset, &byte) in program.iter().take(state.memory.len()).enumerate() {
+        state.memory[(state.pc as usize + offset) % MEMORY_SIZE] = byte;
+    }
+    cpu.set_state(&state).unwrap();
+    let mut reference = Reference::from_state(&state);
+
+    for step in 0..MAX_STEPS.min(program.len() / 2 + 1) {
+        // go through the decoded instruction cache, which random programs
+        // frequently invalidate by storing over their own code
+        let instruction = cpu.read_instruction();
+        cpu.cycle();
+        let actual = cpu.state();
+
+        assert!(actual.pc < 0x1000, "PC {:#x} outside of memory", actual.pc);
+        assert!(actual.i < 0x1000, "I {:#x} outside of memory", actual.i);
+        assert!(actual.sp <= 16, "SP {} outside of the stack", actual.sp);
+
+        // the model can't predict the CPU's random numbers, so it uses the
+        // one the CPU generated after checking it respects the mask
+        let random = if instruction & 0xF000 == 0xC000 {
+            let (x, kk) = ((instruction >> 8 & 0xF) as usize, instruction as u8);
+            assert_eq!(actual.v[x] & !kk, 0, "{:#06x} ignored its mask", instruction);
+            actual.v[x]
+        } else {
+            0
+        };
+        reference.step(random);
+
+        let expected = reference.to_state(&actual);
+        if actual != expected {
+            panic!(
+                "step {}: {:#06x} diverged from the reference model\n\
+                 expected v={:?} i={:#x} pc={:#x} sp={} delay={} sound={}\n\
+                 actual   v={:?} i={:#x} pc={:#x} sp={} delay={} sound={}\n\
+                 memory equal: {}, screen equal: {}, stack equal: {}",
+                step, instruction,
+                expected.v, expected.i, expected.pc, expected.sp, expected.delay, expected.sound,
+                actual.v, actual.i, actual.pc, actual.sp, actual.delay, actual.sound,
+                expected.memory == actual.memory,
+                expected.screen == actual.screen,
+                expected.stack == actual.stack);
+        }
+    }
+}
@@ -1,10 +1,35 @@
 const path = require('path');
 
-module.exports = {
-    entry: './index.js',
+const output = path.resolve(__dirname, 'dist');
+
+module.exports = [
+  {
+    entry: {
+      index: './index.js',
+      compare: './compare.js',
+    },
+    output: {
+      path: output,
+      filename: '[name].js',
+    },
+    mode: 'development',
+    // SharedArrayBuffer framebuffers for the Web Worker need the page to
+    // be cross-origin isolated
+    devServer: {
+      headers: {
+        'Cross-Origin-Opener-Policy': 'same-origin',
+        'Cross-Origin-Embedder-Policy': 'require-corp',
+      },
+    },
+  },
+  {
+    entry: './worker.js',
+    target: 'webworker',
     output: {
-        path: path.resolve(__dirname, 'dist'),
-        filename: 'index.js',
+      path: output,
+      filename: 'worker.js',
+      chunkFilename: 'worker.[id].js',
     },
-    mode: 'development'
-};
+    mode: 'development',
+  },
+];
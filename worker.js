@@ -0,0 +1,100 @@
+// Runs any number of machines inside a Web Worker, so emulation never holds
+// up the page. Pages talk to it through `Machines` in machines.js.
+//
+// Every message names its machine with `id` and gets a reply with the same
+// `id` and `seq`, or with `error` if it failed:
+//
+//   {type: 'create', id, seed, framebuffer}
+//       framebuffer is an optional SharedArrayBuffer of 64 * 32 * 4 bytes
+//       that `run` draws into, instead of sending a copy of the screen
+//   {type: 'load', id, rom}
+//   {type: 'configure', id, quirks, cyclesPerFrame}
+//       quirks maps chip-8-database quirk names to true or false, and a
+//       cyclesPerFrame of 0 keeps the speed. Nothing changes if either is
+//       invalid
+//   {type: 'run', id, frames, keys}
+//       keys has a bit for each key held. Replies with the frame number,
+//       the status ('running', 'waiting' for a key or 'halted'), the
+//       beeper's state on each frame run, whether the screen changed and,
+//       without a shared framebuffer, the screen as RGBA
+//   {type: 'save', id}              replies with the machine's state
+//   {type: 'restore', id, state}
+//   {type: 'destroy', id}
+const STATUS = ['running', 'waiting', 'halted'];
+const FRAMEBUFFER_LEN = 64 * 32 * 4;
+
+const rust = import('./chip8');
+
+// machine and shared framebuffer, if any, by id
+const machines = new Map();
+
+const handle = (MachineWrapper, message, reply, transfer) => {
+  if (message.type === 'create') {
+    const framebuffer = message.framebuffer ? new Uint8Array(message.framebuffer) : null;
+    machines.set(message.id, { machine: MachineWrapper.new(message.seed >>> 0), framebuffer });
+    return;
+  }
+  const entry = machines.get(message.id);
+  if (!entry) {
+    throw `no machine ${message.id}`;
+  }
+  const { machine } = entry;
+  switch (message.type) {
+    case 'load':
+      machine.load(message.rom);
+      break;
+    case 'configure': {
+      const cycles = message.cyclesPerFrame || 0;
+      if (!Number.isInteger(cycles) || cycles < 0) {
+        throw `invalid cyclesPerFrame ${message.cyclesPerFrame}`;
+      }
+      // checks every quirk before setting any
+      machine.set_quirks(JSON.stringify(message.quirks || {}));
+      if (cycles > 0) {
+        machine.set_cycles_per_frame(cycles);
+      }
+      break;
+    }
+    case 'run': {
+      machine.set_keys(message.keys || 0);
+      reply.status = STATUS[machine.run(message.frames)];
+      reply.frame = machine.frame();
+      reply.audio = Array.from(machine.audio(), beeping => beeping === 1);
+      reply.drawn = machine.drawn();
+      if (entry.framebuffer) {
+        machine.write_framebuffer(entry.framebuffer);
+      } else {
+        reply.framebuffer = new Uint8Array(FRAMEBUFFER_LEN);
+        machine.write_framebuffer(reply.framebuffer);
+        transfer.push(reply.framebuffer.buffer);
+      }
+      break;
+    }
+    case 'save':
+      reply.state = machine.save_state();
+      transfer.push(reply.state.buffer);
+      break;
+    case 'restore':
+      machine.load_state(message.state);
+      break;
+    case 'destroy':
+      machine.free();
+      machines.delete(message.id);
+      break;
+    default:
+      throw `unknown message ${message.type}`;
+  }
+};
+
+self.onmessage = async ({ data: message }) => {
+  const { MachineWrapper } = await rust;
+  const reply = { id: message.id, seq: message.seq };
+  const transfer = [];
+  try {
+    handle(MachineWrapper, message, reply, transfer);
+  } catch (e) {
+    reply.error = String(e);
+    transfer.length = 0;
+  }
+  self.postMessage(reply, transfer);
+};